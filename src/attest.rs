@@ -0,0 +1,117 @@
+// Signed statements that a test passed for a given tree, so that people can
+// trust each other's results instead of running expensive tests themselves.
+// Signing is done by ssh-keygen, the same way Git does SSH commit signing.
+
+use std::{
+    ffi::OsString,
+    fs, io,
+    path::{Path, PathBuf},
+    process::Stdio,
+};
+
+use anyhow::{anyhow, Context as _};
+use serde::{Deserialize, Serialize};
+use tokio::{io::AsyncWriteExt as _, process::Command};
+
+use crate::{
+    process::{CommandExt as _, OutputExt as _, TrackedChild},
+    test::ConfigHash,
+    util::IoResultExt as _,
+};
+
+// Stops signatures made for other purposes with the same key from being
+// passed off as attestations.
+const NAMESPACE: &str = "limmat-attestation";
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Attestation {
+    pub test: String,
+    pub commit: String,
+    pub tree: String,
+    // Identifies the test definition, so the verifier can check it's the same
+    // as theirs.
+    pub config_hash: ConfigHash,
+    pub host: String,
+}
+
+// ssh-keygen always puts the signature next to the signed file.
+pub fn signature_path(path: &Path) -> PathBuf {
+    let mut sig_path = OsString::from(path);
+    sig_path.push(".sig");
+    sig_path.into()
+}
+
+// Write the attestation to path and sign it with the SSH key.
+pub async fn write_signed(
+    attestation: &Attestation,
+    path: &Path,
+    key: &Path,
+) -> anyhow::Result<()> {
+    let content = serde_json::to_vec_pretty(attestation).expect("failed to serialize attestation");
+    fs::write(path, content).with_context(|| format!("writing {}", path.display()))?;
+    // ssh-keygen won't overwrite an old signature.
+    let sig_path = signature_path(path);
+    fs::remove_file(&sig_path)
+        .ignore(io::ErrorKind::NotFound)
+        .with_context(|| format!("removing old {}", sig_path.display()))?;
+    Command::new("ssh-keygen")
+        .args(["-Y", "sign", "-n", NAMESPACE, "-f"])
+        .arg(key)
+        .arg(path)
+        .execute()
+        .await
+        .context("signing attestation")?;
+    Ok(())
+}
+
+// Check the attestation at path was signed by one of the principals in
+// allowed_signers (in the format described in ssh-keygen(1)). Returns the
+// attestation and the principal that signed it.
+pub async fn read_verified(
+    path: &Path,
+    allowed_signers: &Path,
+) -> anyhow::Result<(Attestation, String)> {
+    let sig_path = signature_path(path);
+    let output = Command::new("ssh-keygen")
+        .args(["-Y", "find-principals", "-f"])
+        .arg(allowed_signers)
+        .arg("-s")
+        .arg(&sig_path)
+        .execute()
+        .await
+        .context("signature not made by any allowed signer")?;
+    let principals = String::from_utf8(output.stdout).context("non utf-8 principal")?;
+    let principal = principals
+        .lines()
+        .next()
+        .ok_or_else(|| anyhow!("signature not made by any allowed signer"))?
+        .to_owned();
+    // Read it once, so that what we parse is what we verified.
+    let content = fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+    let mut child = TrackedChild::spawn(
+        Command::new("ssh-keygen")
+            .args(["-Y", "verify", "-n", NAMESPACE, "-f"])
+            .arg(allowed_signers)
+            .arg("-I")
+            .arg(&principal)
+            .arg("-s")
+            .arg(&sig_path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped()),
+    )
+    .context("running ssh-keygen")?;
+    let mut stdin = child.take_stdin().expect("no stdin pipe");
+    stdin
+        .write_all(&content)
+        .await
+        .context("writing attestation to ssh-keygen")?;
+    drop(stdin);
+    let output = child
+        .wait_with_output()
+        .await
+        .context("running ssh-keygen")?;
+    output.ok().context("bad signature")?;
+    let attestation = serde_json::from_slice(&content).context("parsing attestation")?;
+    Ok((attestation, principal))
+}
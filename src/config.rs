@@ -3,27 +3,39 @@ use std::{
     collections::{HashMap, HashSet},
     ffi::OsString,
     hash::Hash as _,
-    path::PathBuf,
+    iter,
+    path::{Component, Path, PathBuf},
     sync::Arc,
+    thread::available_parallelism,
     time::Duration,
 };
 
-use anyhow::{bail, Context as _};
+use anyhow::{anyhow, bail, Context as _};
+use clap::ValueEnum;
+use itertools::Itertools as _;
 #[allow(unused_imports)]
 use log::debug;
+use nix::{sys::stat::Mode, unistd::User};
 use regex::Regex;
 use schemars::JsonSchema;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use sha3::{Digest, Sha3_256};
 
 use crate::{
     dag::{Dag, GraphNode},
-    resource::{self, Pools, ResourceKey},
-    test::{self, CachePolicy, ExitCode, TestDag, TestName},
-    util::DigestHasher,
+    flock::LockMethod,
+    git::{Worktree, WorktreeOptions},
+    gpu,
+    resource::{self, Alternatives, Pools, ResourceKey},
+    test::{self, CachePolicy, CheckoutMode, ExitCode, Namespace, Network, TestDag, TestName},
+    text::Color,
+    util::{
+        available_cpus, free_space, parse_duration, parse_size, shell_quote, total_memory,
+        DigestHasher,
+    },
 };
 
-#[derive(Deserialize, JsonSchema, Debug, Hash, Clone)]
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Hash, Clone)]
 #[serde(deny_unknown_fields)]
 #[serde(untagged)]
 pub enum Resource {
@@ -55,43 +67,176 @@ impl Resource {
     }
 }
 
-#[derive(Deserialize, JsonSchema, Debug, Hash, Clone)]
+// How a test refers to the resources it needs.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Hash, Clone)]
+#[serde(deny_unknown_fields)]
+#[serde(untagged)]
+pub enum ResourceRef {
+    Single(Resource),
+    /// Take count tokens from the first of these resources that has enough
+    /// available, in order of preference. The job can tell which one it got
+    /// from which LIMMAT_RESOURCE_ variables are set.
+    AnyOf {
+        any_of: Vec<String>,
+        #[serde(default = "default_count")]
+        count: usize,
+    },
+}
+
+fn default_count() -> usize {
+    1
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Hash, Clone)]
 #[serde(deny_unknown_fields)]
 #[serde(untagged)]
 pub enum Command {
     Shell(String),
     Raw(Vec<String>),
+    /// Commands to run one after the other in the same directory, stopping at
+    /// the first one that fails.
+    Steps {
+        steps: Vec<Command>,
+    },
 }
 
+// Runs each of its arguments as a shell command. The output of each step is
+// preceded by a line saying which one it is, and the number of the step that
+// failed is written to LIMMAT_FAILED_STEP_FILE.
+const STEPS_SCRIPT: &str = r#"
+n=0
+for step in "$@"; do
+    n=$((n + 1))
+    echo "limmat: step $n/$#: $step" >&2
+    bash -c "$step" || {
+        status=$?
+        echo "limmat: step $n/$# failed with status $status" >&2
+        echo "$n" > "$LIMMAT_FAILED_STEP_FILE"
+        exit $status
+    }
+done
+"#;
+
 impl Command {
     pub fn program(&self) -> OsString {
         match self {
-            Self::Shell(_) => "bash".into(), // TODO: Figure out the user's configured shell.
+            // TODO: Figure out the user's configured shell.
+            Self::Shell(_) | Self::Steps { .. } => "bash".into(),
             Self::Raw(args) => args[0].clone().into(),
         }
     }
 
+    // Program followed by its arguments.
+    pub fn argv(&self) -> Vec<OsString> {
+        iter::once(self.program()).chain(self.args()).collect()
+    }
+
     pub fn args(&self) -> Vec<OsString> {
         match self {
             Self::Shell(cmd) => vec!["-c".into(), cmd.into()],
             Self::Raw(args) => args[1..].iter().map(|s| s.into()).collect(),
+            Self::Steps { steps } => ["-c", STEPS_SCRIPT, "limmat-steps"]
+                .into_iter()
+                .map(OsString::from)
+                .chain(steps.iter().map(|step| step.shell().into()))
+                .collect(),
+        }
+    }
+
+    // As a single command line for the shell.
+    fn shell(&self) -> String {
+        match self {
+            Self::Shell(cmd) => cmd.clone(),
+            Self::Raw(_) | Self::Steps { .. } => self
+                .argv()
+                .iter()
+                .map(|arg| shell_quote(&arg.to_string_lossy()))
+                .join(" "),
         }
     }
 }
 
-#[derive(Deserialize, JsonSchema, Debug, Hash, Clone)]
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct Test {
     name: String,
     command: Command,
+    /// What the test checks. It's shown by "limmat list", when hovering over
+    /// the test's name in the web UI, and in the notification when it fails
+    /// (see the "bell" UI setting). Changing it doesn't affect caching.
+    description: Option<String>,
+    /// Who to ask about the test, shown in the same places as description.
+    owner: Option<String>,
+    /// Link to documentation for the test, like a runbook for when it fails.
+    /// Failures of the test link to it with a 📖 in the status view, and
+    /// "limmat get" prints it for them. Changing it doesn't affect caching.
+    help_url: Option<String>,
+    /// Command to run before the test command, in the same directory and with
+    /// the same environment. If it fails, the test command isn't run and the
+    /// job is reported as an error. Its output goes to setup.txt in the result
+    /// directory.
+    setup: Option<Command>,
+    /// Command to run after the test command (or after the setup command if
+    /// that failed). It runs even if the job is canceled, and doesn't get
+    /// SIGTERMed. If it fails that's recorded separately, it doesn't affect
+    /// the test result. Its output goes to teardown.txt in the result
+    /// directory.
+    teardown: Option<Command>,
+    /// Command to push the test's artifacts somewhere off this machine, like
+    /// a bucket or a fileserver. It's run after the test command passes (and
+    /// after the teardown command), in the same directory and with the same
+    /// environment, so the artifacts are in $LIMMAT_ARTIFACTS. If it fails
+    /// that's recorded separately, it doesn't affect the test result. Its
+    /// output goes to upload.txt in the result directory. Changing it doesn't
+    /// affect caching.
+    artifact_upload: Option<ArtifactUpload>,
+    /// Space the test's results (output, artifacts and so on) can take up in
+    /// the result database, like "20G" (the suffixes K, M, G and T are powers
+    /// of 1024). "limmat gc" deletes the oldest ones that aren't pinned until
+    /// the test is back under its quota. Changing it doesn't affect caching.
+    storage_quota: Option<String>,
+    #[serde(default)]
+    /// Files or directories, relative to $LIMMAT_ARTIFACTS, that the test
+    /// produces. If the test passes without producing one of them, the job is
+    /// reported as an error. Tests that depend on this one get the path of
+    /// each output in LIMMAT_ARTIFACT_<test name>_<output>, with characters
+    /// other than letters and digits in the output's name replaced by
+    /// underscores, like LIMMAT_ARTIFACT_build_report_json.
+    outputs: Vec<String>,
     #[serde(default = "default_requires_worktree")]
     requires_worktree: bool,
     #[serde(default = "default_true")]
     /// If this is disabled, the test is only run when explicitly requested in
-    /// the command-line via the --tests arg.
+    /// the command-line via the --tests arg, or for a single commit with
+    /// "limmat ctl run".
     run_by_default: bool,
+    #[serde(default)]
+    /// If this is enabled, failures of the test are shown in a different
+    /// colour, but they don't count towards the commit's verdict (shown at the
+    /// start of its line in the status view and by "limmat get --verdict"), or
+    /// make "limmat wait", "limmat gate" or "limmat watch --once" fail.
+    advisory: Option<bool>,
+    #[serde(default, skip_serializing)]
+    /// Deprecated, "required = false" is the same as "advisory = true". Can't
+    /// be set along with advisory.
+    required: Option<bool>,
     // TODO: This should only refer to resource names.
-    resources: Option<Vec<Resource>>,
+    resources: Option<Vec<ResourceRef>>,
+    /// Number of CPU cores to reserve for the test, out of the ones Limmat is
+    /// allowed to run on. The job only runs on the cores it reserved, and they
+    /// are exported as a comma-separated list in LIMMAT_CPUS.
+    cpus: Option<usize>,
+    /// Memory the test needs, like "8G" (the suffixes K, M, G and T are powers
+    /// of 1024). Tests don't get started unless the memory declared by all the
+    /// running tests, including this one, fits in the system's RAM. This isn't
+    /// enforced, it's just taken at the test's word.
+    memory: Option<String>,
+    /// Number of GPUs to reserve for the test. They're found when Limmat
+    /// starts, with nvidia-smi if it's installed or otherwise by looking for
+    /// render nodes in /dev/dri. The reserved GPUs are exported as a
+    /// comma-separated list in LIMMAT_GPUS (NVIDIA GPU indexes or render node
+    /// paths), and for NVIDIA GPUs also in CUDA_VISIBLE_DEVICES.
+    gpus: Option<usize>,
     #[serde(default = "default_shutdown_grace_period")]
     /// When a job is no longer needed it's SIGTERMed. If it doesn't respond (by
     /// dying) after this duration it will then be SIGKILLed. This also affects
@@ -100,8 +245,27 @@ pub struct Test {
     shutdown_grace_period_s: u64,
     #[serde(default = "default_cache_policy")]
     cache: CachePolicy,
+    /// How long a failure stays cached, like "30m", "12h" or "7d". Once it's
+    /// older than this the test is run again when it's next needed. Passes
+    /// stay cached. Useful for tests whose failures are often environmental
+    /// and go away on their own.
+    failure_cache_ttl: Option<String>,
+    /// Flag a commit if a passing run of this test on it takes more than this
+    /// many percent longer than the test's recent average. Flagged results are
+    /// marked with 🐢 in the status view and listed by "limmat slowdowns".
+    max_slowdown_percent: Option<u32>,
+    #[serde(default)]
+    /// Fields of this test that don't count as part of its configuration for
+    /// caching, so changing them doesn't throw away its results. Only fields
+    /// that don't affect what the test does can be listed: resources, cpus,
+    /// memory, gpus, shutdown_grace_period_s, cache, failure_cache_ttl,
+    /// max_slowdown_percent, stage, run_by_default, advisory,
+    /// error_exit_codes, skip_if_unchanged and when.
+    ignore_for_cache: Vec<String>,
     #[serde(default)]
     depends_on: Vec<String>,
+    /// Name of the stage this test belongs to, from the top-level stages list.
+    stage: Option<String>,
     #[serde(default)]
     /// If the command exits with an error code listed in this field, instead of
     /// being considered a "failure", it's considered an "error". Errors are not
@@ -113,6 +277,95 @@ pub struct Test {
     /// When false (default), stdout and stderr are merged into output.txt.
     /// When true, they are kept separate as stdout.txt and stderr.txt.
     separate_outputs: bool,
+    #[serde(default)]
+    /// Start each line of the output with the number of seconds since the test
+    /// command started, and with separate_outputs, which stream it came from.
+    /// That way the streams can be put back together in order with sort(1).
+    timestamp_output: bool,
+    /// Directory to run the command in. Relative paths are resolved against
+    /// the root of the worktree the test runs in, which for tests with
+    /// requires_worktree=false is the main worktree. Absolute paths are only
+    /// allowed when requires_worktree=false.
+    cwd: Option<PathBuf>,
+    #[serde(default)]
+    /// By default the worktree is checked out with a detached HEAD. Set this to
+    /// "branch" to check out a temporary branch instead, named after the test
+    /// case and exported as LIMMAT_BRANCH. Set it to "archive" to run the
+    /// test in a fresh directory with just the commit's files in it, on a
+    /// tmpfs (/dev/shm) if there is one. There's no .git so the test can't run
+    /// git commands, but it doesn't take up a worktree and doesn't touch the
+    /// repository's locks. Both are only allowed with requires_worktree=true.
+    checkout: CheckoutMode,
+    /// Octal umask for the job's processes, like "027".
+    umask: Option<String>,
+    #[serde(default)]
+    sandbox: Sandbox,
+    #[serde(default)]
+    /// Set this to "none" to run the job in a new network namespace where
+    /// there's nothing but a loopback interface, so that it can't secretly use
+    /// the network. This needs the ip command from iproute2.
+    network: Network,
+    #[serde(default)]
+    /// Glob patterns, relative to the root of the repository. If set, the test
+    /// is skipped for commits that don't add, modify or delete any file
+    /// matching one of them (compared to the commit's first parent). "*"
+    /// doesn't match "/", use "**" to match any number of directories.
+    skip_if_unchanged: Vec<String>,
+    #[serde(default)]
+    /// Only test commits that meet all of these conditions. For other commits
+    /// the test doesn't exist at all, unless another test that does exist
+    /// depends on it.
+    when: When,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Hash, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ArtifactUpload {
+    command: Command,
+    /// How many more times to try if the command fails.
+    #[serde(default)]
+    retries: u32,
+    /// Seconds to wait before each retry.
+    #[serde(default = "default_retry_delay")]
+    retry_delay_s: u64,
+}
+
+fn default_retry_delay() -> u64 {
+    10
+}
+
+// Applies to the setup and teardown commands too.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Hash, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Sandbox {
+    /// Run the job as this user. Limmat has to be running as root for this.
+    /// The user needs to be able to access the worktree, Limmat doesn't
+    /// change its ownership.
+    user: Option<String>,
+    #[serde(default)]
+    /// Give the job its own temporary directory, exported as TMPDIR and
+    /// deleted when the job finishes.
+    private_tmp: bool,
+    #[serde(default)]
+    /// Run the job in new namespaces of these kinds ("mount", "net" or "pid"),
+    /// using unshare(1). With "net", the job only has a loopback interface,
+    /// which is down. With "pid", the test command is PID 1 in its namespace,
+    /// so it only gets SIGTERMed if it has a handler for SIGTERM.
+    unshare: Vec<Namespace>,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Hash, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct When {
+    #[serde(default)]
+    /// Glob patterns, like in skip_if_unchanged. The commit has to add, modify
+    /// or delete a file matching one of them.
+    paths: Vec<String>,
+    /// Regex that has to match somewhere in the commit message.
+    message: Option<String>,
+    /// Regex that has to match somewhere in the commit author, formatted like
+    /// "Name <email>".
+    author: Option<String>,
 }
 
 fn default_requires_worktree() -> bool {
@@ -141,35 +394,172 @@ impl GraphNode for Test {
 }
 
 impl Test {
+    // A test that isn't in the config file, for running a one-off command.
+    pub fn ad_hoc(name: String, command: Vec<String>) -> Self {
+        Self {
+            name,
+            command: Command::Raw(command),
+            description: None,
+            owner: None,
+            help_url: None,
+            setup: None,
+            teardown: None,
+            artifact_upload: None,
+            storage_quota: None,
+            outputs: Vec::new(),
+            requires_worktree: true,
+            run_by_default: true,
+            advisory: None,
+            required: None,
+            resources: None,
+            cpus: None,
+            memory: None,
+            gpus: None,
+            shutdown_grace_period_s: default_shutdown_grace_period(),
+            cache: CachePolicy::NoCaching,
+            failure_cache_ttl: None,
+            max_slowdown_percent: None,
+            ignore_for_cache: Vec::new(),
+            depends_on: Vec::new(),
+            stage: None,
+            error_exit_codes: Vec::new(),
+            separate_outputs: default_separate_outputs(),
+            timestamp_output: false,
+            cwd: None,
+            checkout: CheckoutMode::default(),
+            umask: None,
+            sandbox: Sandbox::default(),
+            network: Network::default(),
+            skip_if_unchanged: Vec::new(),
+            when: When::default(),
+        }
+    }
+
+    // Copy with the fields from ignore_for_cache reset to their defaults, so
+    // that changing them doesn't change the config hash.
+    fn for_config_hash(&self) -> anyhow::Result<Self> {
+        let mut test = self.clone();
+        test.ignore_for_cache = Vec::new();
+        test.description = None;
+        test.owner = None;
+        test.help_url = None;
+        test.artifact_upload = None;
+        test.storage_quota = None;
+        // So that it doesn't matter how advisory was spelled.
+        test.advisory = self.advisory()?.then_some(true);
+        test.required = None;
+        for field in &self.ignore_for_cache {
+            match field.as_str() {
+                "resources" => test.resources = None,
+                "cpus" => test.cpus = None,
+                "memory" => test.memory = None,
+                "gpus" => test.gpus = None,
+                "shutdown_grace_period_s" => {
+                    test.shutdown_grace_period_s = default_shutdown_grace_period()
+                }
+                "cache" => test.cache = default_cache_policy(),
+                "failure_cache_ttl" => test.failure_cache_ttl = None,
+                "max_slowdown_percent" => test.max_slowdown_percent = None,
+                "stage" => test.stage = None,
+                "run_by_default" => test.run_by_default = true,
+                "advisory" | "required" => test.advisory = None,
+                "error_exit_codes" => test.error_exit_codes = Vec::new(),
+                "skip_if_unchanged" => test.skip_if_unchanged = Vec::new(),
+                "when" => test.when = When::default(),
+                _ => bail!("{field:?} can't be listed in ignore_for_cache"),
+            }
+        }
+        Ok(test)
+    }
+
+    // required used to be how you made a test advisory, it's still accepted.
+    fn advisory(&self) -> anyhow::Result<bool> {
+        match (self.advisory, self.required) {
+            (Some(_), Some(_)) => bail!("required can't be set along with advisory"),
+            (advisory, required) => Ok(advisory.or(required.map(|r| !r)).unwrap_or(false)),
+        }
+    }
+
+    // What goes into the config hash: the test as JSON, without the fields
+    // that are at their defaults. That way adding a field to this struct
+    // doesn't change the hash of configs that don't set it, so upgrading
+    // Limmat doesn't throw away everyone's results. Object keys are sorted.
+    fn canonical_for_hash(&self) -> anyhow::Result<String> {
+        let serde_json::Value::Object(mut fields) =
+            serde_json::to_value(self.for_config_hash()?).expect("failed to serialize test")
+        else {
+            unreachable!("test didn't serialize to an object");
+        };
+        let defaults: Self = serde_json::from_value(serde_json::json!({"name": "", "command": ""}))
+            .expect("failed to build default test");
+        let serde_json::Value::Object(defaults) =
+            serde_json::to_value(defaults).expect("failed to serialize test")
+        else {
+            unreachable!("test didn't serialize to an object");
+        };
+        fields.retain(|name, value| defaults.get(name) != Some(value));
+        Ok(serde_json::Value::Object(fields).to_string())
+    }
+
     // Convert to the "real" object. other_tests is the set of other tests that
     // have already been parsed, which must include all of these test's
     // transitive dependencies (or this will panic).
-    pub fn parse(&self, other_tests: &Dag<Arc<test::Test>>) -> anyhow::Result<test::Test> {
-        let mut seen_resources = HashSet::new();
-        for resource in self.resources.as_ref().unwrap_or(&vec![]) {
-            if seen_resources.contains(&resource.name()) {
-                // TODO: Need better error messages.
-                bail!("duplicate resource reference {:?}", resource.name());
+    pub fn parse(
+        &self,
+        other_tests: &Dag<Arc<test::Test>>,
+        stages: &[String],
+    ) -> anyhow::Result<test::Test> {
+        let mut needs_resources: HashMap<ResourceKey, usize> = HashMap::new();
+        let mut resource_alternatives = Vec::new();
+        for resource in self.resources.iter().flatten() {
+            match resource {
+                ResourceRef::Single(r) => {
+                    let key = ResourceKey::UserToken(r.name().to_owned());
+                    if needs_resources.insert(key, r.count()).is_some() {
+                        // TODO: Need better error messages.
+                        bail!("duplicate resource reference {:?}", r.name());
+                    }
+                }
+                ResourceRef::AnyOf { any_of, count } => {
+                    if any_of.is_empty() {
+                        bail!("any_of must list at least one resource");
+                    }
+                    resource_alternatives.push(Alternatives {
+                        keys: any_of.iter().cloned().map(ResourceKey::UserToken).collect(),
+                        count: *count,
+                    });
+                }
             }
-            seen_resources.insert(resource.name());
         }
-        let mut needs_resources: HashMap<ResourceKey, usize> = self
-            .resources
-            .as_ref()
-            .unwrap_or(&vec![])
-            .iter()
-            .map(|r| (ResourceKey::UserToken(r.name().to_owned()), r.count()))
-            .collect();
-        if self.requires_worktree {
+        if self.requires_worktree && self.checkout != CheckoutMode::Archive {
             needs_resources.insert(ResourceKey::Worktree, 1);
         }
+        if let Some(cpus) = self.cpus {
+            if cpus == 0 {
+                bail!("cpus must be at least 1");
+            }
+            needs_resources.insert(ResourceKey::Cpu, cpus);
+        }
+        if let Some(memory) = &self.memory {
+            let mib = parse_size(memory)?.div_ceil(MIB);
+            if mib == 0 {
+                bail!("memory must be more than 0");
+            }
+            needs_resources.insert(ResourceKey::Memory, mib as usize);
+        }
+        if let Some(gpus) = self.gpus {
+            if gpus == 0 {
+                bail!("gpus must be at least 1");
+            }
+            needs_resources.insert(ResourceKey::Gpu, gpus);
+        }
 
         // Hash the config, also taking into account the hashes of the
         // dependency test configs.
         let mut hasher = DigestHasher {
             digest: Sha3_256::new(),
         };
-        self.hash(&mut hasher);
+        self.canonical_for_hash()?.hash(&mut hasher);
         for dep_name in &self.depends_on {
             other_tests
                 .node(&TestName::new(dep_name))
@@ -180,22 +570,146 @@ impl Test {
         let config_hash = hex::encode(hasher.digest.finalize());
         debug!("Config hash for {}: {:?}", self.name, config_hash);
 
+        if self.requires_worktree && self.cwd.as_ref().is_some_and(|cwd| cwd.is_absolute()) {
+            bail!("absolute cwd is only allowed with requires_worktree = false");
+        }
+
+        if !self.requires_worktree && self.checkout != CheckoutMode::Detached {
+            bail!(
+                "checkout = {} is only allowed with requires_worktree = true",
+                serde_json::to_value(self.checkout).unwrap()
+            );
+        }
+
+        let umask = match &self.umask {
+            Some(umask) => Some(
+                u32::from_str_radix(umask, 8)
+                    .ok()
+                    .filter(|bits| *bits <= 0o777)
+                    .map(Mode::from_bits_truncate)
+                    .ok_or_else(|| anyhow!("bad umask {umask:?}, should be like \"027\""))?,
+            ),
+            None => None,
+        };
+        let user = match &self.sandbox.user {
+            Some(name) => Some(
+                User::from_name(name)
+                    .with_context(|| format!("looking up user {name:?}"))?
+                    .ok_or_else(|| anyhow!("no such user {name:?}"))?,
+            ),
+            None => None,
+        };
+
+        let stage = match &self.stage {
+            Some(name) => Some(stages.iter().position(|s| s == name).ok_or_else(|| {
+                anyhow!(
+                    "undefined stage {name:?} referenced in test {:?}",
+                    self.name
+                )
+            })?),
+            None => None,
+        };
+
+        let globs = |field: &str, patterns: &[String]| {
+            patterns
+                .iter()
+                .map(|pattern| {
+                    glob::Pattern::new(pattern)
+                        .with_context(|| format!("invalid {field} glob {pattern:?}"))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()
+        };
+        let skip_if_unchanged = globs("skip_if_unchanged", &self.skip_if_unchanged)?;
+        let regex = |field: &str, regex: &Option<String>| {
+            regex
+                .as_deref()
+                .map(|regex| {
+                    Regex::new(regex).with_context(|| format!("invalid {field} regex {regex:?}"))
+                })
+                .transpose()
+        };
+        let when = test::When {
+            paths: globs("when.paths", &self.when.paths)?,
+            message: regex("when.message", &self.when.message)?,
+            author: regex("when.author", &self.when.author)?,
+        };
+
         let error_exit_codes: HashSet<_> = self.error_exit_codes.iter().cloned().collect();
         if error_exit_codes.contains(&0) {
             bail!("error_exit_codes must not contain 0");
         }
 
+        let name = TestName::new(self.name.clone());
+        let mut output_vars = HashSet::new();
+        for output in &self.outputs {
+            let inside = Path::new(output)
+                .components()
+                .all(|c| matches!(c, Component::Normal(_)));
+            if output.is_empty() || !inside {
+                bail!("output {output:?} must be a relative path inside $LIMMAT_ARTIFACTS");
+            }
+            let var = test::output_env_var(&name, output);
+            if !output_vars.insert(var.clone()) {
+                bail!("more than one output would be exported as {var}");
+            }
+        }
+
         Ok(test::Test {
-            name: TestName::new(self.name.clone()),
+            name,
+            description: self.description.clone(),
+            owner: self.owner.clone(),
+            help_url: self.help_url.clone(),
             program: self.command.program(),
             args: self.command.args(),
+            setup: self.setup.as_ref().map(Command::argv),
+            teardown: self.teardown.as_ref().map(Command::argv),
+            artifact_upload: self
+                .artifact_upload
+                .as_ref()
+                .map(|upload| test::ArtifactUpload {
+                    argv: upload.command.argv(),
+                    retries: upload.retries,
+                    retry_delay: Duration::from_secs(upload.retry_delay_s),
+                }),
+            outputs: self.outputs.clone(),
+            storage_quota: self
+                .storage_quota
+                .as_deref()
+                .map(parse_size)
+                .transpose()
+                .context("parsing storage_quota")?,
             needs_resources,
+            resource_alternatives,
             shutdown_grace_period: Duration::from_secs(self.shutdown_grace_period_s),
             cache_policy: self.cache,
+            failure_cache_ttl: self
+                .failure_cache_ttl
+                .as_deref()
+                .map(parse_duration)
+                .transpose()
+                .context("parsing failure_cache_ttl")?,
+            max_slowdown_percent: self.max_slowdown_percent,
             config_hash,
             depends_on: self.depends_on.iter().map(TestName::new).collect(),
+            stage,
+            // Filled in once all the tests are parsed.
+            stage_gate: Vec::new(),
             error_exit_codes,
             separate_outputs: self.separate_outputs,
+            timestamp_output: self.timestamp_output,
+            cwd: self.cwd.clone(),
+            checkout: self.checkout,
+            umask,
+            sandbox: test::Sandbox {
+                user,
+                private_tmp: self.sandbox.private_tmp,
+                unshare: self.sandbox.unshare.clone(),
+                network: self.network,
+            },
+            skip_if_unchanged,
+            when,
+            run_by_default: self.run_by_default,
+            advisory: self.advisory()?,
         })
     }
 }
@@ -212,19 +726,315 @@ fn default_shutdown_grace_period() -> u64 {
     60
 }
 
+fn default_ref_debounce() -> u64 {
+    1000
+}
+
 #[derive(Deserialize, JsonSchema, Debug, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct Config {
     #[serde(default = "default_num_worktrees")]
-    pub num_worktrees: usize,
+    /// How many worktrees to run tests in. If this is "auto", it's the number
+    /// of CPUs, reduced if needed so that the worktrees fit in half of the free
+    /// disk space (going by the size of the files in HEAD).
+    pub num_worktrees: NumWorktrees,
+    /// If set, worktrees are created with a cone-mode sparse checkout that
+    /// only contains these directories (plus the files at the root of the
+    /// repository).
+    pub sparse_checkout: Option<Vec<String>>,
+    /// Check out submodules in the worktrees. Where the main worktree has
+    /// already cloned a submodule, its objects are shared with the worktrees.
+    #[serde(default)]
+    pub submodules: bool,
+    /// Fetch and check out Git LFS files in the worktrees. Requires Git LFS to
+    /// be installed. By default this is done for commits whose .gitattributes
+    /// files use the LFS filter.
+    #[serde(default)]
+    pub lfs: Option<bool>,
+    /// Command to run in each worktree before it's removed, for example to
+    /// stop daemons or unmount things that tests left behind. It's run in the
+    /// root of the worktree. If it fails, that's logged but the worktree is
+    /// still removed. Worktrees kept by --persistent-worktree-dir aren't torn
+    /// down.
+    pub worktree_teardown: Option<Command>,
+    /// Directory (must exist) to create temporary worktrees in, instead of the
+    /// system temp dir. Pointing this at a tmpfs like /dev/shm or a fast
+    /// scratch disk can speed up IO-heavy tests. Relative paths are relative to
+    /// the config file. Limmat refuses to start if the worktrees clearly won't
+    /// fit. Overridden by --worktree-dir.
+    pub worktree_dir: Option<PathBuf>,
+    #[serde(default)]
+    /// How to get the files into the worktrees. With "reflink", only one
+    /// worktree is checked out and the others are created as reflink copies of
+    /// it. That's much faster for big repositories, but only works on
+    /// filesystems that support it (like Btrfs and XFS). Elsewhere it falls
+    /// back to "checkout". Not compatible with submodules.
+    pub worktree_provisioning: WorktreeProvisioning,
+    #[serde(default)]
+    /// Keep the worktrees of this many of the most recently failed jobs as
+    /// they were, instead of using them for the next jobs. Their paths are
+    /// shown in the status view. Release one with "limmat ctl release" once
+    /// you're done looking at it, otherwise the oldest goes back into use when
+    /// another job fails. At least one worktree is always left for jobs to
+    /// run in.
+    pub keep_failed_worktrees: usize,
+    #[serde(default)]
+    /// Ordered names of stages. Tests in a stage only run for a commit once
+    /// all the tests in earlier stages have succeeded for that commit.
+    stages: Vec<String>,
+    #[serde(default)]
+    /// Which jobs get resources first when more are ready than can run. With
+    /// "breadth_first", every test gets run on a commit before moving on to
+    /// the next commit. With "depth_first", a test gets run on every commit
+    /// before moving on to the next test, so a quick lint covers the whole
+    /// range before a slow build starts. "fail_fast" is like "breadth_first",
+    /// but the tests for each commit are ordered using their recorded history
+    /// so that the quick ones that often fail come first. Commits go newest
+    /// first. Otherwise tests go in the order they're defined. Jobs still wait
+    /// for their dependencies.
+    pub scheduling: Scheduling,
+    #[serde(default)]
+    /// Set this to "failures" to run tests again when their cached result is
+    /// a failure from before Limmat started, while still trusting cached
+    /// passes. Useful after fixing something in the environment. The "watch"
+    /// command's --rerun overrides this.
+    pub rerun: Rerun,
+    /// For very long ranges: only test every nth commit, counting from the
+    /// oldest, plus the newest. Where a test passes on one of those commits
+    /// and fails on the next, the commits in between are bisected to find
+    /// where it changed. The sampled commits are tested ends first, then the
+    /// middle, and so on.
+    pub sample_every: Option<usize>,
+    #[serde(default)]
+    /// Once all the tests for a commit have finished, record their results in
+    /// a Git note under refs/notes/limmat. See them with "git log
+    /// --notes=limmat", and push or fetch that ref to share them.
+    pub git_notes: bool,
+    #[serde(default = "default_ref_debounce")]
+    /// After a Git ref changes, wait this many milliseconds for further changes
+    /// before re-evaluating the range to test. Overridden by
+    /// --ref-debounce-ms.
+    pub ref_debounce_ms: u64,
+    #[serde(default)]
+    /// Don't re-evaluate the range until there have been no ref changes for
+    /// this many milliseconds. Useful to avoid cancelling and restarting jobs
+    /// repeatedly during an interactive rebase. Overridden by
+    /// --ref-quiet-period-ms.
+    pub ref_quiet_period_ms: u64,
+    #[serde(default)]
+    /// Hold back new jobs while the machine is busy or running on battery.
+    /// Jobs that have already started are left alone.
+    pub throttle: Throttle,
+    /// How to lock entries in the result database, "flock" or "lockfile".
+    /// flock doesn't work reliably on NFS, so by default lock files are used if
+    /// the database is on NFS, and flock otherwise. Instances sharing a
+    /// database must all use the same method.
+    pub database_locking: Option<LockMethod>,
+    #[serde(default)]
+    /// How to show each test status in the status view, for example if the
+    /// default colours are hard to read on your terminal.
+    pub status_theme: StatusTheme,
+    #[serde(default)]
+    /// Settings for the status view.
+    pub ui: Ui,
     resources: Option<Vec<Resource>>,
     // Default is just here to make testing snippets from the documentation easier.
     #[serde(default)]
     tests: Vec<Test>,
 }
 
-fn default_num_worktrees() -> usize {
-    8
+#[derive(Deserialize, JsonSchema, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Throttle {
+    /// Don't start jobs while the 1-minute load average is above this.
+    pub max_load: Option<f64>,
+    /// Don't start jobs while the CPU pressure is above this percentage. This
+    /// is the "some avg10" figure from /proc/pressure/cpu, so it needs a Linux
+    /// kernel with PSI enabled.
+    pub max_cpu_pressure: Option<f64>,
+    /// While running on battery, run at most this many jobs at once. 0 means
+    /// don't start any.
+    pub battery_max_jobs: Option<usize>,
+    /// While running on battery with less than this percentage of charge left,
+    /// don't start any jobs.
+    pub min_battery_percent: Option<f64>,
+    /// Don't create worktrees or start jobs while there's less than this many
+    /// MiB free on the filesystem holding the result database.
+    pub min_free_database_mb: Option<u64>,
+    /// Don't create worktrees or start jobs while there's less than this many
+    /// MiB free on the filesystem holding the worktrees.
+    pub min_free_worktree_mb: Option<u64>,
+}
+
+#[derive(Deserialize, JsonSchema, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Ui {
+    /// How to show each commit in the status view, in the format of "git log
+    /// --format". For a narrower view, leave out some fields. The default is
+    /// "%Cred%h%Creset -%C(yellow)%d%Creset %s %Cgreen(%cr) %C(bold
+    /// blue)<%an>%Creset". Can't contain "%x00".
+    pub log_format: Option<String>,
+    /// Collapse runs of commits where all the tests passed into a single line,
+    /// so that failures in big ranges stay on screen. The tip of the range and
+    /// commits next to ones that didn't pass are always shown.
+    #[serde(default)]
+    pub compact: bool,
+    /// Ring the terminal bell, and send a desktop notification (OSC 9) on
+    /// terminals that support it, when a test starts failing on the tip of
+    /// the range.
+    #[serde(default)]
+    pub bell: bool,
+}
+
+#[derive(Deserialize, JsonSchema, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct StatusTheme {
+    /// Waiting to run.
+    pub enqueued: Option<StatusStyle>,
+    /// Running.
+    pub started: Option<StatusStyle>,
+    /// Finished with exit code 0.
+    pub success: Option<StatusStyle>,
+    /// Finished with any other exit code.
+    pub failure: Option<StatusStyle>,
+    /// Canceled before finishing.
+    pub canceled: Option<StatusStyle>,
+    /// Couldn't be run, or exited with one of its error_exit_codes.
+    pub error: Option<StatusStyle>,
+    /// Not run because a test in an earlier stage failed.
+    pub stage_failed: Option<StatusStyle>,
+    /// Skipped because none of the files it cares about changed.
+    pub skipped: Option<StatusStyle>,
+    /// Skipped because the commit message said to.
+    pub skipped_by_commit: Option<StatusStyle>,
+    /// Not run on the commit at all, because it doesn't meet the test's "when"
+    /// conditions or isn't in the sample.
+    pub not_applicable: Option<StatusStyle>,
+}
+
+#[derive(Deserialize, JsonSchema, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct StatusStyle {
+    /// Shown instead of the default emoji.
+    pub label: Option<String>,
+    /// Text colour. If this or background is set, the default colours for the
+    /// status aren't used.
+    pub color: Option<Color>,
+    /// Background colour.
+    pub background: Option<Color>,
+}
+
+fn default_num_worktrees() -> NumWorktrees {
+    NumWorktrees::Count(8)
+}
+
+#[derive(Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum WorktreeProvisioning {
+    #[default]
+    Checkout,
+    Reflink,
+}
+
+#[derive(Deserialize, JsonSchema, ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Rerun {
+    /// Trust all cached results.
+    #[default]
+    None,
+    /// Run tests again if their cached result is a failure from before
+    /// startup.
+    Failures,
+}
+
+#[derive(Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Scheduling {
+    #[default]
+    BreadthFirst,
+    DepthFirst,
+    FailFast,
+}
+
+impl Scheduling {
+    // Jobs with a lower order get resources first. Commits are indexed in the
+    // order of the range (newest first), tests in the order the policy wants
+    // to run them in.
+    pub fn order(&self, commit_index: usize, test_index: usize) -> (usize, usize) {
+        match self {
+            Self::BreadthFirst | Self::FailFast => (commit_index, test_index),
+            Self::DepthFirst => (test_index, commit_index),
+        }
+    }
+}
+
+#[derive(Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum NumWorktrees {
+    Count(usize),
+    Auto(Auto),
+}
+
+#[derive(Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Auto {
+    Auto,
+}
+
+impl NumWorktrees {
+    // worktree_dir is where the worktrees will be created, it doesn't need to
+    // exist yet.
+    pub async fn resolve(
+        &self,
+        repo: &impl Worktree,
+        worktree_dir: &Path,
+    ) -> anyhow::Result<usize> {
+        if let Self::Count(n) = self {
+            return Ok(*n);
+        }
+        let cpus = available_parallelism().map_or(1, |n| n.get());
+        let free_bytes = free_space(worktree_dir)?;
+        let checkout_size = repo
+            .checkout_size()
+            .await
+            .context("estimating checkout size")?;
+        let n = auto_num_worktrees(cpus, free_bytes, checkout_size);
+        debug!(
+            "num_worktrees = auto: {cpus} CPUs, {free_bytes} bytes free in {worktree_dir:?}, \
+            checkout size {checkout_size} bytes -> {n} worktrees"
+        );
+        Ok(n)
+    }
+}
+
+// Fail up front, rather than when the disk fills up while checking out the
+// worktrees.
+pub async fn check_worktrees_fit(
+    repo: &impl Worktree,
+    worktree_dir: &Path,
+    num_worktrees: usize,
+) -> anyhow::Result<()> {
+    let free_bytes = free_space(worktree_dir)?;
+    let checkout_size = repo
+        .checkout_size()
+        .await
+        .context("estimating checkout size")?;
+    let needed = checkout_size.saturating_mul(num_worktrees as u64);
+    if needed > free_bytes {
+        bail!(
+            "{num_worktrees} worktrees need about {} MiB but there's only {} MiB free in {}, \
+            reduce num_worktrees or use a different worktree_dir",
+            needed.div_ceil(MIB),
+            free_bytes / MIB,
+            worktree_dir.display()
+        );
+    }
+    Ok(())
+}
+
+fn auto_num_worktrees(cpus: usize, free_bytes: u64, checkout_size: u64) -> usize {
+    let fit = (free_bytes / 2) / checkout_size.max(1);
+    cpus.min(fit.try_into().unwrap_or(usize::MAX)).max(1)
 }
 
 type ResourceTokens = HashMap<ResourceKey, Vec<String>>;
@@ -249,6 +1059,31 @@ impl Config {
             .collect()
     }
 
+    // Make each test wait for the tests in earlier stages.
+    fn gate_stages(tests: Dag<Arc<test::Test>>) -> anyhow::Result<Dag<Arc<test::Test>>> {
+        let gated = tests.nodes().map(|test| {
+            let Some(stage) = test.stage else {
+                return test.clone();
+            };
+            // Tests that aren't run by default would hold up the later stages
+            // forever.
+            let stage_gate = tests
+                .nodes()
+                .filter(|other| other.run_by_default && other.stage.is_some_and(|s| s < stage))
+                .map(|other| other.name.clone())
+                .filter(|name| !test.depends_on.contains(name))
+                .collect();
+            Arc::new(test::Test {
+                stage_gate,
+                ..test::Test::clone(test)
+            })
+        });
+        // This will fail if depends_on contradicts the stage order.
+        Ok(Dag::new(gated.collect::<Vec<_>>())?)
+    }
+
+    // Without an explicit tests filter, this includes the tests that aren't
+    // run by default.
     fn parse_tests<S: AsRef<str>>(
         &self,
         resource_tokens: &ResourceTokens,
@@ -271,11 +1106,7 @@ impl Config {
             self.tests
                 .iter()
                 .filter(|t| {
-                    if !only_tests.is_empty() {
-                        if !only_tests.iter().any(|r| r.is_match(&t.name)) {
-                            return false;
-                        }
-                    } else if !t.run_by_default {
+                    if !only_tests.is_empty() && !only_tests.iter().any(|r| r.is_match(&t.name)) {
                         return false;
                     }
                     !skip_tests.iter().any(|r| r.is_match(&t.name))
@@ -295,15 +1126,23 @@ impl Config {
             .try_fold(
                 Dag::empty(),
                 |parsed_dag, test_conf: &Test| -> anyhow::Result<Dag<Arc<test::Test>>> {
-                    let new_node = Arc::new(test_conf.parse(&parsed_dag)?);
-                    Ok(parsed_dag.with_node(new_node).unwrap())
+                    let mut new_node = test_conf.parse(&parsed_dag, &self.stages)?;
+                    new_node.run_by_default |= !only_tests.is_empty();
+                    Ok(parsed_dag.with_node(Arc::new(new_node)).unwrap())
                 },
             )
             .context("parsing tests")?;
 
+        let tests = if self.stages.is_empty() {
+            tests
+        } else {
+            Self::gate_stages(tests).context("setting up stages")?
+        };
+
         // Check for invalid resource references.
         for test in tests.nodes() {
-            for key in test.needs_resources.keys() {
+            let alternative_keys = test.resource_alternatives.iter().flat_map(|a| &a.keys);
+            for key in test.needs_resources.keys().chain(alternative_keys) {
                 if let ResourceKey::UserToken(name) = key {
                     if !resource_tokens.contains_key(key) {
                         bail!(
@@ -320,6 +1159,16 @@ impl Config {
     }
 }
 
+const MIB: u64 = 1024 * 1024;
+
+// The test that needs the most of the resource, and how much that is.
+fn max_need<'a>(tests: &'a TestDag, key: &ResourceKey) -> Option<(&'a Arc<test::Test>, usize)> {
+    tests
+        .nodes()
+        .filter_map(|test| Some((test, *test.needs_resources.get(key)?)))
+        .max_by_key(|(_, need)| *need)
+}
+
 // Messy type to try and capture a pretty arbitrary aspect of initialising the
 // pre-requisites to run jobs.
 // Construct via from. This does NOT create worktrees, that's why it has a
@@ -331,9 +1180,83 @@ impl Config {
 #[derive(Debug)]
 pub struct ParsedConfig {
     pub source_path: PathBuf,
-    pub num_worktrees: usize,
+    pub num_worktrees: NumWorktrees,
+    pub worktree_options: WorktreeOptions,
+    pub worktree_provisioning: WorktreeProvisioning,
+    pub worktree_dir: Option<PathBuf>,
+    pub git_notes: bool,
+    pub ref_debounce: Duration,
+    pub ref_quiet_period: Duration,
+    pub scheduling: Scheduling,
+    pub rerun: Rerun,
+    pub sample_every: Option<usize>,
+    pub throttle: Throttle,
+    pub database_locking: Option<LockMethod>,
+    pub status_theme: StatusTheme,
+    pub ui: Ui,
     pub resource_pools: Arc<Pools>,
+    // The CPUs, memory and GPUs that resource_pools takes from. Other
+    // ParsedConfigs in the same process should share these.
+    pub machine_pools: Arc<Pools>,
     pub tests: TestDag,
+    // Also has the tests that aren't run by default, so that the watch can
+    // run them on request.
+    pub all_tests: TestDag,
+    // What goes into each test's config hash (apart from its dependencies),
+    // for explaining why results got invalidated.
+    pub test_configs: HashMap<TestName, serde_json::Value>,
+}
+
+// Add the CPUs, GPUs and memory that the tests need to the machine-wide pools,
+// unless they're already there for another repository's tests.
+fn provide_machine_resources(machine: &Pools, tests: &TestDag) -> anyhow::Result<()> {
+    let total = |key| machine.usage().get(key).map_or(0, |u| u.total);
+    // Only look at the machine if some test actually needs it to.
+    if let Some((test, num_cpus)) = max_need(tests, &ResourceKey::Cpu) {
+        if total(&ResourceKey::Cpu) == 0 {
+            let cpus = available_cpus()?;
+            machine.add(
+                cpus.into_iter()
+                    .map(|cpu| (ResourceKey::Cpu, resource::Resource::Cpu(cpu))),
+            );
+        }
+        let available = total(&ResourceKey::Cpu);
+        if num_cpus > available {
+            bail!(
+                "test {:?} needs {num_cpus} CPUs but only {available} are available",
+                test.name
+            );
+        }
+    }
+    if let Some((test, num_gpus)) = max_need(tests, &ResourceKey::Gpu) {
+        if total(&ResourceKey::Gpu) == 0 {
+            let gpus = gpu::detect().context("finding GPUs")?;
+            machine.add(
+                gpus.into_iter()
+                    .map(|gpu| (ResourceKey::Gpu, resource::Resource::Gpu(gpu))),
+            );
+        }
+        let found = total(&ResourceKey::Gpu);
+        if num_gpus > found {
+            bail!(
+                "test {:?} needs {num_gpus} GPUs but only {found} were found",
+                test.name
+            );
+        }
+    }
+    if let Some((test, mib)) = max_need(tests, &ResourceKey::Memory) {
+        if total(&ResourceKey::Memory) == 0 {
+            machine.add_quantity(ResourceKey::Memory, (total_memory()? / MIB) as usize);
+        }
+        let total = total(&ResourceKey::Memory);
+        if mib > total {
+            bail!(
+                "test {:?} needs {mib} MiB of memory but there's only {total} MiB",
+                test.name
+            );
+        }
+    }
+    Ok(())
 }
 
 impl ParsedConfig {
@@ -343,8 +1266,46 @@ impl ParsedConfig {
         skip_tests: impl IntoIterator<Item = S>,
         only_tests: impl IntoIterator<Item = S>,
     ) -> anyhow::Result<Self> {
+        Self::new_sharing_machine(
+            config,
+            source_path,
+            skip_tests,
+            only_tests,
+            Arc::new(Pools::new([])),
+        )
+    }
+
+    // Like new, but the tests take CPUs, memory and GPUs from machine_pools,
+    // which should come from another ParsedConfig, so that the same ones
+    // aren't handed out twice.
+    pub fn new_sharing_machine<S: AsRef<str>>(
+        config: Config,
+        source_path: impl Into<PathBuf>,
+        skip_tests: impl IntoIterator<Item = S>,
+        only_tests: impl IntoIterator<Item = S>,
+        machine_pools: Arc<Pools>,
+    ) -> anyhow::Result<Self> {
+        if config.submodules && config.worktree_provisioning == WorktreeProvisioning::Reflink {
+            bail!("worktree_provisioning = \"reflink\" doesn't work with submodules");
+        }
+        if config.sample_every == Some(0) {
+            bail!("sample_every must be at least 1");
+        }
+        if matches!(&config.worktree_teardown, Some(Command::Raw(args)) if args.is_empty()) {
+            bail!("worktree_teardown can't be an empty list");
+        }
+        if config
+            .ui
+            .log_format
+            .as_ref()
+            .is_some_and(|f| f.contains("%x00"))
+        {
+            bail!("ui.log_format can't contain %x00");
+        }
         let resource_tokens = config.parse_resource_tokens();
-        let tests = config.parse_tests(&resource_tokens, skip_tests, only_tests)?;
+        let all_tests = config.parse_tests(&resource_tokens, skip_tests, only_tests)?;
+        let tests = Dag::new(all_tests.nodes().filter(|t| t.run_by_default).cloned())
+            .context("a test that's run by default depends on one that isn't")?;
         let resources: HashMap<ResourceKey, Vec<resource::Resource>> = resource_tokens
             .into_iter()
             .map(|(key, tokens)| {
@@ -357,21 +1318,82 @@ impl ParsedConfig {
                 )
             })
             .collect();
+        provide_machine_resources(&machine_pools, &all_tests)?;
+        let resource_pools = Pools::new(resources)
+            .with_parked_worktrees(config.keep_failed_worktrees)
+            .with_machine(machine_pools.clone());
+        let test_configs = config
+            .tests
+            .iter()
+            .filter(|t| all_tests.node(&TestName::new(&t.name)).is_some())
+            .map(|t| {
+                let value = serde_json::to_value(t.for_config_hash()?)
+                    .expect("failed to serialize test config");
+                Ok((TestName::new(&t.name), value))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        let source_path = source_path.into();
+        let worktree_dir = config
+            .worktree_dir
+            .as_ref()
+            .map(|dir| source_path.parent().unwrap_or(Path::new("/")).join(dir));
         Ok(Self {
             num_worktrees: config.num_worktrees,
-            resource_pools: Arc::new(Pools::new(resources)),
-            source_path: source_path.into(),
+            worktree_options: WorktreeOptions {
+                sparse_checkout: config.sparse_checkout,
+                submodules: config.submodules,
+                lfs: config.lfs,
+                teardown: config.worktree_teardown.as_ref().map(Command::argv),
+                reflink_from: None,
+            },
+            worktree_provisioning: config.worktree_provisioning,
+            worktree_dir,
+            git_notes: config.git_notes,
+            ref_debounce: Duration::from_millis(config.ref_debounce_ms),
+            ref_quiet_period: Duration::from_millis(config.ref_quiet_period_ms),
+            scheduling: config.scheduling,
+            rerun: config.rerun,
+            sample_every: config.sample_every,
+            throttle: config.throttle,
+            database_locking: config.database_locking,
+            status_theme: config.status_theme,
+            ui: config.ui,
+            resource_pools: Arc::new(resource_pools),
+            machine_pools,
+            source_path,
             tests,
+            all_tests,
+            test_configs,
         })
     }
 }
 
+// The top-level fields that differ between two snapshots from
+// ParsedConfig::test_configs, as "field: old -> new" lines.
+pub fn diff_test_configs(old: &serde_json::Value, new: &serde_json::Value) -> Vec<String> {
+    let fields = |v: &serde_json::Value| v.as_object().cloned().unwrap_or_default();
+    let (old, new) = (fields(old), fields(new));
+    old.keys()
+        .chain(new.keys())
+        .sorted()
+        .dedup()
+        .filter_map(|key| {
+            let old = old.get(key).unwrap_or(&serde_json::Value::Null);
+            let new = new.get(key).unwrap_or(&serde_json::Value::Null);
+            (old != new).then(|| format!("{key}: {old} -> {new}"))
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
+    use std::fs;
+
     use googletest::{assert_that, expect_that, prelude::*};
     use pretty_assertions::assert_eq;
     use regex::Regex;
     use schemars::schema_for;
+    use tempfile::TempDir;
 
     use super::*;
 
@@ -439,6 +1461,14 @@ mod tests {
         let parsed = ParsedConfig::new(config.clone(), "/fake", Vec::<&str>::new(), Vec::<&str>::new()).unwrap();
         assert_that!(parsed.tests.node(&TestName::new("default_test")), some(anything()));
         assert_that!(parsed.tests.node(&TestName::new("non_default_test")), none());
+        // It's still available to run on request.
+        assert_that!(
+            parsed
+                .all_tests
+                .node(&TestName::new("non_default_test"))
+                .map(|t| t.run_by_default),
+            some(eq(false))
+        );
 
         // Case 2: Explicit filter for non_default. Should include it.
         let parsed = ParsedConfig::new(config, "/fake", Vec::<&str>::new(), vec!["non_default_test"]).unwrap();
@@ -472,4 +1502,574 @@ mod tests {
         let res = ParsedConfig::new(config, "/fake", Vec::<&str>::new(), vec!["A", "B"]);
         assert_that!(res, ok(anything()));
     }
+
+    #[test]
+    fn test_steps() {
+        #[derive(Deserialize)]
+        struct Wrapper {
+            command: Command,
+        }
+        let dir = TempDir::new().unwrap();
+        let failed_step_path = dir.path().join("failed_step");
+        let run = |toml: &str| {
+            let command = toml::from_str::<Wrapper>(toml).unwrap().command;
+            std::process::Command::new(command.program())
+                .args(command.args())
+                .env("LIMMAT_FAILED_STEP_FILE", &failed_step_path)
+                .output()
+                .unwrap()
+        };
+
+        let output = run(r#"command = { steps = ["echo one", ["echo", "it's two"]] }"#);
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "one\nit's two\n");
+        assert!(!failed_step_path.exists());
+
+        let output = run(r#"command = { steps = ["true", ["false"], "echo unreachable"] }"#);
+        assert_eq!(output.status.code(), Some(1));
+        assert!(output.stdout.is_empty());
+        assert_eq!(fs::read_to_string(&failed_step_path).unwrap(), "2\n");
+    }
+
+    #[googletest::test]
+    fn test_num_worktrees() {
+        let parse = |s| toml::from_str::<Config>(s).map(|c| c.num_worktrees);
+        expect_that!(parse(""), ok(eq(&NumWorktrees::Count(8))));
+        expect_that!(parse("num_worktrees = 3"), ok(eq(&NumWorktrees::Count(3))));
+        expect_that!(
+            parse(r#"num_worktrees = "auto""#),
+            ok(eq(&NumWorktrees::Auto(Auto::Auto)))
+        );
+        expect_that!(parse(r#"num_worktrees = "lots""#), err(anything()));
+
+        // Limited by CPUs.
+        expect_that!(auto_num_worktrees(4, 1 << 40, 1 << 20), eq(4));
+        // Limited by disk.
+        expect_that!(auto_num_worktrees(64, 10 << 20, 1 << 20), eq(5));
+        // Always get at least one.
+        expect_that!(auto_num_worktrees(4, 0, 1 << 20), eq(1));
+        expect_that!(auto_num_worktrees(4, 1 << 20, 0), eq(4));
+    }
+
+    #[googletest::test]
+    fn test_absolute_cwd() {
+        let parse = |config_toml| {
+            ParsedConfig::new(
+                toml::from_str(config_toml).unwrap(),
+                "/fake",
+                Vec::<&str>::new(),
+                Vec::<&str>::new(),
+            )
+        };
+        expect_that!(
+            parse(
+                r#"
+                [[tests]]
+                name = "foo"
+                command = "true"
+                cwd = "/tmp"
+            "#
+            ),
+            err(anything())
+        );
+        expect_that!(
+            parse(
+                r#"
+                [[tests]]
+                name = "foo"
+                command = "true"
+                cwd = "/tmp"
+                requires_worktree = false
+            "#
+            ),
+            ok(anything())
+        );
+    }
+
+    #[googletest::test]
+    fn test_stages() {
+        let parse = |config_toml| {
+            ParsedConfig::new(
+                toml::from_str(config_toml).unwrap(),
+                "/fake",
+                Vec::<&str>::new(),
+                Vec::<&str>::new(),
+            )
+        };
+        let config = parse(
+            r#"
+            stages = ["lint", "build", "test"]
+
+            [[tests]]
+            name = "lint"
+            stage = "lint"
+            command = "true"
+
+            [[tests]]
+            name = "build"
+            stage = "build"
+            command = "true"
+
+            [[tests]]
+            name = "unit"
+            stage = "test"
+            depends_on = ["build"]
+            command = "true"
+
+            [[tests]]
+            name = "other"
+            command = "true"
+        "#,
+        )
+        .unwrap();
+        let gate = |name: &str| {
+            let test = config.tests.node(&TestName::new(name)).unwrap();
+            let mut gate: Vec<String> = test.stage_gate.iter().map(|n| n.to_string()).collect();
+            gate.sort();
+            (test.stage, gate)
+        };
+        assert_eq!(gate("lint"), (Some(0), vec![]));
+        assert_eq!(gate("build"), (Some(1), vec!["lint".to_owned()]));
+        assert_eq!(gate("unit"), (Some(2), vec!["lint".to_owned()]));
+        assert_eq!(gate("other"), (None, vec![]));
+
+        // Unknown stage.
+        expect_that!(
+            parse(
+                r#"
+                stages = ["lint"]
+                [[tests]]
+                name = "foo"
+                stage = "build"
+                command = "true"
+            "#
+            ),
+            err(anything())
+        );
+        // Dependency on a later stage.
+        expect_that!(
+            parse(
+                r#"
+                stages = ["lint", "build"]
+                [[tests]]
+                name = "lint"
+                stage = "lint"
+                depends_on = ["build"]
+                command = "true"
+
+                [[tests]]
+                name = "build"
+                stage = "build"
+                command = "true"
+            "#
+            ),
+            err(anything())
+        );
+    }
+
+    #[test]
+    fn test_worktree_dir() {
+        let worktree_dir = |dir: &str| {
+            let config: Config = toml::from_str(&format!("worktree_dir = {dir:?}")).unwrap();
+            ParsedConfig::new(
+                config,
+                "/repo/limmat.toml",
+                Vec::<&str>::new(),
+                Vec::<&str>::new(),
+            )
+            .unwrap()
+            .worktree_dir
+        };
+        assert_eq!(worktree_dir("/dev/shm"), Some(PathBuf::from("/dev/shm")));
+        assert_eq!(
+            worktree_dir("scratch"),
+            Some(PathBuf::from("/repo/scratch"))
+        );
+    }
+
+    #[googletest::test]
+    fn test_empty_worktree_teardown() {
+        let parse = |teardown: &str| {
+            ParsedConfig::new(
+                toml::from_str(&format!("worktree_teardown = {teardown}")).unwrap(),
+                "/fake",
+                Vec::<&str>::new(),
+                Vec::<&str>::new(),
+            )
+        };
+        expect_that!(parse("[]"), err(anything()));
+        expect_that!(parse("[\"true\"]"), ok(anything()));
+    }
+
+    #[googletest::test]
+    fn test_branch_checkout_requires_worktree() {
+        let res = ParsedConfig::new(
+            toml::from_str(
+                r#"
+                [[tests]]
+                name = "foo"
+                command = "true"
+                checkout = "branch"
+                requires_worktree = false
+            "#,
+            )
+            .unwrap(),
+            "/fake",
+            Vec::<&str>::new(),
+            Vec::<&str>::new(),
+        );
+        expect_that!(res, err(anything()));
+    }
+
+    #[googletest::test]
+    fn test_resource_alternatives() {
+        let parse = |any_of: &str| {
+            ParsedConfig::new(
+                toml::from_str(&format!(
+                    r#"
+                    resources = ["fast", {{ name = "slow", count = 2 }}]
+                    [[tests]]
+                    name = "foo"
+                    command = "true"
+                    resources = [{{ any_of = {any_of} }}]
+                "#
+                ))
+                .unwrap(),
+                "/fake",
+                Vec::<&str>::new(),
+                Vec::<&str>::new(),
+            )
+        };
+        let config = parse(r#"["fast", "slow"]"#).unwrap();
+        let test = config.tests.node(&TestName::new("foo")).unwrap();
+        expect_that!(
+            test.resource_alternatives,
+            elements_are![eq(&Alternatives {
+                keys: vec![
+                    ResourceKey::UserToken("fast".into()),
+                    ResourceKey::UserToken("slow".into())
+                ],
+                count: 1,
+            })]
+        );
+        expect_that!(parse("[]"), err(anything()));
+        expect_that!(parse(r#"["fast", "medium"]"#), err(anything()));
+    }
+
+    #[googletest::test]
+    fn test_cpus() {
+        let parse = |cpus: usize| {
+            ParsedConfig::new(
+                toml::from_str(&format!(
+                    r#"
+                    [[tests]]
+                    name = "foo"
+                    command = "true"
+                    cpus = {cpus}
+                "#
+                ))
+                .unwrap(),
+                "/fake",
+                Vec::<&str>::new(),
+                Vec::<&str>::new(),
+            )
+        };
+        let config = parse(1).unwrap();
+        expect_that!(
+            config.resource_pools.usage()[&ResourceKey::Cpu].total,
+            eq(available_cpus().unwrap().len())
+        );
+        expect_that!(parse(0), err(anything()));
+        expect_that!(parse(available_cpus().unwrap().len() + 1), err(anything()));
+    }
+
+    #[tokio::test]
+    async fn test_cpus_shared() {
+        let config: Config = toml::from_str(
+            r#"
+            [[tests]]
+            name = "foo"
+            command = "true"
+            cpus = 1
+        "#,
+        )
+        .unwrap();
+        let first = ParsedConfig::new(
+            config.clone(),
+            "/fake",
+            Vec::<&str>::new(),
+            Vec::<&str>::new(),
+        )
+        .unwrap();
+        let second = ParsedConfig::new_sharing_machine(
+            config,
+            "/fake2",
+            Vec::<&str>::new(),
+            Vec::<&str>::new(),
+            first.machine_pools.clone(),
+        )
+        .unwrap();
+        let _cpus = first.resource_pools.get([(ResourceKey::Cpu, 1)]).await;
+        let usage = second.resource_pools.usage()[&ResourceKey::Cpu];
+        assert_eq!(usage.total, available_cpus().unwrap().len());
+        assert_eq!(usage.in_use, 1);
+    }
+
+    #[googletest::test]
+    fn test_memory() {
+        let parse = |memory: &str| {
+            ParsedConfig::new(
+                toml::from_str(&format!(
+                    r#"
+                    [[tests]]
+                    name = "foo"
+                    command = "true"
+                    memory = "{memory}"
+                "#
+                ))
+                .unwrap(),
+                "/fake",
+                Vec::<&str>::new(),
+                Vec::<&str>::new(),
+            )
+        };
+        let config = parse("1500k").unwrap();
+        let test = config.tests.node(&TestName::new("foo")).unwrap();
+        expect_that!(test.needs_resources[&ResourceKey::Memory], eq(2));
+        expect_that!(
+            config.resource_pools.usage()[&ResourceKey::Memory].total,
+            eq((total_memory().unwrap() / MIB) as usize)
+        );
+        expect_that!(parse("0"), err(anything()));
+        expect_that!(parse("8 gigs"), err(anything()));
+        expect_that!(parse("100000T"), err(anything()));
+        expect_that!(parse("1000000000000000000T"), err(anything()));
+    }
+
+    #[googletest::test]
+    fn test_ignore_for_cache() {
+        let config_hash = |extra: &str| {
+            let config: Config = toml::from_str(&format!(
+                r#"
+                [[tests]]
+                name = "foo"
+                command = "true"
+                {extra}
+            "#
+            ))
+            .unwrap();
+            let config =
+                ParsedConfig::new(config, "/fake", Vec::<&str>::new(), Vec::<&str>::new())?;
+            anyhow::Ok(
+                config
+                    .tests
+                    .node(&TestName::new("foo"))
+                    .unwrap()
+                    .config_hash
+                    .clone(),
+            )
+        };
+        let base = config_hash("").unwrap();
+        expect_that!(
+            config_hash("shutdown_grace_period_s = 5"),
+            ok(not(eq(&base)))
+        );
+        let ignored = r#"ignore_for_cache = ["shutdown_grace_period_s", "cpus"]"#;
+        expect_that!(config_hash(ignored), ok(eq(&base)));
+        expect_that!(
+            config_hash(&format!("{ignored}\nshutdown_grace_period_s = 5\ncpus = 1")),
+            ok(eq(&base))
+        );
+        expect_that!(
+            config_hash(&format!("{ignored}\nmemory = \"1G\"")),
+            ok(not(eq(&base)))
+        );
+        expect_that!(
+            config_hash("description = \"Checks stuff\"\nhelp_url = \"https://example.com\""),
+            ok(eq(&base))
+        );
+        expect_that!(
+            config_hash("artifact_upload = { command = \"true\", retries = 2 }"),
+            ok(eq(&base))
+        );
+        expect_that!(
+            config_hash(r#"ignore_for_cache = ["command"]"#),
+            err(anything())
+        );
+    }
+
+    #[test]
+    fn test_config_hash_stable() {
+        // Only fields that have been around forever. If this hash changes,
+        // upgrading Limmat throws away everyone's cached results, so new
+        // fields mustn't affect it unless they're set.
+        let config: Config = toml::from_str(
+            r#"
+            [[tests]]
+            name = "foo"
+            command = "make"
+            requires_worktree = false
+            cache = "by_tree"
+            error_exit_codes = [2]
+        "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.tests[0].canonical_for_hash().unwrap(),
+            r#"{"cache":"by_tree","command":"make","error_exit_codes":[2],"name":"foo","requires_worktree":false}"#
+        );
+        let config =
+            ParsedConfig::new(config, "/fake", Vec::<&str>::new(), Vec::<&str>::new()).unwrap();
+        assert_eq!(
+            config
+                .tests
+                .node(&TestName::new("foo"))
+                .unwrap()
+                .config_hash,
+            "9459b5794de8deda5a674d4cf7dbd8fc21b8db685ab29776d1dbd51a3e1cac53"
+        );
+    }
+
+    #[googletest::test]
+    fn test_required() {
+        let parse = |extra: &str| {
+            let config: Config = toml::from_str(&format!(
+                r#"
+                [[tests]]
+                name = "foo"
+                command = "true"
+                {extra}
+            "#
+            ))
+            .unwrap();
+            let config =
+                ParsedConfig::new(config, "/fake", Vec::<&str>::new(), Vec::<&str>::new())?;
+            let test = config.tests.node(&TestName::new("foo")).unwrap();
+            anyhow::Ok((test.advisory, test.config_hash.clone()))
+        };
+        let (_, advisory_hash) = parse("advisory = true").unwrap();
+        expect_that!(parse("required = false"), ok(eq(&(true, advisory_hash))));
+        let (_, base_hash) = parse("").unwrap();
+        expect_that!(
+            parse("required = true"),
+            ok(eq(&(false, base_hash.clone())))
+        );
+        expect_that!(parse("advisory = false"), ok(eq(&(false, base_hash))));
+        expect_that!(parse("required = false\nadvisory = true"), err(anything()));
+    }
+
+    #[test]
+    fn test_diff_test_configs() {
+        let test_config = |extra: &str| {
+            let config: Config = toml::from_str(&format!(
+                r#"
+                [[tests]]
+                name = "foo"
+                command = "true"
+                {extra}
+            "#
+            ))
+            .unwrap();
+            let config =
+                ParsedConfig::new(config, "/fake", Vec::<&str>::new(), Vec::<&str>::new()).unwrap();
+            config.test_configs[&TestName::new("foo")].clone()
+        };
+        let base = test_config("");
+        assert_eq!(diff_test_configs(&base, &base), Vec::<String>::new());
+        let changed = test_config("error_exit_codes = [3]\nshutdown_grace_period_s = 5");
+        assert_eq!(
+            diff_test_configs(&base, &changed),
+            vec![
+                "error_exit_codes: [] -> [3]",
+                "shutdown_grace_period_s: 60 -> 5"
+            ]
+        );
+        assert_eq!(
+            diff_test_configs(
+                &base,
+                &test_config(
+                    "shutdown_grace_period_s = 5\nignore_for_cache = [\"shutdown_grace_period_s\"]"
+                )
+            ),
+            Vec::<String>::new()
+        );
+    }
+
+    #[googletest::test]
+    fn test_failure_cache_ttl() {
+        let parse = |ttl: &str| {
+            ParsedConfig::new(
+                toml::from_str(&format!(
+                    r#"
+                    [[tests]]
+                    name = "foo"
+                    command = "true"
+                    failure_cache_ttl = "{ttl}"
+                "#
+                ))
+                .unwrap(),
+                "/fake",
+                Vec::<&str>::new(),
+                Vec::<&str>::new(),
+            )
+            .map(|config| {
+                config
+                    .tests
+                    .node(&TestName::new("foo"))
+                    .unwrap()
+                    .failure_cache_ttl
+            })
+        };
+        expect_that!(parse("90s"), ok(some(eq(&Duration::from_secs(90)))));
+        expect_that!(
+            parse("12h"),
+            ok(some(eq(&Duration::from_secs(12 * 60 * 60))))
+        );
+        expect_that!(
+            parse("7d"),
+            ok(some(eq(&Duration::from_secs(7 * 24 * 60 * 60))))
+        );
+        expect_that!(parse("12"), err(anything()));
+        expect_that!(parse("h"), err(anything()));
+        expect_that!(parse("99999999999999999999d"), err(anything()));
+    }
+
+    #[googletest::test]
+    fn test_outputs() {
+        let parse = |outputs: &str| {
+            ParsedConfig::new(
+                toml::from_str(&format!(
+                    r#"
+                    [[tests]]
+                    name = "foo"
+                    command = "true"
+                    outputs = {outputs}
+                "#
+                ))
+                .unwrap(),
+                "/fake",
+                Vec::<&str>::new(),
+                Vec::<&str>::new(),
+            )
+            .map(|config| {
+                config
+                    .tests
+                    .node(&TestName::new("foo"))
+                    .unwrap()
+                    .outputs
+                    .clone()
+            })
+        };
+        expect_that!(
+            parse(r#"["vmlinux", "out/report.json"]"#),
+            ok(eq(&vec![
+                "vmlinux".to_string(),
+                "out/report.json".to_string()
+            ]))
+        );
+        expect_that!(parse(r#"["/vmlinux"]"#), err(anything()));
+        expect_that!(parse(r#"["../vmlinux"]"#), err(anything()));
+        expect_that!(parse(r#"[""]"#), err(anything()));
+        expect_that!(parse(r#"["report.json", "report-json"]"#), err(anything()));
+    }
 }
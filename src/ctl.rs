@@ -0,0 +1,370 @@
+// Control socket for a running "limmat watch". Clients send one JSON Request
+// per line on a Unix socket and get one JSON Response line back for each,
+// except for Attach which gets a Frame response every time the status view
+// changes, for as long as the connection stays open.
+
+use std::{
+    fs::{self, File},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{anyhow, bail, Context as _};
+use async_stream::try_stream;
+use futures::Stream;
+use log::{debug, warn};
+use nix::{
+    errno::Errno,
+    fcntl::{Flock, FlockArg},
+};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt as _, AsyncWriteExt as _, BufReader},
+    net::{unix::OwnedWriteHalf, UnixListener, UnixStream},
+    select,
+    task::JoinSet,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    git::{PersistentWorktree, Worktree as _},
+    http::UiState,
+    test::{Manager, TestCase, TestName, TestStatus},
+    ui::Frame,
+};
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Request {
+    // Report the latest status of every test case in the range.
+    Status,
+    // Cancel a test case, it won't be restarted automatically.
+    Cancel {
+        test: String,
+        rev: String,
+    },
+    // Run a test case again even if it has a cached result.
+    Rerun {
+        test: String,
+        rev: String,
+    },
+    // Make a test case and its dependencies the next to get resources.
+    Bump {
+        test: String,
+        rev: String,
+    },
+    // Start a test case that isn't run automatically, e.g. because the test
+    // has run_by_default = false.
+    Run {
+        test: String,
+        rev: String,
+    },
+    // Put the worktree that was kept after a test case failed back into use.
+    Release {
+        test: String,
+        rev: String,
+    },
+    // Stream the status view as it's shown in the watching terminal.
+    Attach,
+    // Report the status of the test cases for the commits in rev once they've
+    // all finished. The tests are filtered by regexes like the --tests and
+    // --skip-test args.
+    Wait {
+        rev: String,
+        tests: Vec<String>,
+        skip_tests: Vec<String>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum Response {
+    Status { cases: Vec<CaseStatus> },
+    Done,
+    Error { message: String },
+    Frame { frame: Frame },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CaseStatus {
+    pub commit: String,
+    pub test: String,
+    pub status: String,
+    pub succeeded: bool,
+    #[serde(default)]
+    pub advisory: bool,
+}
+
+impl CaseStatus {
+    fn new(test_case: &TestCase, status: &TestStatus) -> Self {
+        Self {
+            commit: test_case.commit_hash.to_string(),
+            test: test_case.test.name.to_string(),
+            status: status.to_string(),
+            succeeded: status.succeeded(),
+            advisory: test_case.test.advisory,
+        }
+    }
+
+    // Whether the case should make the command reporting it fail.
+    pub fn failed(&self) -> bool {
+        !self.succeeded && !self.advisory
+    }
+}
+
+// Sorted by commit then test name, so output is stable.
+pub fn case_statuses(statuses: &[(TestCase, TestStatus)]) -> Vec<CaseStatus> {
+    let mut cases: Vec<CaseStatus> = statuses
+        .iter()
+        .map(|(test_case, status)| CaseStatus::new(test_case, status))
+        .collect();
+    cases.sort_by(|a, b| (&a.commit, &a.test).cmp(&(&b.commit, &b.test)));
+    cases
+}
+
+pub struct CtlServer {
+    listener: UnixListener,
+    path: PathBuf,
+    manager: Arc<Manager<PersistentWorktree>>,
+    repo: Arc<PersistentWorktree>,
+    ui_state: Arc<UiState>,
+    // Held for as long as we own the socket path.
+    _lock: Flock<File>,
+}
+
+impl CtlServer {
+    // Returns None if another instance already owns the socket. In that case
+    // it's up to the caller whether that's a problem.
+    pub fn bind(
+        path: &Path,
+        manager: Arc<Manager<PersistentWorktree>>,
+        repo: Arc<PersistentWorktree>,
+        ui_state: Arc<UiState>,
+    ) -> anyhow::Result<Option<Self>> {
+        let mut lock_path = path.as_os_str().to_owned();
+        lock_path.push(".lock");
+        let lock_file =
+            File::create(&lock_path).with_context(|| format!("creating {lock_path:?}"))?;
+        let lock = match Flock::lock(lock_file, FlockArg::LockExclusiveNonblock) {
+            Ok(lock) => lock,
+            Err((_, Errno::EWOULDBLOCK)) => return Ok(None),
+            Err((_, errno)) => bail!("locking {lock_path:?}: {errno}"),
+        };
+        // Whoever had the lock before us is gone, so any socket left behind
+        // is stale.
+        if path.exists() {
+            fs::remove_file(path).with_context(|| format!("removing stale {}", path.display()))?;
+        }
+        let listener = UnixListener::bind(path)
+            .with_context(|| format!("binding control socket at {}", path.display()))?;
+        Ok(Some(Self {
+            listener,
+            path: path.to_owned(),
+            manager,
+            repo,
+            ui_state,
+            _lock: lock,
+        }))
+    }
+
+    // Serve requests until the token is cancelled. Connections still open at
+    // that point are dropped.
+    pub async fn serve(self, ct: CancellationToken) -> anyhow::Result<()> {
+        let mut conns = JoinSet::new();
+        loop {
+            select! {
+                _ = ct.cancelled() => break,
+                accepted = self.listener.accept() => {
+                    let (stream, _) = accepted.context("accepting control connection")?;
+                    let manager = self.manager.clone();
+                    let repo = self.repo.clone();
+                    let ui_state = self.ui_state.clone();
+                    conns.spawn(async move {
+                        if let Err(e) = handle_conn(stream, &manager, &repo, &ui_state).await {
+                            debug!("control connection failed: {e:#}");
+                        }
+                    });
+                },
+                // Reap finished connections so the set doesn't grow forever.
+                Some(_) = conns.join_next() => {},
+            }
+        }
+        conns.shutdown().await;
+        Ok(())
+    }
+}
+
+impl Drop for CtlServer {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("removing control socket {}: {e}", self.path.display());
+        }
+    }
+}
+
+async fn handle_conn(
+    stream: UnixStream,
+    manager: &Manager<PersistentWorktree>,
+    repo: &PersistentWorktree,
+    ui_state: &UiState,
+) -> anyhow::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await.context("reading request")? {
+        let response = match serde_json::from_str(&line) {
+            Ok(Request::Attach) => return stream_frames(writer, ui_state).await,
+            Ok(request) => match handle_request(request, manager, repo).await {
+                Ok(response) => response,
+                Err(e) => Response::Error {
+                    message: format!("{e:#}"),
+                },
+            },
+            Err(e) => Response::Error {
+                message: format!("invalid request: {e}"),
+            },
+        };
+        write_response(&mut writer, &response).await?;
+    }
+    Ok(())
+}
+
+async fn write_response(writer: &mut OwnedWriteHalf, response: &Response) -> anyhow::Result<()> {
+    let mut out = serde_json::to_vec(response).expect("failed to serialize response");
+    out.push(b'\n');
+    writer.write_all(&out).await.context("writing response")
+}
+
+// Returns once the client goes away, but we only notice that when the status
+// view changes.
+async fn stream_frames(mut writer: OwnedWriteHalf, ui_state: &UiState) -> anyhow::Result<()> {
+    let mut frames = ui_state.subscribe_frames();
+    loop {
+        let frame = frames.borrow_and_update().clone();
+        if let Some(frame) = frame {
+            let frame = Frame::clone(&frame);
+            write_response(&mut writer, &Response::Frame { frame }).await?;
+        }
+        frames.changed().await.context("status view shut down")?;
+    }
+}
+
+async fn handle_request(
+    request: Request,
+    manager: &Manager<PersistentWorktree>,
+    repo: &PersistentWorktree,
+) -> anyhow::Result<Response> {
+    let resolve = |rev: String| async move {
+        repo.rev_parse(rev.as_str())
+            .await
+            .context("looking up revision")?
+            .ok_or_else(|| anyhow!("revision {rev:?} not found"))
+    };
+    match request {
+        Request::Status => Ok(Response::Status {
+            cases: case_statuses(&manager.statuses()),
+        }),
+        Request::Cancel { test, rev } => {
+            manager.cancel(&resolve(rev).await?.hash, &TestName::new(test))?;
+            Ok(Response::Done)
+        }
+        Request::Rerun { test, rev } => {
+            manager
+                .rerun(&resolve(rev).await?.hash, &TestName::new(test))
+                .await?;
+            Ok(Response::Done)
+        }
+        Request::Bump { test, rev } => {
+            manager.bump(&resolve(rev).await?.hash, &TestName::new(test))?;
+            Ok(Response::Done)
+        }
+        Request::Run { test, rev } => {
+            manager.run(&resolve(rev).await?.hash, &TestName::new(test))?;
+            Ok(Response::Done)
+        }
+        Request::Release { test, rev } => {
+            manager
+                .release_worktree(&resolve(rev).await?.hash, &TestName::new(test))
+                .await?;
+            Ok(Response::Done)
+        }
+        Request::Wait {
+            rev,
+            tests,
+            skip_tests,
+        } => {
+            let compile = |regexes: Vec<String>| {
+                regexes
+                    .iter()
+                    .map(|r| Regex::new(r))
+                    .collect::<Result<Vec<_>, _>>()
+                    .context("compiling test regexes")
+            };
+            let (tests, skip_tests) = (compile(tests)?, compile(skip_tests)?);
+            // A single revision means just that commit, not its ancestors.
+            let commits = match repo
+                .rev_parse(rev.as_str())
+                .await
+                .context("looking up revision")?
+            {
+                Some(commit) => vec![commit.hash],
+                None => repo.rev_list(&rev).await.context("listing revisions")?,
+            };
+            if commits.is_empty() {
+                bail!("no commits in {rev:?}");
+            }
+            let statuses = manager
+                .wait(&commits, |name| {
+                    let name = name.to_string();
+                    (tests.is_empty() || tests.iter().any(|r| r.is_match(&name)))
+                        && !skip_tests.iter().any(|r| r.is_match(&name))
+                })
+                .await?;
+            Ok(Response::Status {
+                cases: case_statuses(&statuses),
+            })
+        }
+        Request::Attach => unreachable!("attach requests are handled by the connection"),
+    }
+}
+
+async fn send_request(path: &Path, request: &Request) -> anyhow::Result<UnixStream> {
+    let mut stream = UnixStream::connect(path).await.with_context(|| {
+        format!(
+            "connecting to {} - is limmat watch running?",
+            path.display()
+        )
+    })?;
+    let mut out = serde_json::to_vec(request).expect("failed to serialize request");
+    out.push(b'\n');
+    stream.write_all(&out).await.context("sending request")?;
+    Ok(stream)
+}
+
+// Send a single request to the instance listening at path.
+pub async fn request(path: &Path, request: &Request) -> anyhow::Result<Response> {
+    let stream = send_request(path, request).await?;
+    let line = BufReader::new(stream)
+        .lines()
+        .next_line()
+        .await
+        .context("reading response")?
+        .ok_or_else(|| anyhow!("connection closed without response"))?;
+    serde_json::from_str(&line).context("parsing response")
+}
+
+// Get the status view of the instance listening at path, every time it
+// changes. The stream ends when that instance shuts down.
+pub async fn attach(path: &Path) -> anyhow::Result<impl Stream<Item = anyhow::Result<Frame>>> {
+    let stream = send_request(path, &Request::Attach).await?;
+    let mut lines = BufReader::new(stream).lines();
+    Ok(try_stream! {
+        while let Some(line) = lines.next_line().await.context("reading frame")? {
+            match serde_json::from_str(&line).context("parsing response")? {
+                Response::Frame { frame } => yield frame,
+                Response::Error { message } => Err(anyhow!("{message}"))?,
+                response => Err(anyhow!("unexpected response {response:?}"))?,
+            }
+        }
+    })
+}
@@ -1,22 +1,52 @@
 use std::{
-    fs::{create_dir, create_dir_all, File, OpenOptions},
-    io::ErrorKind::AlreadyExists,
+    collections::BTreeMap,
+    fmt::{self, Display},
+    fs::{self, create_dir, create_dir_all, File, OpenOptions},
+    io::{
+        self,
+        ErrorKind::{AlreadyExists, NotFound},
+        Read as _, Write as _,
+    },
+    mem,
+    os::{
+        fd::OwnedFd,
+        unix::{ffi::OsStrExt as _, fs::MetadataExt as _},
+    },
     path::{Path, PathBuf},
     process::Stdio,
+    time::{Duration, Instant, SystemTime},
 };
 
 use anyhow::{bail, Context, Result};
 #[allow(unused_imports)]
-use log::debug;
+use log::{debug, info, warn};
+use nix::{
+    errno::Errno,
+    fcntl::OFlag,
+    sys::{
+        signal::kill,
+        statfs::{statfs, NFS_SUPER_MAGIC},
+    },
+    unistd::{pipe2, Pid},
+};
 use serde::{Deserialize, Serialize};
 #[cfg(test)]
 use tempfile::NamedTempFile;
+use tokio::{
+    io::{AsyncBufReadExt as _, AsyncReadExt as _, AsyncWriteExt as _, BufReader},
+    net::unix::pipe,
+    task::{self, JoinHandle},
+    time::timeout,
+};
 
 use crate::{
-    flock::{ExclusiveFlock, SharedFlock},
+    flock::{ExclusiveFlock, LockMethod, LockTarget, SharedFlock},
     git::Hash,
-    test::{ConfigHash, ExitCode, TestCase, TestName, TestResult},
-    util::IoResultExt as _,
+    test::{CancelReason, ConfigHash, ExitCode, TestCase, TestName, TestResult},
+    util::{
+        disk_usage, format_age, hostname, shell_quote, write_atomic, IoResultExt as _,
+        ResultExt as _,
+    },
 };
 
 // Result database similar to the design described in
@@ -24,12 +54,256 @@ use crate::{
 // TODO: Actually we should probably separate it by the repo lol. But how?
 pub struct Database {
     pub base_dir: PathBuf,
+    lock_method: LockMethod,
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 struct TestResultEntry {
     config_hash: ConfigHash,
     result: TestResult,
+    // Not recorded by older versions.
+    #[serde(default)]
+    provenance: Option<Provenance>,
+    // For tests made of several steps, the number of the one that failed.
+    #[serde(default)]
+    failed_step: Option<usize>,
+    // Doesn't affect the result, but the user should hear about it.
+    #[serde(default)]
+    teardown_error: Option<String>,
+    // Same for the artifact_upload command.
+    #[serde(default)]
+    upload_error: Option<String>,
+    // What the test wrote to $LIMMAT_METRICS.
+    #[serde(default)]
+    metrics: BTreeMap<String, f64>,
+    // The outputs the test declared, if it passed and they were all there.
+    #[serde(default)]
+    outputs: Vec<String>,
+}
+
+// Where and when a result was produced.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Provenance {
+    pub host: String,
+    // Not recorded by older versions.
+    #[serde(default)]
+    pub started_at: Option<SystemTime>,
+    pub finished_at: SystemTime,
+}
+
+impl Provenance {
+    fn here_and_now(started_at: SystemTime) -> Self {
+        Self {
+            host: hostname(),
+            started_at: Some(started_at),
+            finished_at: SystemTime::now(),
+        }
+    }
+
+    // Like "3d ago", plus the host if it's not this one and how long the test
+    // took if that's known.
+    pub fn describe(&self) -> String {
+        let age = format_age(self.finished_at.elapsed().unwrap_or_default());
+        let mut description = format!("{age} ago");
+        if self.host != hostname() {
+            description += &format!(" on {}", self.host);
+        }
+        if let Some(duration) = self
+            .started_at
+            .and_then(|started_at| self.finished_at.duration_since(started_at).ok())
+        {
+            description += &format!(", took {}", format_age(duration));
+        }
+        description
+    }
+}
+
+// What a job ran and how, so that it can be reproduced by hand.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct JobSnapshot {
+    // Including the wrappers for the sandbox, if any.
+    pub argv: Vec<String>,
+    pub cwd: PathBuf,
+    // The job's whole environment, not just what Limmat set.
+    pub env: BTreeMap<String, String>,
+    pub resource_tokens: BTreeMap<String, Vec<String>>,
+    // "git describe" of the directory the job ran in, None if that isn't a
+    // Git checkout.
+    pub git_describe: Option<String>,
+}
+
+impl JobSnapshot {
+    // A command line for the shell that runs the job the same way, as far as
+    // the environment and arguments go.
+    pub fn shell_command(&self) -> String {
+        let mut words = vec!["cd".to_owned(), shell_quote(&self.cwd.to_string_lossy())];
+        words.extend(["&&".to_owned(), "env".to_owned(), "-i".to_owned()]);
+        words.extend(
+            self.env
+                .iter()
+                .map(|(k, v)| shell_quote(&format!("{k}={v}"))),
+        );
+        words.extend(self.argv.iter().map(|arg| shell_quote(arg)));
+        words.join(" ")
+    }
+}
+
+// Marker file in the result directory, present if the result is pinned.
+const PINNED_FILENAME: &str = "pinned";
+// Why the latest attempt to run the test case was canceled.
+const CANCELED_FILENAME: &str = "canceled.json";
+// A Slowdown, present if the test case ran slower than usual.
+const SLOWDOWN_FILENAME: &str = "slowdown.json";
+// A JobSnapshot of the latest run.
+const JOB_SNAPSHOT_FILENAME: &str = "job.json";
+// Where a test made of several steps writes the number of the one that failed.
+const FAILED_STEP_FILENAME: &str = "failed_step";
+// Where a test writes its metrics, one "name=value" per line.
+const METRICS_FILENAME: &str = "metrics.txt";
+// A RunningMarker, present while a test is being run.
+const RUNNING_FILENAME: &str = "running.json";
+// How long recover_orphans waits for a result's lock. If it's held, the test
+// is being run after all.
+const RECOVERY_LOCK_TIMEOUT: Duration = Duration::from_secs(1);
+// Longer lines of job output are split, so a job can't make us buffer an
+// unlimited amount of it.
+const MAX_OUTPUT_LINE: usize = 64 * 1024;
+// Once the job is done, how long we wait for the rest of its output. Something
+// it started in the background might keep the pipe open forever.
+const OUTPUT_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Who is running a test, so that if they crash, the next instance can tell the
+// result directory was left half-done.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+struct RunningMarker {
+    host: String,
+    pid: u32,
+    started_at: SystemTime,
+}
+
+// Removes the RunningMarker when dropped.
+struct RunningGuard(PathBuf);
+
+impl RunningGuard {
+    fn new(result_dir: &Path) -> anyhow::Result<Self> {
+        let path = result_dir.join(RUNNING_FILENAME);
+        let marker = RunningMarker {
+            host: hostname(),
+            pid: std::process::id(),
+            started_at: SystemTime::now(),
+        };
+        write_atomic(
+            &path,
+            &serde_json::to_vec(&marker).expect("failed to serialize"),
+        )?;
+        Ok(Self(path))
+    }
+}
+
+impl Drop for RunningGuard {
+    fn drop(&mut self) {
+        fs::remove_file(&self.0)
+            .ignore(NotFound)
+            .with_context(|| format!("removing {}", self.0.display()))
+            .or_log_error("cleaning up after test");
+    }
+}
+
+// What recover_orphans did.
+#[derive(Debug, Default, PartialEq)]
+pub struct Recovered {
+    // The test finished, only the marker was left.
+    pub adopted: usize,
+    // Removed, so that the test gets run again.
+    pub removed: usize,
+}
+
+// What recover_orphans did to one result.
+enum Recovery {
+    Adopted,
+    Removed,
+}
+
+// Directory in the database holding a History for each test name.
+const HISTORY_DIRNAME: &str = "history";
+// How much each new run counts for in a History, vs. all the ones before.
+const HISTORY_WEIGHT: f64 = 0.2;
+
+// How a test has been behaving recently, as moving averages over the runs
+// that finished on any commit.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct History {
+    pub failure_rate: f64,
+    pub duration_secs: f64,
+}
+
+// A run that took longer than the test's history says it usually does.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct Slowdown {
+    pub duration_secs: f64,
+    // The History duration from before the run.
+    pub baseline_secs: f64,
+}
+
+impl Slowdown {
+    // If the run took more than threshold_percent longer than the baseline.
+    pub fn check(duration: Duration, history: &History, threshold_percent: u32) -> Option<Self> {
+        let slowdown = Self {
+            duration_secs: duration.as_secs_f64(),
+            baseline_secs: history.duration_secs,
+        };
+        (slowdown.percent() > threshold_percent as f64).then_some(slowdown)
+    }
+
+    pub fn percent(&self) -> f64 {
+        (self.duration_secs / self.baseline_secs - 1.0) * 100.0
+    }
+}
+
+impl Display for Slowdown {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "took {}, {:.0}% longer than usual ({})",
+            format_age(Duration::from_secs_f64(self.duration_secs)),
+            self.percent(),
+            format_age(Duration::from_secs_f64(self.baseline_secs))
+        )
+    }
+}
+
+const CONFIGS_DIRNAME: &str = "configs";
+
+// A test's config as it was when a run started, with the fields from
+// ignore_for_cache left out.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct ConfigSnapshot {
+    pub config_hash: ConfigHash,
+    pub recorded_at: SystemTime,
+    pub config: serde_json::Value,
+}
+
+// The last two distinct configs a test was run with.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct ConfigSnapshots {
+    pub current: ConfigSnapshot,
+    pub previous: Option<ConfigSnapshot>,
+}
+
+// A result found by scanning the whole database, see Database::all_results.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredResult {
+    pub exit_code: ExitCode,
+    pub provenance: Option<Provenance>,
+    pub dir: PathBuf,
+    // Bytes on disk, for the output, artifacts and everything else.
+    pub size: u64,
+    pub pinned: bool,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+struct CancelEntry {
+    reason: CancelReason,
 }
 
 pub enum LookupResult {
@@ -46,13 +320,50 @@ pub enum LookupResult {
 // For now I am just gonna assume flock has the most helpful semantics among the
 // range of ambiguity and hope it's fine.
 impl Database {
+    // Entries are locked with lock files if the database is on NFS, where
+    // flock isn't reliable, unless that's overridden with with_lock_method.
     pub fn create_or_open(base_dir: &Path) -> anyhow::Result<Self> {
         create_dir_all(base_dir).context(format!(
             "creating result database dir at {}",
             base_dir.display()
         ))?;
+        let fs_type = statfs(base_dir)
+            .with_context(|| format!("checking filesystem of {}", base_dir.display()))?
+            .filesystem_type();
+        let lock_method = if fs_type == NFS_SUPER_MAGIC {
+            debug!("{} is on NFS, using lock files", base_dir.display());
+            LockMethod::Lockfile
+        } else {
+            LockMethod::Flock
+        };
         Ok(Self {
             base_dir: base_dir.to_owned(),
+            lock_method,
+        })
+    }
+
+    pub fn with_lock_method(mut self, lock_method: LockMethod) -> Self {
+        self.lock_method = lock_method;
+        self
+    }
+
+    pub fn lock_method(&self) -> LockMethod {
+        self.lock_method
+    }
+
+    fn open_json(&self, result_dir: &Path) -> anyhow::Result<LockTarget> {
+        let path = result_dir.join("result.json");
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .context("opening result JSON")?;
+        Ok(LockTarget {
+            file,
+            path,
+            method: self.lock_method,
         })
     }
 
@@ -72,53 +383,18 @@ impl Database {
             .with_context(|| format!("creating commit result dir at {}", result_dir.display()))?;
         let json_path = result_dir.join("result.json");
 
-        let parse_result = |json: &str| -> Option<TestResultEntry> {
-            // Manually ignore empty JSON to avoid log spam.
-            if json.is_empty() {
-                return None;
-            }
-            match serde_json::from_str::<TestResultEntry>(json) {
-                Ok(test_result) => {
-                    // Has the configuration changed? if not we need to rerun regardless.
-                    if test_result.config_hash == test_case.test.config_hash {
-                        // Was the test configured to accept cached results?
-                        if test_case.cache_hash.is_some() {
-                            // Cool, we're done.
-                            return Some(test_result);
-                        }
-                    }
-                }
-                Err(e) => {
-                    // This probably just means limmat got killed before we finished
-                    // writing the result.
-                    debug!(
-                        "Error reading result JSON from {}: {e} - JSON\n{:?}",
-                        json_path.display(),
-                        json,
-                    );
-                }
-            }
-            None
-        };
-
         // Don't block forever.
         for _ in 0..5 {
-            let json_file = OpenOptions::new()
-                .read(true)
-                .write(true)
-                .create(true)
-                .truncate(false)
-                .open(result_dir.join("result.json"))
-                .context("opening result JSON")?;
-            let flock = SharedFlock::new(json_file)
+            let flock = SharedFlock::new(self.open_json(&result_dir)?)
                 .await
                 .context("locking JSON file for reading")?;
 
-            if let Some(test_result) = parse_result(flock.content()) {
+            if let Some(test_result) = parse_result(flock.content(), &json_path, test_case) {
                 return Ok(LookupResult::FoundResult(DatabaseEntry {
                     base_path: result_dir.clone(),
                     result: test_result,
-                    _json_flock: flock,
+                    cached: true,
+                    hold: EntryHold::Lock { _json_flock: flock },
                     #[cfg(test)]
                     _tempfile: None,
                 }));
@@ -129,7 +405,7 @@ impl Database {
 
             // But, that upgrade wasn't atomic, someone else might have jumped
             // in and run the test. Check if that's the case...
-            if parse_result(flock.content()).is_some() {
+            if parse_result(flock.content(), &json_path, test_case).is_some() {
                 // OK great someone ran the test, so we just wanna return the result. But for that
                 // we need to downgrade the lock to a shared lock, which is also not atomic. At the
                 // time of writing, this is harmless: we know the test case is cacheable (otherwise
@@ -152,20 +428,613 @@ impl Database {
         }
         bail!("too much database contention, something fishy going on")
     }
+
+    // Like lookup, but doesn't create anything if there's no result, for when
+    // we are only reading the database. This doesn't take the lock, so it
+    // never waits for a running test: there's no result until it's done, or
+    // the previous one if it's being run again. Nor does the entry stop the
+    // test being run again, see DatabaseEntry::check_unchanged.
+    pub fn find(&self, test_case: &TestCase) -> Result<Option<DatabaseEntry>> {
+        let result_dir = self.result_path(test_case.storage_hash(), &test_case.test.name);
+        let json_path = result_dir.join("result.json");
+        // Don't spin forever.
+        for _ in 0..5 {
+            let mut json = match File::open(&json_path) {
+                Ok(json) => json,
+                Err(e) if matches!(e.kind(), NotFound | io::ErrorKind::NotADirectory) => {
+                    return Ok(None)
+                }
+                Err(e) => {
+                    return Err(e).with_context(|| format!("opening {}", json_path.display()))
+                }
+            };
+            let mut content = String::new();
+            json.read_to_string(&mut content)
+                .with_context(|| format!("reading {}", json_path.display()))?;
+            let len = content.len() as u64;
+            // If someone's running the test again, this is still the result
+            // until they replace it, but check_unchanged will fail.
+            if !json_current(&result_dir, &json, len)? {
+                // A new result just got written, read that one.
+                continue;
+            }
+            let Some(test_result) = parse_result(&content, &json_path, test_case) else {
+                return Ok(None);
+            };
+            return Ok(Some(DatabaseEntry {
+                base_path: result_dir,
+                result: test_result,
+                cached: true,
+                hold: EntryHold::Snapshot { json, len },
+                #[cfg(test)]
+                _tempfile: None,
+            }));
+        }
+        bail!("result at {} keeps changing", json_path.display())
+    }
+
+    // Like find, but if someone is running the test this waits for the result,
+    // and the entry stops anyone running it again until it's dropped.
+    pub async fn find_waiting(&self, test_case: &TestCase) -> Result<Option<DatabaseEntry>> {
+        let result_dir = self.result_path(test_case.storage_hash(), &test_case.test.name);
+        let json_path = result_dir.join("result.json");
+        if !json_path
+            .try_exists()
+            .with_context(|| format!("checking for {}", json_path.display()))?
+        {
+            return Ok(None);
+        }
+        let flock = SharedFlock::new(self.open_json(&result_dir)?)
+            .await
+            .context("locking JSON file for reading")?;
+        let Some(test_result) = parse_result(flock.content(), &json_path, test_case) else {
+            return Ok(None);
+        };
+        Ok(Some(DatabaseEntry {
+            base_path: result_dir,
+            result: test_result,
+            cached: true,
+            hold: EntryHold::Lock { _json_flock: flock },
+            #[cfg(test)]
+            _tempfile: None,
+        }))
+    }
+
+    // When the test case started running, if someone is running it right now
+    // (or crashed while they were at it).
+    pub fn running_since(&self, test_case: &TestCase) -> anyhow::Result<Option<SystemTime>> {
+        let result_dir = self.result_path(test_case.storage_hash(), &test_case.test.name);
+        Ok(read_running_marker(&result_dir)?.map(|marker| marker.started_at))
+    }
+
+    // Record why the latest attempt to run the test case was canceled, or
+    // clear any existing record if it wasn't. This is kept separately from
+    // the result JSON, so it doesn't need the lock.
+    pub fn set_cancel_reason(
+        &self,
+        test_case: &TestCase,
+        reason: Option<CancelReason>,
+    ) -> anyhow::Result<()> {
+        self.write_beside_result(
+            test_case,
+            CANCELED_FILENAME,
+            reason.map(|reason| CancelEntry { reason }).as_ref(),
+        )
+    }
+
+    pub fn cancel_reason(&self, test_case: &TestCase) -> anyhow::Result<Option<CancelReason>> {
+        let entry: Option<CancelEntry> = self.read_beside_result(test_case, CANCELED_FILENAME)?;
+        Ok(entry.map(|entry| entry.reason))
+    }
+
+    // Record that the test case ran slower than usual, or clear any existing
+    // record if it didn't. Like the cancel reason, this is kept outside the
+    // result JSON.
+    pub fn set_slowdown(
+        &self,
+        test_case: &TestCase,
+        slowdown: Option<Slowdown>,
+    ) -> anyhow::Result<()> {
+        self.write_beside_result(test_case, SLOWDOWN_FILENAME, slowdown.as_ref())
+    }
+
+    pub fn slowdown(&self, test_case: &TestCase) -> anyhow::Result<Option<Slowdown>> {
+        self.read_beside_result(test_case, SLOWDOWN_FILENAME)
+    }
+
+    // For files in the result directory that don't need the lock. None
+    // removes the file.
+    fn write_beside_result<T: Serialize>(
+        &self,
+        test_case: &TestCase,
+        filename: &str,
+        value: Option<&T>,
+    ) -> anyhow::Result<()> {
+        let result_dir = self.result_path(test_case.storage_hash(), &test_case.test.name);
+        let path = result_dir.join(filename);
+        let Some(value) = value else {
+            return fs::remove_file(&path)
+                .ignore(NotFound)
+                .with_context(|| format!("removing {}", path.display()));
+        };
+        create_dir_all(&result_dir)
+            .with_context(|| format!("creating commit result dir at {}", result_dir.display()))?;
+        // Write it atomically so readers never see a partial entry.
+        write_atomic(
+            &path,
+            &serde_json::to_vec(value).expect("failed to serialize"),
+        )
+    }
+
+    fn read_beside_result<T: for<'de> Deserialize<'de>>(
+        &self,
+        test_case: &TestCase,
+        filename: &str,
+    ) -> anyhow::Result<Option<T>> {
+        let path = self
+            .result_path(test_case.storage_hash(), &test_case.test.name)
+            .join(filename);
+        let json = match fs::read(&path) {
+            Ok(json) => json,
+            Err(e) if e.kind() == NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("reading {}", path.display())),
+        };
+        serde_json::from_slice(&json)
+            .map(Some)
+            .with_context(|| format!("parsing {}", path.display()))
+    }
+
+    // Pinned results are never to be pruned from the database. Like the cancel
+    // reason, this is kept outside the result JSON.
+    pub fn is_pinned(&self, test_case: &TestCase) -> anyhow::Result<bool> {
+        let path = self
+            .result_path(test_case.storage_hash(), &test_case.test.name)
+            .join(PINNED_FILENAME);
+        path.try_exists()
+            .with_context(|| format!("checking for {}", path.display()))
+    }
+
+    // None if the test has never been run to completion.
+    pub fn history(&self, test_name: &TestName) -> anyhow::Result<Option<History>> {
+        self.read_per_test(HISTORY_DIRNAME, test_name)
+    }
+
+    fn read_per_test<T: for<'de> Deserialize<'de>>(
+        &self,
+        dirname: &str,
+        test_name: &TestName,
+    ) -> anyhow::Result<Option<T>> {
+        let path = self.base_dir.join(dirname).join(test_name);
+        let json = match fs::read(&path) {
+            Ok(json) => json,
+            Err(e) if e.kind() == NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("reading {}", path.display())),
+        };
+        serde_json::from_slice(&json)
+            .map(Some)
+            .with_context(|| format!("parsing {}", path.display()))
+    }
+
+    // Atomically, so that readers never see a partial file.
+    fn write_per_test<T: Serialize>(
+        &self,
+        dirname: &str,
+        test_name: &TestName,
+        value: &T,
+    ) -> anyhow::Result<()> {
+        let dir = self.base_dir.join(dirname);
+        create_dir_all(&dir).with_context(|| format!("creating {}", dir.display()))?;
+        write_atomic(
+            &dir.join(test_name),
+            &serde_json::to_vec(value).expect("failed to serialize"),
+        )
+    }
+
+    // Fold a finished run into the test's history. If other instances are
+    // doing this at the same time, some runs might not get counted. That
+    // doesn't matter much for a scheduling hint.
+    pub fn record_run(
+        &self,
+        test_name: &TestName,
+        duration: Duration,
+        failed: bool,
+    ) -> anyhow::Result<()> {
+        let run = History {
+            failure_rate: if failed { 1.0 } else { 0.0 },
+            duration_secs: duration.as_secs_f64(),
+        };
+        let history = match self.history(test_name)? {
+            Some(old) => History {
+                failure_rate: old.failure_rate
+                    + HISTORY_WEIGHT * (run.failure_rate - old.failure_rate),
+                duration_secs: old.duration_secs
+                    + HISTORY_WEIGHT * (run.duration_secs - old.duration_secs),
+            },
+            None => run,
+        };
+        self.write_per_test(HISTORY_DIRNAME, test_name, &history)
+    }
+
+    // Every result for the test in the database, whatever commit and config
+    // it was for. This doesn't take any locks, results that are being written
+    // just get skipped.
+    pub fn all_results(&self, test_name: &TestName) -> anyhow::Result<Vec<StoredResult>> {
+        let mut results = Vec::new();
+        let read_err = || format!("reading {}", self.base_dir.display());
+        for entry in fs::read_dir(&self.base_dir).with_context(read_err)? {
+            let entry = entry.with_context(read_err)?;
+            if !entry.file_type().context("checking file type")?.is_dir() {
+                continue;
+            }
+            let dir = entry.path().join(test_name);
+            let path = dir.join("result.json");
+            let json = match fs::read(&path) {
+                Ok(json) => json,
+                // Most likely the test was never run for this commit, or
+                // this is one of our other directories.
+                Err(e) if matches!(e.kind(), NotFound | io::ErrorKind::NotADirectory) => continue,
+                Err(e) => return Err(e).with_context(|| format!("reading {}", path.display())),
+            };
+            let Ok(entry) = serde_json::from_slice::<TestResultEntry>(&json) else {
+                continue;
+            };
+            results.push(StoredResult {
+                exit_code: entry.result.exit_code,
+                provenance: entry.provenance,
+                size: disk_usage(&dir)?,
+                pinned: dir.join(PINNED_FILENAME).exists(),
+                dir,
+            });
+        }
+        Ok(results)
+    }
+
+    // Delete a result found by all_results, once nobody is using it.
+    pub async fn remove_result(&self, result: &StoredResult) -> anyhow::Result<()> {
+        let mut flock = ExclusiveFlock::new(self.open_json(&result.dir)?)
+            .await
+            .context("locking result JSON")?;
+        // Anyone already waiting for the lock will find there's no result.
+        flock.clear().context("clearing result JSON")?;
+        fs::remove_dir_all(&result.dir)
+            .with_context(|| format!("removing {}", result.dir.display()))
+    }
+
+    // Deal with results that instances on this host were in the middle of
+    // running when they died. We can't tell whether an instance on another
+    // host is still alive, so those are left alone. Anything that can't be
+    // read or cleaned up is logged and skipped, one broken entry shouldn't
+    // stop the rest of the database being used.
+    pub async fn recover_orphans(&self) -> Recovered {
+        let mut recovered = Recovered::default();
+        // Walking the whole database is slow, and all synchronous IO, so that's
+        // done on a blocking thread. Only the waiting for locks happens here.
+        let base_dir = self.base_dir.clone();
+        let orphans = task::spawn_blocking(move || find_orphans(&base_dir, &hostname()))
+            .await
+            .unwrap();
+        for (dir, marker) in orphans {
+            match self.recover_orphan(&dir, marker).await {
+                Ok(Some(Recovery::Adopted)) => recovered.adopted += 1,
+                Ok(Some(Recovery::Removed)) => recovered.removed += 1,
+                Ok(None) => (),
+                Err(e) => warn!("Couldn't recover {}: {e:#}", dir.display()),
+            }
+        }
+        recovered
+    }
+
+    // The part of recover_orphans for a single result directory.
+    async fn recover_orphan(
+        &self,
+        dir: &Path,
+        marker: RunningMarker,
+    ) -> anyhow::Result<Option<Recovery>> {
+        let Ok(flock) = timeout(
+            RECOVERY_LOCK_TIMEOUT,
+            ExclusiveFlock::new(self.open_json(dir)?),
+        )
+        .await
+        else {
+            return Ok(None);
+        };
+        let flock = flock.context("locking result JSON")?;
+        let dir = dir.to_owned();
+        task::spawn_blocking(move || remove_orphan(&dir, &marker, flock)).await?
+    }
+
+    pub fn config_snapshots(
+        &self,
+        test_name: &TestName,
+    ) -> anyhow::Result<Option<ConfigSnapshots>> {
+        self.read_per_test(CONFIGS_DIRNAME, test_name)
+    }
+
+    // Remember the config the test is about to be run with, if it's different
+    // from last time, so that explain-cache can say what changed. Like
+    // record_run, this can lose updates that race with each other.
+    pub fn record_config(
+        &self,
+        test_name: &TestName,
+        config_hash: &ConfigHash,
+        config: &serde_json::Value,
+    ) -> anyhow::Result<()> {
+        let old = self.config_snapshots(test_name)?;
+        if old
+            .as_ref()
+            .is_some_and(|old| old.current.config_hash == *config_hash)
+        {
+            return Ok(());
+        }
+        let snapshots = ConfigSnapshots {
+            current: ConfigSnapshot {
+                config_hash: config_hash.clone(),
+                recorded_at: SystemTime::now(),
+                config: config.clone(),
+            },
+            previous: old.map(|old| old.current),
+        };
+        self.write_per_test(CONFIGS_DIRNAME, test_name, &snapshots)
+    }
+
+    // Like lookup, but ignore any existing result so that the test gets run
+    // again. The old result stays in place until the new one is written.
+    pub async fn lookup_rerun(&self, test_case: &TestCase) -> Result<LookupResult> {
+        let result_dir = self.result_path(test_case.storage_hash(), &test_case.test.name);
+        create_dir_all(&result_dir)
+            .with_context(|| format!("creating commit result dir at {}", result_dir.display()))?;
+        let flock = ExclusiveFlock::new(self.open_json(&result_dir)?)
+            .await
+            .context("locking JSON file for writing")?;
+        Ok(LookupResult::YouRunIt(
+            DatabaseOutput::new(
+                result_dir,
+                test_case.test.config_hash.clone(),
+                flock,
+                test_case.test.separate_outputs,
+            )
+            .context("creating database entry")?,
+        ))
+    }
+}
+
+fn read_metrics(path: &Path) -> anyhow::Result<BTreeMap<String, f64>> {
+    match fs::read_to_string(path) {
+        Ok(content) => {
+            parse_metrics(&content).with_context(|| format!("parsing {}", path.display()))
+        }
+        Err(e) if e.kind() == NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(e).with_context(|| format!("reading {}", path.display())),
+    }
+}
+
+// Later values for the same name win, so a test can just append as it goes.
+fn parse_metrics(content: &str) -> anyhow::Result<BTreeMap<String, f64>> {
+    let mut metrics = BTreeMap::new();
+    for (i, line) in content.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (name, value) = line
+            .split_once('=')
+            .with_context(|| format!("line {}: expected name=value, got {line:?}", i + 1))?;
+        let name = name.trim();
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "_.-/".contains(c))
+        {
+            bail!("line {}: bad metric name {name:?}", i + 1);
+        }
+        let value: f64 = value
+            .trim()
+            .parse()
+            .with_context(|| format!("line {}: bad value for {name}", i + 1))?;
+        // JSON can't represent these.
+        if !value.is_finite() {
+            bail!("line {}: {name} isn't a finite number", i + 1);
+        }
+        metrics.insert(name.to_owned(), value);
+    }
+    Ok(metrics)
+}
+
+// None if there's no marker, i.e. nobody was running the test.
+// Result directories under base_dir with running markers left by processes on
+// this host that have died.
+fn find_orphans(base_dir: &Path, host: &str) -> Vec<(PathBuf, RunningMarker)> {
+    let mut orphans = Vec::new();
+    let commit_entries = match fs::read_dir(base_dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!(
+                "Couldn't look for orphaned results: reading {}: {e}",
+                base_dir.display()
+            );
+            return orphans;
+        }
+    };
+    for commit_dir in commit_entries.flatten().map(|entry| entry.path()) {
+        if !commit_dir.is_dir() {
+            continue;
+        }
+        let entries = match fs::read_dir(&commit_dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!(
+                    "Couldn't look for orphaned results in {}: {e}",
+                    commit_dir.display()
+                );
+                continue;
+            }
+        };
+        for dir in entries.flatten().map(|entry| entry.path()) {
+            match read_running_marker(&dir) {
+                Ok(Some(marker)) if marker.host == host && !pid_alive(marker.pid) => {
+                    orphans.push((dir, marker))
+                }
+                Ok(_) => (),
+                Err(e) => warn!("Couldn't recover {}: {e:#}", dir.display()),
+            }
+        }
+    }
+    orphans
 }
 
-// Existing entry in the database. Until you drop this object, the entry is read-locked, meaning
-// you prevent anyone else from re-running the test.
+// The part of recover_orphan that happens once the result is locked.
+fn remove_orphan(
+    dir: &Path,
+    marker: &RunningMarker,
+    mut flock: ExclusiveFlock,
+) -> anyhow::Result<Option<Recovery>> {
+    // Someone might have taken over while we were waiting.
+    if read_running_marker(dir)?.as_ref() != Some(marker) {
+        return Ok(None);
+    }
+    // It might have been a rerun that died, then this is the old result
+    // and the output is a mess.
+    let finished = serde_json::from_str::<TestResultEntry>(flock.content())
+        .ok()
+        .and_then(|entry| entry.provenance)
+        .is_some_and(|p| p.finished_at >= marker.started_at);
+    if finished {
+        info!(
+            "Adopting {}, its runner died after finishing",
+            dir.display()
+        );
+        let path = dir.join(RUNNING_FILENAME);
+        fs::remove_file(&path).with_context(|| format!("removing {}", path.display()))?;
+        Ok(Some(Recovery::Adopted))
+    } else {
+        info!(
+            "Removing {}, its runner (PID {}) died",
+            dir.display(),
+            marker.pid
+        );
+        flock.clear().context("clearing result JSON")?;
+        fs::remove_dir_all(dir).with_context(|| format!("removing {}", dir.display()))?;
+        Ok(Some(Recovery::Removed))
+    }
+}
+
+fn read_running_marker(result_dir: &Path) -> anyhow::Result<Option<RunningMarker>> {
+    let path = result_dir.join(RUNNING_FILENAME);
+    let json = match fs::read(&path) {
+        Ok(json) => json,
+        Err(e) if matches!(e.kind(), NotFound | io::ErrorKind::NotADirectory) => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("reading {}", path.display())),
+    };
+    serde_json::from_slice(&json)
+        .map(Some)
+        .with_context(|| format!("parsing {}", path.display()))
+}
+
+fn pid_alive(pid: u32) -> bool {
+    // A corrupt marker, nothing can be running with that.
+    let Ok(pid) = pid.try_into() else {
+        return false;
+    };
+    // EPERM means it exists but belongs to someone else.
+    !matches!(kill(Pid::from_raw(pid), None), Err(Errno::ESRCH))
+}
+
+fn parse_result(json: &str, json_path: &Path, test_case: &TestCase) -> Option<TestResultEntry> {
+    // Manually ignore empty JSON to avoid log spam.
+    if json.is_empty() {
+        return None;
+    }
+    match serde_json::from_str::<TestResultEntry>(json) {
+        Ok(test_result) => {
+            // Has the configuration changed? if not we need to rerun regardless.
+            if test_result.config_hash == test_case.test.config_hash {
+                // Was the test configured to accept cached results?
+                if test_case.cache_hash.is_some() {
+                    // Cool, we're done.
+                    return Some(test_result);
+                }
+            }
+        }
+        Err(e) => {
+            // This probably just means limmat got killed before we finished
+            // writing the result.
+            debug!(
+                "Error reading result JSON from {}: {e} - JSON\n{:?}",
+                json_path.display(),
+                json,
+            );
+        }
+    }
+    None
+}
+
+// What keeps a DatabaseEntry's result from changing under it.
+#[derive(Debug)]
+enum EntryHold {
+    // Nobody else can run the test again until the entry is dropped.
+    Lock { _json_flock: SharedFlock },
+    // Read without the lock. The open JSON file, and how long it was.
+    Snapshot { json: File, len: u64 },
+}
+
+// Whether a result read without the lock is still the current one. Writing a
+// result replaces the JSON file and removing one truncates it, and whoever
+// runs the test leaves a RunningMarker while they're at it. Keeping the file
+// open means its inode can't be reused by a later result.
+fn snapshot_current(result_dir: &Path, json: &File, len: u64) -> anyhow::Result<bool> {
+    let marker_path = result_dir.join(RUNNING_FILENAME);
+    if marker_path
+        .try_exists()
+        .with_context(|| format!("checking for {}", marker_path.display()))?
+    {
+        return Ok(false);
+    }
+    json_current(result_dir, json, len)
+}
+
+// Like snapshot_current but ignoring the RunningMarker, i.e. whether the JSON
+// itself is still the current result.
+fn json_current(result_dir: &Path, json: &File, len: u64) -> anyhow::Result<bool> {
+    let open = json.metadata().context("checking result JSON")?;
+    let json_path = result_dir.join("result.json");
+    match fs::metadata(&json_path) {
+        Ok(current) => {
+            Ok((current.dev(), current.ino(), current.len()) == (open.dev(), open.ino(), len))
+        }
+        Err(e) if e.kind() == NotFound => Ok(false),
+        Err(e) => Err(e).with_context(|| format!("checking {}", json_path.display())),
+    }
+}
+
+// Existing entry in the database. Entries from Database::lookup and
+// DatabaseOutput::set_result are read-locked until you drop this object,
+// meaning you prevent anyone else from re-running the test.
 #[derive(Debug)]
 pub struct DatabaseEntry {
     base_path: PathBuf,
     result: TestResultEntry,
-    _json_flock: SharedFlock,
+    // Whether the result was already there, as opposed to having just been
+    // written.
+    cached: bool,
+    hold: EntryHold,
     #[cfg(test)]
     _tempfile: Option<NamedTempFile>,
 }
 
 impl DatabaseEntry {
+    // Entries from Database::find aren't locked, so the test might have been
+    // run again, or the result deleted, since. Call this after reading
+    // anything else from the result directory, to make sure it went with
+    // this result.
+    pub fn check_unchanged(&self) -> anyhow::Result<()> {
+        let EntryHold::Snapshot { json, len } = &self.hold else {
+            return Ok(());
+        };
+        if !snapshot_current(&self.base_path, json, *len)? {
+            bail!("the result changed while it was being read, try again");
+        }
+        Ok(())
+    }
+
     pub fn result(&self) -> &TestResult {
         &self.result.result
     }
@@ -174,6 +1043,56 @@ impl DatabaseEntry {
         self.result.result.exit_code
     }
 
+    pub fn cached(&self) -> bool {
+        self.cached
+    }
+
+    pub fn provenance(&self) -> Option<&Provenance> {
+        self.result.provenance.as_ref()
+    }
+
+    pub fn failed_step(&self) -> Option<usize> {
+        self.result.failed_step
+    }
+
+    pub fn teardown_error(&self) -> Option<&str> {
+        self.result.teardown_error.as_deref()
+    }
+
+    pub fn upload_error(&self) -> Option<&str> {
+        self.result.upload_error.as_deref()
+    }
+
+    pub fn metrics(&self) -> &BTreeMap<String, f64> {
+        &self.result.metrics
+    }
+
+    pub fn outputs(&self) -> &[String] {
+        &self.result.outputs
+    }
+
+    // None for results from older versions, which didn't record it.
+    pub fn job_snapshot(&self) -> anyhow::Result<Option<JobSnapshot>> {
+        let path = self.base_path.join(JOB_SNAPSHOT_FILENAME);
+        let json = match fs::read(&path) {
+            Ok(json) => Some(json),
+            Err(e) if e.kind() == NotFound => None,
+            Err(e) => return Err(e).with_context(|| format!("reading {}", path.display())),
+        };
+        self.check_unchanged()?;
+        let Some(json) = json else {
+            return Ok(None);
+        };
+        serde_json::from_slice(&json)
+            .map(Some)
+            .with_context(|| format!("parsing {}", path.display()))
+    }
+
+    // Output of the setup, teardown or upload command.
+    pub fn hook_output_path(&self, name: &str) -> PathBuf {
+        self.base_path.join(format!("{name}.txt"))
+    }
+
     pub fn stdout_path(&self) -> PathBuf {
         self.base_path.join("stdout.txt")
     }
@@ -182,14 +1101,30 @@ impl DatabaseEntry {
         self.base_path.join("stderr.txt")
     }
 
+    // Where stdout and stderr go, unless the test has separate_outputs.
+    pub fn merged_output_path(&self) -> PathBuf {
+        self.base_path.join("output.txt")
+    }
+
     pub fn artifacts_dir(&self) -> PathBuf {
         self.base_path.join("artifacts")
     }
+
+    pub fn set_pinned(&self, pinned: bool) -> anyhow::Result<()> {
+        let path = self.base_path.join(PINNED_FILENAME);
+        if pinned {
+            fs::write(&path, "").with_context(|| format!("writing {}", path.display()))
+        } else {
+            fs::remove_file(&path)
+                .ignore(NotFound)
+                .with_context(|| format!("removing {}", path.display()))
+        }
+    }
 }
 
 // Output for an individual test job, which may or may not be stored into the
 // database depending on where it came from. If it is, it ncludes an exclusive
-// lock on the database entry, nobody can read the result or run the test case
+// lock on the database entry, nobody can find a result or run the test case
 // until you drop this object.
 pub struct DatabaseOutput {
     base_dir: PathBuf,      // Must exist.
@@ -203,10 +1138,26 @@ pub struct DatabaseOutput {
     provided_stderr: Option<Stdio>,
     status_written: bool,
     config_hash: ConfigHash,
+    // Before json_flock so it goes away before the lock is released. None for
+    // ephemeral outputs.
+    running: Option<RunningGuard>,
     json_flock: ExclusiveFlock,
     separate_outputs: bool,
-    // For merged output mode, we need to share the same file between stdout and stderr
-    shared_output_file: Option<File>,
+    // In merged output mode, what the stream that gets opened second will
+    // share with the first one. Dropped once it's handed over, so that we
+    // don't hold fds for the job's whole lifetime, and so that a pipe gets
+    // closed when the job exits.
+    shared_output: Option<OwnedFd>,
+    // If set, output is also copied to our stderr, with this at the start of
+    // each line.
+    echo_prefix: Option<String>,
+    echo_tasks: Vec<JoinHandle<()>>,
+    timestamp_lines: bool,
+    // When the first output stream was opened, timestamps count from here.
+    opened_at: Option<Instant>,
+    teardown_error: Option<String>,
+    upload_error: Option<String>,
+    outputs: Vec<String>,
 }
 
 impl DatabaseOutput {
@@ -221,6 +1172,20 @@ impl DatabaseOutput {
         create_dir(&artifacts_dir)
             .ignore(AlreadyExists)
             .context("creating artifacts dir")?;
+        // Before touching anything left from an earlier run, so that readers
+        // that don't take the lock know it's not theirs any more.
+        let running = RunningGuard::new(&base_dir)?;
+        // Left over from an earlier run.
+        for filename in [
+            FAILED_STEP_FILENAME,
+            METRICS_FILENAME,
+            JOB_SNAPSHOT_FILENAME,
+        ] {
+            let path = base_dir.join(filename);
+            fs::remove_file(&path)
+                .ignore(NotFound)
+                .with_context(|| format!("removing {}", path.display()))?;
+        }
         Ok(Self {
             artifacts_dir,
             base_dir,
@@ -230,9 +1195,17 @@ impl DatabaseOutput {
             provided_stderr: None,
             status_written: false,
             config_hash,
+            running: Some(running),
             json_flock,
             separate_outputs,
-            shared_output_file: None,
+            shared_output: None,
+            echo_prefix: None,
+            echo_tasks: Vec::new(),
+            timestamp_lines: false,
+            opened_at: None,
+            teardown_error: None,
+            upload_error: None,
+            outputs: Vec::new(),
         })
     }
 
@@ -248,11 +1221,12 @@ impl DatabaseOutput {
     ) -> anyhow::Result<Self> {
         let artifacts_dir = base_dir.join("artifacts").to_owned();
         create_dir(&artifacts_dir).context("creating artifacts dir")?;
+        let json_path = base_dir.join("result.json");
         let json_file = OpenOptions::new()
             .read(true)
             .write(true)
             .create_new(true)
-            .open(base_dir.join("result.json"))
+            .open(&json_path)
             .context("creating ephemeral result JSON")?;
         Ok(Self {
             base_dir,
@@ -263,13 +1237,25 @@ impl DatabaseOutput {
             provided_stderr: Some(stderr),
             status_written: false,
             config_hash: "".to_string(),
+            running: None,
             // Note the locking is unnecessary in the ephemeral case but it's
             // just easier to do it anyway.
-            json_flock: ExclusiveFlock::new(json_file)
-                .await
-                .context("locking ephemeral JSON result")?,
+            json_flock: ExclusiveFlock::new(LockTarget {
+                file: json_file,
+                path: json_path,
+                method: LockMethod::Flock,
+            })
+            .await
+            .context("locking ephemeral JSON result")?,
             separate_outputs,
-            shared_output_file: None,
+            shared_output: None,
+            echo_prefix: None,
+            echo_tasks: Vec::new(),
+            timestamp_lines: false,
+            opened_at: None,
+            teardown_error: None,
+            upload_error: None,
+            outputs: Vec::new(),
         })
     }
 
@@ -288,6 +1274,99 @@ impl DatabaseOutput {
         File::create(&path).with_context(|| format!("creating {}", path.display()))
     }
 
+    // Merged output mode - both streams write to the same file, or through
+    // the same pipe if the output needs processing on the way there.
+    fn shared_output(&mut self) -> anyhow::Result<OwnedFd> {
+        if let Some(fd) = self.shared_output.take() {
+            return Ok(fd);
+        }
+        let file = self.output_file()?;
+        let fd = self.output_fd(file, "output")?;
+        self.shared_output = Some(fd.try_clone().context("duplicating output fd")?);
+        Ok(fd)
+    }
+
+    // Also copy the output to our own stderr, prefixing each line so the user
+    // can tell it apart from the output of other jobs. Must be called before
+    // the stdout and stderr handles are opened.
+    pub fn echo_with_prefix(&mut self, prefix: String) {
+        assert!(!self.stdout_opened && !self.stderr_opened);
+        self.echo_prefix = Some(prefix);
+    }
+
+    // Start each line written to the database with a timestamp, and with the
+    // name of the stream if they go to separate files. Must be called before
+    // the stdout and stderr handles are opened.
+    pub fn timestamp_lines(&mut self) {
+        assert!(!self.stdout_opened && !self.stderr_opened);
+        self.timestamp_lines = true;
+    }
+
+    // What the job should write the stream to. That's the file itself unless
+    // we need to do something with the output, then it's a pipe.
+    fn output_fd(&mut self, file: File, stream: &'static str) -> anyhow::Result<OwnedFd> {
+        let opened_at = *self.opened_at.get_or_insert_with(Instant::now);
+        if self.echo_prefix.is_none() && !self.timestamp_lines {
+            return Ok(file.into());
+        }
+        let echo_prefix = self.echo_prefix.clone();
+        let timestamp_lines = self.timestamp_lines;
+        let tag = self.separate_outputs.then_some(stream);
+        let (rx, tx) = pipe2(OFlag::O_CLOEXEC).context("creating output pipe")?;
+        // Not a tokio::fs::File, reading one of those blocks a thread, which
+        // can't be aborted if the pipe never closes.
+        let rx = pipe::Receiver::from_owned_fd(rx).context("setting up output pipe")?;
+        let mut lines = BufReader::new(rx);
+        let mut file = tokio::fs::File::from_std(file);
+        self.echo_tasks.push(tokio::spawn(async move {
+            let mut line = Vec::new();
+            // Whether the last chunk read was a whole line, or the end of one.
+            let mut line_start = true;
+            loop {
+                line.clear();
+                let read = (&mut lines)
+                    .take(MAX_OUTPUT_LINE as u64)
+                    .read_until(b'\n', &mut line)
+                    .await;
+                match read {
+                    Ok(0) => return,
+                    Ok(_) => (),
+                    Err(e) => {
+                        debug!("reading job output: {e}");
+                        return;
+                    }
+                }
+                let mut stored = Vec::new();
+                if timestamp_lines && line_start {
+                    let secs = opened_at.elapsed().as_secs_f64();
+                    stored.extend(format!("[{secs:12.6}] ").bytes());
+                    if let Some(tag) = tag {
+                        stored.extend(format!("{tag}: ").bytes());
+                    }
+                }
+                stored.extend_from_slice(&line);
+                line_start = line.ends_with(b"\n");
+                // One write per line, so that merged stdout and stderr lines
+                // don't get mixed up.
+                if let Err(e) = file.write_all(&stored).await {
+                    debug!("writing job output: {e}");
+                    return;
+                }
+                let Some(prefix) = &echo_prefix else {
+                    continue;
+                };
+                if !line.ends_with(b"\n") {
+                    line.push(b'\n');
+                }
+                // Same here, so lines from different jobs don't get mixed up.
+                let mut echoed = prefix.as_bytes().to_vec();
+                echoed.extend_from_slice(&line);
+                let _ = io::stderr().lock().write_all(&echoed);
+            }
+        }));
+        Ok(tx)
+    }
+
     pub fn stdout(&mut self) -> Result<Stdio> {
         assert!(!self.stdout_opened);
         self.stdout_opened = true;
@@ -295,20 +1374,11 @@ impl DatabaseOutput {
             return Ok(stdout);
         }
 
-        if self.separate_outputs {
-            Ok(self.stdout_file()?.into())
-        } else {
-            // Merged output mode - create shared file if not already created
-            if self.shared_output_file.is_none() {
-                self.shared_output_file = Some(self.output_file()?);
-            }
-            Ok(self
-                .shared_output_file
-                .as_ref()
-                .unwrap()
-                .try_clone()?
-                .into())
+        if !self.separate_outputs {
+            return Ok(self.shared_output()?.into());
         }
+        let file = self.stdout_file()?;
+        Ok(self.output_fd(file, "stdout")?.into())
     }
 
     pub fn stderr(&mut self) -> Result<Stdio> {
@@ -318,20 +1388,11 @@ impl DatabaseOutput {
             return Ok(stderr);
         }
 
-        if self.separate_outputs {
-            Ok(self.stderr_file()?.into())
-        } else {
-            // Merged output mode - create shared file if not already created
-            if self.shared_output_file.is_none() {
-                self.shared_output_file = Some(self.output_file()?);
-            }
-            Ok(self
-                .shared_output_file
-                .as_ref()
-                .unwrap()
-                .try_clone()?
-                .into())
+        if !self.separate_outputs {
+            return Ok(self.shared_output()?.into());
         }
+        let file = self.stderr_file()?;
+        Ok(self.output_fd(file, "stderr")?.into())
     }
 
     // Set the result and return the created entry. Unfortunately because flock
@@ -343,27 +1404,68 @@ impl DatabaseOutput {
     // downgrade rarely gets "beaten" by an exclusive lock. (Also, it should be
     // rare that we delete an entry when there's a test running that depends on
     // it).
-    pub async fn set_result(mut self, result: &TestResult) -> anyhow::Result<DatabaseEntry> {
+    // If the output goes through a pipe, this waits until everything that has
+    // it open has closed it, up to OUTPUT_DRAIN_TIMEOUT. started_at is when the
+    // test began.
+    pub async fn set_result(
+        mut self,
+        result: &TestResult,
+        started_at: SystemTime,
+    ) -> anyhow::Result<DatabaseEntry> {
         assert!(!self.status_written);
         self.status_written = true;
+        for mut task in self.echo_tasks.drain(..) {
+            match timeout(OUTPUT_DRAIN_TIMEOUT, &mut task).await {
+                Ok(res) => res.context("echoing output")?,
+                Err(_) => {
+                    warn!("Job's output is still open after it finished, ignoring the rest");
+                    task.abort();
+                }
+            }
+        }
+        let failed_step_path = self.failed_step_path();
+        let failed_step = match fs::read_to_string(&failed_step_path) {
+            Ok(content) => Some(
+                content
+                    .trim()
+                    .parse()
+                    .with_context(|| format!("parsing {}", failed_step_path.display()))?,
+            ),
+            Err(e) if e.kind() == NotFound => None,
+            Err(e) => {
+                return Err(e).with_context(|| format!("reading {}", failed_step_path.display()))
+            }
+        };
+        // The test wrote this, it's not worth throwing away its result over.
+        let metrics = read_metrics(&self.metrics_path()).unwrap_or_else(|e| {
+            warn!("Ignoring metrics: {e:#}");
+            BTreeMap::new()
+        });
         let entry = TestResultEntry {
-            config_hash: self.config_hash.clone(),
+            config_hash: self.config_hash,
             result: result.clone(),
+            provenance: Some(Provenance::here_and_now(started_at)),
+            failed_step,
+            teardown_error: self.teardown_error.take(),
+            upload_error: self.upload_error.take(),
+            metrics,
+            outputs: mem::take(&mut self.outputs),
         };
         self.json_flock
             .set_content(&serde_json::to_vec(&entry).expect("failed to serialize TestStatus"))
             .context("writing JSON result")?;
+        drop(self.running.take());
         Ok(DatabaseEntry {
             base_path: self.base_dir,
-            result: TestResultEntry {
-                config_hash: self.config_hash,
-                result: result.clone(),
+            result: entry,
+            cached: false,
+            hold: EntryHold::Lock {
+                _json_flock: self
+                    .json_flock
+                    .downgrade()
+                    .await
+                    .context("downgrading result JSON flock")?,
             },
-            _json_flock: self
-                .json_flock
-                .downgrade()
-                .await
-                .context("downgrading result JSON flock")?,
             #[cfg(test)]
             _tempfile: None,
         })
@@ -372,22 +1474,78 @@ impl DatabaseOutput {
     pub fn artifacts_dir(&mut self) -> &Path {
         &self.artifacts_dir
     }
+
+    // For the setup, teardown or upload command.
+    pub fn hook_output(&self, name: &str) -> anyhow::Result<File> {
+        let path = self.base_dir.join(format!("{name}.txt"));
+        File::create(&path).with_context(|| format!("creating {}", path.display()))
+    }
+
+    pub fn set_teardown_error(&mut self, error: String) {
+        self.teardown_error = Some(error);
+    }
+
+    pub fn set_upload_error(&mut self, error: String) {
+        self.upload_error = Some(error);
+    }
+
+    pub fn set_outputs(&mut self, outputs: Vec<String>) {
+        self.outputs = outputs;
+    }
+
+    pub fn failed_step_path(&self) -> PathBuf {
+        self.base_dir.join(FAILED_STEP_FILENAME)
+    }
+
+    pub fn metrics_path(&self) -> PathBuf {
+        self.base_dir.join(METRICS_FILENAME)
+    }
+
+    pub fn write_job_snapshot(&self, snapshot: &JobSnapshot) -> anyhow::Result<()> {
+        let path = self.base_dir.join(JOB_SNAPSHOT_FILENAME);
+        let json = serde_json::to_vec(snapshot).expect("failed to serialize JobSnapshot");
+        write_atomic(&path, &json)
+    }
+
+    // Write out a list of paths, one per line, and return where it went.
+    pub fn write_changed_files(&self, paths: &[PathBuf]) -> anyhow::Result<PathBuf> {
+        let path = self.base_dir.join("changed_files.txt");
+        let mut content = Vec::new();
+        for p in paths {
+            content.extend_from_slice(p.as_os_str().as_bytes());
+            content.push(b'\n');
+        }
+        fs::write(&path, content).with_context(|| format!("writing {}", path.display()))?;
+        Ok(path)
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{io::Write as _, sync::Arc};
+    use std::{io::Write as _, pin::pin, sync::Arc};
 
     use tempfile::TempDir;
+    use test_case::test_case;
+    use tokio::{
+        select,
+        time::{sleep, timeout},
+    };
 
-    use crate::{git::Commit, test::test_utils::TestBuilder};
+    use crate::{
+        git::{Commit, CommitHash},
+        test::test_utils::TestBuilder,
+    };
 
     use super::*;
 
+    #[test_case(LockMethod::Flock ; "flock")]
+    #[test_case(LockMethod::Lockfile ; "lockfile")]
     #[tokio::test]
-    async fn test_corrupted_result() {
+    async fn test_corrupted_result(lock_method: LockMethod) {
         let db_dir = TempDir::new().unwrap();
-        let db = Database::create_or_open(db_dir.path()).unwrap();
+        let db = Database::create_or_open(db_dir.path())
+            .unwrap()
+            .with_lock_method(lock_method);
 
         // Setup: Create a corrupted database entry. This simulates Limmat
         // getting killed in the middle of writing.
@@ -414,7 +1572,7 @@ mod tests {
                 .unwrap();
             let json_path = output.base_dir.join("result.json");
             output
-                .set_result(&TestResult { exit_code: 1 })
+                .set_result(&TestResult { exit_code: 1 }, SystemTime::now())
                 .await
                 .unwrap();
             json_path
@@ -437,7 +1595,7 @@ mod tests {
                 LookupResult::YouRunIt(output) => output,
             };
             output
-                .set_result(&TestResult { exit_code: 2 })
+                .set_result(&TestResult { exit_code: 2 }, SystemTime::now())
                 .await
                 .unwrap();
         }
@@ -447,6 +1605,529 @@ mod tests {
             LookupResult::YouRunIt(_) => panic!("no JSON found after DB corruption"),
         };
     }
+
+    #[test_case(LockMethod::Flock ; "flock")]
+    #[test_case(LockMethod::Lockfile ; "lockfile")]
+    #[tokio::test]
+    async fn test_lookup_waits_for_result(lock_method: LockMethod) {
+        let db_dir = TempDir::new().unwrap();
+        let db = Database::create_or_open(db_dir.path())
+            .unwrap()
+            .with_lock_method(lock_method);
+        let test_case = TestCase::new(
+            Commit::arbitrary(),
+            Arc::new(TestBuilder::new("my_test", "", [""]).build()),
+        );
+        let LookupResult::YouRunIt(output) = db.lookup(&test_case).await.unwrap() else {
+            panic!("Found result in empty database");
+        };
+        let result_dir = output.base_dir.clone();
+
+        // This is waiting on the file that set_result replaces, it should
+        // still find the result.
+        let mut waiter = pin!(db.lookup(&test_case));
+        select! {
+            _ = &mut waiter => panic!("lookup didn't wait for running test"),
+            _ = sleep(Duration::from_millis(100)) => (),
+        }
+        output
+            .set_result(&TestResult { exit_code: 3 }, SystemTime::now())
+            .await
+            .unwrap();
+        match timeout(Duration::from_secs(5), waiter)
+            .await
+            .unwrap()
+            .unwrap()
+        {
+            LookupResult::FoundResult(entry) => assert_eq!(entry.result.result.exit_code, 3),
+            LookupResult::YouRunIt(_) => panic!("didn't find result written while waiting"),
+        };
+        let leftovers: Vec<_> = fs::read_dir(&result_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .filter(|name| name.to_string_lossy().starts_with(".tmp"))
+            .collect();
+        assert!(leftovers.is_empty(), "{leftovers:?}");
+    }
+
+    #[test]
+    fn test_pid_alive() {
+        assert!(pid_alive(std::process::id()));
+        // Doesn't fit in a pid_t, e.g. from a corrupt marker.
+        assert!(!pid_alive(u32::MAX));
+    }
+
+    #[tokio::test]
+    async fn test_recover_orphans() {
+        let db_dir = TempDir::new().unwrap();
+        let db = Database::create_or_open(db_dir.path()).unwrap();
+        let mut dead = std::process::Command::new("true").spawn().unwrap();
+        dead.wait().unwrap();
+        let started_at = SystemTime::now();
+        let mark_running = |dir: &Path| {
+            let marker = RunningMarker {
+                host: hostname(),
+                pid: dead.id(),
+                started_at,
+            };
+            fs::write(
+                dir.join(RUNNING_FILENAME),
+                serde_json::to_vec(&marker).unwrap(),
+            )
+            .unwrap();
+        };
+        let test_case = |name| {
+            TestCase::new(
+                Commit::arbitrary(),
+                Arc::new(TestBuilder::new(name, "", [""]).build()),
+            )
+        };
+        let db = &db;
+        let run = |test_case| async move {
+            match db.lookup(&test_case).await.unwrap() {
+                LookupResult::YouRunIt(output) => output,
+                LookupResult::FoundResult(_) => panic!("Found result in empty database"),
+            }
+        };
+
+        // The runner died after writing the result.
+        let finished = test_case("finished");
+        let output = run(finished.clone()).await;
+        assert!(output.base_dir.join(RUNNING_FILENAME).exists());
+        let entry = output
+            .set_result(&TestResult { exit_code: 0 }, started_at)
+            .await
+            .unwrap();
+        let finished_dir = entry.base_path.clone();
+        drop(entry);
+        assert!(!finished_dir.join(RUNNING_FILENAME).exists());
+        mark_running(&finished_dir);
+
+        // The runner died halfway through.
+        let unfinished_dir = run(test_case("unfinished")).await.base_dir.clone();
+        mark_running(&unfinished_dir);
+
+        // We're running this one.
+        let running = run(test_case("running")).await;
+
+        // This one is just broken, that shouldn't stop the others being
+        // recovered.
+        let broken_dir = finished_dir.parent().unwrap().join("broken");
+        fs::create_dir(&broken_dir).unwrap();
+        fs::write(broken_dir.join(RUNNING_FILENAME), "garbage").unwrap();
+
+        assert_eq!(
+            db.recover_orphans().await,
+            Recovered {
+                adopted: 1,
+                removed: 1
+            }
+        );
+        assert!(!finished_dir.join(RUNNING_FILENAME).exists());
+        assert!(db.find(&finished).unwrap().is_some());
+        assert!(!unfinished_dir.exists());
+        assert!(running.base_dir.join(RUNNING_FILENAME).exists());
+    }
+
+    #[test]
+    fn test_parse_metrics() {
+        assert_eq!(
+            parse_metrics("build_secs=12.5\n\nbinary.size = 1024\nbuild_secs=13\n").unwrap(),
+            BTreeMap::from([("binary.size".into(), 1024.0), ("build_secs".into(), 13.0)])
+        );
+        assert!(parse_metrics("build_secs 12\n").is_err());
+        assert!(parse_metrics("build secs=12\n").is_err());
+        assert!(parse_metrics("build_secs=fast\n").is_err());
+        assert!(parse_metrics("build_secs=nan\n").is_err());
+        assert!(parse_metrics("build_secs=-inf\n").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_bad_metrics() {
+        let db_dir = TempDir::new().unwrap();
+        let db = Database::create_or_open(db_dir.path()).unwrap();
+        let test_case = TestCase::new(
+            Commit::arbitrary(),
+            Arc::new(TestBuilder::new("my_test", "", [""]).build()),
+        );
+        let LookupResult::YouRunIt(output) = db.lookup(&test_case).await.unwrap() else {
+            panic!("Found result in empty database");
+        };
+        fs::write(output.metrics_path(), "build_secs=nan\n").unwrap();
+        let entry = output
+            .set_result(&TestResult { exit_code: 0 }, SystemTime::now())
+            .await
+            .unwrap();
+        assert!(entry.metrics().is_empty());
+        drop(entry);
+        let LookupResult::FoundResult(entry) = db.lookup(&test_case).await.unwrap() else {
+            panic!("result not found");
+        };
+        assert_eq!(entry.exit_code(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_provenance() {
+        let db_dir = TempDir::new().unwrap();
+        let db = Database::create_or_open(db_dir.path()).unwrap();
+        let test_case = TestCase::new(
+            Commit::arbitrary(),
+            Arc::new(TestBuilder::new("my_test", "", [""]).build()),
+        );
+        let json_path = {
+            let LookupResult::YouRunIt(output) = db.lookup(&test_case).await.unwrap() else {
+                panic!("Found result in empty database");
+            };
+            let json_path = output.base_dir.join("result.json");
+            let started_at = SystemTime::now() - Duration::from_secs(180);
+            let entry = output
+                .set_result(&TestResult { exit_code: 0 }, started_at)
+                .await
+                .unwrap();
+            assert!(!entry.cached());
+            json_path
+        };
+        {
+            let LookupResult::FoundResult(entry) = db.lookup(&test_case).await.unwrap() else {
+                panic!("no result found");
+            };
+            assert!(entry.cached());
+            let provenance = entry.provenance().unwrap();
+            assert_eq!(provenance.host, hostname());
+            assert_eq!(provenance.describe(), "0s ago, took 3m");
+        }
+
+        // Results from before provenance was recorded should still be found.
+        let mut json: serde_json::Value =
+            serde_json::from_slice(&fs::read(&json_path).unwrap()).unwrap();
+        json.as_object_mut().unwrap().remove("provenance");
+        fs::write(&json_path, serde_json::to_vec(&json).unwrap()).unwrap();
+        let LookupResult::FoundResult(entry) = db.lookup(&test_case).await.unwrap() else {
+            panic!("old result not found");
+        };
+        assert_eq!(entry.provenance(), None);
+    }
+
+    #[tokio::test]
+    async fn test_find() {
+        let db_dir = TempDir::new().unwrap();
+        let db = Database::create_or_open(db_dir.path()).unwrap();
+        let test_case = TestCase::new(
+            Commit::arbitrary(),
+            Arc::new(TestBuilder::new("my_test", "", [""]).build()),
+        );
+        assert!(db.find(&test_case).unwrap().is_none());
+        let result_dir = db.result_path(test_case.storage_hash(), &test_case.test.name);
+        assert!(!result_dir.exists());
+
+        let LookupResult::YouRunIt(output) = db.lookup(&test_case).await.unwrap() else {
+            panic!("Found result in empty database");
+        };
+        output
+            .set_result(&TestResult { exit_code: 3 }, SystemTime::now())
+            .await
+            .unwrap();
+        let entry = db.find(&test_case).unwrap().unwrap();
+        assert_eq!(entry.result().exit_code, 3);
+    }
+
+    #[test_case(LockMethod::Flock ; "flock")]
+    #[test_case(LockMethod::Lockfile ; "lockfile")]
+    #[tokio::test]
+    async fn test_find_during_rerun(lock_method: LockMethod) {
+        let db_dir = TempDir::new().unwrap();
+        let db = Database::create_or_open(db_dir.path())
+            .unwrap()
+            .with_lock_method(lock_method);
+        let test_case = TestCase::new(
+            Commit::arbitrary(),
+            Arc::new(TestBuilder::new("my_test", "", [""]).build()),
+        );
+        let LookupResult::YouRunIt(output) = db.lookup(&test_case).await.unwrap() else {
+            panic!("Found result in empty database");
+        };
+        drop(
+            output
+                .set_result(&TestResult { exit_code: 3 }, SystemTime::now())
+                .await
+                .unwrap(),
+        );
+        let old_entry = db.find(&test_case).unwrap().unwrap();
+        old_entry.check_unchanged().unwrap();
+        assert!(db.running_since(&test_case).unwrap().is_none());
+
+        // The rerun holds the lock, find mustn't wait for it. The old result
+        // stands until it's replaced, but not the outputs that go with it.
+        let LookupResult::YouRunIt(output) = db.lookup_rerun(&test_case).await.unwrap() else {
+            panic!("lookup_rerun found result");
+        };
+        let during = db.find(&test_case).unwrap().unwrap();
+        assert_eq!(during.result().exit_code, 3);
+        assert!(during.check_unchanged().is_err());
+        assert!(db.running_since(&test_case).unwrap().is_some());
+        assert!(old_entry.check_unchanged().is_err());
+        // Unless you ask it to.
+        let mut waiter = pin!(db.find_waiting(&test_case));
+        select! {
+            _ = &mut waiter => panic!("find_waiting didn't wait for running test"),
+            _ = sleep(Duration::from_millis(100)) => (),
+        }
+
+        drop(
+            output
+                .set_result(&TestResult { exit_code: 4 }, SystemTime::now())
+                .await
+                .unwrap(),
+        );
+        let waited = timeout(Duration::from_secs(5), waiter)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(waited.unwrap().result().exit_code, 4);
+        let new_entry = db.find(&test_case).unwrap().unwrap();
+        assert_eq!(new_entry.result().exit_code, 4);
+        new_entry.check_unchanged().unwrap();
+        assert!(old_entry.check_unchanged().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_all_results() {
+        let db_dir = TempDir::new().unwrap();
+        let db = Database::create_or_open(db_dir.path()).unwrap();
+        let test = Arc::new(TestBuilder::new("my_test", "", [""]).build());
+        for (hash, exit_code) in [("1111", 0), ("2222", 1)] {
+            let commit = Commit {
+                hash: CommitHash::new(hash),
+                ..Commit::arbitrary()
+            };
+            let LookupResult::YouRunIt(output) = db
+                .lookup(&TestCase::new(commit, test.clone()))
+                .await
+                .unwrap()
+            else {
+                panic!("Found result in empty database");
+            };
+            output
+                .set_result(&TestResult { exit_code }, SystemTime::now())
+                .await
+                .unwrap();
+        }
+        // Shouldn't get confused by the other stuff in the database.
+        db.record_run(&test.name, Duration::from_secs(1), false)
+            .unwrap();
+        fs::write(db_dir.path().join("events.jsonl"), "").unwrap();
+
+        let mut results = db.all_results(&test.name).unwrap();
+        results.sort_by_key(|r| r.exit_code);
+        let exit_codes: Vec<_> = results.iter().map(|r| r.exit_code).collect();
+        assert_eq!(exit_codes, vec![0, 1]);
+        assert!(results.iter().all(|r| r.size > 0 && !r.pinned));
+        assert_eq!(
+            db.all_results(&TestName::new("other_test")).unwrap(),
+            vec![]
+        );
+
+        db.remove_result(&results[0]).await.unwrap();
+        let remaining = db.all_results(&test.name).unwrap();
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].exit_code, 1);
+    }
+
+    #[tokio::test]
+    async fn test_merged_output_order() {
+        let db_dir = TempDir::new().unwrap();
+        let db = Database::create_or_open(db_dir.path()).unwrap();
+        let test = TestBuilder::new("my_test", "", [""]).build();
+        let test_case = TestCase::new(Commit::arbitrary(), Arc::new(test));
+        let LookupResult::YouRunIt(mut output) = db.lookup(&test_case).await.unwrap() else {
+            panic!("Found result in empty database");
+        };
+        // Makes the output go through a pipe. Both streams share it, so lines
+        // can't get reordered.
+        output.timestamp_lines();
+        let status = tokio::process::Command::new("bash")
+            .args([
+                "-c",
+                "for i in $(seq 100); do echo out$i; echo err$i >&2; done",
+            ])
+            .stdout(output.stdout().unwrap())
+            .stderr(output.stderr().unwrap())
+            .status()
+            .await
+            .unwrap();
+        assert!(status.success());
+        assert!(output.shared_output.is_none());
+        let entry = output
+            .set_result(&TestResult { exit_code: 0 }, SystemTime::now())
+            .await
+            .unwrap();
+
+        let texts: Vec<String> = fs::read_to_string(entry.merged_output_path())
+            .unwrap()
+            .lines()
+            .map(|line| line.split_once("] ").unwrap().1.to_owned())
+            .collect();
+        let want: Vec<String> = (1..=100)
+            .flat_map(|i| [format!("out{i}"), format!("err{i}")])
+            .collect();
+        assert_eq!(texts, want);
+    }
+
+    #[tokio::test]
+    async fn test_output_held_open() {
+        let db_dir = TempDir::new().unwrap();
+        let db = Database::create_or_open(db_dir.path()).unwrap();
+        let test = TestBuilder::new("my_test", "", [""]).build();
+        let test_case = TestCase::new(Commit::arbitrary(), Arc::new(test));
+        let LookupResult::YouRunIt(mut output) = db.lookup(&test_case).await.unwrap() else {
+            panic!("Found result in empty database");
+        };
+        output.timestamp_lines();
+        // A daemon that outlives the job, with its stdout still open. The line
+        // is too long to buffer, that shouldn't stop us from storing it.
+        let status = tokio::process::Command::new("bash")
+            .args([
+                "-c",
+                "head -c 200000 /dev/zero | tr '\\0' x; echo; sleep 60 &",
+            ])
+            .stdout(output.stdout().unwrap())
+            .stderr(output.stderr().unwrap())
+            .status()
+            .await
+            .unwrap();
+        assert!(status.success());
+        let entry = timeout(
+            OUTPUT_DRAIN_TIMEOUT * 2,
+            output.set_result(&TestResult { exit_code: 0 }, SystemTime::now()),
+        )
+        .await
+        .expect("set_result hung")
+        .unwrap();
+
+        let stored = fs::read_to_string(entry.merged_output_path()).unwrap();
+        assert_eq!(stored.matches('x').count(), 200000);
+        assert_eq!(stored.lines().count(), 1);
+    }
+
+    #[test_case(false ; "merged")]
+    #[test_case(true ; "separate")]
+    #[tokio::test]
+    async fn test_timestamp_lines(separate_outputs: bool) {
+        let db_dir = TempDir::new().unwrap();
+        let db = Database::create_or_open(db_dir.path()).unwrap();
+        let mut test = TestBuilder::new("my_test", "", [""]);
+        if separate_outputs {
+            test = test.separate_outputs();
+        }
+        let test_case = TestCase::new(Commit::arbitrary(), Arc::new(test.build()));
+        let LookupResult::YouRunIt(mut output) = db.lookup(&test_case).await.unwrap() else {
+            panic!("Found result in empty database");
+        };
+        output.timestamp_lines();
+        let status = tokio::process::Command::new("bash")
+            .args(["-c", "echo out; sleep 0.1; echo err >&2"])
+            .stdout(output.stdout().unwrap())
+            .stderr(output.stderr().unwrap())
+            .status()
+            .await
+            .unwrap();
+        assert!(status.success());
+        let entry = output
+            .set_result(&TestResult { exit_code: 0 }, SystemTime::now())
+            .await
+            .unwrap();
+
+        let parse = |line: &str| {
+            let (time, rest) = line.strip_prefix('[').unwrap().split_once("] ").unwrap();
+            (time.trim().parse::<f64>().unwrap(), rest.to_owned())
+        };
+        let read = |path: PathBuf| {
+            fs::read_to_string(path)
+                .unwrap()
+                .lines()
+                .map(parse)
+                .collect::<Vec<_>>()
+        };
+        let lines = if separate_outputs {
+            [read(entry.stdout_path()), read(entry.stderr_path())].concat()
+        } else {
+            read(entry.base_path.join("output.txt"))
+        };
+        let texts: Vec<_> = lines.iter().map(|(_, text)| text.as_str()).collect();
+        if separate_outputs {
+            assert_eq!(texts, ["stdout: out", "stderr: err"]);
+        } else {
+            assert_eq!(texts, ["out", "err"]);
+        }
+        assert!(lines[1].0 - lines[0].0 >= 0.1, "{lines:?}");
+    }
+
+    #[test]
+    fn test_history() {
+        let db_dir = TempDir::new().unwrap();
+        let db = Database::create_or_open(db_dir.path()).unwrap();
+        let name = TestName::new("my_test");
+        assert_eq!(db.history(&name).unwrap(), None);
+
+        db.record_run(&name, Duration::from_secs(10), true).unwrap();
+        let expected = History {
+            failure_rate: 1.0,
+            duration_secs: 10.0,
+        };
+        assert_eq!(db.history(&name).unwrap(), Some(expected));
+
+        db.record_run(&name, Duration::from_secs(20), false)
+            .unwrap();
+        let expected = History {
+            failure_rate: 0.8,
+            duration_secs: 12.0,
+        };
+        assert_eq!(db.history(&name).unwrap(), Some(expected));
+        assert_eq!(db.history(&TestName::new("other_test")).unwrap(), None);
+    }
+
+    #[test]
+    fn test_config_snapshots() {
+        let db_dir = TempDir::new().unwrap();
+        let db = Database::create_or_open(db_dir.path()).unwrap();
+        let name = TestName::new("my_test");
+        assert_eq!(db.config_snapshots(&name).unwrap(), None);
+
+        let config1 = serde_json::json!({"command": "true"});
+        db.record_config(&name, &"hash1".to_owned(), &config1)
+            .unwrap();
+        let snapshots = db.config_snapshots(&name).unwrap().unwrap();
+        assert_eq!(snapshots.current.config, config1);
+        assert_eq!(snapshots.previous, None);
+
+        // Same config again, so the snapshot shouldn't be replaced.
+        db.record_config(&name, &"hash1".to_owned(), &config1)
+            .unwrap();
+        assert_eq!(db.config_snapshots(&name).unwrap(), Some(snapshots.clone()));
+
+        let config2 = serde_json::json!({"command": "false"});
+        db.record_config(&name, &"hash2".to_owned(), &config2)
+            .unwrap();
+        let new_snapshots = db.config_snapshots(&name).unwrap().unwrap();
+        assert_eq!(new_snapshots.current.config_hash, "hash2");
+        assert_eq!(new_snapshots.current.config, config2);
+        assert_eq!(new_snapshots.previous, Some(snapshots.current));
+    }
+
+    #[test]
+    fn test_job_snapshot_shell_command() {
+        let snapshot = JobSnapshot {
+            argv: vec!["bash".into(), "-c".into(), "echo 'hi' $FOO".into()],
+            cwd: PathBuf::from("/my dir"),
+            env: BTreeMap::from([("FOO".into(), "a b".into()), ("BAR".into(), "".into())]),
+            resource_tokens: BTreeMap::new(),
+            git_describe: None,
+        };
+        assert_eq!(
+            snapshot.shell_command(),
+            r#"cd '/my dir' && env -i 'BAR=' 'FOO=a b' 'bash' '-c' 'echo '\''hi'\'' $FOO'"#
+        );
+    }
 }
 // TODO:
 // - Test behaviour on already-existing directories
@@ -0,0 +1,423 @@
+// A log of everything the test managers do, one JSON object per line, for
+// external tools and for figuring out afterwards why things got scheduled the
+// way they did. Several instances can share a database, so they all append to
+// the same file. They hold a shared lock on a separate lock file while doing
+// so, pruning the log holds it exclusively while it replaces the file.
+
+use std::{
+    collections::HashSet,
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io::{self, SeekFrom, Write as _},
+    os::unix::fs::MetadataExt as _,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context as _;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{
+        AsyncBufReadExt as _, AsyncReadExt as _, AsyncSeekExt as _, AsyncWriteExt as _, BufReader,
+    },
+    select,
+    sync::broadcast::{error::RecvError, Receiver},
+    time::sleep,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    flock::{ExclusiveFlock, LockMethod, LockTarget, SharedFlock},
+    git::CommitHash,
+    test::{ExitCode, Notification, TestInconclusive, TestName, TestStatus},
+    util::{write_atomic, ResultExt as _},
+};
+
+pub const EVENTS_FILENAME: &str = "events.jsonl";
+
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(200);
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum EventKind {
+    Enqueued,
+    Started,
+    Finished {
+        // None if the test didn't run to completion, then error says why.
+        exit_code: Option<ExitCode>,
+        error: Option<String>,
+        cached: bool,
+    },
+    Canceled,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Event {
+    pub time: SystemTime,
+    // Tells apart the instances sharing the database.
+    pub pid: u32,
+    pub repo: PathBuf,
+    pub commit: String,
+    pub test: String,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+impl Event {
+    // None for notifications that aren't about a job.
+    fn new(repo: &Path, notif: &Notification) -> Option<Self> {
+        let kind = match &notif.status {
+            TestStatus::Enqueued => EventKind::Enqueued,
+            TestStatus::Started(_) => EventKind::Started,
+            TestStatus::Finished(Err(TestInconclusive::Canceled)) => EventKind::Canceled,
+            TestStatus::Finished(memory) => EventKind::Finished {
+                exit_code: memory.as_ref().ok().map(|result| result.exit_code),
+                error: memory.as_ref().err().map(|e| e.to_string()),
+                cached: notif.cached,
+            },
+            TestStatus::NotApplicable(_) => return None,
+        };
+        Some(Self {
+            time: SystemTime::now(),
+            pid: std::process::id(),
+            repo: repo.to_owned(),
+            commit: notif.test_case.commit_hash.to_string(),
+            test: notif.test_case.test.name.to_string(),
+            kind,
+        })
+    }
+}
+
+// Append an event for each notification until the channel closes, i.e. when
+// the test manager is dropped. Progress updates for a running job aren't
+// recorded, only that it started.
+pub async fn write_events(
+    path: &Path,
+    lock_method: LockMethod,
+    repo: &Path,
+    mut notifs: Receiver<Arc<Notification>>,
+) {
+    let mut started: HashSet<(CommitHash, TestName)> = HashSet::new();
+    loop {
+        let notif = match notifs.recv().await {
+            Ok(notif) => notif,
+            Err(RecvError::Lagged(num_dropped)) => {
+                warn!("Dropped {num_dropped} notifications, event log is missing events");
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        let id = (
+            notif.test_case.commit_hash.clone(),
+            notif.test_case.test.name.clone(),
+        );
+        if matches!(notif.status, TestStatus::Started(_)) {
+            if !started.insert(id) {
+                continue;
+            }
+        } else {
+            started.remove(&id);
+        }
+        if let Some(event) = Event::new(repo, &notif) {
+            append(path, lock_method, &event)
+                .await
+                .or_log_error("writing event log");
+        }
+    }
+}
+
+fn lock_target(path: &Path, method: LockMethod) -> anyhow::Result<LockTarget> {
+    let mut name = OsString::from(path.file_name().expect("no file name"));
+    name.push(".lock");
+    let path = path.with_file_name(name);
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)
+        .with_context(|| format!("opening {}", path.display()))?;
+    Ok(LockTarget { file, path, method })
+}
+
+async fn append(path: &Path, lock_method: LockMethod, event: &Event) -> anyhow::Result<()> {
+    let mut line = serde_json::to_vec(event).expect("failed to serialize event");
+    line.push(b'\n');
+    let _lock = SharedFlock::new(lock_target(path, lock_method)?).await?;
+    // A single write in append mode, so lines from different instances don't
+    // get interleaved.
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(&line))
+        .with_context(|| format!("appending to {}", path.display()))
+}
+
+// Lines that can't be parsed, e.g. because they are still being written, are
+// skipped. Returns nothing if the log doesn't exist yet.
+pub fn read_events(path: &Path) -> anyhow::Result<Vec<Event>> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("reading {}", path.display())),
+    };
+    Ok(content
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect())
+}
+
+// Drop the events from before cutoff, unless dry_run. Returns how many bytes
+// that frees. Lines that can't be parsed are kept.
+pub async fn prune_events(
+    path: &Path,
+    lock_method: LockMethod,
+    cutoff: SystemTime,
+    dry_run: bool,
+) -> anyhow::Result<u64> {
+    let _lock = ExclusiveFlock::new(lock_target(path, lock_method)?).await?;
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e).with_context(|| format!("reading {}", path.display())),
+    };
+    let kept: String = content
+        .split_inclusive('\n')
+        .filter(|line| !serde_json::from_str::<Event>(line).is_ok_and(|e| e.time < cutoff))
+        .collect();
+    let freed = (content.len() - kept.len()) as u64;
+    if freed > 0 && !dry_run {
+        write_atomic(path, kept.as_bytes())?;
+    }
+    Ok(freed)
+}
+
+// The length of the start of new that's made up of lines from old, for when
+// old was pruned into new and then more lines were appended. Pruning only
+// drops lines, and events are unique, so that's the lines that match old
+// ones, in the same order.
+fn pruned_len(old: &str, new: &str) -> usize {
+    let mut old_lines = old.split_inclusive('\n');
+    new.split_inclusive('\n')
+        .take_while(|line| line.ends_with('\n') && old_lines.any(|old_line| old_line == *line))
+        .map(str::len)
+        .sum()
+}
+
+async fn replaced(file: &tokio::fs::File, path: &Path) -> anyhow::Result<bool> {
+    let open = file.metadata().await.context("checking event log")?;
+    match fs::metadata(path) {
+        Ok(current) => Ok((current.dev(), current.ino()) != (open.dev(), open.ino())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e).with_context(|| format!("checking {}", path.display())),
+    }
+}
+
+// Copy the event log to stdout. With follow, keep copying new events as they
+// get appended, until the token is cancelled. If the log gets pruned, this
+// carries on from the same place in the new one.
+pub async fn print_events(path: &Path, follow: bool, ct: CancellationToken) -> anyhow::Result<()> {
+    let file = loop {
+        match File::open(path) {
+            Ok(file) => break file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound && follow => (),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e).with_context(|| format!("opening {}", path.display())),
+        }
+        select! {
+            _ = ct.cancelled() => return Ok(()),
+            _ = sleep(FOLLOW_INTERVAL) => (),
+        }
+    };
+    let mut reader = BufReader::new(tokio::fs::File::from_std(file));
+    let mut stdout = tokio::io::stdout();
+    let mut line = String::new();
+    // Set when the log was found to have been replaced. Nothing gets appended
+    // to the old one after that, but it still has to be read to the end.
+    let mut draining = false;
+    loop {
+        let len = reader
+            .read_line(&mut line)
+            .await
+            .with_context(|| format!("reading {}", path.display()))?;
+        // Don't print half-written lines, the rest will show up later.
+        if line.ends_with('\n') {
+            stdout
+                .write_all(line.as_bytes())
+                .await
+                .context("writing events")?;
+            line.clear();
+            continue;
+        }
+        if len == 0 {
+            if !follow {
+                return Ok(());
+            }
+            if draining {
+                let mut old = String::new();
+                let file = reader.get_mut();
+                file.rewind().await.context("rewinding event log")?;
+                file.read_to_string(&mut old)
+                    .await
+                    .with_context(|| format!("reading {}", path.display()))?;
+                let mut file = tokio::fs::File::open(path)
+                    .await
+                    .with_context(|| format!("opening {}", path.display()))?;
+                let mut new = String::new();
+                file.read_to_string(&mut new)
+                    .await
+                    .with_context(|| format!("reading {}", path.display()))?;
+                file.seek(SeekFrom::Start(pruned_len(&old, &new) as u64))
+                    .await
+                    .with_context(|| format!("seeking in {}", path.display()))?;
+                reader = BufReader::new(file);
+                line.clear();
+                draining = false;
+                continue;
+            }
+            if replaced(reader.get_ref(), path).await? {
+                draining = true;
+                continue;
+            }
+            stdout.flush().await.context("writing events")?;
+            select! {
+                _ = ct.cancelled() => return Ok(()),
+                _ = sleep(FOLLOW_INTERVAL) => (),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::{
+        git::Commit,
+        test::{test_utils::TestBuilder, NotApplicable, TestCase, TestResult},
+    };
+
+    #[tokio::test]
+    async fn test_prune_events() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join(EVENTS_FILENAME);
+        let now = SystemTime::now();
+        let event = |time| Event {
+            time,
+            pid: 1,
+            repo: "/repo".into(),
+            commit: "abc".into(),
+            test: "my_test".into(),
+            kind: EventKind::Started,
+        };
+        let old = event(now - Duration::from_secs(3600));
+        let new = event(now);
+        append(&path, LockMethod::Flock, &old).await.unwrap();
+        // Appenders have to wait while it's being pruned.
+        let lock = ExclusiveFlock::new(lock_target(&path, LockMethod::Flock).unwrap())
+            .await
+            .unwrap();
+        let mut appending = Box::pin(append(&path, LockMethod::Flock, &new));
+        assert!(futures::poll!(&mut appending).is_pending());
+        drop(lock);
+        appending.await.unwrap();
+        fs::write(
+            &path,
+            fs::read_to_string(&path).unwrap() + "{\"half-written",
+        )
+        .unwrap();
+
+        let cutoff = now - Duration::from_secs(60);
+        let freed = prune_events(&path, LockMethod::Flock, cutoff, true)
+            .await
+            .unwrap();
+        assert!(freed > 0);
+        assert_eq!(read_events(&path).unwrap(), vec![old, new.clone()]);
+        assert_eq!(
+            prune_events(&path, LockMethod::Flock, cutoff, false)
+                .await
+                .unwrap(),
+            freed
+        );
+        assert_eq!(read_events(&path).unwrap(), vec![new]);
+        assert!(fs::read_to_string(&path)
+            .unwrap()
+            .ends_with("{\"half-written"));
+        assert_eq!(
+            prune_events(
+                &dir.path().join("nonexistent"),
+                LockMethod::Flock,
+                cutoff,
+                false
+            )
+            .await
+            .unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_pruned_len() {
+        assert_eq!(pruned_len("a\nb\nc\n", "b\nc\nd\n"), 4);
+        assert_eq!(pruned_len("a\nb\nc\n", "a\nc\nd\ne\n"), 4);
+        assert_eq!(pruned_len("a\nb\n", "c\n"), 0);
+        assert_eq!(pruned_len("a\nb\n", ""), 0);
+    }
+
+    #[tokio::test]
+    async fn test_write_events() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join(EVENTS_FILENAME);
+        let test_case = TestCase::new(
+            Commit::arbitrary(),
+            Arc::new(TestBuilder::new("my_test", "", [""]).build()),
+        );
+        let (tx, rx) = tokio::sync::broadcast::channel(16);
+        for status in [
+            TestStatus::NotApplicable(NotApplicable::NotSampled),
+            TestStatus::Enqueued,
+            TestStatus::Started(None),
+            TestStatus::Started(Some("50%".into())),
+            TestStatus::Finished(Ok(TestResult { exit_code: 1 })),
+            TestStatus::Started(None),
+            TestStatus::Finished(Err(TestInconclusive::Canceled)),
+        ] {
+            tx.send(Arc::new(Notification {
+                test_case: test_case.clone(),
+                status,
+                cached: false,
+                provenance: None,
+            }))
+            .unwrap();
+        }
+        drop(tx);
+        write_events(&path, LockMethod::Flock, Path::new("/repo"), rx).await;
+
+        let events = read_events(&path).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap().lines().count(),
+            events.len()
+        );
+        let kinds: Vec<EventKind> = events.iter().map(|e| e.kind.clone()).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                EventKind::Enqueued,
+                EventKind::Started,
+                EventKind::Finished {
+                    exit_code: Some(1),
+                    error: None,
+                    cached: false
+                },
+                EventKind::Started,
+                EventKind::Canceled,
+            ]
+        );
+        assert_eq!(events[0].test, "my_test");
+        assert_eq!(events[0].commit, test_case.commit_hash.to_string());
+    }
+}
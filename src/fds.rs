@@ -0,0 +1,81 @@
+// Limmat keeps a lot of file descriptors open: each job holds its database
+// entry and output files, running jobs have pipes to them, and git commands
+// run in parallel. The default soft limit of 1024 doesn't go far on a big
+// machine, so check up front that the configuration fits, raising the limit if
+// it has to be, instead of hitting EMFILE halfway through a run. Children
+// inherit the limit, so it's only raised as far as it needs to be.
+
+use anyhow::{bail, Context as _};
+use log::debug;
+use nix::sys::resource::{getrlimit, setrlimit, Resource};
+
+use crate::{git::MAX_GIT_COMMANDS, test::MAX_ACTIVE_JOBS};
+
+// The HTTP server, the control socket, logs, the terminal, tokio's internals
+// and so on.
+const BASE_FDS: u64 = 128;
+// Pipes for the command's stdio.
+const FDS_PER_GIT_COMMAND: u64 = 4;
+// The database entry's lock and output files, held from when the job starts
+// waiting for resources.
+const FDS_PER_ACTIVE_JOB: u64 = 4;
+// Progress and output pipes, and output files for hooks. At most one job runs
+// in each worktree.
+const FDS_PER_RUNNING_JOB: u64 = 8;
+
+// Roughly how many fds we need at most, with this many test managers (one per
+// repository) and worktrees between them.
+fn budget(num_managers: usize, num_worktrees: usize) -> u64 {
+    BASE_FDS
+        + MAX_GIT_COMMANDS as u64 * FDS_PER_GIT_COMMAND
+        + (num_managers * MAX_ACTIVE_JOBS) as u64 * FDS_PER_ACTIVE_JOB
+        + num_worktrees as u64 * FDS_PER_RUNNING_JOB
+}
+
+// Fails if the budget doesn't fit in the hard limit on open files, otherwise
+// raises the soft limit to fit it if needed.
+pub fn check_budget(num_managers: usize, num_worktrees: usize) -> anyhow::Result<()> {
+    let (soft, hard) = getrlimit(Resource::RLIMIT_NOFILE).context("getting fd limit")?;
+    let needed = budget(num_managers, num_worktrees);
+    if needed <= soft {
+        return Ok(());
+    }
+    if needed > hard {
+        bail!(
+            "{num_worktrees} worktrees need about {needed} file descriptors but the hard limit \
+            is {hard}, reduce num_worktrees or raise the limit with ulimit -Hn"
+        );
+    }
+    setrlimit(Resource::RLIMIT_NOFILE, needed, hard)
+        .with_context(|| format!("raising fd limit from {soft} to {needed}"))?;
+    debug!("Raised fd limit from {soft} to {needed}");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_budget() {
+        // A typical config shouldn't need the limit raising from the usual
+        // default.
+        assert!(budget(1, 8) <= 1024);
+        assert!(budget(1, 256) > budget(1, 8));
+        assert!(budget(2, 8) > budget(1, 8));
+    }
+
+    #[test]
+    fn test_check_budget() {
+        let (soft, hard) = getrlimit(Resource::RLIMIT_NOFILE).unwrap();
+        if hard != nix::sys::resource::RLIM_INFINITY {
+            // Each worktree needs several fds.
+            assert!(check_budget(1, hard as usize).is_err());
+        }
+        if budget(1, 0) <= soft {
+            // Fits already, so the limit is left alone.
+            check_budget(1, 0).unwrap();
+            assert_eq!(getrlimit(Resource::RLIMIT_NOFILE).unwrap(), (soft, hard));
+        }
+    }
+}
@@ -8,11 +8,19 @@
 //
 // This is a very simple flock library that is not really generic, it serves the
 // rather specific needs of using small files kinda like "database entries".
+// Since flock doesn't work reliably on NFS it can also use lock files instead.
+// Content gets replaced by renaming a new file over the old one, so a crash
+// can't leave it half-written. That means whoever gets a lock has to check
+// they locked the file that's currently at the path.
 
 use std::{
-    fs::File,
-    io::{Read as _, Seek as _, Write as _},
-    os::fd::{AsRawFd as _, RawFd},
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind::NotFound, Read as _, Seek as _, Write as _},
+    os::{
+        fd::{AsRawFd as _, RawFd},
+        unix::fs::MetadataExt as _,
+    },
+    path::PathBuf,
 };
 
 use anyhow::{anyhow, Context as _};
@@ -21,9 +29,24 @@ use nix::{
     errno::Errno,
     libc::{self, LOCK_EX, LOCK_SH},
 };
+use schemars::JsonSchema;
+use serde::Deserialize;
+use tempfile::NamedTempFile;
 use tokio::task::{self};
 
-#[derive(Debug)]
+use crate::{
+    lockfile::{self, LockfileGuard},
+    util::sync_dir,
+};
+
+#[derive(Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LockMethod {
+    Flock,
+    Lockfile,
+}
+
+#[derive(Debug, Clone, Copy)]
 enum LockKind {
     Shared,
     Exclusive,
@@ -54,21 +77,80 @@ async fn flock_async(fd: RawFd, kind: LockKind) -> anyhow::Result<()> {
     task::spawn_blocking(move || flock(fd, kind)).await.unwrap()
 }
 
+// The file to lock, and how to lock it. With flock the lock belongs to the
+// open file, with lock files it belongs to the guard.
+#[derive(Debug)]
+pub struct LockTarget {
+    pub file: File,
+    pub path: PathBuf,
+    pub method: LockMethod,
+}
+
+impl LockTarget {
+    async fn lock(&mut self, kind: LockKind) -> anyhow::Result<Option<LockfileGuard>> {
+        loop {
+            let lockfile = match (self.method, kind) {
+                (LockMethod::Flock, kind) => {
+                    flock_async(self.file.as_raw_fd(), kind).await?;
+                    None
+                }
+                (LockMethod::Lockfile, LockKind::Shared) => {
+                    Some(lockfile::lock_shared(&self.path).await?)
+                }
+                (LockMethod::Lockfile, LockKind::Exclusive) => {
+                    Some(lockfile::lock_exclusive(&self.path).await?)
+                }
+            };
+            if !self.replaced()? {
+                return Ok(lockfile);
+            }
+            // Dropping the old file drops any flock on it. A lock file covers
+            // the path so that one is still good.
+            self.file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&self.path)
+                .with_context(|| format!("reopening {}", self.path.display()))?;
+            if lockfile.is_some() {
+                return Ok(lockfile);
+            }
+        }
+    }
+
+    // Whether the file at the path isn't the one we have open any more. If it
+    // was deleted there's nothing newer to look at.
+    fn replaced(&self) -> anyhow::Result<bool> {
+        let open = self.file.metadata().context("checking locked file")?;
+        match fs::metadata(&self.path) {
+            Ok(current) => Ok((current.dev(), current.ino()) != (open.dev(), open.ino())),
+            Err(e) if e.kind() == NotFound => Ok(false),
+            Err(e) => Err(e).with_context(|| format!("checking {}", self.path.display())),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct SharedFlock {
-    file: File,
+    target: LockTarget,
     content: String,
+    _lockfile: Option<LockfileGuard>,
 }
 
 impl SharedFlock {
     // Lock an open file, this also immediately reads the whole content which
     // can access via `content`. The file should be freshly-opened.
-    pub async fn new(mut file: File) -> anyhow::Result<Self> {
-        flock_async(file.as_raw_fd(), LockKind::Shared).await?;
+    pub async fn new(mut target: LockTarget) -> anyhow::Result<Self> {
+        let lockfile = target.lock(LockKind::Shared).await?;
         let mut content = String::new();
-        file.read_to_string(&mut content)
+        target
+            .file
+            .read_to_string(&mut content)
             .context("reading locked file")?;
-        Ok(Self { file, content })
+        Ok(Self {
+            target,
+            content,
+            _lockfile: lockfile,
+        })
     }
 
     // The content of the file.
@@ -83,47 +165,88 @@ impl SharedFlock {
     // that the reference returned by `content` is invalid now, so you should
     // check the `content` of the result again.
     pub async fn upgrade(mut self) -> anyhow::Result<ExclusiveFlock> {
-        self.file.rewind().context("rewinding locked file")?;
-        ExclusiveFlock::new(self.file).await
+        self.target.file.rewind().context("rewinding locked file")?;
+        // A lock file can't be converted, so let go of it first.
+        drop(self._lockfile);
+        ExclusiveFlock::new(self.target).await
     }
 }
 
 // A simple "write" lock on a file.
 pub struct ExclusiveFlock {
-    file: File,
+    target: LockTarget,
     content: String,
+    _lockfile: Option<LockfileGuard>,
 }
 
 impl ExclusiveFlock {
     // Even though this is a "write lock" in a sense, the file needs to be open for reading too.
-    pub async fn new(mut file: File) -> anyhow::Result<Self> {
-        debug_assert_eq!(file.stream_position().unwrap(), 0);
-        flock_async(file.as_raw_fd(), LockKind::Exclusive).await?;
+    pub async fn new(mut target: LockTarget) -> anyhow::Result<Self> {
+        debug_assert_eq!(target.file.stream_position().unwrap(), 0);
+        let lockfile = target.lock(LockKind::Exclusive).await?;
         let mut content = String::new();
-        file.read_to_string(&mut content)
+        target
+            .file
+            .read_to_string(&mut content)
             .context("reading locked")?;
-        file.rewind().context("rewinding locked file")?;
-        Ok(Self { file, content })
+        target.file.rewind().context("rewinding locked file")?;
+        Ok(Self {
+            target,
+            content,
+            _lockfile: lockfile,
+        })
     }
 
     pub fn content(&self) -> &str {
         &self.content
     }
 
-    // Replace the content of the file.
+    // Replace the content of the file, by renaming a new file over it. The
+    // lock moves to the new file, anyone waiting for the old one will notice
+    // it was replaced.
     pub fn set_content(&mut self, content: &[u8]) -> anyhow::Result<()> {
-        debug_assert_eq!(self.file.stream_position().unwrap(), 0);
-        self.file.set_len(0).context("truncating locked file")?;
-        self.file
-            .write_all(content)
-            .context("writing locked file")?;
+        let path = &self.target.path;
+        let dir = path.parent().context("locked file has no parent")?;
+        let mut new = NamedTempFile::new_in(dir).context("creating temp file")?;
+        new.write_all(content)
+            .with_context(|| format!("writing {}", path.display()))?;
+        let permissions = self
+            .target
+            .file
+            .metadata()
+            .context("checking locked file")?;
+        new.as_file()
+            .set_permissions(permissions.permissions())
+            .context("setting permissions")?;
+        new.as_file()
+            .sync_all()
+            .with_context(|| format!("syncing {}", path.display()))?;
+        // Nobody else can have it open yet, so this doesn't block.
+        if self.target.method == LockMethod::Flock {
+            flock(new.as_file().as_raw_fd(), LockKind::Exclusive)?;
+        }
+        let mut file = new
+            .persist(path)
+            .with_context(|| format!("writing {}", path.display()))?;
+        sync_dir(dir)?;
         // TODO: it would be nicer if this method consumed self, then we wouldn't have to rewind.
-        self.file.rewind().context("rewinding locked file")
+        file.rewind().context("rewinding locked file")?;
+        self.target.file = file;
+        Ok(())
+    }
+
+    // Empty the file in place. Unlike set_content, whoever is waiting for the
+    // lock will see this even if the file gets deleted in the meantime.
+    pub fn clear(&mut self) -> anyhow::Result<()> {
+        let file = &mut self.target.file;
+        debug_assert_eq!(file.stream_position().unwrap(), 0);
+        file.set_len(0).context("truncating locked file")
     }
 
     // See SharedFlock::upgrade - same limiations apply.
     pub async fn downgrade(mut self) -> anyhow::Result<SharedFlock> {
-        self.file.rewind().context("rewinding locked file")?;
-        SharedFlock::new(self.file).await
+        self.target.file.rewind().context("rewinding locked file")?;
+        drop(self._lockfile);
+        SharedFlock::new(self.target).await
     }
 }
@@ -1,11 +1,14 @@
 use core::fmt;
 use core::fmt::{Debug, Display};
+use std::cmp::max;
+use std::collections::HashMap;
 use std::ffi::{OsStr, OsString};
+use std::fs::{self, File};
 use std::ops::Deref;
 use std::os::unix::ffi::OsStrExt as _;
 use std::path::{Path, PathBuf};
 use std::pin::pin;
-use std::process::{self, Command as SyncCommand};
+use std::process::{self, Command as SyncCommand, Stdio};
 use std::sync::LazyLock;
 use std::time::Duration;
 use std::{io, str};
@@ -17,13 +20,17 @@ use colored::control::SHOULD_COLORIZE;
 use futures::future::BoxFuture;
 use futures::{future::Fuse, select, FutureExt, SinkExt as _, StreamExt as _};
 use futures_core::{stream::Stream, FusedFuture};
+use gix::bstr::ByteSlice as _;
+use gix::revision::plumbing::Spec;
 #[allow(unused_imports)]
 use log::{debug, error, info, warn};
-use notify::{Config, RecommendedWatcher, RecursiveMode, Watcher};
+use nix::errno::Errno;
+use nix::fcntl::{Flock, FlockArg};
+use notify::{Config, Event, RecommendedWatcher, RecursiveMode, Watcher};
 use tempfile::TempDir;
 use tokio::process::Command;
 use tokio::sync::{Semaphore, SemaphorePermit};
-use tokio::time::sleep;
+use tokio::time::{sleep_until, Instant};
 use tokio_util::sync::CancellationToken;
 
 use crate::process::OutputExt;
@@ -172,6 +179,10 @@ impl Worktree for PersistentWorktree {
 pub struct Commit {
     pub hash: CommitHash,
     pub tree: TreeHash,
+    pub parents: Vec<CommitHash>,
+    pub message: String,
+    // Like "Name <email>".
+    pub author: String,
 }
 
 impl Commit {
@@ -180,6 +191,9 @@ impl Commit {
         Self {
             hash: CommitHash::new("080b8ecbad3e34e55c5a035af80100f73b742a8d"),
             tree: TreeHash::new("6366d790125291272542a6b40f6fd3400e080821"),
+            parents: Vec::new(),
+            message: String::new(),
+            author: String::new(),
         }
     }
 }
@@ -190,12 +204,14 @@ impl From<Commit> for CommitHash {
     }
 }
 
-pub enum LogStyle {
-    WithGraph,
-    NoGraph,
-}
+// Namespace for branches that jobs get checked out on. This mustn't be the name
+// of a branch itself, or git won't be able to create branches inside it.
+pub const JOB_BRANCH_PREFIX: &str = "limmat-job";
+
+// How many git commands can run at once.
+pub const MAX_GIT_COMMANDS: usize = 64;
 
-static COMMAND_SEM: LazyLock<Semaphore> = LazyLock::new(|| Semaphore::new(64));
+static COMMAND_SEM: LazyLock<Semaphore> = LazyLock::new(|| Semaphore::new(MAX_GIT_COMMANDS));
 
 // Wrapper for a Command, that holds a semaphore for as long as the process
 // exists. Just delegates enough methods to allow you to use it without
@@ -220,12 +236,17 @@ impl GitCommand {
         self
     }
 
+    fn env(&mut self, key: impl AsRef<OsStr>, val: impl AsRef<OsStr>) -> &mut GitCommand {
+        self.command.env(key, val);
+        self
+    }
+
     async fn execute(&mut self) -> anyhow::Result<process::Output> {
         self.command.execute().await
     }
 
     pub async fn output(&mut self) -> io::Result<process::Output> {
-        self.command.output().await
+        self.command.tracked_output().await
     }
 }
 
@@ -256,6 +277,8 @@ trait WorktreePriv: Worktree {
             // prevent us from shutting down. The benefit is that we don't get
             // annoying confusing errors on shut down.
             cmd.process_group(0);
+            // Git mustn't prompt for anything.
+            cmd.stdin(Stdio::null());
             GitCommand {
                 _permit: COMMAND_SEM.acquire().await.unwrap(),
                 command: cmd,
@@ -267,6 +290,53 @@ trait WorktreePriv: Worktree {
 
 impl<W: Worktree + ?Sized> WorktreePriv for W {}
 
+// Run a read-only query in-process with gitoxide. This is much cheaper than spawning git, which
+// matters because we do these queries for every commit in the range, every time it changes. Note
+// this ignores the configured git binary.
+async fn with_gix<T, F>(path: &Path, f: F) -> anyhow::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&gix::Repository) -> anyhow::Result<T> + Send + 'static,
+{
+    let path = path.to_owned();
+    // gitoxide is synchronous and might be walking a lot of history.
+    tokio::task::spawn_blocking(move || {
+        let repo = gix::discover(&path).with_context(|| format!("opening repo at {path:?}"))?;
+        f(&repo)
+    })
+    .await
+    .context("gitoxide task panicked")?
+}
+
+// The object might be a tag.
+fn gix_peel_to_commit(
+    repo: &gix::Repository,
+    id: gix::ObjectId,
+) -> anyhow::Result<gix::Commit<'_>> {
+    repo.find_object(id)
+        .context("looking up object")?
+        .peel_to_commit()
+        .with_context(|| format!("{id} is not a commit"))
+}
+
+fn gix_lookup_commit(repo: &gix::Repository, id: gix::ObjectId) -> anyhow::Result<Commit> {
+    let commit = gix_peel_to_commit(repo, id)?;
+    let author = commit.author().context("decoding commit author")?;
+    Ok(Commit {
+        hash: CommitHash::new(commit.id.to_string()),
+        tree: TreeHash::new(commit.tree_id().context("decoding commit")?.to_string()),
+        parents: commit
+            .parent_ids()
+            .map(|id| CommitHash::new(id.to_string()))
+            .collect(),
+        message: commit
+            .message_raw()
+            .context("decoding commit message")?
+            .to_string(),
+        author: format!("{} <{}>", author.name, author.email),
+    })
+}
+
 // This is a weird kinda inheritance type thing to enable different types of worktree (with
 // different fields and drop behaviours) to share the functionality that users actually care about.
 // Not really sure if this is the Rust Way or not.
@@ -275,14 +345,18 @@ pub trait Worktree: Debug + Sync {
     fn path(&self) -> &Path;
     // Path to Git binary.
     fn git_binary(&self) -> &Path;
+    // How checkouts should be set up.
+    fn options(&self) -> &WorktreeOptions {
+        &DEFAULT_WORKTREE_OPTIONS
+    }
 
-    async fn lookup_git_dir(&self, rev_parse_arg: &str) -> anyhow::Result<PathBuf> {
+    async fn lookup_git_dir(&self, rev_parse_args: &[&str]) -> anyhow::Result<PathBuf> {
         let output = self
-            .git(["rev-parse", rev_parse_arg])
+            .git([&["rev-parse"], rev_parse_args].concat())
             .await
             .execute()
             .await
-            .map_err(|e| anyhow!("'git rev-parse {rev_parse_arg}' failed: {e}"))?;
+            .map_err(|e| anyhow!("'git rev-parse {}' failed: {e}", rev_parse_args.join(" ")))?;
         let mut bytes = output.stdout;
         while bytes.last() == Some(&b'\n') {
             bytes.pop();
@@ -292,27 +366,135 @@ pub trait Worktree: Debug + Sync {
 
     // Directory where the main git database lives, shared by all worktrees.
     async fn git_common_dir(&self) -> anyhow::Result<PathBuf> {
-        self.lookup_git_dir("--git-common-dir").await
+        self.lookup_git_dir(&["--git-common-dir"]).await
+    }
+
+    // Root directory of this worktree.
+    async fn toplevel(&self) -> anyhow::Result<PathBuf> {
+        self.lookup_git_dir(&["--show-toplevel"]).await
     }
 
     // Directory where this workrtee's local git database lives.
     // See https://git-scm.com/docs/git-worktree#_details (I haven't read this properly lmao).
     async fn git_dir(&self) -> anyhow::Result<PathBuf> {
-        self.lookup_git_dir("--absolute-git-dir").await
+        self.lookup_git_dir(&["--absolute-git-dir"]).await
+    }
+
+    // Where Git looks for hooks, which core.hooksPath can move out of the Git
+    // directory.
+    async fn hooks_dir(&self) -> anyhow::Result<PathBuf> {
+        Ok(self
+            .path()
+            .join(self.lookup_git_dir(&["--git-path", "hooks"]).await?))
+    }
+
+    async fn is_shallow(&self) -> anyhow::Result<bool> {
+        with_gix(self.path(), |repo| Ok(repo.is_shallow())).await
+    }
+
+    // Total size of the files in HEAD, i.e. roughly how much space a checkout
+    // takes up.
+    async fn checkout_size(&self) -> anyhow::Result<u64> {
+        let output = self
+            .git(["ls-tree", "-r", "-l", "-z", "HEAD"])
+            .await
+            .execute()
+            .await
+            .context("listing files in HEAD")?;
+        // Entries look like "<mode> <type> <object> <size>\t<path>", the size
+        // is "-" for submodules.
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .split('\0')
+            .filter_map(|entry| entry.split('\t').next()?.split_whitespace().nth(3))
+            .filter_map(|size| size.parse::<u64>().ok())
+            .sum())
+    }
+
+    // Fetch all the history that's missing from a shallow clone.
+    async fn unshallow(&self) -> anyhow::Result<()> {
+        self.git(["fetch", "--unshallow"])
+            .await
+            .execute()
+            .await
+            .context("'git fetch --unshallow' failed")?;
+        Ok(())
+    }
+
+    // Errors that can come from objects being missing are pretty confusing
+    // when they come from shallow or partial clones, this adds an explanation.
+    async fn explain_missing_objects(&self, err: anyhow::Error) -> anyhow::Error {
+        let hint = with_gix(self.path(), |repo| {
+            Ok(if repo.is_shallow() {
+                Some(concat!(
+                    "this is a shallow clone, the revision might be outside the history ",
+                    "that was fetched (see --unshallow)"
+                ))
+            } else if repo
+                .config_snapshot()
+                .string("extensions.partialClone")
+                .is_some()
+            {
+                Some("this is a partial clone, Git might have failed to fetch missing objects")
+            } else {
+                None
+            })
+        })
+        .await;
+        match hint {
+            Ok(Some(hint)) => err.context(hint),
+            _ => err,
+        }
     }
 
     async fn rev_list<S>(&self, range_spec: S) -> anyhow::Result<Vec<CommitHash>>
     where
         S: AsRef<OsStr>,
     {
+        let spec_bytes = range_spec.as_ref().as_bytes().to_owned();
+        // None means it's a kind of spec we don't handle in-process.
+        let revs = with_gix(self.path(), move |repo| {
+            let spec = repo
+                .rev_parse(spec_bytes.as_bstr())
+                .context("parsing range spec")?
+                .detach();
+            let (tip, hidden) = match spec {
+                Spec::Include(tip) => (tip, None),
+                Spec::Range { from, to } => (to, Some(from)),
+                _ => return Ok(None),
+            };
+            let peel = |id| gix_peel_to_commit(repo, id).map(|commit| commit.id);
+            repo.rev_walk([peel(tip)?])
+                .with_hidden(hidden.map(peel).transpose()?)
+                .all()
+                .context("starting revision walk")?
+                .map(|info| {
+                    Ok(CommitHash::new(
+                        info.context("walking revisions")?.id.to_string(),
+                    ))
+                })
+                .collect::<anyhow::Result<_>>()
+                .map(Some)
+        })
+        .await;
+        match revs {
+            Ok(Some(revs)) => return Ok(revs),
+            Ok(None) => (),
+            Err(e) => return Err(self.explain_missing_objects(e).await),
+        }
+
+        // Fall back to git for fancier stuff like A...B.
         let output = self
             .git(["rev-list"])
             .await
             .arg(range_spec)
             .execute()
             .await
-            .context("'git rev-list' failed")?;
-        // See coment in rev_parse.
+            .context("'git rev-list' failed");
+        let output = match output {
+            Ok(output) => output,
+            Err(e) => return Err(self.explain_missing_objects(e).await),
+        };
+        // Hack: empirically, git returns 128 when the range is invalid.
         if output.code_not_killed()? == 128 {
             return Ok(vec![]);
         }
@@ -329,44 +511,236 @@ pub trait Worktree: Debug + Sync {
         Ok(out_str.lines().map(CommitHash::new).collect())
     }
 
-    async fn checkout(&self, commit: &CommitHash) -> anyhow::Result<()> {
-        self.git(["checkout"])
+    // Full name of the ref that the checked-out branch is tracking, None if
+    // there isn't one or HEAD is detached.
+    async fn upstream_ref(&self) -> anyhow::Result<Option<String>> {
+        let output = self
+            .git(["rev-parse", "--symbolic-full-name", "@{upstream}"])
             .await
-            .arg(commit)
             .output()
-            .await?
-            .ok()
-            .context(format!(
-                "checking out revision {:?} in {:?}",
-                commit,
-                self.path()
-            ))
+            .await
+            .context("looking up upstream branch")?;
+        if !output.status.success() {
+            return Ok(None);
+        }
+        let name = String::from_utf8(output.stdout).context("non utf-8 ref name")?;
+        Ok(Some(name.trim().to_owned()))
     }
 
-    async fn log<S, T>(
+    // Local branches matching the pattern (as for git for-each-ref, relative
+    // to refs/heads/) that are in line with HEAD, i.e. they contain it or it
+    // contains them, and that haven't been merged into base. These are the
+    // branches of the stack that HEAD is part of, with their tips.
+    async fn stack_branches(
         &self,
-        range_spec: S,
-        format_spec: T,
-        style: LogStyle,
-    ) -> anyhow::Result<Vec<u8>>
+        base: &OsStr,
+        pattern: &str,
+    ) -> anyhow::Result<Vec<(String, CommitHash)>> {
+        let mut no_merged_arg = OsString::from("--no-merged=");
+        no_merged_arg.push(base);
+        let mut branches = Vec::new();
+        for relation in ["--contains=HEAD", "--merged=HEAD"] {
+            let output = self
+                .git([
+                    "for-each-ref",
+                    "--format=%(objectname) %(refname:short)",
+                    relation,
+                ])
+                .await
+                .arg(&no_merged_arg)
+                .arg(format!("refs/heads/{pattern}"))
+                .execute()
+                .await
+                .context("listing stacked branches")?;
+            let stdout = String::from_utf8(output.stdout).context("non utf-8 branch name")?;
+            for line in stdout.lines() {
+                let (hash, name) = line
+                    .split_once(' ')
+                    .with_context(|| format!("parsing for-each-ref output {line:?}"))?;
+                // HEAD's own branch shows up both times.
+                if !branches.iter().any(|(n, _)| n == name) {
+                    branches.push((name.to_owned(), CommitHash::new(hash)));
+                }
+            }
+        }
+        Ok(branches)
+    }
+
+    // If branch is set the commit is checked out on that branch, which is
+    // created or reset as needed. Otherwise HEAD is detached.
+    async fn checkout(&self, commit: &CommitHash, branch: Option<&str>) -> anyhow::Result<()> {
+        let mut cmd = self.git(["checkout"]).await;
+        if let Some(branch) = branch {
+            cmd.args(["-B", branch]);
+        }
+        let res = cmd.arg(commit).output().await?.ok().context(format!(
+            "checking out revision {:?} in {:?}",
+            commit,
+            self.path()
+        ));
+        if let Err(e) = res {
+            return Err(self.explain_missing_objects(e).await);
+        }
+        self.finish_checkout().await
+    }
+
+    // Go back to a detached HEAD and delete the branch.
+    async fn delete_branch(&self, branch: &str) -> anyhow::Result<()> {
+        self.git(["checkout", "--detach"])
+            .await
+            .execute()
+            .await
+            .context("detaching HEAD")?;
+        self.git(["branch", "-D", branch])
+            .await
+            .execute()
+            .await
+            .with_context(|| format!("deleting branch {branch}"))?;
+        Ok(())
+    }
+
+    // Throw away any changes to tracked files and delete everything that isn't
+    // tracked, including ignored files.
+    async fn reset(&self) -> anyhow::Result<()> {
+        self.git(["reset", "--hard", "-q"])
+            .await
+            .execute()
+            .await
+            .context("resetting worktree")?;
+        self.git(["clean", "-fdxq"])
+            .await
+            .execute()
+            .await
+            .context("cleaning worktree")?;
+        Ok(())
+    }
+
+    // Stuff that git checkout doesn't do for us, depending on the options.
+    async fn finish_checkout(&self) -> anyhow::Result<()> {
+        if self.options().submodules {
+            self.update_submodules().await?;
+        }
+        match self.options().lfs {
+            Some(true) => self
+                .lfs_pull()
+                .await
+                .context("'git lfs pull' failed (is Git LFS installed?)")?,
+            Some(false) => (),
+            None => {
+                if self.uses_lfs().await? {
+                    if let Err(e) = self.lfs_pull().await {
+                        warn!(
+                            "Commit uses Git LFS but 'git lfs pull' failed, tests will see \
+                             pointer files (set lfs = false to silence this): {e:#}"
+                        );
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Whether any .gitattributes file in the checked-out commit routes files
+    // through the LFS filter. This doesn't need Git LFS to be installed.
+    async fn uses_lfs(&self) -> anyhow::Result<bool> {
+        let output = self
+            .git(["grep", "-q", "--fixed-strings", "filter=lfs", "HEAD", "--"])
+            .await
+            .arg(":(glob)**/.gitattributes")
+            .output()
+            .await
+            .context("looking for LFS attributes")?;
+        // 1 means no matches.
+        if output.code_not_killed()? == 1 {
+            return Ok(false);
+        }
+        output.ok().context("looking for LFS attributes")?;
+        Ok(true)
+    }
+
+    // Replace LFS pointer files with their content. The object store is pinned
+    // to the common dir so that it's shared with the main worktree, and objects
+    // only get downloaded once no matter how many worktrees need them.
+    async fn lfs_pull(&self) -> anyhow::Result<()> {
+        let mut storage_arg = OsString::from("lfs.storage=");
+        storage_arg.push(self.path().join(self.git_common_dir().await?).join("lfs"));
+        self.git(["-c"])
+            .await
+            .arg(storage_arg)
+            .args(["lfs", "pull"])
+            .execute()
+            .await?;
+        Ok(())
+    }
+
+    // Check out the submodules for the current commit. If the main worktree
+    // already has a submodule's repository, it's used as a reference so that we
+    // don't download everything again.
+    async fn update_submodules(&self) -> anyhow::Result<()> {
+        let output = self
+            .git(["config", "--file", ".gitmodules", "--get-regexp"])
+            .await
+            .arg(r"^submodule\..*\.path$")
+            .output()
+            .await
+            .context("listing submodules")?;
+        // This means there aren't any.
+        if output.code_not_killed()? == 1 {
+            return Ok(());
+        }
+        output.ok().context("listing submodules")?;
+        let modules_dir = self
+            .path()
+            .join(self.git_common_dir().await?)
+            .join("modules");
+        let stdout = String::from_utf8(output.stdout).context("non utf-8 .gitmodules")?;
+        for line in stdout.lines() {
+            let (name, path) = line
+                .split_once(' ')
+                .and_then(|(key, path)| {
+                    let name = key.strip_prefix("submodule.")?.strip_suffix(".path")?;
+                    Some((name, path))
+                })
+                .with_context(|| format!("parsing .gitmodules entry {line:?}"))?;
+            let mut cmd = self
+                .git(["submodule", "update", "--init", "--recursive"])
+                .await;
+            let reference = modules_dir.join(name);
+            if reference.exists() {
+                cmd.arg("--reference").arg(reference);
+            }
+            let res = cmd
+                .arg("--")
+                .arg(path)
+                .execute()
+                .await
+                .with_context(|| format!("updating submodule {name}"));
+            if let Err(e) = res {
+                return Err(self.explain_missing_objects(e).await);
+            }
+        }
+        Ok(())
+    }
+
+    // Output of "git log --graph". The range can be made of several revision
+    // arguments, like "^base tip1 tip2".
+    async fn graph_log<S, T>(&self, range: &[S], format_spec: T) -> anyhow::Result<Vec<u8>>
     where
-        S: AsRef<OsStr>,
+        S: AsRef<OsStr> + Sync,
         T: AsRef<OsStr>,
     {
         let mut format_arg = OsString::from("--format=");
         format_arg.push(format_spec.as_ref());
         let stdout = self
-            .git(match style {
-                LogStyle::WithGraph => vec!["log", "--graph"],
-                LogStyle::NoGraph => vec!["log"],
-            })
+            .git(["log", "--graph"])
             .await
-            .args([&format_arg, range_spec.as_ref()])
+            .arg(&format_arg)
+            .args(range)
             .execute()
             .await
             .context(format!(
                 "getting graph log for {:?} with format {:?}",
-                range_spec.as_ref(),
+                range.iter().map(|s| s.as_ref()).collect::<Vec<_>>(),
                 format_spec.as_ref(),
             ))?
             .stdout;
@@ -374,12 +748,15 @@ pub trait Worktree: Debug + Sync {
     }
 
     // Watch for events that could change the meaning of a revspec. When that happens, send an event
-    // on the channel with the new resolved spec.
+    // on the channel with the new resolved spec. Updates are produced debounce after the first
+    // event in a burst, but not until there have been no events for quiet_period.
     fn watch_refs<'a>(
         &'a self,
         // TODO: Write this in a way where the user doesn't have to deal with converting to OsStr.
         // (Needs to also work with both owned and reference types I think).
         range_spec: &'a OsStr,
+        debounce: Duration,
+        quiet_period: Duration,
     ) -> anyhow::Result<impl Stream<Item = anyhow::Result<Vec<CommitHash>>> + 'a> {
         // Alternatives considered/attempted:
         //
@@ -396,7 +773,7 @@ pub trait Worktree: Debug + Sync {
         // this use of futures::executor::block_on is legit - the notify crate spins up a thread
         // under the hood so it's fine to block that thread, and block_on seems to be the proper way
         // to bridge into async code from sync code.
-        let (mut tx, mut rx) = futures::channel::mpsc::unbounded();
+        let (mut tx, mut rx) = futures::channel::mpsc::unbounded::<notify::Result<Event>>();
 
         let mut watcher = RecommendedWatcher::new(
             move |res| {
@@ -419,37 +796,62 @@ pub trait Worktree: Debug + Sync {
             },
             Config::default(),
         )?;
-        // This logic "debounces" consecutive events within the same 1s window, to avoid thrashing
-        // on the downstream logic as Git works its way through changes.
+        // This logic "debounces" consecutive events within the same window, to avoid thrashing
+        // on the downstream logic as Git works its way through changes. The quiet period is for
+        // stuff like interactive rebases, which generate long bursts of changes.
         Ok(try_stream! {
-            let git_common_dir = &self.git_common_dir().await.context("getting git common dir")?;
-            let git_dir = &self.git_dir().await.context("getting git common dir")?;
+            // This can be relative to the worktree.
+            let git_common_dir =
+                &self.path().join(self.git_common_dir().await.context("getting git common dir")?);
+            let git_dir = &self.git_dir().await.context("getting git dir")?;
             debug!("watching {git_dir:?} and {git_common_dir:?}");
+            // Only watch the refs directories recursively, otherwise we'd see all the activity in
+            // objects/ and in other worktrees. Irrelevant files in the top-level directories are
+            // filtered out below.
             watcher
-                .watch(git_dir, RecursiveMode::Recursive)
+                .watch(git_dir, RecursiveMode::NonRecursive)
                 .context("setting up watcher")?;
             if git_dir != git_common_dir {
                 watcher
-                    .watch(git_common_dir, RecursiveMode::Recursive)
+                    .watch(git_common_dir, RecursiveMode::NonRecursive)
                     .context("setting up watcher")?;
             }
+            watcher
+                .watch(&git_common_dir.join("refs"), RecursiveMode::Recursive)
+                .context("setting up watcher")?;
 
             // Produce an initial update.
             yield self.rev_list(range_spec).await?;
 
             // Start with an expired timer.
             let mut sleep_fut = pin!(Fuse::terminated());
+            // When the current burst of events started.
+            let mut burst_start = None;
             loop {
                 select! {
                     // Produce an update when the timer expires.
-                    () = sleep_fut =>  yield self.rev_list(range_spec).await?,
+                    () = sleep_fut => {
+                        burst_start = None;
+                        yield self.rev_list(range_spec).await?;
+                    },
                     // Ensure the timer is set when we see an update.
                     result = rx.next() => {
                         // There's a bug if the sender has shut down, we should always receive
                         // something.
-                        let _ = result.expect("git watcher internal receive error");
-                        if sleep_fut.is_terminated() {
-                            sleep_fut.set(sleep(Duration::from_secs(1)).fuse());
+                        let result = result.expect("git watcher internal receive error");
+                        // Errors might mean we missed something so treat them as relevant.
+                        if let Ok(event) = result {
+                            let relevant =
+                                event.paths.iter().any(|p| is_ref_path(p, git_dir, git_common_dir));
+                            if !relevant {
+                                continue;
+                            }
+                        }
+                        let now = Instant::now();
+                        let burst_start = *burst_start.get_or_insert(now);
+                        let deadline = max(burst_start + debounce, now + quiet_period);
+                        if sleep_fut.is_terminated() || !quiet_period.is_zero() {
+                            sleep_fut.set(sleep_until(deadline).fuse());
                         }
                     },
                 }
@@ -462,59 +864,214 @@ pub trait Worktree: Debug + Sync {
     where
         S: AsRef<OsStr>,
     {
-        // We don't use log_n1 here because we want to check the exit code,
-        // that API is designed for users who assume the revision exists.
-        let output = {
-            let mut cmd = self.git(["log", "-n1", "--format=%H %T"]).await;
-            let cmd = cmd.arg(rev_spec);
-            cmd.output().await.context("failed to run 'git log -n1'")?
-        };
-        // Hack: empirically, git returns 128 when the range is invalid, it's not documented
-        // but hopefully this is stable behaviour that we're supposed to be able to rely on for
-        // this...?
-        let exit_code = output.code_not_killed()?;
-        if exit_code == 128 {
+        let rev_spec = rev_spec.as_ref().as_bytes().to_owned();
+        with_gix(self.path(), move |repo| {
+            // Like git, we don't distinguish between invalid and nonexistent revisions.
+            let Ok(id) = repo.rev_parse_single(rev_spec.as_bstr()) else {
+                return Ok(None);
+            };
+            gix_lookup_commit(repo, id.detach()).map(Some)
+        })
+        .await
+    }
+
+    // Merge base of each commit with the upstream revision. Commits with no
+    // common history with it are left out.
+    async fn merge_bases(
+        &self,
+        upstream: &OsStr,
+        commits: Vec<CommitHash>,
+    ) -> anyhow::Result<HashMap<CommitHash, CommitHash>> {
+        let upstream = upstream.as_bytes().to_owned();
+        with_gix(self.path(), move |repo| {
+            let upstream = repo
+                .rev_parse_single(upstream.as_bstr())
+                .with_context(|| format!("looking up {}", upstream.as_bstr()))?;
+            let mut bases = HashMap::new();
+            for commit in commits {
+                let id = gix::ObjectId::from_hex(commit.to_string().as_bytes())
+                    .with_context(|| format!("parsing commit hash {commit}"))?;
+                if let Ok(base) = repo.merge_base(id, upstream) {
+                    bases.insert(commit, CommitHash::new(base.to_string()));
+                }
+            }
+            Ok(bases)
+        })
+        .await
+    }
+
+    // Contents of the commit's note in refs/notes/{notes_ref}, if it has one.
+    async fn note(&self, notes_ref: &str, commit: &CommitHash) -> anyhow::Result<Option<String>> {
+        let output = self
+            .git(["notes", "--ref", notes_ref, "show"])
+            .await
+            .arg(commit)
+            .output()
+            .await
+            .with_context(|| format!("reading note for {commit}"))?;
+        // This means there isn't one.
+        if output.code_not_killed()? == 1 {
             return Ok(None);
         }
-        if exit_code != 0 {
-            bail!("'git log -n1' failed with code {exit_code}");
+        output
+            .ok()
+            .with_context(|| format!("reading note for {commit}"))?;
+        Ok(Some(
+            String::from_utf8(output.stdout).context("non utf-8 note")?,
+        ))
+    }
+
+    // Replace the commit's note in refs/notes/{notes_ref}.
+    async fn set_note(
+        &self,
+        notes_ref: &str,
+        commit: &CommitHash,
+        message: &str,
+    ) -> anyhow::Result<()> {
+        self.git([
+            "notes",
+            "--ref",
+            notes_ref,
+            "add",
+            "--force",
+            "--message",
+            message,
+        ])
+        .await
+        .arg(commit)
+        .execute()
+        .await
+        .with_context(|| format!("writing note for {commit}"))?;
+        Ok(())
+    }
+
+    // Like rev_parse for a bunch of revisions at once, but it's an error if any of them don't
+    // exist.
+    async fn rev_parse_all<I, S>(&self, rev_specs: I) -> anyhow::Result<Vec<Commit>>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        let rev_specs: Vec<Vec<u8>> = rev_specs
+            .into_iter()
+            .map(|s| s.as_ref().as_bytes().to_owned())
+            .collect();
+        let res = with_gix(self.path(), move |repo| {
+            rev_specs
+                .iter()
+                .map(|rev_spec| {
+                    let id = repo
+                        .rev_parse_single(rev_spec.as_bstr())
+                        .with_context(|| format!("looking up {}", rev_spec.as_bstr()))?;
+                    gix_lookup_commit(repo, id.detach())
+                })
+                .collect()
+        })
+        .await;
+        match res {
+            Ok(commits) => Ok(commits),
+            Err(e) => Err(self.explain_missing_objects(e).await),
+        }
+    }
+}
+
+// How to set up a TempWorktree.
+#[derive(Debug, Clone, Default)]
+pub struct WorktreeOptions {
+    // Make it a cone-mode sparse checkout of these directories.
+    pub sparse_checkout: Option<Vec<String>>,
+    // Check out submodules, and update them whenever checking out a commit.
+    pub submodules: bool,
+    // Replace Git LFS pointer files with their content whenever checking out
+    // a commit. If this is None, that's done for commits whose .gitattributes
+    // use LFS.
+    pub lfs: Option<bool>,
+    // Program and args to run in the worktree before it's removed.
+    pub teardown: Option<Vec<OsString>>,
+    // Populate new worktrees by making reflink copies of the files in this
+    // other worktree, instead of checking them out. Falls back to a checkout if
+    // that fails.
+    pub reflink_from: Option<PathBuf>,
+}
+
+static DEFAULT_WORKTREE_OPTIONS: WorktreeOptions = WorktreeOptions {
+    sparse_checkout: None,
+    submodules: false,
+    lfs: None,
+    teardown: None,
+    reflink_from: None,
+};
+
+// Where a TempWorktree lives.
+#[derive(Debug)]
+pub enum WorktreeDir {
+    // Deleted along with the worktree.
+    Temp(TempDir),
+    // Left in place on cleanup so that later runs can reuse it instead of
+    // paying for a fresh checkout. The lock stops concurrent instances from
+    // sharing it.
+    Persistent { path: PathBuf, _lock: Flock<File> },
+}
+
+impl WorktreeDir {
+    // Returns None if another instance is already using this path.
+    pub fn persistent(path: PathBuf) -> anyhow::Result<Option<Self>> {
+        let mut lock_path = path.as_os_str().to_owned();
+        lock_path.push(".lock");
+        let lock_file =
+            File::create(&lock_path).with_context(|| format!("creating {lock_path:?}"))?;
+        match Flock::lock(lock_file, FlockArg::LockExclusiveNonblock) {
+            Ok(lock) => Ok(Some(Self::Persistent { path, _lock: lock })),
+            Err((_, Errno::EWOULDBLOCK)) => Ok(None),
+            Err((_, errno)) => bail!("locking {lock_path:?}: {errno}"),
         }
-        let out_string =
-            String::from_utf8(output.stdout).context("reading git rev-parse output")?;
-        let parts: Vec<&str> = out_string.trim().splitn(2, " ").collect();
-        if parts.len() != 2 {
-            bail!(
-                "Failed to parse result of 'git log -n1' - {out_string:?}\nstderr: {:?}",
-                String::from_utf8_lossy(&output.stderr)
-            );
+    }
+
+    fn path(&self) -> &Path {
+        match self {
+            Self::Temp(temp_dir) => temp_dir.path(),
+            Self::Persistent { path, .. } => path,
         }
-        Ok(Some(Commit {
-            hash: CommitHash::new(parts[0]),
-            tree: TreeHash::new(parts[1]),
-        }))
     }
 }
 
-// A worktree that is deleted when dropped. This is kind of a dumb API that just happens to fit this
-// project's exact needs. Instead probably Repo::new and this method should return a common trait or
-// something.
+impl From<TempDir> for WorktreeDir {
+    fn from(temp_dir: TempDir) -> Self {
+        Self::Temp(temp_dir)
+    }
+}
+
+// A worktree that is deleted when dropped (unless it's in a persistent
+// WorktreeDir). This is kind of a dumb API that just happens to fit this
+// project's exact needs. Instead probably Repo::new and this method should
+// return a common trait or something.
 #[derive(Debug)]
 pub struct TempWorktree {
     origin: PathBuf, // Path of repo this was created from.
-    temp_dir: TempDir,
+    dir: WorktreeDir,
+    // Set once a persistent worktree is known to be valid, so we don't delete
+    // it on cleanup.
+    keep: bool,
+    // Set once the worktree is fully set up, i.e. once tests might have used
+    // it and it might need tearing down.
+    ready: bool,
     cleaned_up: bool,
     git_binary: PathBuf,
+    options: WorktreeOptions,
 }
 
 impl TempWorktree {
-    // Create a worktree based on the origin repo, directly in the temp dir (which should be empty)
+    // Create a worktree based on the origin repo, directly in the dir. If it's
+    // a temp dir, it should be empty. If it's persistent and already contains a
+    // worktree of the origin repo, that gets reused, otherwise it's replaced.
     // You must call cleanup on the result, or drop will panic.
     // Cancelling this will ensure we clean up efficiently. If you drop the
     // future without doing that, it has the same consequences as failing to call cleanup.
     pub async fn new<W>(
         ct: &CancellationToken,
         origin: &W,
-        temp_dir: TempDir,
+        dir: impl Into<WorktreeDir>,
+        options: &WorktreeOptions,
     ) -> anyhow::Result<TempWorktree>
     where
         W: Worktree,
@@ -522,42 +1079,215 @@ impl TempWorktree {
         // We create the object now even though it is not actually valid yet.
         // This is a hack to let the drop behaviour kick in immediately even if
         // this constructor is cancelled.
-        let zelf = Self {
+        let mut zelf = Self {
             origin: origin.path().to_owned(),
-            temp_dir,
+            dir: dir.into(),
+            keep: false,
+            ready: false,
             cleaned_up: false,
             git_binary: origin.git_binary().to_owned(),
+            options: options.clone(),
         };
-        // Dumb workaround for https://github.com/bjackman/limmat/issues/14
-        let mut attempts = 1;
-        loop {
-            let mut cmd = origin.git(["worktree", "add"]).await;
-            let cmd = cmd.arg(zelf.temp_dir.path()).arg("HEAD");
-            select! {
-                _ = ct.cancelled().fuse() => {
-                    zelf.cleanup().await;
-                    bail!("canceled")
-                },
-                res = cmd.execute().fuse() => {
-                    match res {
-                        Ok(_) => return Ok(zelf),
-                        Err(e) => {
-                            if attempts >= 5 {
-                                bail!("git worktree add failed: {}", e);
-                            }
-                            attempts += 1;
-                        },
+        let persistent = matches!(zelf.dir, WorktreeDir::Persistent { .. });
+        // For sparse checkouts, we can only set up the patterns once the
+        // worktree exists, so we check it out ourselves after that. For
+        // reflinks we don't want Git to check anything out at all.
+        let no_checkout = options.sparse_checkout.is_some() || options.reflink_from.is_some();
+        let reused = persistent && is_registered_worktree(origin, zelf.path()).await?;
+        if reused {
+            debug!("Reusing worktree at {:?}", zelf.path());
+        } else {
+            if persistent {
+                // Whatever is there is junk, probably from a run that got killed
+                // while creating it.
+                if zelf.path().exists() {
+                    fs::remove_dir_all(zelf.path())
+                        .with_context(|| format!("removing stale worktree {:?}", zelf.path()))?;
+                }
+                origin
+                    .git(["worktree", "prune"])
+                    .await
+                    .execute()
+                    .await
+                    .context("pruning stale worktrees")?;
+            }
+            // Dumb workaround for https://github.com/bjackman/limmat/issues/14
+            let mut attempts = 1;
+            loop {
+                let mut cmd = origin.git(["worktree", "add"]).await;
+                if no_checkout {
+                    cmd.arg("--no-checkout");
+                }
+                let cmd = cmd.arg(zelf.path()).arg("HEAD");
+                select! {
+                    _ = ct.cancelled().fuse() => {
+                        zelf.cleanup().await;
+                        bail!("canceled")
+                    },
+                    res = cmd.execute().fuse() => {
+                        match res {
+                            Ok(_) => break,
+                            Err(e) => {
+                                if attempts >= 5 {
+                                    let e = anyhow!("git worktree add failed: {}", e);
+                                    return Err(origin.explain_missing_objects(e).await);
+                                }
+                                attempts += 1;
+                            },
+                        }
+                    },
+                }
+            }
+        }
+        // A reused worktree might have been set up with different options.
+        let setup = async {
+            if reused || options.sparse_checkout.is_some() {
+                zelf.update_sparse_checkout(options.sparse_checkout.as_deref())
+                    .await?;
+            }
+            let mut copied = false;
+            if let (false, Some(template)) = (reused, &options.reflink_from) {
+                match zelf.copy_checkout(template).await {
+                    Ok(()) => copied = true,
+                    Err(e) => {
+                        warn!(
+                            "Couldn't copy {template:?} to {:?}, checking it out instead: {e:#}",
+                            zelf.path()
+                        );
+                        zelf.remove_files()?;
                     }
-                },
+                }
+            }
+            if !reused && !copied && no_checkout {
+                zelf.git(["checkout"])
+                    .await
+                    .execute()
+                    .await
+                    .context("checking out worktree")?;
+            }
+            zelf.finish_checkout().await
+        };
+        if let Err(e) = setup.await {
+            zelf.cleanup().await;
+            return Err(e);
+        }
+        zelf.keep = persistent;
+        zelf.ready = true;
+        Ok(zelf)
+    }
+
+    // Populate a fresh --no-checkout worktree by copying the files and index
+    // from another worktree of the same repo. Where the filesystem supports
+    // reflinks that's much cheaper than a checkout since no file data gets
+    // copied. Fails if it doesn't support them.
+    async fn copy_checkout(&self, template: &Path) -> anyhow::Result<()> {
+        let mut cmd = Command::new("cp");
+        cmd.args(["-a", "--reflink=always"]);
+        for entry in fs::read_dir(template).with_context(|| format!("reading {template:?}"))? {
+            let entry = entry.with_context(|| format!("reading {template:?}"))?;
+            if entry.file_name() != ".git" {
+                cmd.arg(entry.path());
+            }
+        }
+        cmd.arg(self.path())
+            .execute()
+            .await
+            .context("'cp --reflink=always' failed")?;
+        // The index has to match the copied files, otherwise Git would think
+        // they were all modified.
+        let template = PersistentWorktree {
+            path: template.to_owned(),
+            git_binary: self.git_binary.clone(),
+        };
+        let index = template.git_dir().await?.join("index");
+        fs::copy(&index, self.git_dir().await?.join("index"))
+            .with_context(|| format!("copying {index:?}"))?;
+        // In case HEAD has moved on since the template was checked out. The
+        // files are unchanged so this only needs to read them.
+        self.git(["reset", "--hard", "-q"])
+            .await
+            .execute()
+            .await
+            .context("resetting copied worktree")?;
+        Ok(())
+    }
+
+    // Delete everything in the worktree except the .git file.
+    fn remove_files(&self) -> anyhow::Result<()> {
+        let read_err = || format!("reading {:?}", self.path());
+        for entry in fs::read_dir(self.path()).with_context(read_err)? {
+            let entry = entry.with_context(read_err)?;
+            if entry.file_name() == ".git" {
+                continue;
+            }
+            let path = entry.path();
+            let result = if entry.file_type().is_ok_and(|t| t.is_dir()) {
+                fs::remove_dir_all(&path)
+            } else {
+                fs::remove_file(&path)
+            };
+            result.with_context(|| format!("removing {path:?}"))?;
+        }
+        Ok(())
+    }
+
+    async fn update_sparse_checkout(
+        &self,
+        sparse_checkout: Option<&[String]>,
+    ) -> anyhow::Result<()> {
+        match sparse_checkout {
+            Some(dirs) => {
+                self.git(["sparse-checkout", "set", "--cone", "--"])
+                    .await
+                    .args(dirs)
+                    .execute()
+                    .await
+                    .context("setting sparse-checkout directories")?;
+            }
+            None => {
+                // Avoid running "sparse-checkout disable" unnecessarily, it
+                // messes with the repo's config.
+                let output = self
+                    .git(["config", "--type=bool", "core.sparseCheckout"])
+                    .await
+                    .output()
+                    .await
+                    .context("checking sparse-checkout config")?;
+                if output.stdout.trim_ascii() == b"true" {
+                    self.git(["sparse-checkout", "disable"])
+                        .await
+                        .execute()
+                        .await
+                        .context("disabling sparse-checkout")?;
+                }
             }
         }
+        Ok(())
+    }
+
+    fn teardown_cmd(&self) -> Option<SyncCommand> {
+        let args = self.options.teardown.as_ref()?;
+        // A persistent worktree isn't removed, so whatever's in it might
+        // still be wanted next time.
+        if !self.ready || self.keep {
+            return None;
+        }
+        let mut cmd = SyncCommand::new(&args[0]);
+        cmd.args(&args[1..])
+            .current_dir(self.path())
+            .env("LIMMAT_ORIGIN", &self.origin);
+        Some(cmd)
     }
 
     fn cleanup_cmd(&self) -> Option<SyncCommand> {
+        if self.keep {
+            debug!("Keeping persistent worktree at {:?}", self.path());
+            return None;
+        }
         if !self.origin.exists() {
             debug!(
                 "Not de-registering worktree at {:?} as origin repo ({:?}) is gone.",
-                self.temp_dir.path(),
+                self.path(),
                 self.origin
             );
             return None;
@@ -572,7 +1302,7 @@ impl TempWorktree {
         // Double --force means remove it even if we were in the middle of
         // creating it.
         cmd.args(["worktree", "remove", "--force", "--force"])
-            .arg(self.temp_dir.path())
+            .arg(self.path())
             .current_dir(&self.origin);
         Some(cmd)
     }
@@ -582,14 +1312,19 @@ impl TempWorktree {
     // for parallelism) and you will feel like a dumb idiot and your friends
     // will laugh at you.
     pub async fn cleanup(mut self) {
+        if let Some(cmd) = self.teardown_cmd() {
+            if let Err(e) = Command::from(cmd).execute().await {
+                warn!("worktree_teardown failed in {:?}: {e:#}", self.path());
+            }
+        }
         if let Some(cmd) = self.cleanup_cmd() {
             match Command::from(cmd).execute().await {
                 Err(e) => {
                     // This is totally normal, because the constructor creates this
                     // object before being certain the worktree was even created.
-                    debug!("Couldn't clean up worktree {:?}: {:?}", &self.temp_dir, e);
+                    debug!("Couldn't clean up worktree {:?}: {:?}", &self.dir, e);
                 }
-                Ok(_) => debug!("Delorted worktree at {:?}", self.temp_dir.path()),
+                Ok(_) => debug!("Delorted worktree at {:?}", self.path()),
             }
         }
 
@@ -597,14 +1332,183 @@ impl TempWorktree {
     }
 }
 
+// Write the files of a commit into dir, without any git metadata. This goes via
+// a temporary index, so it doesn't touch the repo's own index or take any of
+// its locks.
+pub async fn extract_commit(
+    repo: &impl Worktree,
+    commit: &CommitHash,
+    dir: &Path,
+) -> anyhow::Result<()> {
+    let index_dir = TempDir::with_prefix("limmat-index-").context("creating temp dir")?;
+    let index = index_dir.path().join("index");
+    repo.git(["read-tree"])
+        .await
+        .env("GIT_INDEX_FILE", &index)
+        .arg(commit)
+        .execute()
+        .await
+        .with_context(|| format!("reading tree of {commit:?}"))?;
+    let mut prefix = OsString::from("--prefix=");
+    prefix.push(dir);
+    prefix.push("/");
+    repo.git(["checkout-index", "--all"])
+        .await
+        .env("GIT_INDEX_FILE", &index)
+        .arg(prefix)
+        .execute()
+        .await
+        .with_context(|| format!("extracting {commit:?} into {}", dir.display()))?;
+    Ok(())
+}
+
+// Files that the commit adds, modifies or deletes compared to its first
+// parent. For a root commit (no parent) that's all of its files.
+pub async fn changed_files(
+    worktree: &impl Worktree,
+    commit: &CommitHash,
+    first_parent: Option<&CommitHash>,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let mut cmd = worktree
+        .git(["diff-tree", "-r", "-z", "--name-only", "--no-commit-id"])
+        .await;
+    match first_parent {
+        Some(parent) => cmd.arg(parent),
+        None => cmd.arg("--root"),
+    };
+    let output = cmd
+        .arg(commit)
+        .execute()
+        .await
+        .with_context(|| format!("listing files changed by {commit}"))?;
+    Ok(output
+        .stdout
+        .split(|b| *b == b'\0')
+        .filter(|path| !path.is_empty())
+        .map(|path| PathBuf::from(OsStr::from_bytes(path)))
+        .collect())
+}
+
+// A branch that a job checks out its commit on. If this is dropped before the
+// branch is deleted, it's deleted then, so that it isn't left behind when the
+// job bails out early.
+pub struct JobBranch<'a> {
+    worktree: &'a TempWorktree,
+    // None once the branch is deleted.
+    name: Option<String>,
+}
+
+impl<'a> JobBranch<'a> {
+    pub fn new(worktree: &'a TempWorktree, name: String) -> Self {
+        Self {
+            worktree,
+            name: Some(name),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        self.name.as_deref().expect("branch already deleted")
+    }
+
+    // Go back to a detached HEAD and delete the branch.
+    pub async fn delete(mut self) -> anyhow::Result<()> {
+        let name = self.name.take().expect("branch already deleted");
+        self.worktree.delete_branch(&name).await
+    }
+}
+
+impl Drop for JobBranch<'_> {
+    fn drop(&mut self) {
+        let Some(name) = self.name.take() else {
+            return;
+        };
+        // We can't await here.
+        let git = |args: &[&str]| {
+            SyncCommand::new(self.worktree.git_binary())
+                .current_dir(self.worktree.path())
+                .args(args)
+                .stdin(Stdio::null())
+                .execute()
+        };
+        let res = git(&["checkout", "--detach"]).and_then(|_| git(&["branch", "-D", &name]));
+        if let Err(e) = res {
+            warn!("Failed to clean up branch {name}: {e:#}");
+        }
+    }
+}
+
+// Delete job branches that were left behind by instances that crashed. Ones
+// that are checked out in a worktree might still be in use, so git refuses to
+// delete those and they're skipped. Returns how many were deleted.
+pub async fn prune_job_branches(origin: &impl Worktree) -> anyhow::Result<usize> {
+    let output = origin
+        .git(["for-each-ref", "--format=%(refname:short)"])
+        .await
+        .arg(format!("refs/heads/{JOB_BRANCH_PREFIX}/"))
+        .execute()
+        .await
+        .context("listing job branches")?;
+    let stdout = String::from_utf8(output.stdout).context("non utf-8 branch name")?;
+    let mut pruned = 0;
+    for branch in stdout.lines() {
+        match origin.git(["branch", "-D", branch]).await.execute().await {
+            Ok(_) => pruned += 1,
+            Err(e) => debug!("Not pruning job branch {branch}: {e:#}"),
+        }
+    }
+    Ok(pruned)
+}
+
+// What's checked out, relative to the nearest tag if there is one, with
+// "-dirty" on the end if there are uncommitted changes.
+pub async fn describe(worktree: &impl Worktree) -> anyhow::Result<String> {
+    let output = worktree
+        .git(["describe", "--always", "--tags", "--dirty"])
+        .await
+        .execute()
+        .await
+        .context("'git describe' failed")?;
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+// Is path the root of a (non-stale) worktree of the origin repo?
+async fn is_registered_worktree(origin: &impl Worktree, path: &Path) -> anyhow::Result<bool> {
+    let Ok(path) = path.canonicalize() else {
+        return Ok(false);
+    };
+    let output = origin
+        .git(["worktree", "list", "--porcelain", "-z"])
+        .await
+        .execute()
+        .await
+        .context("listing worktrees")?;
+    let output = String::from_utf8_lossy(&output.stdout);
+    // The output is a sequence of records, each of which is a sequence of
+    // NUL-terminated "attribute value" lines, followed by an extra NUL.
+    for record in output.split("\0\0") {
+        let mut attrs = record.split('\0');
+        let Some(wt_path) = attrs.next().and_then(|line| line.strip_prefix("worktree ")) else {
+            continue;
+        };
+        if Path::new(wt_path).canonicalize().ok().as_ref() == Some(&path) {
+            return Ok(!attrs.any(|attr| attr.starts_with("prunable")));
+        }
+    }
+    Ok(false)
+}
+
 impl Worktree for TempWorktree {
     fn path(&self) -> &Path {
-        self.temp_dir.path()
+        self.dir.path()
     }
 
     fn git_binary(&self) -> &Path {
         &self.git_binary
     }
+
+    fn options(&self) -> &WorktreeOptions {
+        &self.options
+    }
 }
 
 impl Drop for TempWorktree {
@@ -616,19 +1520,43 @@ impl Drop for TempWorktree {
             "TempWorktree was not cleaned up before drop. \
                 This is functionally harmless but probably slows things down."
         );
+        if let Some(mut cmd) = self.teardown_cmd() {
+            if let Err(e) = cmd.execute() {
+                warn!("worktree_teardown failed in {:?}: {e:#}", self.path());
+            }
+        }
         if let Some(mut cmd) = self.cleanup_cmd() {
             match cmd.execute() {
                 Err(e) => {
                     // This is totally normal, because the constructor creates this
                     // object before being certain the worktree was even created.
-                    debug!("Couldn't clean up worktree {:?}: {:?}", &self.temp_dir, e);
+                    debug!("Couldn't clean up worktree {:?}: {:?}", &self.dir, e);
                 }
-                Ok(_) => debug!("Delorted worktree at {:?}", self.temp_dir.path()),
+                Ok(_) => debug!("Delorted worktree at {:?}", self.path()),
             }
         }
     }
 }
 
+// Could a change to this path affect the meaning of a revspec?
+fn is_ref_path(path: &Path, git_dir: &Path, git_common_dir: &Path) -> bool {
+    if let Ok(rel_path) = path.strip_prefix(git_common_dir) {
+        // Those are the branches we create for jobs.
+        if rel_path.starts_with(Path::new("refs/heads").join(JOB_BRANCH_PREFIX)) {
+            return false;
+        }
+        if rel_path == Path::new("packed-refs") || rel_path.starts_with("refs") {
+            return true;
+        }
+    }
+    // HEAD, and also stuff like ORIG_HEAD and FETCH_HEAD. Note Git updates these by renaming a
+    // .lock file over them, and we'll see an event for the new name.
+    path.parent() == Some(git_dir)
+        && path
+            .file_name()
+            .is_some_and(|name| name.as_bytes().ends_with(b"HEAD"))
+}
+
 #[cfg(test)]
 pub mod test_utils {
 
@@ -643,10 +1571,15 @@ pub mod test_utils {
     // Empty repository in a temporary directory, torn down on drop.
     impl TempRepo {
         pub async fn new() -> anyhow::Result<Self> {
+            Self::with_git_binary(PathBuf::from("git")).await
+        }
+
+        // Worktrees made from it use the same binary.
+        pub async fn with_git_binary(git_binary: PathBuf) -> anyhow::Result<Self> {
             // https://www.youtube.com/watch?v=_MwboA5NIVA
             let zelf = Self {
                 temp_dir: TempDir::with_prefix("fixture-").expect("couldn't make tempdir"),
-                git_binary: PathBuf::from("git"),
+                git_binary,
             };
             zelf.git(["init"]).await.execute().await?;
             Ok(zelf)
@@ -684,6 +1617,16 @@ pub mod test_utils {
                 .ok_or(anyhow!("no HEAD after committing"))
         }
 
+        // Stage all changes to the worktree, including new and deleted files.
+        async fn add_all(&self) -> anyhow::Result<()> {
+            self.git(["add", "-A"])
+                .await
+                .execute()
+                .await
+                .context("'git add' failed")?;
+            Ok(())
+        }
+
         async fn merge(&self, parents: &[CommitHash]) -> anyhow::Result<Commit> {
             self.git(["merge", "-m", "merge commit"])
                 .await
@@ -705,11 +1648,692 @@ pub mod test_utils {
 mod tests {
     use std::fs::File;
     use std::io::Write;
+    use std::os::unix::fs::PermissionsExt as _;
 
     use tempfile::TempDir;
 
+    use super::test_utils::{TempRepo, WorktreeExt as _};
     use super::*;
 
+    #[test]
+    fn test_is_ref_path() {
+        let common = Path::new("/repo/.git");
+        let linked = Path::new("/repo/.git/worktrees/wt");
+        for (path, git_dir, want) in [
+            ("/repo/.git/HEAD", common, true),
+            ("/repo/.git/FETCH_HEAD", common, true),
+            ("/repo/.git/packed-refs", common, true),
+            ("/repo/.git/refs/heads/foo/bar", common, true),
+            ("/repo/.git/refs/heads/foo.lock", common, true),
+            ("/repo/.git/refs/heads/limmat-job/test/abc", common, false),
+            ("/repo/.git/refs/heads/limmat", common, true),
+            ("/repo/.git/index", common, false),
+            ("/repo/.git/objects/ab/cdef", common, false),
+            ("/repo/.git/worktrees/wt/HEAD", common, false),
+            ("/repo/.git/worktrees/wt/HEAD", linked, true),
+            ("/repo/.git/worktrees/other/HEAD", linked, false),
+            ("/repo/.git/packed-refs", linked, true),
+            ("/repo/.git/HEAD", linked, false),
+        ] {
+            assert_eq!(
+                is_ref_path(Path::new(path), git_dir, common),
+                want,
+                "{path} with git dir {git_dir:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_watch_refs_quiet_period() {
+        let repo = TempRepo::new().await.unwrap();
+        repo.commit("1").await.unwrap();
+        let quiet_period = Duration::from_secs(2);
+        let mut revs = pin!(repo
+            .watch_refs(OsStr::new("HEAD"), Duration::from_millis(50), quiet_period)
+            .unwrap());
+        assert_eq!(revs.next().await.unwrap().unwrap().len(), 1);
+
+        // The gaps are longer than the debounce, so without the quiet period
+        // each of these would produce an update.
+        let mut last = None;
+        for i in 0..5 {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            last = Some(repo.commit(format!("burst {i}")).await.unwrap());
+        }
+        let burst_end = Instant::now();
+        let revs_after = revs.next().await.unwrap().unwrap();
+        assert!(burst_end.elapsed() >= quiet_period - Duration::from_millis(500));
+        assert_eq!(revs_after.len(), 6);
+        assert!(revs_after.contains(&last.unwrap().hash));
+        assert!(
+            tokio::time::timeout(Duration::from_secs(1), revs.next())
+                .await
+                .is_err(),
+            "burst produced more than one update"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_persistent_worktree() {
+        let repo = TempRepo::new().await.unwrap();
+        repo.commit("1").await.unwrap();
+        let parent = TempDir::new().unwrap();
+        let path = parent.path().join("wt");
+        let ct = CancellationToken::new();
+
+        let dir = WorktreeDir::persistent(path.clone()).unwrap().unwrap();
+        assert!(
+            WorktreeDir::persistent(path.clone()).unwrap().is_none(),
+            "persistent worktree dir locked twice"
+        );
+        let worktree = TempWorktree::new(&ct, &repo, dir, &WorktreeOptions::default())
+            .await
+            .unwrap();
+        fs::write(path.join("marker"), "hello").unwrap();
+        worktree.cleanup().await;
+        assert!(path.join("marker").exists(), "worktree deleted on cleanup");
+
+        // Should get reused, not recreated.
+        let dir = WorktreeDir::persistent(path.clone()).unwrap().unwrap();
+        let worktree = TempWorktree::new(&ct, &repo, dir, &WorktreeOptions::default())
+            .await
+            .unwrap();
+        assert!(path.join("marker").exists(), "worktree not reused");
+        worktree.cleanup().await;
+
+        // If it's not a real worktree it should get replaced.
+        repo.git(["worktree", "remove", "--force"])
+            .await
+            .arg(&path)
+            .execute()
+            .await
+            .unwrap();
+        fs::create_dir(&path).unwrap();
+        fs::write(path.join("junk"), "junk").unwrap();
+        let dir = WorktreeDir::persistent(path.clone()).unwrap().unwrap();
+        let worktree = TempWorktree::new(&ct, &repo, dir, &WorktreeOptions::default())
+            .await
+            .unwrap();
+        assert!(!path.join("junk").exists(), "stale dir not replaced");
+        assert!(is_registered_worktree(&repo, &path).await.unwrap());
+        worktree.cleanup().await;
+    }
+
+    #[tokio::test]
+    async fn test_sparse_checkout() {
+        let repo = TempRepo::new().await.unwrap();
+        for file in ["top", "a/file", "b/file"] {
+            let path = repo.path().join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, file).unwrap();
+        }
+        repo.git(["add", "."]).await.execute().await.unwrap();
+        repo.commit("1").await.unwrap();
+        let parent = TempDir::new().unwrap();
+        let path = parent.path().join("wt");
+        let ct = CancellationToken::new();
+
+        let dir = WorktreeDir::persistent(path.clone()).unwrap().unwrap();
+        let worktree = TempWorktree::new(
+            &ct,
+            &repo,
+            dir,
+            &WorktreeOptions {
+                sparse_checkout: Some(vec!["a".to_string()]),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        assert!(path.join("top").exists());
+        assert!(path.join("a/file").exists());
+        assert!(!path.join("b/file").exists());
+        worktree.cleanup().await;
+
+        // Reusing it without the sparse config should get us everything.
+        let dir = WorktreeDir::persistent(path.clone()).unwrap().unwrap();
+        let worktree = TempWorktree::new(&ct, &repo, dir, &WorktreeOptions::default())
+            .await
+            .unwrap();
+        assert!(path.join("b/file").exists());
+        worktree.cleanup().await;
+    }
+
+    #[tokio::test]
+    async fn test_submodules() {
+        let sub = TempRepo::new().await.unwrap();
+        fs::write(sub.path().join("file1"), "1").unwrap();
+        sub.git(["add", "."]).await.execute().await.unwrap();
+        sub.commit("1").await.unwrap();
+        // Newer Git won't clone submodules from local paths by default. The
+        // clone doesn't see the superproject's config, but it does inherit -c
+        // options, so wrap Git to pass one.
+        let bin_dir = TempDir::new().unwrap();
+        let git = bin_dir.path().join("git");
+        fs::write(
+            &git,
+            "#!/bin/sh\nexec git -c protocol.file.allow=always \"$@\"\n",
+        )
+        .unwrap();
+        fs::set_permissions(&git, fs::Permissions::from_mode(0o755)).unwrap();
+        let repo = TempRepo::with_git_binary(git).await.unwrap();
+        repo.git(["submodule", "add"])
+            .await
+            .arg(sub.path())
+            .arg("sub")
+            .execute()
+            .await
+            .unwrap();
+        repo.commit("add submodule").await.unwrap();
+        let parent = TempDir::new().unwrap();
+        let path = parent.path().join("wt");
+        let dir = WorktreeDir::persistent(path.clone()).unwrap().unwrap();
+        let worktree = TempWorktree::new(
+            &CancellationToken::new(),
+            &repo,
+            dir,
+            &WorktreeOptions {
+                submodules: true,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        assert!(path.join("sub/file1").exists());
+        let sub_worktree = PersistentWorktree {
+            path: path.join("sub"),
+            git_binary: PathBuf::from("git"),
+        };
+        assert!(
+            sub_worktree
+                .git_dir()
+                .await
+                .unwrap()
+                .join("objects/info/alternates")
+                .exists(),
+            "submodule objects not shared with main worktree"
+        );
+
+        // Checking out a commit should update the submodule.
+        fs::write(sub.path().join("file2"), "2").unwrap();
+        sub.git(["add", "."]).await.execute().await.unwrap();
+        sub.commit("2").await.unwrap();
+        let main_sub = PersistentWorktree {
+            path: repo.path().join("sub"),
+            git_binary: PathBuf::from("git"),
+        };
+        main_sub.git(["pull"]).await.execute().await.unwrap();
+        repo.git(["add", "sub"]).await.execute().await.unwrap();
+        let commit = repo.commit("update submodule").await.unwrap();
+        worktree.checkout(&commit.hash, None).await.unwrap();
+        assert!(path.join("sub/file2").exists());
+
+        worktree.cleanup().await;
+    }
+
+    #[tokio::test]
+    async fn test_uses_lfs() {
+        let repo = TempRepo::new().await.unwrap();
+        repo.commit("1").await.unwrap();
+        assert!(!repo.uses_lfs().await.unwrap());
+        fs::write(repo.path().join(".gitattributes"), "*.txt text\n").unwrap();
+        repo.git(["add", "."]).await.execute().await.unwrap();
+        repo.commit("2").await.unwrap();
+        assert!(!repo.uses_lfs().await.unwrap());
+        fs::create_dir(repo.path().join("assets")).unwrap();
+        fs::write(
+            repo.path().join("assets/.gitattributes"),
+            "*.bin filter=lfs diff=lfs merge=lfs -text\n",
+        )
+        .unwrap();
+        repo.git(["add", "."]).await.execute().await.unwrap();
+        repo.commit("3").await.unwrap();
+        assert!(repo.uses_lfs().await.unwrap());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Git LFS"]
+    async fn test_lfs() {
+        // Stop the checkout from smudging the files itself, so that it's
+        // really the pull that gets the content.
+        let bin_dir = TempDir::new().unwrap();
+        let git = bin_dir.path().join("git");
+        fs::write(&git, "#!/bin/sh\nGIT_LFS_SKIP_SMUDGE=1 exec git \"$@\"\n").unwrap();
+        fs::set_permissions(&git, fs::Permissions::from_mode(0o755)).unwrap();
+        let repo = TempRepo::with_git_binary(git).await.unwrap();
+        repo.git(["lfs", "install", "--local"])
+            .await
+            .execute()
+            .await
+            .expect("Git LFS not installed");
+        // LFS always wants a remote, even if it doesn't need to download anything.
+        repo.git(["remote", "add", "origin"])
+            .await
+            .arg(repo.path())
+            .execute()
+            .await
+            .unwrap();
+        fs::write(
+            repo.path().join(".gitattributes"),
+            "*.bin filter=lfs diff=lfs merge=lfs -text\n",
+        )
+        .unwrap();
+        fs::write(repo.path().join("big.bin"), "real content").unwrap();
+        repo.git(["add", "."]).await.execute().await.unwrap();
+        repo.commit("add LFS file").await.unwrap();
+
+        let parent = TempDir::new().unwrap();
+        let path = parent.path().join("wt");
+        let dir = WorktreeDir::persistent(path.clone()).unwrap().unwrap();
+        let worktree = TempWorktree::new(
+            &CancellationToken::new(),
+            &repo,
+            dir,
+            &WorktreeOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            fs::read_to_string(path.join("big.bin")).unwrap(),
+            "real content"
+        );
+        assert!(
+            !worktree.git_dir().await.unwrap().join("lfs").exists(),
+            "worktree has its own LFS object store"
+        );
+        assert!(repo
+            .path()
+            .join(repo.git_common_dir().await.unwrap())
+            .join("lfs/objects")
+            .is_dir());
+
+        worktree.cleanup().await;
+    }
+
+    #[tokio::test]
+    async fn test_worktree_teardown() {
+        let repo = TempRepo::new().await.unwrap();
+        repo.commit("1").await.unwrap();
+        let ct = CancellationToken::new();
+        let out_dir = TempDir::new().unwrap();
+        let marker = out_dir.path().join("marker");
+        let teardown = |cmd: &str| WorktreeOptions {
+            teardown: Some(vec!["bash".into(), "-c".into(), cmd.into()]),
+            ..Default::default()
+        };
+
+        let worktree = TempWorktree::new(
+            &ct,
+            &repo,
+            TempDir::new().unwrap(),
+            &teardown(&format!("ls -a > {:?}", marker)),
+        )
+        .await
+        .unwrap();
+        let path = worktree.path().to_owned();
+        worktree.cleanup().await;
+        // It should have run in the worktree, before it was removed.
+        assert!(fs::read_to_string(&marker).unwrap().contains(".git"));
+        assert!(!path.exists());
+
+        // Failure shouldn't stop the worktree from being removed.
+        let worktree = TempWorktree::new(&ct, &repo, TempDir::new().unwrap(), &teardown("false"))
+            .await
+            .unwrap();
+        let path = worktree.path().to_owned();
+        worktree.cleanup().await;
+        assert!(!path.exists());
+
+        // Persistent worktrees are kept, so they aren't torn down.
+        fs::remove_file(&marker).unwrap();
+        let dir = WorktreeDir::persistent(out_dir.path().join("wt"))
+            .unwrap()
+            .unwrap();
+        let worktree = TempWorktree::new(&ct, &repo, dir, &teardown(&format!("touch {marker:?}")))
+            .await
+            .unwrap();
+        worktree.cleanup().await;
+        assert!(!marker.exists());
+    }
+
+    #[tokio::test]
+    async fn test_reflink_from() {
+        let repo = TempRepo::new().await.unwrap();
+        fs::write(repo.path().join("foo"), "foo").unwrap();
+        repo.git(["add", "."]).await.execute().await.unwrap();
+        repo.commit("1").await.unwrap();
+        let ct = CancellationToken::new();
+        let template = TempWorktree::new(
+            &ct,
+            &repo,
+            TempDir::new().unwrap(),
+            &WorktreeOptions::default(),
+        )
+        .await
+        .unwrap();
+        fs::write(repo.path().join("bar"), "bar").unwrap();
+        repo.git(["add", "."]).await.execute().await.unwrap();
+        repo.commit("2").await.unwrap();
+
+        // Whether or not the filesystem supports reflinks, we should get a
+        // clean worktree at the new HEAD.
+        let options = WorktreeOptions {
+            reflink_from: Some(template.path().to_owned()),
+            ..Default::default()
+        };
+        let worktree = TempWorktree::new(&ct, &repo, TempDir::new().unwrap(), &options)
+            .await
+            .unwrap();
+        assert!(worktree.path().join("bar").exists());
+        let status = worktree
+            .git(["status", "--porcelain"])
+            .await
+            .execute()
+            .await
+            .unwrap();
+        assert_eq!(String::from_utf8(status.stdout).unwrap(), "");
+        worktree.cleanup().await;
+        template.cleanup().await;
+    }
+
+    #[tokio::test]
+    async fn test_checkout_size() {
+        let repo = TempRepo::new().await.unwrap();
+        fs::create_dir(repo.path().join("dir")).unwrap();
+        fs::write(repo.path().join("foo"), "12345").unwrap();
+        fs::write(repo.path().join("dir/bar"), "123").unwrap();
+        repo.git(["add", "."]).await.execute().await.unwrap();
+        repo.commit("1").await.unwrap();
+        assert_eq!(repo.checkout_size().await.unwrap(), 8);
+    }
+
+    #[tokio::test]
+    async fn test_changed_files() {
+        let repo = TempRepo::new().await.unwrap();
+        fs::create_dir(repo.path().join("dir")).unwrap();
+        fs::write(repo.path().join("foo"), "1").unwrap();
+        fs::write(repo.path().join("dir/bar"), "1").unwrap();
+        repo.git(["add", "."]).await.execute().await.unwrap();
+        let root = repo.commit("1").await.unwrap();
+        assert_eq!(
+            changed_files(&repo, &root.hash, root.parents.first())
+                .await
+                .unwrap(),
+            vec![PathBuf::from("dir/bar"), PathBuf::from("foo")]
+        );
+
+        fs::write(repo.path().join("foo"), "2").unwrap();
+        fs::remove_file(repo.path().join("dir/bar")).unwrap();
+        fs::write(repo.path().join("baz"), "1").unwrap();
+        repo.git(["add", "-A"]).await.execute().await.unwrap();
+        let commit = repo.commit("2").await.unwrap();
+        assert_eq!(
+            changed_files(&repo, &commit.hash, commit.parents.first())
+                .await
+                .unwrap(),
+            vec![
+                PathBuf::from("baz"),
+                PathBuf::from("dir/bar"),
+                PathBuf::from("foo")
+            ]
+        );
+
+        let empty = repo.commit("3").await.unwrap();
+        assert_eq!(
+            changed_files(&repo, &empty.hash, empty.parents.first())
+                .await
+                .unwrap(),
+            Vec::<PathBuf>::new()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_checkout_branch() {
+        let repo = TempRepo::new().await.unwrap();
+        let commit = repo.commit("1").await.unwrap();
+        let ct = CancellationToken::new();
+        let worktree = TempWorktree::new(
+            &ct,
+            &repo,
+            TempDir::new().unwrap(),
+            &WorktreeOptions::default(),
+        )
+        .await
+        .unwrap();
+        // The user's own branches shouldn't get in the way.
+        repo.git(["branch", "limmat"])
+            .await
+            .execute()
+            .await
+            .unwrap();
+        let branch = "limmat-job/foo/bar";
+        worktree.checkout(&commit.hash, Some(branch)).await.unwrap();
+        let head = worktree
+            .git(["symbolic-ref", "HEAD"])
+            .await
+            .execute()
+            .await
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(head.stdout).unwrap().trim(),
+            format!("refs/heads/{branch}")
+        );
+        // Checking out again should just reset the branch.
+        worktree.checkout(&commit.hash, Some(branch)).await.unwrap();
+
+        worktree.delete_branch(branch).await.unwrap();
+        assert!(repo.rev_parse(branch).await.unwrap().is_none());
+        worktree.cleanup().await;
+    }
+
+    #[tokio::test]
+    async fn test_job_branch() {
+        let repo = TempRepo::new().await.unwrap();
+        let commit = repo.commit("1").await.unwrap();
+        let ct = CancellationToken::new();
+        let worktree = TempWorktree::new(
+            &ct,
+            &repo,
+            TempDir::new().unwrap(),
+            &WorktreeOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        // Dropping it without deleting it should still clean up.
+        let branch = JobBranch::new(&worktree, "limmat-job/foo/dropped".into());
+        worktree
+            .checkout(&commit.hash, Some(branch.name()))
+            .await
+            .unwrap();
+        drop(branch);
+        assert!(repo
+            .rev_parse("limmat-job/foo/dropped")
+            .await
+            .unwrap()
+            .is_none());
+
+        // A crashed instance left one branch behind, another is in use.
+        repo.git(["branch", "limmat-job/foo/stale"])
+            .await
+            .execute()
+            .await
+            .unwrap();
+        worktree
+            .checkout(&commit.hash, Some("limmat-job/foo/in-use"))
+            .await
+            .unwrap();
+        assert_eq!(prune_job_branches(&repo).await.unwrap(), 1);
+        assert!(repo
+            .rev_parse("limmat-job/foo/stale")
+            .await
+            .unwrap()
+            .is_none());
+        assert!(repo
+            .rev_parse("limmat-job/foo/in-use")
+            .await
+            .unwrap()
+            .is_some());
+        worktree.cleanup().await;
+    }
+
+    #[tokio::test]
+    async fn test_shallow_clone() {
+        let origin = TempRepo::new().await.unwrap();
+        let commit1 = origin.commit("1").await.unwrap();
+        origin.commit("2").await.unwrap();
+        let commit3 = origin.commit("3").await.unwrap();
+        let clone_dir = TempDir::new().unwrap();
+        let mut origin_url = OsString::from("file://");
+        origin_url.push(origin.path());
+        origin
+            .git(["clone", "--depth=1"])
+            .await
+            .arg(origin_url)
+            .arg(clone_dir.path())
+            .execute()
+            .await
+            .unwrap();
+        let clone = PersistentWorktree {
+            path: clone_dir.path().to_path_buf(),
+            git_binary: PathBuf::from("git"),
+        };
+
+        assert!(clone.is_shallow().await.unwrap());
+        assert_eq!(
+            clone.rev_list("HEAD").await.unwrap(),
+            vec![commit3.hash.clone()]
+        );
+        let range = format!("{}..HEAD", commit1.hash);
+        let err = clone.rev_list(&range).await.unwrap_err();
+        assert!(
+            format!("{err:#}").contains("shallow clone"),
+            "unhelpful error: {err:#}"
+        );
+
+        clone.unshallow().await.unwrap();
+        assert!(!clone.is_shallow().await.unwrap());
+        assert_eq!(clone.rev_list(&range).await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_rev_list() {
+        let repo = TempRepo::new().await.unwrap();
+        let commit1 = repo.commit("1").await.unwrap();
+        repo.git(["tag", "-a", "-m", "tag", "mytag"])
+            .await
+            .execute()
+            .await
+            .unwrap();
+        let commit2 = repo.commit("2").await.unwrap();
+        let commit3 = repo.commit("3").await.unwrap();
+        let mut got = repo.rev_list("mytag..HEAD").await.unwrap();
+        got.sort_by_key(|h| h.to_string());
+        let mut want = vec![commit2.hash.clone(), commit3.hash.clone()];
+        want.sort_by_key(|h| h.to_string());
+        assert_eq!(got, want);
+        assert_eq!(
+            repo.rev_list(&*commit1.hash).await.unwrap(),
+            vec![commit1.hash.clone()]
+        );
+        // This one isn't handled in-process.
+        assert_eq!(
+            repo.rev_list(format!("{}...{}", commit3.hash, commit2.hash))
+                .await
+                .unwrap(),
+            vec![commit3.hash.clone()]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_upstream_ref() {
+        let repo = TempRepo::new().await.unwrap();
+        repo.commit("1").await.unwrap();
+        assert_eq!(repo.upstream_ref().await.unwrap(), None);
+        for args in [
+            ["branch", "upstream"],
+            ["branch", "--set-upstream-to=upstream"],
+        ] {
+            repo.git(args).await.execute().await.unwrap();
+        }
+        assert_eq!(
+            repo.upstream_ref().await.unwrap(),
+            Some("refs/heads/upstream".to_owned())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stack_branches() {
+        let repo = TempRepo::new().await.unwrap();
+        repo.commit("base").await.unwrap();
+        let checkout = |branch: &'static str| {
+            let repo = &repo;
+            async move {
+                repo.git(["checkout", "-q", "-b", branch])
+                    .await
+                    .execute()
+                    .await
+                    .unwrap();
+            }
+        };
+        checkout("base").await;
+        checkout("unrelated").await;
+        repo.commit("unrelated").await.unwrap();
+        repo.git(["checkout", "-q", "base"])
+            .await
+            .execute()
+            .await
+            .unwrap();
+        let mut tips = Vec::new();
+        for branch in ["a", "b", "c"] {
+            checkout(branch).await;
+            tips.push(repo.commit(branch).await.unwrap().hash);
+        }
+        repo.git(["checkout", "-q", "b"])
+            .await
+            .execute()
+            .await
+            .unwrap();
+
+        let mut got = repo.stack_branches(OsStr::new("base"), "*").await.unwrap();
+        got.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let want: Vec<_> = ["a", "b", "c"]
+            .into_iter()
+            .map(String::from)
+            .zip(tips)
+            .collect();
+        assert_eq!(got, want);
+        assert_eq!(
+            repo.stack_branches(OsStr::new("base"), "c").await.unwrap(),
+            want[2..]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rev_parse_all() {
+        let repo = TempRepo::new().await.unwrap();
+        let commit1 = repo.commit("1").await.unwrap();
+        let commit2 = repo.commit("2").await.unwrap();
+        let commits = repo
+            .rev_parse_all([&commit2.hash, &commit1.hash])
+            .await
+            .unwrap();
+        assert_eq!(
+            commits.iter().map(|c| &c.hash).collect::<Vec<_>>(),
+            vec![&commit2.hash, &commit1.hash]
+        );
+        assert_eq!(commits[0].tree, commit2.tree);
+        assert!(repo
+            .rev_parse_all(Vec::<&str>::new())
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(repo
+            .rev_parse_all([commit1.hash.to_string(), "nonexistent".to_string()])
+            .await
+            .is_err());
+    }
+
     #[tokio::test]
     async fn test_new_gitdir_notgit() {
         let tmp_dir = TempDir::new().expect("couldn't make tempdir");
@@ -0,0 +1,115 @@
+// Finds the GPUs on the machine, so that tests can reserve them without the
+// user having to list them as resource tokens. NVIDIA GPUs are found with
+// nvidia-smi, anything else via the DRM render nodes.
+
+use std::{
+    fmt::{self, Display},
+    fs, io,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::{anyhow, Context as _};
+use log::debug;
+
+use crate::process::OutputExt as _;
+
+const DRI_DIR: &str = "/dev/dri";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Gpu {
+    // Index as used in CUDA_VISIBLE_DEVICES.
+    Nvidia(u32),
+    // Render node.
+    Dri(PathBuf),
+}
+
+impl Display for Gpu {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Nvidia(index) => write!(f, "{index}"),
+            Self::Dri(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+pub fn detect() -> anyhow::Result<Vec<Gpu>> {
+    let output = match Command::new("nvidia-smi")
+        .args(["--query-gpu=index", "--format=csv,noheader"])
+        .output()
+    {
+        Ok(output) => output,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return dri_gpus(Path::new(DRI_DIR)),
+        Err(e) => return Err(e).context("running nvidia-smi"),
+    };
+    // It's often installed without a working driver, like on machines that
+    // only sometimes have an NVIDIA card plugged in.
+    if let Err(e) = output.ok() {
+        debug!("nvidia-smi failed, looking for render nodes instead: {e:#}");
+        return dri_gpus(Path::new(DRI_DIR));
+    }
+    parse_nvidia_smi(&String::from_utf8_lossy(&output.stdout))
+}
+
+fn parse_nvidia_smi(output: &str) -> anyhow::Result<Vec<Gpu>> {
+    output
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            line.trim()
+                .parse()
+                .map(Gpu::Nvidia)
+                .map_err(|_| anyhow!("unexpected nvidia-smi output {line:?}"))
+        })
+        .collect()
+}
+
+fn dri_gpus(dir: &Path) -> anyhow::Result<Vec<Gpu>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("reading {}", dir.display())),
+    };
+    let mut paths = Vec::new();
+    for entry in entries {
+        let entry = entry.with_context(|| format!("reading {}", dir.display()))?;
+        if entry.file_name().to_string_lossy().starts_with("renderD") {
+            paths.push(entry.path());
+        }
+    }
+    paths.sort();
+    Ok(paths.into_iter().map(Gpu::Dri).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_nvidia_smi() {
+        assert_eq!(
+            parse_nvidia_smi("0\n1\n").unwrap(),
+            vec![Gpu::Nvidia(0), Gpu::Nvidia(1)]
+        );
+        assert_eq!(parse_nvidia_smi("").unwrap(), vec![]);
+        assert!(parse_nvidia_smi("No devices were found\n").is_err());
+    }
+
+    #[test]
+    fn test_dri_gpus() {
+        let dir = TempDir::new().unwrap();
+        assert_eq!(dri_gpus(&dir.path().join("nonexistent")).unwrap(), vec![]);
+        for name in ["card0", "renderD129", "renderD128"] {
+            fs::write(dir.path().join(name), "").unwrap();
+        }
+        assert_eq!(
+            dri_gpus(dir.path()).unwrap(),
+            vec![
+                Gpu::Dri(dir.path().join("renderD128")),
+                Gpu::Dri(dir.path().join("renderD129"))
+            ]
+        );
+    }
+}
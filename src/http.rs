@@ -17,7 +17,7 @@ use tokio::{net::TcpListener, select, sync::watch};
 use tokio_util::sync::CancellationToken;
 use tower_http::services::ServeDir;
 
-use crate::text::RenderHtmlPre;
+use crate::{text::RenderHtmlPre, ui::Frame};
 
 async fn handle_404() -> impl IntoResponse {
     (StatusCode::NOT_FOUND, "File not found")
@@ -81,6 +81,8 @@ impl Ui {
 pub struct UiState {
     // This holds the pre-rendered log & test result buffer with links etc.
     log_html_pre: watch::Sender<String>,
+    // The same thing again for terminals attached via the control socket.
+    frame: watch::Sender<Option<Arc<Frame>>>,
     title: String,
 }
 
@@ -88,6 +90,7 @@ impl UiState {
     fn new(title: String) -> Self {
         Self {
             log_html_pre: watch::Sender::new("[starting up...]".into()),
+            frame: watch::Sender::new(None),
             title,
         }
     }
@@ -95,6 +98,14 @@ impl UiState {
     pub fn set_log_buf(&self, render: RenderHtmlPre) {
         self.log_html_pre.send_replace(render.to_string());
     }
+
+    pub fn set_frame(&self, frame: Frame) {
+        self.frame.send_replace(Some(Arc::new(frame)));
+    }
+
+    pub fn subscribe_frames(&self) -> watch::Receiver<Option<Arc<Frame>>> {
+        self.frame.subscribe()
+    }
 }
 
 // Handles request to create a websocket.
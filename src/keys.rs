@@ -0,0 +1,117 @@
+// Keyboard shortcuts for the watch command. Keys are read as soon as they're
+// pressed instead of a line at a time, and aren't echoed over the status view.
+
+use std::{
+    io::{self, IsTerminal as _, Read as _},
+    iter, thread,
+};
+
+use anyhow::Context as _;
+use nix::{
+    sys::termios::{self, LocalFlags, SetArg, Termios},
+    unistd::{getpgrp, tcgetpgrp},
+};
+use tokio::sync::mpsc;
+
+use crate::{
+    text::{Class, Line, Span, Text},
+    util::ResultExt as _,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    RerunFailed,
+    ToggleHelp,
+}
+
+// Each key, what it does and how that's described in the help.
+const BINDINGS: &[(u8, Action, &str)] = &[
+    (b'r', Action::RerunFailed, "rerun the tests that failed"),
+    (b'?', Action::ToggleHelp, "show or hide this help"),
+];
+
+fn action(key: u8) -> Option<Action> {
+    BINDINGS
+        .iter()
+        .find(|(k, _, _)| *k == key)
+        .map(|(_, action, _)| *action)
+}
+
+pub fn help() -> Text<'static> {
+    let heading = Line::from(Span::new("Keys:").with_class(Class::TestName));
+    let bindings = BINDINGS
+        .iter()
+        .map(|(key, _, description)| Line::from(format!("  {}  {description}", *key as char)));
+    Text::from_iter(iter::once(heading).chain(bindings))
+}
+
+// The terminal is put back how it was when this is dropped.
+pub struct Keys {
+    original: Termios,
+    rx: mpsc::UnboundedReceiver<u8>,
+}
+
+impl Keys {
+    // Returns None if stdin isn't a terminal, or we're in the background (like
+    // "limmat watch &"), where touching the terminal would get us stopped
+    // with SIGTTOU or SIGTTIN.
+    pub fn new() -> anyhow::Result<Option<Self>> {
+        let stdin = io::stdin();
+        if !stdin.is_terminal() || tcgetpgrp(&stdin) != Ok(getpgrp()) {
+            return Ok(None);
+        }
+        let original = termios::tcgetattr(&stdin).context("getting terminal attributes")?;
+        let mut unbuffered = original.clone();
+        unbuffered
+            .local_flags
+            .remove(LocalFlags::ICANON | LocalFlags::ECHO);
+        termios::tcsetattr(&stdin, SetArg::TCSANOW, &unbuffered)
+            .context("setting terminal attributes")?;
+        // Tokio's stdin would hold up shutdown while it's blocked reading, a
+        // plain thread just gets killed when we exit.
+        let (tx, rx) = mpsc::unbounded_channel();
+        thread::spawn(move || {
+            for byte in io::stdin().lock().bytes() {
+                let Ok(byte) = byte else {
+                    return;
+                };
+                if tx.send(byte).is_err() {
+                    return;
+                }
+            }
+        });
+        Ok(Some(Self { original, rx }))
+    }
+
+    // Returns None once stdin is closed. Keys that don't do anything are
+    // ignored.
+    pub async fn next(&mut self) -> Option<Action> {
+        loop {
+            if let Some(action) = action(self.rx.recv().await?) {
+                return Some(action);
+            }
+        }
+    }
+}
+
+impl Drop for Keys {
+    fn drop(&mut self) {
+        termios::tcsetattr(io::stdin(), SetArg::TCSANOW, &self.original)
+            .context("restoring terminal attributes")
+            .or_log_error("cleaning up keyboard input");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_action() {
+        assert_eq!(action(b'r'), Some(Action::RerunFailed));
+        assert_eq!(action(b'?'), Some(Action::ToggleHelp));
+        assert_eq!(action(b'x'), None);
+        // Every binding gets a line, plus the heading.
+        assert_eq!(help().into_lines().count(), BINDINGS.len() + 1);
+    }
+}
@@ -0,0 +1,388 @@
+// Lock files, for filesystems where flock doesn't work reliably, i.e. NFS.
+// The lock for a file lives in a directory next to it, holding a "writer" file
+// for the exclusive holder and a "reader.*" file for each shared holder.
+// Creating a file with O_EXCL is atomic even on NFS. Each holder creates its
+// file first and then checks for conflicting ones, so two conflicting holders
+// can't both think they got the lock.
+//
+// Each file records the host and PID of its holder. If the holder is on this
+// host and has died, or it's on another host and we've watched it not touch the
+// file for a while, it's considered stale and gets removed.
+
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Write as _},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        LazyLock,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::Context as _;
+use log::{debug, warn};
+use nix::{errno::Errno, sys::signal::kill, unistd::Pid};
+use parking_lot::Mutex;
+use tokio::{task::JoinHandle, time::sleep};
+
+use crate::util::{hostname, IoResultExt as _, ResultExt as _};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+const STALE_AFTER: Duration = Duration::from_secs(60);
+
+// Distinguishes the reader files of a single process.
+static NEXT_READER: AtomicU64 = AtomicU64::new(0);
+
+static HEARTBEATS: LazyLock<Mutex<Heartbeats>> = LazyLock::new(Default::default);
+
+// The last heartbeat we saw in each lock file held by another host, and when
+// we first saw it. The other host's clock can disagree with ours, so the
+// heartbeat itself can't be compared with our clock, only with itself.
+#[derive(Default)]
+struct Heartbeats(HashMap<PathBuf, (SystemTime, Instant)>);
+
+impl Heartbeats {
+    // Records the file's heartbeat as of now. Returns true if it's the same one
+    // we saw at least STALE_AFTER ago.
+    fn stopped(&mut self, path: &Path, heartbeat: SystemTime, now: Instant) -> bool {
+        match self.0.get(path) {
+            Some(&(seen, since)) if seen == heartbeat => now.duration_since(since) > STALE_AFTER,
+            _ => {
+                self.0.insert(path.to_owned(), (heartbeat, now));
+                false
+            }
+        }
+    }
+
+    fn forget(&mut self, path: &Path) {
+        self.0.remove(path);
+    }
+}
+
+// The lock is held until this is dropped.
+#[derive(Debug)]
+pub struct LockfileGuard {
+    path: PathBuf,
+    heartbeat: JoinHandle<()>,
+}
+
+impl Drop for LockfileGuard {
+    fn drop(&mut self) {
+        self.heartbeat.abort();
+        fs::remove_file(&self.path)
+            .with_context(|| format!("removing lock file {}", self.path.display()))
+            .or_log_error("releasing lock");
+    }
+}
+
+fn lock_dir(locked: &Path) -> anyhow::Result<PathBuf> {
+    let mut name = locked.file_name().expect("no file name").to_owned();
+    name.push(".lock");
+    let dir = locked.with_file_name(name);
+    fs::create_dir_all(&dir).with_context(|| format!("creating {}", dir.display()))?;
+    Ok(dir)
+}
+
+// Returns None if the file already exists.
+fn try_create(path: &Path) -> anyhow::Result<Option<LockfileGuard>> {
+    let mut file = match OpenOptions::new().write(true).create_new(true).open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("creating {}", path.display())),
+    };
+    writeln!(file, "{} {}", hostname(), std::process::id())
+        .with_context(|| format!("writing {}", path.display()))?;
+    let heartbeat = tokio::spawn({
+        let path = path.to_owned();
+        async move {
+            loop {
+                sleep(HEARTBEAT_INTERVAL).await;
+                File::options()
+                    .write(true)
+                    .open(&path)
+                    .and_then(|file| file.set_modified(SystemTime::now()))
+                    .with_context(|| format!("touching lock file {}", path.display()))
+                    .or_log_error("refreshing lock");
+            }
+        }
+    });
+    Ok(Some(LockfileGuard {
+        path: path.to_owned(),
+        heartbeat,
+    }))
+}
+
+fn is_stale(path: &Path) -> anyhow::Result<bool> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        // Already released.
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            HEARTBEATS.lock().forget(path);
+            return Ok(false);
+        }
+        Err(e) => return Err(e).with_context(|| format!("reading {}", path.display())),
+    };
+    let pid = content
+        .split_once(' ')
+        .filter(|(host, _)| *host == hostname())
+        .and_then(|(_, pid)| pid.trim().parse().ok());
+    if let Some(pid) = pid {
+        return Ok(kill(Pid::from_raw(pid), None) == Err(Errno::ESRCH));
+    }
+    // Another host, or we can't tell who holds it (maybe they haven't finished
+    // writing it). Either way we can only go by whether it's still touched.
+    let modified = match fs::metadata(path).and_then(|m| m.modified()) {
+        Ok(modified) => modified,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            HEARTBEATS.lock().forget(path);
+            return Ok(false);
+        }
+        Err(e) => return Err(e).with_context(|| format!("checking age of {}", path.display())),
+    };
+    Ok(HEARTBEATS.lock().stopped(path, modified, Instant::now()))
+}
+
+// Returns true if the file exists and isn't stale. Stale files are removed,
+// if someone else is already removing one this returns true too.
+fn is_held(path: &Path) -> anyhow::Result<bool> {
+    if !path.exists() {
+        return Ok(false);
+    }
+    if !is_stale(path)? {
+        return Ok(true);
+    }
+    Ok(!remove_stale(path)?)
+}
+
+// Remove a file that was found to be stale, returns whether it did. By now
+// someone else might have removed it too and taken the lock with a new file,
+// which mustn't be removed. So removals are serialized with a "breaking" file,
+// and whoever holds that checks again before removing anything.
+fn remove_stale(path: &Path) -> anyhow::Result<bool> {
+    let mut name = OsString::from("breaking.");
+    name.push(path.file_name().expect("no file name"));
+    let breaking = path.with_file_name(name);
+    let Some(_guard) = try_create(&breaking)? else {
+        // It's only held for a moment, so if its holder died it's fair to
+        // assume they won't be back.
+        if is_stale(&breaking)? {
+            warn!("removing stale lock file {}", breaking.display());
+            fs::remove_file(&breaking)
+                .ignore(ErrorKind::NotFound)
+                .with_context(|| format!("removing {}", breaking.display()))?;
+            HEARTBEATS.lock().forget(&breaking);
+        }
+        return Ok(false);
+    };
+    if !is_stale(path)? {
+        return Ok(false);
+    }
+    warn!("removing stale lock file {}", path.display());
+    fs::remove_file(path)
+        .ignore(ErrorKind::NotFound)
+        .with_context(|| format!("removing {}", path.display()))?;
+    HEARTBEATS.lock().forget(path);
+    Ok(true)
+}
+
+fn readers(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut readers = Vec::new();
+    for entry in fs::read_dir(dir).with_context(|| format!("reading {}", dir.display()))? {
+        let entry = entry.with_context(|| format!("reading {}", dir.display()))?;
+        if entry.file_name().to_string_lossy().starts_with("reader.") {
+            readers.push(entry.path());
+        }
+    }
+    Ok(readers)
+}
+
+// A reader file that no one else has. A file with the name we'd pick can
+// already exist if a dead process on this host had our PID, in that case it's
+// skipped and eventually removed as stale by a writer.
+fn create_reader(dir: &Path) -> anyhow::Result<LockfileGuard> {
+    loop {
+        let name = format!(
+            "reader.{}.{}.{}",
+            hostname(),
+            std::process::id(),
+            NEXT_READER.fetch_add(1, Ordering::Relaxed)
+        );
+        if let Some(guard) = try_create(&dir.join(name))? {
+            return Ok(guard);
+        }
+    }
+}
+
+// Blocks while anyone holds the exclusive lock.
+pub async fn lock_shared(locked: &Path) -> anyhow::Result<LockfileGuard> {
+    let dir = lock_dir(locked)?;
+    loop {
+        let guard = create_reader(&dir)?;
+        if !is_held(&dir.join("writer"))? {
+            return Ok(guard);
+        }
+        // Back off so the writer can see we're not reading.
+        drop(guard);
+        sleep(POLL_INTERVAL).await;
+    }
+}
+
+// Blocks while anyone else holds the lock, shared or exclusive.
+pub async fn lock_exclusive(locked: &Path) -> anyhow::Result<LockfileGuard> {
+    let dir = lock_dir(locked)?;
+    let writer = dir.join("writer");
+    let guard = loop {
+        if let Some(guard) = try_create(&writer)? {
+            break guard;
+        }
+        if !is_held(&writer)? {
+            continue;
+        }
+        sleep(POLL_INTERVAL).await;
+    };
+    // New readers will back off now, wait for the existing ones to finish.
+    loop {
+        let mut held = false;
+        for reader in readers(&dir)? {
+            held |= is_held(&reader)?;
+        }
+        if !held {
+            debug!("got exclusive lock on {}", locked.display());
+            return Ok(guard);
+        }
+        sleep(POLL_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use futures::FutureExt as _;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn should_exclude() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("result.json");
+
+        let reader1 = lock_shared(&path).await.unwrap();
+        let reader2 = lock_shared(&path).now_or_never().unwrap().unwrap();
+        let mut writer = Box::pin(lock_exclusive(&path));
+        assert!(futures::poll!(&mut writer).is_pending());
+        drop(reader1);
+        assert!(futures::poll!(&mut writer).is_pending());
+        drop(reader2);
+        let writer = writer.await.unwrap();
+
+        let mut reader = Box::pin(lock_shared(&path));
+        assert!(futures::poll!(&mut reader).is_pending());
+        assert!(futures::poll!(Box::pin(lock_exclusive(&path))).is_pending());
+        drop(writer);
+        reader.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn should_remove_stale() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("result.json");
+        let mut child = Command::new("true").spawn().unwrap();
+        let dead_pid = child.id();
+        child.wait().unwrap();
+        let lock_dir = lock_dir(&path).unwrap();
+        fs::write(
+            lock_dir.join("writer"),
+            format!("{} {dead_pid}\n", hostname()),
+        )
+        .unwrap();
+        // Another host, touched recently.
+        fs::write(lock_dir.join("reader.elsewhere"), "elsewhere 1\n").unwrap();
+
+        lock_shared(&path).now_or_never().unwrap().unwrap();
+        assert!(futures::poll!(Box::pin(lock_exclusive(&path))).is_pending());
+        fs::remove_file(lock_dir.join("reader.elsewhere")).unwrap();
+        lock_exclusive(&path).now_or_never().unwrap().unwrap();
+    }
+
+    #[test]
+    fn should_remove_stale_from_other_host() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("result.json");
+        let reader = lock_dir(&path).unwrap().join("reader.elsewhere");
+        fs::write(&reader, "elsewhere 1\n").unwrap();
+        // Going by our clock it hasn't been touched for ages, but the other
+        // host's clock might be behind.
+        File::options()
+            .write(true)
+            .open(&reader)
+            .unwrap()
+            .set_modified(SystemTime::now() - 10 * STALE_AFTER)
+            .unwrap();
+        assert!(!is_stale(&reader).unwrap());
+
+        let modified = fs::metadata(&reader).unwrap().modified().unwrap();
+        let mut heartbeats = Heartbeats::default();
+        let start = Instant::now();
+        assert!(!heartbeats.stopped(&reader, modified, start));
+        assert!(!heartbeats.stopped(&reader, modified, start + STALE_AFTER / 2));
+        // It was touched, so it starts again.
+        let touched = modified + HEARTBEAT_INTERVAL;
+        assert!(!heartbeats.stopped(&reader, touched, start + STALE_AFTER));
+        assert!(!heartbeats.stopped(&reader, touched, start + STALE_AFTER * 2));
+        assert!(heartbeats.stopped(&reader, touched, start + STALE_AFTER * 3));
+    }
+
+    #[tokio::test]
+    async fn should_skip_leftover_reader_file() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("result.json");
+        let lock_dir = lock_dir(&path).unwrap();
+        // Left behind by a process that died and had the same PID as us.
+        let next = NEXT_READER.load(Ordering::Relaxed);
+        for i in next..next + 3 {
+            fs::write(
+                lock_dir.join(format!("reader.{}.{}.{i}", hostname(), std::process::id())),
+                "",
+            )
+            .unwrap();
+        }
+
+        lock_shared(&path).now_or_never().unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn should_not_remove_fresh_lock() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("result.json");
+        let mut child = Command::new("true").spawn().unwrap();
+        let dead_pid = child.id();
+        child.wait().unwrap();
+        let writer = lock_dir(&path).unwrap().join("writer");
+
+        // Someone found a stale lock, but it was replaced before they got
+        // round to removing it.
+        let guard = lock_exclusive(&path).await.unwrap();
+        assert!(!remove_stale(&writer).unwrap());
+        assert!(futures::poll!(Box::pin(lock_exclusive(&path))).is_pending());
+        drop(guard);
+
+        // Someone else is removing a stale lock.
+        fs::write(&writer, format!("{} {dead_pid}\n", hostname())).unwrap();
+        let breaking = writer.with_file_name("breaking.writer");
+        fs::write(
+            &breaking,
+            format!("{} {}\n", hostname(), std::process::id()),
+        )
+        .unwrap();
+        assert!(futures::poll!(Box::pin(lock_exclusive(&path))).is_pending());
+        assert!(writer.exists());
+        fs::write(&breaking, format!("{} {dead_pid}\n", hostname())).unwrap();
+        lock_exclusive(&path).await.unwrap();
+    }
+}
@@ -1,51 +1,73 @@
 use anyhow::{anyhow, bail, Context};
-use clap::{arg, Parser as _, Subcommand, ValueEnum};
-use config::{Config, ParsedConfig};
+use clap::{Parser as _, Subcommand, ValueEnum};
+use colored::{Color, Colorize as _};
+use config::{Config, ParsedConfig, Rerun, WorktreeProvisioning};
+use ctl::CtlServer;
 use dag::{Dag, GraphNode as _};
-use database::{Database, DatabaseEntry, DatabaseOutput, LookupResult};
+use database::{ConfigSnapshot, Database, DatabaseEntry, DatabaseOutput};
 use flexi_logger::{detailed_format, Cleanup, Criterion, FileSpec, Logger, Naming};
-use futures::future::join_all;
+use futures::future::{join_all, BoxFuture};
+use futures::FutureExt as _;
 use futures::StreamExt;
-use git::{Commit, PersistentWorktree, TempWorktree};
+use git::{Commit, CommitHash, PersistentWorktree, TempWorktree, WorktreeDir, WorktreeOptions};
 use http::Ui;
-use log::{debug, error, warn};
-use nix::sys::utsname::uname;
+use keys::Keys;
+use log::{debug, info, warn};
+use nix::sys::signal::kill;
+use nix::unistd::{setsid, Pid};
+use process::{CommandExt as _, ExitStatusExt as _};
+use regex::Regex;
 use resource::Pools;
-use resource::{Resource, ResourceKey};
+use resource::{Priority, Resource, ResourceKey};
+use stats::TestStats;
 use std::borrow::Borrow as _;
-use std::cmp::min;
-use std::collections::HashMap;
-use std::ffi::OsString;
+use std::cmp::{min, Reverse};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
 use std::fmt::Display;
-use std::io::{stdout, Stdout};
-use std::path::{absolute, PathBuf};
+use std::io::{stdout, Stdout, Write as _};
+use std::os::unix::fs::PermissionsExt as _;
+use std::os::unix::process::CommandExt as _;
+use std::path::{absolute, Path, PathBuf};
 use std::pin::pin;
 use std::process::{ExitCode, Stdio};
 use std::sync::{Arc, LazyLock, Mutex};
-use std::{env, fmt, fs, str};
-use tempfile::TempDir;
+use std::time::{Duration, SystemTime};
+use std::{env, fmt, fs, iter, str};
+use tempfile::{NamedTempFile, TempDir};
 use test::{base_job_env, Manager, TestCase, TestCaseId, TestJob, TestJobBuilder, TestName};
-use test::{DepDatabaseEntries, Test};
+use test::{DepDatabaseEntries, Shard, Test, TestStatus, Verdict};
+use throttle::{Restriction, Throttle};
 use tokio::select;
 use tokio::signal::unix::{signal, SignalKind};
-use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::watch;
 use tokio_util::sync::CancellationToken;
-use util::{DisplayablePathBuf, ErrGroup};
+use util::{format_age, format_size, hostname, DisplayablePathBuf, ErrGroup, ResultExt as _};
 
 use crate::git::Worktree;
 use crate::terminal::TerminalSizeWatcher;
 
+mod attest;
 mod config;
+mod ctl;
 mod dag;
 mod database;
+mod events;
+mod fds;
 mod flock;
 mod git;
+mod gpu;
 mod http;
+mod keys;
+mod lockfile;
+mod notes;
 mod process;
 mod resource;
+mod stats;
 mod terminal;
 mod test;
 mod text;
+mod throttle;
 mod ui;
 mod util;
 
@@ -69,9 +91,19 @@ struct Args {
     /// Filename prefix for temporary worktrees.
     #[arg(long, default_value_t = {"limmat-worktree".to_string()}, global = true)]
     worktree_prefix: String,
-    /// Directory (must exist) to create temporary worktrees in.
-    #[arg(long, default_value_t = {env::temp_dir().to_string_lossy().into_owned()}, global = true)]
-    worktree_dir: String,
+    /// Directory (must exist) to create temporary worktrees in. Defaults to
+    /// worktree_dir from the config file, or the system temp dir.
+    #[arg(long, global = true)]
+    worktree_dir: Option<PathBuf>,
+    /// Keep worktrees in this directory (created if needed) when shutting
+    /// down, and reuse them next time instead of creating new ones. This
+    /// overrides --worktree-dir.
+    #[arg(long, global = true)]
+    persistent_worktree_dir: Option<PathBuf>,
+    /// If the repository is a shallow clone, fetch the rest of its history
+    /// before testing (watch, test and run-across).
+    #[arg(long, global = true)]
+    unshallow: bool,
     /// Git binary - default will use $PATH.
     #[arg(long, default_value_t = {DisplayablePathBuf("git".into())}, global = true)]
     git_binary: DisplayablePathBuf,
@@ -81,6 +113,13 @@ struct Args {
     /// Regexes of tests to include. If specified, only tests matching these regexes will be run.
     #[arg(long, global = true)]
     tests: Vec<String>,
+    /// Unix socket used to control a running "watch". Default is limmat.sock
+    /// in the repository's git directory.
+    #[arg(long, global = true)]
+    ctl_socket: Option<PathBuf>,
+    /// Don't use colours in the output. Setting $NO_COLOR does the same.
+    #[arg(long, global = true)]
+    no_color: bool,
     #[command(subcommand)]
     command: Command,
 }
@@ -93,12 +132,92 @@ struct WatchArgs {
     #[arg(long, default_value_t = {"0.0.0.0:0".to_string()})]
     http_sockaddr: String,
     /// Hostname to use for HTTP URLs
-    #[arg(long, default_value_t = default_hostname())]
+    #[arg(long, default_value_t = hostname())]
     hostname: String,
     /// Base of range to test. Will test commits between this (exclusive) and
     /// HEAD (inclusive). Whenever HEAD changes, this string will be re-evaluated
-    /// to find the base of the range.
-    base: String,
+    /// to find the base of the range. The default is the upstream of the
+    /// checked-out branch, which is looked up again when a different branch
+    /// gets checked out.
+    base: Option<String>,
+    /// Keep running in the background, detached from the terminal. Use the
+    /// "attach" and "ctl" commands to interact with it, and stop it with
+    /// SIGTERM.
+    #[arg(long, default_value_t = false)]
+    daemon: bool,
+    /// Write the PID to this file while running. With --daemon, the default is
+    /// limmat.pid in the repository's git directory.
+    #[arg(long)]
+    pidfile: Option<PathBuf>,
+    /// Overrides the "ref_debounce_ms" setting from the config.
+    #[arg(long)]
+    ref_debounce_ms: Option<u64>,
+    /// Overrides the "ref_quiet_period_ms" setting from the config.
+    #[arg(long)]
+    ref_quiet_period_ms: Option<u64>,
+    /// Instead of watching forever, test the commits that are in the range at
+    /// startup, print a summary and exit. The exit code is 0 if all the tests
+    /// succeeded, apart from advisory ones.
+    #[arg(long, default_value_t = false, conflicts_with = "daemon")]
+    once: bool,
+    /// Only run a share of the test cases, given as "k/n" for the kth of n
+    /// shards. Instances with the same config and repository each given a
+    /// different shard split the test cases between them. Each instance also
+    /// runs the dependencies of its test cases.
+    #[arg(long)]
+    shard: Option<Shard>,
+    /// Only show some test cases in the status view, this doesn't affect what
+    /// gets run. Either "status=<status>" where <status> is one of enqueued,
+    /// started, success, failure, canceled, error, stage_failed, skipped,
+    /// skipped_by_commit or not_applicable, or "test=<regex>" to match test
+    /// names. Can be given more than once, a test case is shown if it matches
+    /// any of the statuses and any of the regexes.
+    #[arg(long)]
+    ui_filter: Vec<ui::FilterTerm>,
+    /// Test the whole stack of branches that HEAD is part of, instead of just
+    /// up to HEAD. That's the local branches that contain HEAD or that HEAD
+    /// contains, that haven't been merged into the base.
+    #[arg(long, default_value_t = false)]
+    stack: bool,
+    /// With --stack, only consider branches matching this pattern, as for "git
+    /// for-each-ref" (relative to refs/heads/).
+    #[arg(long, default_value = "*", requires = "stack")]
+    stack_branches: String,
+    /// Also watch another repository, given as "<path>[:<base>]" where <base>
+    /// is as above. Its tests come from the limmat.toml or .limmat.toml at its
+    /// root. Can be given more than once. The repositories share the status
+    /// view, and the number of jobs running at once across all of them is
+    /// limited to this repository's num_worktrees. Only this repository can be
+    /// controlled with "ctl" and "wait".
+    #[arg(long)]
+    also_watch: Vec<ExtraRepo>,
+    /// Overrides the "rerun" setting from the config.
+    #[arg(long)]
+    rerun: Option<Rerun>,
+}
+
+#[derive(Debug, Clone)]
+struct ExtraRepo {
+    path: PathBuf,
+    base: Option<String>,
+}
+
+impl str::FromStr for ExtraRepo {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (path, base) = match s.split_once(':') {
+            Some((path, base)) => (path, Some(base.to_owned())),
+            None => (s, None),
+        };
+        if path.is_empty() {
+            bail!("expected <path>[:<base>]");
+        }
+        Ok(Self {
+            path: path.into(),
+            base,
+        })
+    }
 }
 
 static PROJECT_DIRS: LazyLock<directories::ProjectDirs> = LazyLock::new(|| {
@@ -109,15 +228,6 @@ fn default_result_db() -> DisplayablePathBuf {
     DisplayablePathBuf(PROJECT_DIRS.data_local_dir().to_owned())
 }
 
-fn default_hostname() -> String {
-    uname()
-        .expect("couldn't get nodename")
-        .nodename()
-        .to_owned()
-        .into_string()
-        .expect("hostname wasn't utf-8")
-}
-
 // Returns the path we should look for the the config. Although this is
 // influenced by the existence of files, it doesn't guarantee that the returned
 // file exists.
@@ -128,21 +238,49 @@ fn find_config(config_arg: &Option<PathBuf>) -> anyhow::Result<PathBuf> {
     if let Some(path) = env::var_os("LIMMAT_CONFIG") {
         return Ok(PathBuf::from(path));
     }
-    let limmat_dot_toml = PathBuf::from("./limmat.toml");
-    if limmat_dot_toml.exists() {
-        return Ok(limmat_dot_toml);
-    }
-    let dot_limmat_dot_toml = PathBuf::from("./.limmat.toml");
-    if dot_limmat_dot_toml.exists() {
-        return Ok(dot_limmat_dot_toml);
+    if let Some(path) = find_config_in(Path::new(".")) {
+        return Ok(path);
     }
     bail!("Neither config nor $LIMMAT_CONFIG were set. No ./limmat.toml or ./.limmat.toml found");
 }
 
+fn find_config_in(dir: &Path) -> Option<PathBuf> {
+    ["limmat.toml", ".limmat.toml"]
+        .into_iter()
+        .map(|name| dir.join(name))
+        .find(|path| path.exists())
+}
+
+fn read_config(path: &Path) -> anyhow::Result<Config> {
+    let content = fs::read_to_string(path).context("couldn't read config")?;
+    debug!("config:\n{}", &content);
+    toml::from_str(&content).context("couldn't parse config")
+}
+
 #[derive(clap::Args, Debug)]
 struct TestArgs {
     /// Name of the test to run, per the "name" field in the config file.
     test: String,
+    /// Run the test against this revision in a temporary worktree, instead of
+    /// in the main worktree. Like with watch, cached results are used if
+    /// present and new results are stored.
+    #[arg(long)]
+    rev: Option<String>,
+}
+
+#[derive(clap::Args, Debug)]
+struct ExplainCacheArgs {
+    /// Name of the test, per the "name" field in the config file.
+    test: String,
+}
+
+#[derive(clap::Args, Debug)]
+struct RunAcrossArgs {
+    /// Range of commits to run the command on, as understood by git rev-list.
+    range: String,
+    /// The command and its arguments. This isn't run via a shell.
+    #[arg(last = true, required = true)]
+    command: Vec<String>,
 }
 
 // Args common to commands that get results from the database
@@ -157,12 +295,96 @@ struct DatabaseLookupArgs {
     rev: String,
 }
 
+// Without a subcommand, this is the original "artifacts <test> <rev>" that
+// prints the path.
 #[derive(clap::Args, Debug)]
-struct GetArgs {
+#[command(args_conflicts_with_subcommands = true)]
+struct ArtifactsArgs {
+    #[command(subcommand)]
+    command: Option<ArtifactsCommand>,
+    #[command(flatten)]
+    lookup_args: Option<DatabaseLookupArgs>,
+}
+
+#[derive(clap::Args, Debug)]
+struct ArtifactsListArgs {
+    /// Commits to list artifacts for. A single revision means just that
+    /// commit, use a range like "main..HEAD" for more. The default is the
+    /// commits between the upstream of the checked-out branch and HEAD.
+    rev: Option<String>,
+}
+
+#[derive(clap::Args, Debug)]
+struct ArtifactsCpArgs {
+    #[command(flatten)]
+    lookup_args: DatabaseLookupArgs,
+    /// Directory to copy the artifacts into, created if needed.
+    dest: PathBuf,
+}
+
+#[derive(Subcommand, Debug)]
+enum ArtifactsCommand {
+    /// Show the files in the artifacts of each test that has a result for the
+    /// commits, with their sizes. Use --tests and --skip-test to only list
+    /// some of the tests.
+    List(ArtifactsListArgs),
+    /// Copy a test's artifacts for a revision out of the result database.
+    /// Returns exit code 50 if the result doesn't exist.
+    Cp(ArtifactsCpArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct AttestArgs {
+    #[command(flatten)]
+    lookup_args: DatabaseLookupArgs,
+    /// SSH key to sign the attestation with, as for "ssh-keygen -Y sign". This
+    /// can be a public key if the private key is in ssh-agent.
+    #[arg(long)]
+    key: PathBuf,
+    /// Where to write the attestation. The signature is written alongside it
+    /// with a ".sig" suffix.
+    #[arg(long)]
+    output: PathBuf,
+}
+
+#[derive(clap::Args, Debug)]
+struct PinArgs {
     #[command(flatten)]
     lookup_args: DatabaseLookupArgs,
+    /// Remove the pin instead, so the result can be pruned again.
+    #[arg(long, default_value_t = false)]
+    unpin: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct VerifyAttestationArgs {
+    /// Attestation written by the "attest" command. Its signature must be
+    /// alongside it with a ".sig" suffix.
+    attestation: PathBuf,
+    /// Keys to trust, in the allowed signers format from ssh-keygen(1).
+    #[arg(long)]
+    allowed_signers: PathBuf,
+    /// Fail unless the attestation is for this revision's tree.
+    #[arg(long)]
+    rev: Option<String>,
+}
+
+#[derive(clap::Args, Debug)]
+#[command(
+    mut_arg("test", |arg| arg.required(false).required_unless_present("verdict")),
+    mut_arg("rev", |arg| arg.required(false).required_unless_present("verdict"))
+)]
+struct GetArgs {
+    #[command(flatten)]
+    lookup_args: Option<DatabaseLookupArgs>,
+    /// Instead of a test's output, print the commit's verdict ("pass", "fail"
+    /// or "pending") going by the results of its tests in the database,
+    /// apart from advisory ones. Exits with 1 if it's "fail" and 50 if it's
+    /// "pending".
+    #[arg(long, value_name = "REV", conflicts_with_all = ["test", "run"])]
+    verdict: Option<String>,
     /// Which output from the job do we want?
-    #[arg(default_value_t = GetOutput::Stdout)]
+    #[arg(index = 3, default_value_t = GetOutput::Stdout)]
     output: GetOutput,
 }
 
@@ -185,19 +407,288 @@ impl Display for GetOutput {
     }
 }
 
+#[derive(clap::Args, Debug)]
+struct ReproArgs {
+    #[command(flatten)]
+    lookup_args: DatabaseLookupArgs,
+    /// Run the command instead of printing it, and exit with its exit code.
+    #[arg(long)]
+    exec: bool,
+    /// Run the command here instead of where the job ran. That's gone if it
+    /// was a temporary worktree, so check out the commit somewhere and point
+    /// this at it.
+    #[arg(long)]
+    cwd: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+struct ShellArgs {
+    /// Name of the test, per the "name" field in the config file.
+    test: String,
+    /// Revision to check out. Any git revspec is fine.
+    rev: String,
+}
+
+#[derive(clap::Args, Debug)]
+struct LogsArgs {
+    #[command(flatten)]
+    lookup_args: DatabaseLookupArgs,
+    /// Which output from the job do we want? Ignored unless the test has
+    /// separate_outputs, otherwise they are one and the same.
+    #[arg(default_value_t = GetOutput::Stdout)]
+    output: GetOutput,
+    /// Strip ANSI escape sequences (colors and so on) from the output.
+    #[arg(long)]
+    no_ansi: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct GrepArgs {
+    /// Regex to search for.
+    pattern: String,
+    /// Commits to search. A single revision means just that commit, use a
+    /// range like "main..HEAD" for more. The default is the commits between
+    /// the upstream of the checked-out branch and HEAD.
+    rev: Option<String>,
+}
+
+#[derive(clap::Args, Debug)]
+struct MetricsArgs {
+    #[command(subcommand)]
+    command: MetricsCommand,
+}
+
+#[derive(clap::Args, Debug)]
+struct MetricsRangeArgs {
+    /// Commits to show metrics for. A single revision means just that commit,
+    /// use a range like "main..HEAD" for more. The default is the commits
+    /// between the upstream of the checked-out branch and HEAD.
+    rev: Option<String>,
+}
+
+#[derive(Subcommand, Debug)]
+enum MetricsCommand {
+    /// Print the metrics as CSV, with a row per commit, test and metric.
+    Export(MetricsRangeArgs),
+    /// Draw a bar chart of each metric, with a bar per commit.
+    Plot(MetricsRangeArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct SlowdownsArgs {
+    /// Commits to look at. A single revision means just that commit, use a
+    /// range like "main..HEAD" for more. The default is the commits between
+    /// the upstream of the checked-out branch and HEAD.
+    rev: Option<String>,
+}
+
+#[derive(clap::Args, Debug)]
+struct DiffArgs {
+    /// Revision to compare against. Any git revspec is fine.
+    old: String,
+    /// Revision to compare.
+    new: String,
+    /// Also show how the output changed, for the tests whose outcome changed.
+    /// ANSI escape sequences are stripped first.
+    #[arg(long)]
+    logs: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct EventsArgs {
+    /// Keep printing new events as they happen, until interrupted.
+    #[arg(long)]
+    follow: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct GcArgs {
+    /// Just print what would be deleted.
+    #[arg(long)]
+    plan: bool,
+    /// Also drop the events older than this from the event log, like "30d".
+    #[arg(long, value_parser = util::parse_duration)]
+    events_max_age: Option<Duration>,
+}
+
+#[derive(clap::Args, Debug)]
+struct WaitArgs {
+    /// Commits to wait for. A single revision means just that commit, use a
+    /// range like "main..HEAD" for more.
+    rev: String,
+}
+
+#[derive(clap::Args, Debug)]
+struct GateArgs {
+    /// Commits to test. Each is a single revision, meaning just that commit,
+    /// or a range like "main..HEAD". The default is the range between the
+    /// upstream of the checked-out branch and HEAD.
+    revs: Vec<String>,
+    /// Don't run any tests, just check the results in the database. Tests
+    /// that don't have a result count as failed.
+    #[arg(long)]
+    no_run: bool,
+    /// Instead of testing anything, print a Git pre-push hook script that
+    /// runs this command on the commits being pushed.
+    #[arg(long, conflicts_with = "revs")]
+    print_hook: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct InstallHooksArgs {
+    /// Have the hook run the tests that don't have a result yet, instead of
+    /// refusing to push.
+    #[arg(long)]
+    run: bool,
+    /// Have the hook push anyway when tests failed, after listing them.
+    #[arg(long)]
+    warn: bool,
+    /// Replace an existing pre-push hook.
+    #[arg(long)]
+    force: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct CtlArgs {
+    #[command(subcommand)]
+    command: CtlCommand,
+}
+
+#[derive(clap::Args, Debug)]
+struct CtlCaseArgs {
+    /// Name of the test, per the "name" field in the config file.
+    test: String,
+    /// Revision of the test case. Any git revspec is fine.
+    rev: String,
+}
+
+#[derive(Subcommand, Debug)]
+enum CtlCommand {
+    /// Print the current status of every test case.
+    Status,
+    /// Cancel a test case. It won't be restarted unless you rerun it.
+    Cancel(CtlCaseArgs),
+    /// Run a test case again, ignoring any cached result. Test cases that
+    /// depend on it are restarted too.
+    Rerun(CtlCaseArgs),
+    /// Give a test case and its dependencies priority for resources.
+    Bump(CtlCaseArgs),
+    /// Run a test on a commit in the range where it wouldn't be run
+    /// automatically, e.g. because the test has run_by_default = false.
+    Run(CtlCaseArgs),
+    /// Put the worktree that was kept after a test case failed (see the
+    /// keep_failed_worktrees config field) back into use. Any changes you made
+    /// in it are left there.
+    Release(CtlCaseArgs),
+}
+
 #[derive(Subcommand, Debug)]
 enum Command {
     /// The main command. Watch a repository and run tests whenever the revision
     /// range changes.
     Watch(WatchArgs),
-    /// Run a one-shot test in the specified repo. Do not cache the results.
+    /// Run a one-shot test in the specified repo. The results are only cached
+    /// with --rev.
     Test(TestArgs),
     /// EXPERIMENTAL: Get the path of a test's output in the result database.
-    /// Returns exit code 50 if the result doesn't exist.
+    /// Returns exit code 50 if the result doesn't exist, and says why if the
+    /// test is running right now or the last attempt to run it was canceled.
     Get(GetArgs),
-    /// Get the path to the artifacts for a given test. Returns exit code 50
+    /// Print a test's output from the result database. Returns exit code 50
     /// if the result doesn't exist.
-    Artifacts(DatabaseLookupArgs),
+    Logs(LogsArgs),
+    /// Search the stored output of tests for lines matching a regex, ignoring
+    /// ANSI escape sequences, and print them with the commit and test they
+    /// came from. Exits with 0 if any lines matched. Use --tests and
+    /// --skip-test to only search some of the tests.
+    Grep(GrepArgs),
+    /// Compare the results in the database for two commits, listing the tests
+    /// whose outcome changed. Exits with 0 unless a test passed for the old
+    /// commit and failed for the new one. Use --tests and --skip-test to only
+    /// compare some of the tests.
+    Diff(DiffArgs),
+    /// Print the event log from the result database. This has a JSON object
+    /// per line, for each time a "watch" command enqueued, started, finished
+    /// or canceled a test job.
+    Events(EventsArgs),
+    /// List the results that were flagged for taking longer than usual, per
+    /// the max_slowdown_percent test config field. Exits with 0 if there are
+    /// none.
+    Slowdowns(SlowdownsArgs),
+    /// Show the metrics that tests wrote to $LIMMAT_METRICS, oldest commit
+    /// first. Use --tests and --skip-test to only show some of the tests.
+    Metrics(MetricsArgs),
+    /// For each test, sum up the results in the database: how many times it
+    /// ran, the machine time that took, its mean duration overall and over
+    /// the last few runs, how often it failed, how often "watch" found its
+    /// result in the cache and how much space its results take up. Tests that
+    /// took the most time come first. Use --tests and --skip-test to only
+    /// show some of the tests.
+    Stats,
+    /// Delete the oldest results of tests that take up more space in the
+    /// result database than their storage_quota, apart from pinned ones. Use
+    /// --tests and --skip-test to only look at some of the tests.
+    Gc(GcArgs),
+    /// List the tests in the config with their descriptions, owners and help
+    /// URLs. Use --tests and --skip-test to only list some of them.
+    List,
+    /// Get the path to the artifacts for a given test. Returns exit code 50
+    /// if the result doesn't exist. The "list" and "cp" subcommands browse
+    /// and extract them without needing to know where they are.
+    Artifacts(ArtifactsArgs),
+    /// Print a shell command that runs a test job the way Limmat ran it, with
+    /// the same environment, arguments and working directory, for debugging
+    /// tests that only fail under Limmat. Returns exit code 50 if the result
+    /// doesn't exist.
+    Repro(ReproArgs),
+    /// Check out a revision in a worktree and start $SHELL there, with the
+    /// environment that a test's job would get: its dependencies are run, its
+    /// resources are reserved and its environment variables are set. The
+    /// setup and teardown commands aren't run. When the shell exits, the
+    /// resources are given back and its exit code is passed on.
+    Shell(ShellArgs),
+    /// Mark a test's result and artifacts as never to be pruned from the result
+    /// database. Returns exit code 50 if the result doesn't exist.
+    Pin(PinArgs),
+    /// Write a signed statement that a test passed for a revision's tree on
+    /// this machine, so others can trust the result. Fails if the test didn't
+    /// pass, and returns exit code 50 if the result doesn't exist.
+    Attest(AttestArgs),
+    /// Check the signature on an attestation from the "attest" command and
+    /// that it's for the same test definition as in our config, then print
+    /// what it says.
+    VerifyAttestation(VerifyAttestationArgs),
+    /// Talk to a running "watch" command in the same repository.
+    Ctl(CtlArgs),
+    /// Shorthand for "ctl rerun".
+    Rerun(CtlCaseArgs),
+    /// Show the status view of a running "watch" command in the same
+    /// repository.
+    Attach,
+    /// Wait for a running "watch" command in the same repository to finish
+    /// testing some commits, then print the results. Exits with 0 if all the
+    /// tests succeeded, apart from advisory ones. Use --tests and --skip-test
+    /// to only wait for some of the tests.
+    Wait(WaitArgs),
+    /// Make sure every test has a result for every commit in a range, running
+    /// the tests that don't, then print the results. Exits with 0 if all the
+    /// tests apart from advisory ones succeeded, otherwise lists the commits
+    /// that failed. Meant for scripts and Git hooks, see --print-hook. Use
+    /// --tests and --skip-test to only gate on some of the tests.
+    Gate(GateArgs),
+    /// Install a Git pre-push hook that runs "gate --no-run" on the commits
+    /// being pushed, so that it only looks at results that are already in the
+    /// database. Set the limmat.gate Git config to "block", "warn" or "off" to
+    /// override what the hook does, for example "git -c limmat.gate=off push".
+    InstallHooks(InstallHooksArgs),
+    /// Run a command that isn't in the config on every commit in a range, in
+    /// parallel in the worktrees, then print the result for each commit.
+    /// Exits with 0 if it succeeded for all of them. Results aren't cached, the
+    /// output is left in a temporary directory for you to delete.
+    RunAcross(RunAcrossArgs),
+    /// Show which fields of a test's config changed last time its config hash
+    /// changed, i.e. why results from before then aren't being reused.
+    ExplainCache(ExplainCacheArgs),
 }
 
 // Kitchen-sink object for global shit.
@@ -206,53 +697,339 @@ struct Env {
     repo: Arc<git::PersistentWorktree>,
     database: Arc<Database>,
     worktree_builder: WorktreeBuilder,
+    // Where the main git database lives, usable from the current directory.
+    git_common_dir: PathBuf,
+    ctl_socket: PathBuf,
+}
+
+impl Env {
+    async fn num_worktrees(&self) -> anyhow::Result<usize> {
+        let num_worktrees = self
+            .config
+            .num_worktrees
+            .resolve(self.repo.as_ref(), &self.worktree_builder.parent_dir)
+            .await
+            .context("figuring out num_worktrees")?;
+        // Persistent worktrees are mostly there already, and sparse or reflinked
+        // ones take up much less than a full checkout each.
+        if !self.worktree_builder.persistent
+            && self.config.worktree_options.sparse_checkout.is_none()
+            && self.config.worktree_provisioning != WorktreeProvisioning::Reflink
+        {
+            config::check_worktrees_fit(
+                self.repo.as_ref(),
+                &self.worktree_builder.parent_dir,
+                num_worktrees,
+            )
+            .await?;
+        }
+        Ok(num_worktrees)
+    }
+}
+
+// Returns the repository's main worktree, and where its git database lives.
+async fn open_repo(
+    path: PathBuf,
+    git_binary: PathBuf,
+) -> anyhow::Result<(PersistentWorktree, PathBuf)> {
+    let repo = git::PersistentWorktree { path, git_binary };
+    // Check repo is valid.
+    let git_common_dir = repo.path().join(
+        repo.git_common_dir()
+            .await
+            .with_context(|| format!("opening repo {}", repo.path().display()))?,
+    );
+    // The path might point into a subdirectory, but jobs that run in the main
+    // worktree should get a predictable working directory.
+    let repo = git::PersistentWorktree {
+        path: repo
+            .toplevel()
+            .await
+            .context("finding root of main worktree")?,
+        git_binary: repo.git_binary,
+    };
+    Ok((repo, git_common_dir))
+}
+
+// For watching another repository alongside the one in env, sharing its
+// database. The worktrees get a prefix of their own so that persistent ones
+// don't get mixed up between repositories. CPUs, memory and GPUs are shared
+// with env too, they belong to the machine.
+async fn extra_env(env: &Env, extra: &ExtraRepo) -> anyhow::Result<Env> {
+    let (repo, git_common_dir) = open_repo(extra.path.clone(), env.repo.git_binary.clone()).await?;
+    let config_path = find_config_in(repo.path()).with_context(|| {
+        format!(
+            "no limmat.toml or .limmat.toml in {}",
+            repo.path().display()
+        )
+    })?;
+    let config = ParsedConfig::new_sharing_machine(
+        read_config(&config_path)?,
+        config_path,
+        iter::empty::<&str>(),
+        iter::empty(),
+        env.config.machine_pools.clone(),
+    )?;
+    let mut prefix = env.worktree_builder.prefix.clone();
+    prefix.push(format!("{}-", repo_name(repo.path())?));
+    Ok(Env {
+        config,
+        repo: Arc::new(repo),
+        database: env.database.clone(),
+        worktree_builder: WorktreeBuilder {
+            prefix,
+            parent_dir: env.worktree_builder.parent_dir.clone(),
+            persistent: env.worktree_builder.persistent,
+        },
+        ctl_socket: git_common_dir.join("limmat.sock"),
+        git_common_dir,
+    })
+}
+
+// So that explain-cache can say what changed when a config hash changes.
+fn record_configs<'a>(
+    env: &Env,
+    tests: impl IntoIterator<Item = &'a Arc<Test>>,
+) -> anyhow::Result<()> {
+    for test in tests {
+        env.database
+            .record_config(
+                &test.name,
+                &test.config_hash,
+                &env.config.test_configs[&test.name],
+            )
+            .with_context(|| format!("recording config of {}", test.name))?;
+    }
+    Ok(())
+}
+
+fn repo_name(path: &Path) -> anyhow::Result<String> {
+    Ok(absolute(path)
+        .context("error getting absolute path of repo")?
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or("<unknown>".into()))
 }
 
 // Fallback instead of https://github.com/Stebalien/tempfile/pull/308
 struct WorktreeBuilder {
     prefix: OsString,
     parent_dir: PathBuf,
+    persistent: bool,
 }
 
 impl WorktreeBuilder {
-    pub fn build(&self) -> anyhow::Result<TempDir> {
-        tempfile::Builder::new()
-            .prefix(&self.prefix)
-            .tempdir_in(&self.parent_dir)
-            .context("creating temp dir for worktree")
+    pub fn build(&self) -> anyhow::Result<WorktreeDir> {
+        if !self.persistent {
+            return tempfile::Builder::new()
+                .prefix(&self.prefix)
+                .tempdir_in(&self.parent_dir)
+                .context("creating temp dir for worktree")
+                .map(WorktreeDir::from);
+        }
+        fs::create_dir_all(&self.parent_dir)
+            .with_context(|| format!("creating {}", self.parent_dir.display()))?;
+        // Take the first slot that isn't in use, either by us or by another
+        // instance sharing the directory.
+        for i in 0.. {
+            let mut name = self.prefix.clone();
+            name.push(i.to_string());
+            if let Some(dir) = WorktreeDir::persistent(self.parent_dir.join(name))? {
+                return Ok(dir);
+            }
+        }
+        unreachable!()
     }
 }
 
+// Tasks that create worktrees and add them to the resource pools as they
+// become ready. Nothing happens until they are run, but once they are, whoever
+// runs them has to clean up the worktrees from the pools afterwards.
+// If there's a throttle, worktrees aren't created while it's pausing jobs, so
+// that we don't fill up the disk.
+fn worktree_creation_tasks(
+    env: &Env,
+    cancellation_token: &CancellationToken,
+    num_worktrees: usize,
+    throttle: Option<Arc<Throttle>>,
+) -> anyhow::Result<Vec<BoxFuture<'static, anyhow::Result<()>>>> {
+    let dirs = (0..num_worktrees)
+        .map(|_| env.worktree_builder.build())
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let wait_unpaused = move |ct: CancellationToken| {
+        let throttle = throttle.clone();
+        async move {
+            let Some(throttle) = throttle else {
+                return Ok(());
+            };
+            select! {
+                _ = ct.cancelled() => bail!("canceled"),
+                () = throttle.wait_unpaused() => Ok(()),
+            }
+        }
+    };
+    let create = {
+        let repo = env.repo.clone();
+        let ct = cancellation_token.child_token();
+        let resource_pools = env.config.resource_pools.clone();
+        let wait_unpaused = wait_unpaused.clone();
+        move |dir, worktree_options: WorktreeOptions| {
+            let repo = repo.clone();
+            let ct = ct.clone();
+            let resource_pools = resource_pools.clone();
+            let wait_unpaused = wait_unpaused(ct.clone());
+            async move {
+                wait_unpaused.await?;
+                let worktree = TempWorktree::new::<PersistentWorktree>(
+                    &ct,
+                    repo.as_ref(),
+                    dir,
+                    &worktree_options,
+                )
+                .await?;
+                resource_pools.add([(ResourceKey::Worktree, Resource::Worktree(worktree))]);
+                anyhow::Ok(())
+            }
+        }
+    };
+    let worktree_options = env.config.worktree_options.clone();
+    Ok(match env.config.worktree_provisioning {
+        WorktreeProvisioning::Checkout => dirs
+            .into_iter()
+            .map(|dir| create(dir, worktree_options.clone()).boxed())
+            .collect(),
+        WorktreeProvisioning::Reflink => {
+            let ct = cancellation_token.child_token();
+            let repo = env.repo.clone();
+            let resource_pools = env.config.resource_pools.clone();
+            vec![async move {
+                let mut dirs = dirs.into_iter();
+                let Some(first_dir) = dirs.next() else {
+                    return Ok(());
+                };
+                wait_unpaused(ct.clone()).await?;
+                let template = TempWorktree::new::<PersistentWorktree>(
+                    &ct,
+                    repo.as_ref(),
+                    first_dir,
+                    &worktree_options,
+                )
+                .await?;
+                let copy_options = WorktreeOptions {
+                    reflink_from: Some(template.path().to_owned()),
+                    ..worktree_options
+                };
+                let results = join_all(dirs.map(|dir| create(dir, copy_options.clone()))).await;
+                // Only now that nothing is copying it can jobs start messing
+                // with the template.
+                resource_pools.add([(ResourceKey::Worktree, Resource::Worktree(template))]);
+                results.into_iter().collect()
+            }
+            .boxed()]
+        }
+    })
+}
+
+// How the range to test is tracked.
+struct RangeWatch {
+    // Base of the range.
+    rev: String,
+    // Whether to look up the upstream of the checked-out branch again
+    // whenever the refs change, and use that instead.
+    follow_upstream: bool,
+    // Passed on to Worktree::watch_refs.
+    debounce: Duration,
+    quiet_period: Duration,
+}
+
 // This is the main loop of the program. Take notifications from the Git tree,
 // feed them to the test manager, feed the test manager's results to the status
 // viewer (basically the UI).
+async fn handle_keys(
+    ct: CancellationToken,
+    mut keys: Keys,
+    test_managers: Vec<Arc<test::Manager<PersistentWorktree>>>,
+    screen: Arc<parking_lot::Mutex<ui::SharedScreen<Stdout>>>,
+) -> anyhow::Result<()> {
+    loop {
+        let action = select! {
+            _ = ct.cancelled() => return Ok(()),
+            action = keys.next() => action,
+        };
+        match action {
+            Some(keys::Action::RerunFailed) => {
+                for test_manager in &test_managers {
+                    test_manager
+                        .rerun_failed()
+                        .await
+                        .or_log_error("rerunning failed tests");
+                }
+            }
+            Some(keys::Action::ToggleHelp) => screen.lock().toggle_help(keys::help())?,
+            None => return Ok(()),
+        }
+    }
+}
+
 async fn watch_loop(
     cancellation_token: CancellationToken,
     test_manager: Arc<test::Manager<PersistentWorktree>>,
     mut ui: ui::StatusViewer<PersistentWorktree, Stdout>,
-    range_spec: OsString,
     repo: Arc<PersistentWorktree>,
-) -> anyhow::Result<()> {
-    let mut revs_stream = pin!(repo.watch_refs(&range_spec)?);
-    let mut notifs = test_manager.results();
+    watch_args: &WatchArgs,
+    range: RangeWatch,
+    mut restriction: watch::Receiver<Option<Restriction>>,
+) -> anyhow::Result<Option<Vec<(TestCase, TestStatus)>>> {
+    let RangeWatch {
+        rev: mut base,
+        follow_upstream,
+        debounce,
+        quiet_period,
+    } = range;
+    let range_spec: OsString = format!("{base}..HEAD").into();
+    // Ref changes that could move the stack also trigger an update of this,
+    // even though it only covers HEAD.
+    let mut revs_stream = pin!(repo.watch_refs(&range_spec, debounce, quiet_period)?);
+    let notifs = test_manager.coalesced_results();
 
     let size_watcher = TerminalSizeWatcher::new()?;
     let mut resizes = pin!(size_watcher.resizes());
 
-    loop {
+    // With once, this is set up when we get the first range and resolves when
+    // it's all been tested. After that we ignore further ref changes.
+    let mut finished: Option<BoxFuture<_>> = None;
+    let statuses = loop {
         select! {
             // We can sometimes get spammed with huge numbers of notifications; ensure that
             // ctrl-C is processed first.
             biased;
 
             _ =  cancellation_token.cancelled() => {
-                break;
+                break None;
+            }
+            statuses = async { finished.as_mut().unwrap().await }, if finished.is_some() => {
+                break Some(statuses?);
             }
             // TODO: It's dumb that we have two different types of communication here (one exposes
             // the channel, one implements Stream).
-            revs = revs_stream.next() => {
+            revs = revs_stream.next(), if finished.is_none() => {
                 // TODO: figure out if/how this can actually fail.
                 let mut revs = revs.expect("revset stream terminated")?;
+                if follow_upstream {
+                    match repo.upstream_ref().await? {
+                        Some(upstream) => base = upstream,
+                        None => warn!("Current branch has no upstream, still testing from {base}"),
+                    }
+                    revs = repo.rev_list(format!("{base}..HEAD")).await?;
+                }
+                let mut range = vec![OsString::from(format!("{base}..HEAD"))];
+                if watch_args.stack {
+                    let stack = stack_range(&repo, &base, &watch_args.stack_branches)
+                        .await
+                        .context("finding stacked branches")?;
+                    (revs, range) = (stack.revs, stack.range);
+                    ui.set_branches(stack.branches);
+                }
                 // When we accidentally get run on a massive range,
                 // set_revisions can take a long time, which with this
                 // simplistic loop approach can block the UI which is annoying.
@@ -263,35 +1040,45 @@ async fn watch_loop(
                     warn!("Got %d revisions in range. Will only test 1024");
                 }
                 revs.truncate(1024);
+                if watch_args.once {
+                    let test_manager = test_manager.clone();
+                    let revs = revs.clone();
+                    finished =
+                        Some(async move { test_manager.wait(&revs, |_| true).await }.boxed());
+                }
                 // Paying for a pointless clone here so we can do set_revisions
                 // (mostly just kicks off background stuff) before awaiting the
                 // UI reset (does synchronhous work).
+                test_manager.set_upstream(&base);
                 test_manager.set_revisions(revs).await.context("setting revisions to test")?;
-                ui.set_range(&range_spec).await.context("resetting status viewer")?;
+                ui.set_range(&range).await.context("resetting status viewer")?;
                 ui.repaint(&size_watcher.size()).context("error painting status to stdout")?;
             },
-            notif = notifs.recv() => {
-                let notif = match notif {
-                    Ok(n) => n,
-                    Err(RecvError::Lagged(num_dropped)) => {
-                        error!("Dropped {num_dropped} notifications.");
-                        continue;
-                    },
-                    // https://github.com/rust-lang/futures-rs/issues/1857
-                    // AFAICS there is no way to encode a stream that never terminates.
-                    Err(RecvError::Closed) => { panic!("notification stream terminated"); },
-                };
-                ui.update(notif);
+            // When lots of results come in at once, like from the cache at
+            // startup, these get merged so there's one repaint for all of them.
+            batch = notifs.recv() => {
+                if batch.iter().any(|notif| matches!(notif.status, TestStatus::Finished(_))) {
+                    test_manager.refine_sample().context("bisecting sampled commits")?;
+                }
+                for notif in batch {
+                    ui.update(notif);
+                }
                 ui.repaint(&size_watcher.size()).context("error painting status to stdout")?;
             },
             _ = resizes.next() => {
                 ui.repaint(&size_watcher.size()).context("error painting status to stdout")?;
             },
+            Ok(()) = restriction.changed() => {
+                ui.set_restriction(restriction.borrow_and_update().as_ref().map(|r| r.to_string()));
+                ui.repaint(&size_watcher.size()).context("error painting status to stdout")?;
+            },
         }
-    }
+    };
     // Break out of the TUI.
     drop(ui);
-    eprintln!("Got shutdown signal, terminating jobs and waiting");
+    if statuses.is_none() {
+        eprintln!("Got shutdown signal, terminating jobs and waiting");
+    }
     test_manager
         .cancel_running()
         .await
@@ -299,14 +1086,170 @@ async fn watch_loop(
     eprintln!("Shutting down - waiting for jobs to terminate");
     // Ensure jobs are shut down before we delort stuff etc.
     test_manager.settled().await;
-    Ok(())
+    Ok(statuses)
+}
+
+// Print the results of testing with --once. Succeeds if all the tests did,
+// apart from advisory ones.
+fn report_once(statuses: &[(TestCase, TestStatus)]) -> ExitCode {
+    let cases = ctl::case_statuses(statuses);
+    print_cases(&cases);
+    let num_succeeded = cases.iter().filter(|case| case.succeeded).count();
+    println!("{num_succeeded}/{} tests succeeded", cases.len());
+    if !cases.iter().any(|case| case.failed()) {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+struct StackRange {
+    // Arguments for git log.
+    range: Vec<OsString>,
+    revs: Vec<CommitHash>,
+    // Branch names by their tip.
+    branches: HashMap<CommitHash, Vec<String>>,
+}
+
+// The commits between base and the tips of the stack that HEAD is part of.
+async fn stack_range(
+    repo: &PersistentWorktree,
+    base: &str,
+    pattern: &str,
+) -> anyhow::Result<StackRange> {
+    let stack = repo.stack_branches(OsStr::new(base), pattern).await?;
+    let mut range: Vec<OsString> = vec![format!("^{base}").into(), "HEAD".into()];
+    range.extend(stack.iter().map(|(_, tip)| OsString::from(tip.to_string())));
+    let mut revs = Vec::new();
+    let mut seen = HashSet::new();
+    for tip in &range[1..] {
+        let mut spec = OsString::from(format!("{base}.."));
+        spec.push(tip);
+        for rev in repo.rev_list(&spec).await? {
+            if seen.insert(rev.clone()) {
+                revs.push(rev);
+            }
+        }
+    }
+    let mut branches: HashMap<CommitHash, Vec<String>> = HashMap::new();
+    for (name, tip) in stack {
+        branches.entry(tip).or_default().push(name);
+    }
+    Ok(StackRange {
+        range,
+        revs,
+        branches,
+    })
+}
+
+// Re-run the current command line in a new session with no terminal and
+// return the PID. We don't just fork because that doesn't mix with the Tokio
+// runtime.
+fn spawn_daemon(pidfile: &Path) -> anyhow::Result<u32> {
+    if let Ok(content) = fs::read_to_string(pidfile) {
+        if let Ok(pid) = content.trim().parse() {
+            if kill(Pid::from_raw(pid), None).is_ok() {
+                bail!("already running with PID {pid} (per {})", pidfile.display());
+            }
+        }
+    }
+    let mut cmd = std::process::Command::new(env::current_exe().context("finding own binary")?);
+    cmd.args(env::args_os().skip(1).filter(|arg| arg != "--daemon"))
+        // If the user specified it then this is a harmless duplicate.
+        .arg("--pidfile")
+        .arg(pidfile)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    // SAFETY: setsid is async-signal-safe.
+    unsafe {
+        cmd.pre_exec(|| {
+            setsid()?;
+            Ok(())
+        });
+    }
+    let child = cmd.spawn().context("spawning daemon")?;
+    Ok(child.id())
 }
 
 async fn watch(
     env: Env,
     cancellation_token: CancellationToken,
     watch_args: WatchArgs,
-) -> anyhow::Result<()> {
+) -> anyhow::Result<ExitCode> {
+    let mut repos = Vec::new();
+    for extra in &watch_args.also_watch {
+        repos.push(WatchedRepo {
+            env: extra_env(&env, extra).await?,
+            base: extra.base.clone(),
+        });
+    }
+    if watch_args.daemon {
+        let pidfile = watch_args
+            .pidfile
+            .unwrap_or_else(|| env.git_common_dir.join("limmat.pid"));
+        let pid = spawn_daemon(&pidfile)?;
+        eprintln!("Running in the background with PID {pid}. See it with \"limmat attach\".");
+        return Ok(ExitCode::SUCCESS);
+    }
+    if let Some(pidfile) = &watch_args.pidfile {
+        fs::write(pidfile, format!("{}\n", std::process::id()))
+            .with_context(|| format!("writing {}", pidfile.display()))?;
+    }
+    repos.insert(
+        0,
+        WatchedRepo {
+            env,
+            base: watch_args.base.clone(),
+        },
+    );
+    let result = do_watch(repos, cancellation_token, &watch_args).await;
+    if let Some(pidfile) = &watch_args.pidfile {
+        fs::remove_file(pidfile).or_log_error("removing pidfile");
+    }
+    result
+}
+
+// A repository being watched.
+struct WatchedRepo {
+    env: Env,
+    // Base of the range, as given by the user.
+    base: Option<String>,
+}
+
+async fn do_watch(
+    repos: Vec<WatchedRepo>,
+    cancellation_token: CancellationToken,
+    watch_args: &WatchArgs,
+) -> anyhow::Result<ExitCode> {
+    let started_at = SystemTime::now();
+    // Figure all this out before creating anything we'd have to clean up.
+    let mut repos_setup = Vec::new();
+    for repo in repos {
+        let base = match &repo.base {
+            Some(base) => base.clone(),
+            None => repo.env.repo.upstream_ref().await?.with_context(|| {
+                format!(
+                    "no base given for {}, and the current branch has no upstream to default to",
+                    repo.env.repo.path().display()
+                )
+            })?,
+        };
+        let num_worktrees = repo.env.num_worktrees().await?;
+        repos_setup.push((repo, base, num_worktrees));
+    }
+    let total_worktrees: usize = repos_setup.iter().map(|(_, _, n)| n).sum();
+    fds::check_budget(repos_setup.len(), total_worktrees)?;
+    let main_env = &repos_setup[0].0.env;
+    let mut throttle = Throttle::new(
+        main_env.config.throttle.clone(),
+        main_env.database.base_dir.clone(),
+        main_env.worktree_builder.parent_dir.clone(),
+    )?;
+    if repos_setup.len() > 1 {
+        throttle = throttle.with_max_jobs(repos_setup[0].2);
+    }
+    let throttle = Arc::new(throttle);
     let mut eg = ErrGroup::new(cancellation_token.clone());
 
     // Create HTTP server, to serve the result artifacts to the user when they
@@ -317,41 +1260,160 @@ async fn watch(
     let ui = Ui::new(
         watch_args.hostname.clone(),
         listener,
-        env.database.base_dir.clone(),
-        format!(
-            "Limmat | {}",
-            absolute(env.repo.path())
-                .context("error getting absolute path of repo")?
-                .file_name()
-                .map(|n| n.to_string_lossy())
-                .unwrap_or("<unknown>".into())
-        ),
+        main_env.database.base_dir.clone(),
+        format!("Limmat | {}", repo_name(main_env.repo.path())?),
     );
     let result_url_base = ui.result_url_base()?;
     let home_url = ui.home_url()?;
     let ui_state = ui.state();
+    let ctl_ui_state = ui_state.clone();
     eg.spawn(ui.serve(cancellation_token.child_token()));
+    let screen = Arc::new(parking_lot::Mutex::new(ui::SharedScreen::new(
+        stdout(),
+        ui_state,
+        home_url,
+    )));
 
-    // Set up the test manager, which is the weirdly-scoped god-object that
-    // orchestrates test jobs.
-    let test_manager = Arc::new(Manager::new(
-        env.repo.clone(),
-        &env.config.source_path,
-        env.database,
-        env.config.resource_pools.clone(),
-        env.config.tests,
-    ));
+    eg.spawn({
+        let throttle = throttle.clone();
+        let ct = cancellation_token.child_token();
+        async move { throttle.monitor(ct).await }
+    });
 
-    // Set up the UI, which shows the user what's going on in the terminal.
-    let ui = ui::StatusViewer::new(
-        env.repo.clone(),
-        stdout(),
-        ui_state,
-        result_url_base,
-        home_url,
-    );
+    let main_repo = main_env.repo.clone();
+    let main_ctl_socket = main_env.ctl_socket.clone();
+    let mut worktree_tasks = Vec::new();
+    let mut test_managers = Vec::new();
+    let mut notif_tasks = Vec::new();
+    let mut loops = Vec::new();
+    // If one repository's loop fails, the others should stop too.
+    let loops_ct = cancellation_token.child_token();
+    for (repo, base, num_worktrees) in repos_setup {
+        let env = repo.env;
+        worktree_tasks.extend(worktree_creation_tasks(
+            &env,
+            &cancellation_token,
+            num_worktrees,
+            Some(throttle.clone()),
+        )?);
+
+        record_configs(&env, env.config.all_tests.nodes())?;
+
+        // Set up the test manager, which is the weirdly-scoped god-object that
+        // orchestrates test jobs.
+        let test_manager = Manager::new(
+            env.repo.clone(),
+            &env.config.source_path,
+            env.database.clone(),
+            env.config.resource_pools.clone(),
+            env.config.all_tests,
+        )
+        .with_throttle(throttle.clone())
+        .with_scheduling(env.config.scheduling);
+        let test_manager = match watch_args.rerun.unwrap_or(env.config.rerun) {
+            Rerun::Failures => test_manager.with_rerun_failures_before(started_at),
+            Rerun::None => test_manager,
+        };
+        let test_manager = match env.config.sample_every {
+            Some(every) => test_manager.with_sample(every),
+            None => test_manager,
+        };
+        let test_manager = Arc::new(match watch_args.shard {
+            Some(shard) => test_manager.with_shard(shard),
+            None => test_manager,
+        });
+
+        // These aren't part of the ErrGroup, they run until the test manager is gone.
+        if env.config.git_notes {
+            let repo = env.repo.clone();
+            let notifs = test_manager.results();
+            let reported_tests = test_manager.reported_tests();
+            notif_tasks.push(tokio::spawn(async move {
+                notes::write_notes(repo.as_ref(), reported_tests, notifs).await
+            }));
+        }
+        let events_path = env.database.base_dir.join(events::EVENTS_FILENAME);
+        let lock_method = env.database.lock_method();
+        let repo_path = env.repo.path().to_owned();
+        let notifs = test_manager.results();
+        notif_tasks.push(tokio::spawn(async move {
+            events::write_events(&events_path, lock_method, &repo_path, notifs).await
+        }));
+
+        // Set up the UI, which shows the user what's going on in the terminal.
+        let ui = ui::StatusViewer::new(
+            env.repo.clone(),
+            env.database,
+            screen.clone(),
+            repo_name(env.repo.path())?,
+            result_url_base.clone(),
+            env.config.status_theme,
+        );
+        let ui = match env.config.ui.log_format {
+            Some(log_format) => ui.with_log_format(log_format),
+            None => ui,
+        }
+        .with_compact(env.config.ui.compact)
+        .with_bell(env.config.ui.bell)
+        .with_filter(ui::Filter::new(watch_args.ui_filter.clone()))
+        .with_resource_pools(env.config.resource_pools.clone());
+
+        let ct = loops_ct.clone();
+        let watch_loop = watch_loop(
+            loops_ct.clone(),
+            test_manager.clone(),
+            ui,
+            env.repo,
+            watch_args,
+            RangeWatch {
+                rev: base,
+                follow_upstream: repo.base.is_none(),
+                debounce: watch_args
+                    .ref_debounce_ms
+                    .map_or(env.config.ref_debounce, Duration::from_millis),
+                quiet_period: watch_args
+                    .ref_quiet_period_ms
+                    .map_or(env.config.ref_quiet_period, Duration::from_millis),
+            },
+            throttle.subscribe(),
+        );
+        loops.push(async move {
+            let result = watch_loop.await;
+            if result.is_err() {
+                ct.cancel();
+            }
+            result
+        });
+        test_managers.push(test_manager);
+    }
+    if let Some(keys) = Keys::new()? {
+        eg.spawn(handle_keys(
+            cancellation_token.child_token(),
+            keys,
+            test_managers.clone(),
+            screen.clone(),
+        ));
+    }
+    // The status viewers and the key handler own it now, so it gets torn down
+    // with the last one.
+    drop(screen);
+
+    // Only one instance per repo can own the control socket. That's fine,
+    // other instances just can't be controlled.
+    match CtlServer::bind(
+        &main_ctl_socket,
+        test_managers[0].clone(),
+        main_repo,
+        ctl_ui_state,
+    )? {
+        Some(server) => eg.spawn(server.serve(cancellation_token.child_token())),
+        None => warn!(
+            "{} is owned by another instance, not serving control requests",
+            main_ctl_socket.display()
+        ),
+    }
 
-    // Kick off creation of the worktrees that the test manager will run jobs in.
+    // Kick off creation of the worktrees that the test managers will run jobs in.
     //
     // Once we've done this, we can no longer return from this function until
     // we've also cleaned the worktrees up. This is stinky and gross. AFAICT
@@ -361,40 +1423,45 @@ async fn watch(
     // this, but the solution would be to create the worktrees ondemand, when we have a revision we
     // are actually trying to test. That might be a good idea anyway, so probably it's preferable to
     // just do that for its own sake and leave the empty-repo problem as a nice freebie.
-    eprintln!("Creating {} worktrees...", env.config.num_worktrees);
-    for _ in 0..env.config.num_worktrees {
-        let repo = env.repo.clone();
-        let ct = cancellation_token.child_token();
-        let resource_pools = env.config.resource_pools.clone();
-        let dir = env.worktree_builder.build()?;
-        eg.spawn(async move {
-            let worktree = TempWorktree::new::<PersistentWorktree>(&ct, repo.as_ref(), dir).await?;
-            resource_pools.add([(ResourceKey::Worktree, Resource::Worktree(worktree))]);
-            Ok(())
-        });
+    eprintln!("Creating {} worktrees...", total_worktrees);
+    for task in worktree_tasks {
+        eg.spawn(task);
     }
 
-    // DO THE THING.
-    eg.spawn(watch_loop(
-        cancellation_token.child_token(),
-        test_manager.clone(),
-        ui,
-        format!("{}..HEAD", watch_args.base).into(),
-        env.repo,
-    ));
-
-    let end_result = eg.wait().await;
+    // DO THE THING. The other tasks cancel the token if they fail.
+    let loop_result = join_all(loops)
+        .await
+        .into_iter()
+        .collect::<anyhow::Result<Vec<_>>>()
+        .map(
+            |statuses| match statuses.into_iter().collect::<Option<Vec<_>>>() {
+                Some(statuses) => report_once(&statuses.concat()),
+                None => ExitCode::SUCCESS,
+            },
+        );
+    let end_result = if cancellation_token.is_cancelled() {
+        eg.wait().await.and(loop_result)
+    } else {
+        // We finished testing with --once, or failed. Either way, the other
+        // tasks failing because of this cancellation isn't news.
+        cancellation_token.cancel();
+        eg.wait().await.or_log_error("stopping tasks");
+        loop_result
+    };
 
     // Now we have to remember to clean up before returning the result :/
     eprintln!("Tearing down worktrees...");
-    join_all(
+    join_all(test_managers.into_iter().flat_map(|test_manager| {
         Arc::into_inner(test_manager)
             .expect("leaked test manager reference")
             .into_resource_pools()
             .try_remove_worktrees()
-            .map(|w| w.cleanup()),
-    )
+            .map(|w| w.cleanup())
+    }))
     .await;
+    for task in notif_tasks {
+        task.await.expect("notification task panicked");
+    }
 
     end_result
 }
@@ -403,17 +1470,18 @@ async fn ensure_job_success(
     database: Arc<Database>,
     resource_pools: Arc<Pools>,
     job: TestJob,
-    origin_worktree: PathBuf,
+    origin_worktree: Arc<PersistentWorktree>,
 ) -> anyhow::Result<Arc<DatabaseEntry>> {
     let name = job.test_name().to_owned();
     let db_entry = job
-        .run(database, resource_pools.as_ref(), &origin_worktree)
+        .run(database, resource_pools.as_ref(), origin_worktree.as_ref())
         .await
-        .with_context(|| format!("running dependency job {name}"))?;
+        .with_context(|| format!("running job {name}"))?;
     if db_entry.exit_code() != 0 {
         bail!(
-            "dependency job {name} failed with exit code {}",
-            db_entry.exit_code()
+            "job {name} failed with exit code {}, output in {}",
+            db_entry.exit_code(),
+            db_entry.stdout_path().display()
         );
     }
     Ok(db_entry)
@@ -429,12 +1497,38 @@ async fn ensure_tests_run(
 ) -> anyhow::Result<DepDatabaseEntries> {
     let tests = tests.into_iter();
     let num_worktrees = min(
-        env.config.num_worktrees,
+        env.num_worktrees().await?,
         tests.clone().filter(|t| t.needs_worktree()).count(),
     );
 
     let job_env = Arc::new(base_job_env(env.repo.path(), &env.config.source_path));
 
+    // The jobs run concurrently, so give each one's output a distinctly
+    // coloured prefix.
+    let name_width = tests
+        .clone()
+        .map(|t| t.name.to_string().len())
+        .max()
+        .unwrap_or(0);
+    let colors = [
+        Color::Cyan,
+        Color::Magenta,
+        Color::Yellow,
+        Color::Blue,
+        Color::Green,
+        Color::BrightCyan,
+        Color::BrightMagenta,
+        Color::BrightYellow,
+    ];
+    let prefixes: HashMap<TestName, String> = tests
+        .clone()
+        .zip(colors.iter().cycle())
+        .map(|(t, color)| {
+            let prefix = format!("{:>name_width$} | ", t.name.to_string()).color(*color);
+            (t.name.clone(), prefix.to_string())
+        })
+        .collect();
+
     // Get the graph of tests we need to run as dependencies.
     // This is kinda inefficient: we're building a new Dag based on a subset of
     // the old one, so the validation in the constructor is not strictly
@@ -469,6 +1563,7 @@ async fn ensure_tests_run(
                 job_env.clone(),
                 wait_for,
             )
+            .with_echo_prefix(prefixes[&test_case.test.name].clone())
             .build();
             jobs.insert(test_case.id().borrow().to_owned(), job);
             Ok(jobs)
@@ -476,19 +1571,9 @@ async fn ensure_tests_run(
     )?;
 
     // Kick off creation of the worktrees that the dep jobs will run in.
-    // This is horribly copy-pasted from watch. I dunno, I can't figure out how
-    // to fix that without insanely complex async stuff.
     let mut eg = ErrGroup::new(cancellation_token.clone());
-    for _ in 0..num_worktrees {
-        let repo = env.repo.clone();
-        let ct = cancellation_token.child_token();
-        let resource_pools = env.config.resource_pools.clone();
-        let dir = env.worktree_builder.build()?;
-        eg.spawn(async move {
-            let worktree = TempWorktree::new::<PersistentWorktree>(&ct, repo.as_ref(), dir).await?;
-            resource_pools.add([(ResourceKey::Worktree, Resource::Worktree(worktree))]);
-            Ok(())
-        });
+    for task in worktree_creation_tasks(env, &cancellation_token, num_worktrees, None)? {
+        eg.spawn(task);
     }
 
     let dep_db_entries = Arc::new(Mutex::new(HashMap::new()));
@@ -496,10 +1581,10 @@ async fn ensure_tests_run(
         let dep_db_entries = dep_db_entries.clone();
         let db = env.database.clone();
         let resource_pools = env.config.resource_pools.clone();
-        let repo_path = env.repo.path().to_owned();
+        let repo = env.repo.clone();
         eg.spawn(async move {
             let test_name = job.test_name().clone();
-            let db_entry = ensure_job_success(db, resource_pools, job, repo_path).await?;
+            let db_entry = ensure_job_success(db, resource_pools, job, repo).await?;
             dep_db_entries.lock().unwrap().insert(test_name, db_entry);
             Ok(())
         });
@@ -529,6 +1614,19 @@ async fn test(
     test_args: &TestArgs,
 ) -> anyhow::Result<()> {
     let test_name = TestName::new(test_args.test.clone());
+    record_configs(
+        &env,
+        env.config
+            .tests
+            .top_down_from(&test_name)
+            .ok_or(anyhow!("no such test {:?}", test_name.to_string()))?,
+    )?;
+    // Unlike commands that just need the odd result, this can be used to run
+    // lots of tests at once.
+    fds::check_budget(1, env.num_worktrees().await?)?;
+    if let Some(rev) = &test_args.rev {
+        return test_rev(env, cancellation_token, &test_name, rev).await;
+    }
     // So we can cache the results in the database, the dependency jobs will be run at HEAD.
     let head = env
         .repo
@@ -556,6 +1654,7 @@ async fn test(
     }
 
     let test = env.config.tests.node(&test_name).unwrap();
+    dep_db_entries.retain(|name, _| !test.stage_gate.contains(name));
     let test_case = TestCase::new(head.clone(), test.clone());
     let mut needs_resources = test_case.test.needs_resources.clone();
     let job = TestJobBuilder::new(
@@ -567,8 +1666,16 @@ async fn test(
     .build();
     // Doesn't need a worktree, it's gonna do it live and direct in the main tree.
     needs_resources.remove(&ResourceKey::Worktree);
-    let resources = env.config.resource_pools.get(needs_resources).await;
-    let output_dir = TempDir::with_prefix("limmat-output-")?.into_path();
+    let resources = env
+        .config
+        .resource_pools
+        .get_with_priority(
+            needs_resources,
+            test.resource_alternatives.clone(),
+            &Priority::default(),
+        )
+        .await;
+    let output_dir = TempDir::with_prefix("limmat-output-")?.keep();
     eprintln!(
         "Test artifacts will be stored under {}",
         output_dir.display()
@@ -581,7 +1688,7 @@ async fn test(
     )
     .await?;
     let db_entry = job
-        .run_with(env.repo.path(), &resources, output, dep_db_entries)
+        .run_with(env.repo.as_ref(), &resources, output, dep_db_entries)
         .await?;
     eprintln!("Finished: {}", db_entry.result());
     if db_entry.result().exit_code == 0 {
@@ -591,13 +1698,298 @@ async fn test(
     }
 }
 
+// Unlike the normal test command, this runs the test just like the dependency
+// jobs, so its result goes in the database.
+async fn test_rev(
+    env: Env,
+    cancellation_token: CancellationToken,
+    test_name: &TestName,
+    rev: &str,
+) -> anyhow::Result<()> {
+    let commit = env
+        .repo
+        .rev_parse(rev)
+        .await
+        .context("error looking up commit")?
+        .ok_or_else(|| anyhow!("revision {rev:?} not found"))?;
+    let tests: Vec<&Arc<Test>> = env
+        .config
+        .tests
+        .top_down_from(test_name)
+        .ok_or(anyhow!("no such test {:?}", test_name.to_string()))?
+        .collect();
+    eprintln!("Running {} jobs at {}...", tests.len(), commit.hash);
+    let db_entries = ensure_tests_run(&env, cancellation_token, tests, &commit).await?;
+    let db_entry = &db_entries[test_name];
+    eprintln!("Finished: {}", db_entry.result());
+    eprintln!("Output is in {}", db_entry.stdout_path().display());
+    Ok(())
+}
+
+const PRE_PUSH_HOOK: &str = include_str!("pre-push.sh");
+
+// The hook runs "limmat gate" with these extra args. Mode is the default for
+// the limmat.gate Git config setting, "block" or "warn".
+fn pre_push_hook(gate_args: &str, mode: &str) -> String {
+    PRE_PUSH_HOOK
+        .replace("@GATE_ARGS@", gate_args)
+        .replace("@MODE@", mode)
+}
+
+// Run the tests on the commits, or wait for the results if something else is
+// already running them.
+async fn run_for_gate(
+    env: Env,
+    cancellation_token: CancellationToken,
+    commits: &[Commit],
+) -> anyhow::Result<Vec<ctl::CaseStatus>> {
+    record_configs(&env, env.config.all_tests.nodes())?;
+    let revs: Vec<CommitHash> = commits.iter().map(|c| c.hash.clone()).collect();
+    let upstream = env.repo.upstream_ref().await?;
+    let num_worktrees = min(env.num_worktrees().await?, revs.len());
+    let worktree_tasks = worktree_creation_tasks(&env, &cancellation_token, num_worktrees, None)?;
+    let test_manager = Manager::new(
+        env.repo.clone(),
+        &env.config.source_path,
+        env.database.clone(),
+        env.config.resource_pools.clone(),
+        env.config.all_tests,
+    )
+    .with_scheduling(env.config.scheduling);
+    if let Some(upstream) = upstream {
+        test_manager.set_upstream(upstream);
+    }
+    let mut eg = ErrGroup::new(cancellation_token.clone());
+    for task in worktree_tasks {
+        eg.spawn(task);
+    }
+    // If creating the worktrees fails, that cancels the token, so this can't
+    // get stuck waiting for jobs that will never run.
+    let result = async {
+        test_manager
+            .set_revisions(revs.clone())
+            .await
+            .context("setting revisions to test")?;
+        select! {
+            _ = cancellation_token.cancelled() => Ok(None),
+            statuses = test_manager.wait(&revs, |_| true) => statuses.map(Some),
+        }
+    };
+    let (created, statuses) = futures::join!(eg.wait(), result);
+    let cancel_result = test_manager.cancel_running().await;
+    test_manager.settled().await;
+
+    // Now we have to remember to clean up before returning the result :/
+    join_all(
+        test_manager
+            .into_resource_pools()
+            .try_remove_worktrees()
+            .map(|w| w.cleanup()),
+    )
+    .await;
+    created?;
+    cancel_result.context("cancelling tests")?;
+    let statuses = statuses?.ok_or_else(|| anyhow!("interrupted"))?;
+    Ok(ctl::case_statuses(&statuses))
+}
+
+// Only look in the database. Results that aren't there count as failures,
+// but if another instance is running a test, this waits for its result.
+async fn stored_for_gate(env: &Env, commits: &[Commit]) -> anyhow::Result<Vec<ctl::CaseStatus>> {
+    let mut cases = Vec::new();
+    for commit in commits {
+        let changed_files =
+            git::changed_files(env.repo.as_ref(), &commit.hash, commit.parents.first())
+                .await
+                .context("listing changed files")?;
+        for test in env.config.all_tests.nodes() {
+            if !test.runs_on(commit, &changed_files) {
+                continue;
+            }
+            let test_case = TestCase::new(commit.clone(), test.clone());
+            let db_entry = env
+                .database
+                .find_waiting(&test_case)
+                .await
+                .context("database lookup")?;
+            cases.push(ctl::CaseStatus {
+                commit: commit.hash.to_string(),
+                test: test.name.to_string(),
+                status: match &db_entry {
+                    Some(db_entry) => db_entry.result().to_string(),
+                    None => "no result".into(),
+                },
+                succeeded: db_entry.is_some_and(|e| e.exit_code() == 0),
+                advisory: test.advisory,
+            });
+        }
+    }
+    Ok(cases)
+}
+
+async fn gate(
+    env: Env,
+    cancellation_token: CancellationToken,
+    args: GateArgs,
+) -> anyhow::Result<ExitCode> {
+    if args.print_hook {
+        let gate_args = if args.no_run { "--no-run" } else { "" };
+        print!("{}", pre_push_hook(gate_args, "block"));
+        return Ok(ExitCode::SUCCESS);
+    }
+    let mut commits = Vec::new();
+    if args.revs.is_empty() {
+        commits = commits_in(&env, None).await?;
+    }
+    let mut seen = HashSet::new();
+    for rev in args.revs {
+        for commit in commits_in(&env, Some(rev)).await? {
+            if seen.insert(commit.hash.clone()) {
+                commits.push(commit);
+            }
+        }
+    }
+    if commits.is_empty() {
+        eprintln!("No commits to test");
+        return Ok(ExitCode::SUCCESS);
+    }
+    let cases = if args.no_run {
+        stored_for_gate(&env, &commits).await?
+    } else {
+        run_for_gate(env, cancellation_token, &commits).await?
+    };
+
+    print_cases(&cases);
+    let failed: HashSet<&str> = cases
+        .iter()
+        .filter(|case| case.failed())
+        .map(|case| case.commit.as_str())
+        .collect();
+    if failed.is_empty() {
+        return Ok(ExitCode::SUCCESS);
+    }
+    println!("Commits with failing tests:");
+    for commit in &commits {
+        if failed.contains(commit.hash.to_string().as_str()) {
+            let subject = commit.message.lines().next().unwrap_or_default();
+            println!("  {} {subject}", commit.hash.abbrev());
+        }
+    }
+    Ok(ExitCode::FAILURE)
+}
+
+async fn install_hooks(env: Env, args: InstallHooksArgs) -> anyhow::Result<ExitCode> {
+    let hooks_dir = env
+        .repo
+        .hooks_dir()
+        .await
+        .context("finding hooks directory")?;
+    let path = hooks_dir.join("pre-push");
+    if path.exists() && !args.force {
+        bail!(
+            "{} already exists, use --force to replace it",
+            path.display()
+        );
+    }
+    let gate_args = if args.run { "" } else { "--no-run" };
+    let mode = if args.warn { "warn" } else { "block" };
+    fs::create_dir_all(&hooks_dir).with_context(|| format!("creating {}", hooks_dir.display()))?;
+    fs::write(&path, pre_push_hook(gate_args, mode))
+        .with_context(|| format!("writing {}", path.display()))?;
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755))
+        .with_context(|| format!("making {} executable", path.display()))?;
+    eprintln!("Installed {}", path.display());
+    Ok(ExitCode::SUCCESS)
+}
+
+async fn run_across(
+    env: Env,
+    cancellation_token: CancellationToken,
+    args: RunAcrossArgs,
+) -> anyhow::Result<ExitCode> {
+    let commit_hashes = env
+        .repo
+        .rev_list(&args.range)
+        .await
+        .context("listing revisions")?;
+    if commit_hashes.is_empty() {
+        bail!("no commits in {:?}", args.range);
+    }
+    let commits = env
+        .repo
+        .rev_parse_all(&commit_hashes)
+        .await
+        .context("looking up commits")?;
+    let test = config::Test::ad_hoc("run-across".into(), args.command)
+        .parse(&Dag::empty(), &[])
+        .context("setting up command")?;
+    let test = Arc::new(test);
+    // The job machinery needs a database, but these results aren't worth
+    // keeping in the real one. It's deleted if we don't get as far as telling
+    // the user where the output is.
+    let db_dir = TempDir::with_prefix("limmat-run-across-")?;
+    let database = Arc::new(Database::create_or_open(db_dir.path())?);
+    let job_env = Arc::new(base_job_env(env.repo.path(), &env.config.source_path));
+
+    let num_worktrees = min(env.num_worktrees().await?, commits.len());
+    fds::check_budget(1, num_worktrees)?;
+    let mut eg = ErrGroup::new(cancellation_token.clone());
+    for task in worktree_creation_tasks(&env, &cancellation_token, num_worktrees, None)? {
+        eg.spawn(task);
+    }
+    let statuses = Arc::new(Mutex::new(Vec::new()));
+    for commit in commits {
+        let test_case = TestCase::new(commit, test.clone());
+        let job = TestJobBuilder::new(
+            cancellation_token.clone(),
+            test_case.clone(),
+            job_env.clone(),
+            Vec::new(), // wait_for
+        )
+        .build();
+        let statuses = statuses.clone();
+        let database = database.clone();
+        let resource_pools = env.config.resource_pools.clone();
+        let repo = env.repo.clone();
+        eg.spawn(async move {
+            let outcome = job
+                .run(database, resource_pools.as_ref(), repo.as_ref())
+                .await;
+            let status = TestStatus::Finished(outcome.map(|e| e.result().clone()));
+            statuses.lock().unwrap().push((test_case, status));
+            Ok(())
+        });
+    }
+    let result = eg.wait().await;
+    join_all(
+        env.config
+            .resource_pools
+            .try_remove_worktrees()
+            .map(|w| w.cleanup()),
+    )
+    .await;
+    result?;
+
+    let cases = ctl::case_statuses(&statuses.lock().unwrap());
+    print_cases(&cases);
+    eprintln!(
+        "Output is stored under {}, delete it when you're done",
+        db_dir.keep().display()
+    );
+    Ok(if cases.iter().all(|case| case.succeeded) {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
+
 // Returns Ok(None) if nothing found and --run wasn't set.
 // Otherwise returns the database entry or an error.
 async fn lookup(
     env: Env,
     cancellation_token: CancellationToken,
     lookup_args: &DatabaseLookupArgs,
-) -> anyhow::Result<Option<DatabaseEntry>> {
+) -> anyhow::Result<Option<Arc<DatabaseEntry>>> {
     let test_name = TestName::new(lookup_args.test.clone());
     let rev = env
         .repo
@@ -616,8 +2008,19 @@ async fn lookup(
 
         // Write to stderr so the output can just be the path, for scripting.
         eprintln!("Running {} tests...", tests.len());
-        ensure_tests_run(&env, cancellation_token.child_token(), tests, &rev).await?;
+        let mut entries =
+            ensure_tests_run(&env, cancellation_token.child_token(), tests, &rev).await?;
         eprintln!("Tests complete");
+        // These are locked, so the result can't be replaced under us by
+        // someone running the test again.
+        return Ok(Some(entries.remove(&test_name).ok_or_else(|| {
+            anyhow!(
+                "no database entry for test {:?} at revision {:?} after running ({})",
+                test_name.to_string(),
+                lookup_args.rev,
+                rev.hash
+            )
+        })?));
     }
 
     let test = env
@@ -625,26 +2028,26 @@ async fn lookup(
         .tests
         .node(&test_name)
         .ok_or(anyhow!("no such test {:?}", test_name.to_string()))?;
-    match env
+    let test_case = TestCase::new(rev.clone(), test.clone());
+    // This doesn't wait for anyone who's running the test right now.
+    if let Some(entry) = env.database.find(&test_case).context("database lookup")? {
+        return Ok(Some(Arc::new(entry)));
+    }
+    if let Some(started_at) = env
         .database
-        .lookup(&TestCase::new(rev.clone(), test.clone()))
-        .await
-        .context("database lookup")?
+        .running_since(&test_case)
+        .context("checking for a running test")?
     {
-        LookupResult::FoundResult(e) => Ok(Some(e)),
-        LookupResult::YouRunIt(_) => {
-            if lookup_args.run {
-                bail!(
-                    "no database entry for test {:?} at revision {:?} after running ({})",
-                    test_name.to_string(),
-                    lookup_args.rev,
-                    rev.hash
-                )
-            } else {
-                Ok(None)
-            }
-        }
+        let age = format_age(started_at.elapsed().unwrap_or_default());
+        eprintln!("No result yet, the test started running {age} ago");
+    } else if let Some(reason) = env
+        .database
+        .cancel_reason(&test_case)
+        .context("looking up cancellation")?
+    {
+        eprintln!("No result, the last run was canceled because {reason}");
     }
+    Ok(None)
 }
 
 const NO_RESULT_FOUND_EXIT_CODE: u8 = 50;
@@ -654,10 +2057,44 @@ async fn get(
     cancellation_token: CancellationToken,
     get_args: GetArgs,
 ) -> anyhow::Result<ExitCode> {
-    let db_entry = match lookup(env, cancellation_token, &get_args.lookup_args).await? {
+    if let Some(rev) = get_args.verdict {
+        return verdict(&env, &rev).await;
+    }
+    let lookup_args = get_args
+        .lookup_args
+        .context("a test and revision are required unless --verdict is given")?;
+    let help_url = env
+        .config
+        .all_tests
+        .node(&TestName::new(lookup_args.test.clone()))
+        .and_then(|test| test.help_url.clone());
+    let db_entry = match lookup(env, cancellation_token, &lookup_args).await? {
         None => return Ok(ExitCode::from(NO_RESULT_FOUND_EXIT_CODE)),
         Some(e) => e,
     };
+    // Write to stderr so the output can just be the path, for scripting.
+    match db_entry.provenance() {
+        Some(provenance) => eprintln!("Result recorded {}", provenance.describe()),
+        None => eprintln!("Result recorded by an older version, provenance unknown"),
+    }
+    if let Some(step) = db_entry.failed_step() {
+        eprintln!("Failed at step {step}");
+    }
+    if let Some(help_url) = help_url.filter(|_| db_entry.exit_code() != 0) {
+        eprintln!("Test failed, see {help_url} for help");
+    }
+    if let Some(error) = db_entry.teardown_error() {
+        eprintln!(
+            "Teardown failed ({error}), see {}",
+            db_entry.hook_output_path("teardown").display()
+        );
+    }
+    if let Some(error) = db_entry.upload_error() {
+        eprintln!(
+            "Artifact upload failed ({error}), see {}",
+            db_entry.hook_output_path("upload").display()
+        );
+    }
     match get_args.output {
         GetOutput::Stdout => println!("{}", db_entry.stdout_path().display()),
         GetOutput::Stderr => println!("{}", db_entry.stderr_path().display()),
@@ -665,99 +2102,1095 @@ async fn get(
     Ok(ExitCode::SUCCESS)
 }
 
-async fn artifacts(
+async fn verdict(env: &Env, rev: &str) -> anyhow::Result<ExitCode> {
+    let commit = env
+        .repo
+        .rev_parse(rev)
+        .await
+        .context("error looking up commit")?
+        .ok_or_else(|| anyhow!("revision {rev:?} not found"))?;
+    let changed_files = git::changed_files(env.repo.as_ref(), &commit.hash, commit.parents.first())
+        .await
+        .context("listing changed files")?;
+    let mut statuses = Vec::new();
+    for test in env.config.all_tests.nodes() {
+        if !test.runs_on(&commit, &changed_files) {
+            continue;
+        }
+        let db_entry = env
+            .database
+            .find(&TestCase::new(commit.clone(), test.clone()))
+            .context("database lookup")?;
+        // As far as the verdict goes, a missing result is as good as one
+        // that's on its way.
+        let status = match db_entry {
+            Some(db_entry) => TestStatus::Finished(Ok(db_entry.result().clone())),
+            None => TestStatus::Enqueued,
+        };
+        statuses.push((&**test, status));
+    }
+    let verdict = Verdict::of(statuses.iter().map(|(test, status)| (*test, status)));
+    println!("{verdict}");
+    Ok(match verdict {
+        Verdict::Pass => ExitCode::SUCCESS,
+        Verdict::Fail => ExitCode::FAILURE,
+        Verdict::Pending => ExitCode::from(NO_RESULT_FOUND_EXIT_CODE),
+    })
+}
+
+async fn logs(
     env: Env,
     cancellation_token: CancellationToken,
-    lookup_args: DatabaseLookupArgs,
+    logs_args: LogsArgs,
 ) -> anyhow::Result<ExitCode> {
-    let db_entry = match lookup(env, cancellation_token, &lookup_args).await? {
+    let separate_outputs = env
+        .config
+        .tests
+        .node(&TestName::new(logs_args.lookup_args.test.clone()))
+        .is_some_and(|test| test.separate_outputs);
+    let db_entry = match lookup(env, cancellation_token, &logs_args.lookup_args).await? {
         None => return Ok(ExitCode::from(NO_RESULT_FOUND_EXIT_CODE)),
         Some(e) => e,
     };
-    println!("{}", db_entry.artifacts_dir().display());
+    let path = match (separate_outputs, logs_args.output) {
+        (false, _) => db_entry.merged_output_path(),
+        (true, GetOutput::Stdout) => db_entry.stdout_path(),
+        (true, GetOutput::Stderr) => db_entry.stderr_path(),
+    };
+    // If the test got run again while we were reading, that's the reason for
+    // any error.
+    let content = fs::read(&path);
+    db_entry.check_unchanged()?;
+    let mut content = content.with_context(|| format!("reading {}", path.display()))?;
+    if logs_args.no_ansi {
+        content = strip_ansi_escapes::strip(&content);
+    }
+    stdout()
+        .lock()
+        .write_all(&content)
+        .context("writing output")?;
     Ok(ExitCode::SUCCESS)
 }
 
-const MEGABYTE: u64 = 1024 * 1024;
+// The files holding a test's output, with the name of the stream if the test
+// has separate_outputs.
+fn stored_outputs(test: &Test, db_entry: &DatabaseEntry) -> Vec<(Option<&'static str>, PathBuf)> {
+    if test.separate_outputs {
+        vec![
+            (Some("stdout"), db_entry.stdout_path()),
+            (Some("stderr"), db_entry.stderr_path()),
+        ]
+    } else {
+        vec![(None, db_entry.merged_output_path())]
+    }
+}
 
-// Hack so we can use anyhow::Result infrastructure for convenient coding but
-// also control the exit code directly in some cases: return a result - if it's
-// an error we just use the default error exit code.
-async fn do_main() -> anyhow::Result<ExitCode> {
-    let mut logger = Logger::try_with_env_or_str("debug")?.format(detailed_format);
-    logger = match env::var_os("LIMMAT_LOGFILE") {
-        Some(path) => logger.log_to_file(
-            FileSpec::try_from(&path)
-                .with_context(|| format!("configuring logging to {:?}", path))?,
-        ),
+fn read_stripped(path: &Path) -> anyhow::Result<Vec<u8>> {
+    let content = fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+    Ok(strip_ansi_escapes::strip(&content))
+}
+
+// A single revision means just that commit, not its ancestors. None means
+// the same range "watch" would test by default.
+async fn commits_in(env: &Env, rev: Option<String>) -> anyhow::Result<Vec<Commit>> {
+    let rev = match rev {
+        Some(rev) => rev,
         None => {
-            let log_dir = PROJECT_DIRS
-                .state_dir()
-                .unwrap_or(PROJECT_DIRS.data_local_dir());
-            logger
-                .log_to_file(FileSpec::default().directory(log_dir))
-                .create_symlink(log_dir.join("latest.log"))
-                .rotate(
-                    Criterion::Size(64 * MEGABYTE),
-                    Naming::TimestampsDirect,
-                    Cleanup::KeepLogFiles(10),
-                )
+            let upstream = env.repo.upstream_ref().await?.context(
+                "no revision given, and the current branch has no upstream to default to",
+            )?;
+            format!("{upstream}..HEAD")
         }
     };
-    logger.start()?;
-
-    // Set up shutdown first, to ensure we correctly handle early signals.
-    // It seems extremely difficult to use Tokio's ctrl_c API for this correctly
-    // (It only installs the handler the first time the signal gets polled)
-    // so we just go directly for the "interrupt" thing, which might not work on
-    // Windows, not sure. (I don't think this code is likely to work on Windows
-    // anyway).
-    let cancellation_token = CancellationToken::new();
-    let mut sigint = signal(SignalKind::interrupt()).context("registering SIGINT handler")?;
-    let token = cancellation_token.clone();
-    tokio::spawn(async move {
-        sigint.recv().await;
-        token.cancel()
-    });
+    match env
+        .repo
+        .rev_parse(rev.as_str())
+        .await
+        .context("looking up revision")?
+    {
+        Some(commit) => Ok(vec![commit]),
+        None => {
+            let hashes = env.repo.rev_list(&rev).await.context("listing revisions")?;
+            env.repo
+                .rev_parse_all(&hashes)
+                .await
+                .context("looking up commits")
+        }
+    }
+}
+
+async fn grep(env: Env, args: GrepArgs) -> anyhow::Result<ExitCode> {
+    let pattern = Regex::new(&args.pattern).context("compiling pattern")?;
+    let commits = commits_in(&env, args.rev).await?;
+    let mut matched = false;
+    for commit in commits {
+        for test in env.config.all_tests.nodes() {
+            let test_case = TestCase::new(commit.clone(), test.clone());
+            let Some(db_entry) = env.database.find(&test_case).context("database lookup")? else {
+                continue;
+            };
+            for (stream, path) in stored_outputs(test, &db_entry) {
+                let name = match stream {
+                    Some(stream) => format!("{}/{stream}", test.name),
+                    None => test.name.to_string(),
+                };
+                let content = read_stripped(&path);
+                db_entry.check_unchanged()?;
+                let content = content?;
+                for (i, line) in String::from_utf8_lossy(&content).lines().enumerate() {
+                    if pattern.is_match(line) {
+                        matched = true;
+                        println!("{} {name}:{}: {line}", commit.hash.abbrev(), i + 1);
+                    }
+                }
+            }
+        }
+    }
+    Ok(if matched {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
+
+async fn slowdowns(env: Env, args: SlowdownsArgs) -> anyhow::Result<ExitCode> {
+    let mut found = false;
+    for commit in commits_in(&env, args.rev).await? {
+        for test in env.config.all_tests.nodes() {
+            let test_case = TestCase::new(commit.clone(), test.clone());
+            if let Some(slowdown) = env.database.slowdown(&test_case)? {
+                found = true;
+                println!("{} {}: {slowdown}", commit.hash.abbrev(), test.name);
+            }
+        }
+    }
+    Ok(if found {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    })
+}
+
+const PLOT_WIDTH: usize = 40;
+
+async fn metrics(env: Env, args: MetricsArgs) -> anyhow::Result<ExitCode> {
+    let (MetricsCommand::Export(range_args) | MetricsCommand::Plot(range_args)) = &args.command;
+    let mut commits = commits_in(&env, range_args.rev.clone()).await?;
+    commits.reverse();
+    // Keyed by test and metric name, in the order the commits come in.
+    let mut series: BTreeMap<(String, String), Vec<(&Commit, f64)>> = BTreeMap::new();
+    for commit in &commits {
+        for test in env.config.all_tests.nodes() {
+            let test_case = TestCase::new(commit.clone(), test.clone());
+            let Some(db_entry) = env.database.find(&test_case).context("database lookup")? else {
+                continue;
+            };
+            for (name, value) in db_entry.metrics() {
+                series
+                    .entry((test.name.to_string(), name.clone()))
+                    .or_default()
+                    .push((commit, *value));
+            }
+        }
+    }
+
+    match args.command {
+        MetricsCommand::Export(_) => {
+            println!("commit,test,metric,value");
+            for ((test, name), points) in &series {
+                for (commit, value) in points {
+                    println!("{},{test},{name},{value}", commit.hash);
+                }
+            }
+        }
+        MetricsCommand::Plot(_) => {
+            for ((test, name), points) in &series {
+                println!("{test}: {name}");
+                let max = points.iter().map(|(_, v)| *v).fold(0.0, f64::max);
+                for (commit, value) in points {
+                    let len = if max > 0.0 {
+                        (value / max * PLOT_WIDTH as f64).round().max(0.0) as usize
+                    } else {
+                        0
+                    };
+                    println!(
+                        "  {} {:PLOT_WIDTH$} {value}",
+                        commit.hash.abbrev(),
+                        "█".repeat(len)
+                    );
+                }
+            }
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn describe_outcome(db_entry: Option<&DatabaseEntry>) -> String {
+    match db_entry.map(|e| e.exit_code()) {
+        None => "no result".into(),
+        Some(0) => "passed".into(),
+        Some(code) => format!("failed (exit code {code})"),
+    }
+}
+
+// Unified diff of two files, via diff(1).
+async fn diff_files(old: (&str, &Path), new: (&str, &Path)) -> anyhow::Result<String> {
+    let output = tokio::process::Command::new("diff")
+        .args(["-u", "--label", old.0, "--label", new.0])
+        .arg(old.1)
+        .arg(new.1)
+        .output()
+        .await
+        .context("running diff")?;
+    // 1 means the files differ.
+    if !matches!(output.status.code(), Some(0 | 1)) {
+        bail!(
+            "diff failed ({}): {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    String::from_utf8(output.stdout).context("non utf-8 diff output")
+}
+
+async fn diff(env: Env, args: DiffArgs) -> anyhow::Result<ExitCode> {
+    let mut commits = Vec::new();
+    for rev in [&args.old, &args.new] {
+        commits.push(
+            env.repo
+                .rev_parse(rev.as_str())
+                .await
+                .context("error looking up commit")?
+                .ok_or_else(|| anyhow!("revision {rev:?} not found"))?,
+        );
+    }
+    let (old_commit, new_commit) = (&commits[0], &commits[1]);
+
+    let mut changed = false;
+    let mut newly_failing = false;
+    for test in env.config.all_tests.nodes() {
+        let mut entries = Vec::new();
+        for commit in [old_commit, new_commit] {
+            entries.push(
+                env.database
+                    .find(&TestCase::new(commit.clone(), test.clone()))
+                    .context("database lookup")?,
+            );
+        }
+        let (old_entry, new_entry) = (entries[0].as_ref(), entries[1].as_ref());
+        let (old_outcome, new_outcome) = (describe_outcome(old_entry), describe_outcome(new_entry));
+        if old_outcome == new_outcome {
+            continue;
+        }
+        changed = true;
+        let mut line = format!("{}: {old_outcome} -> {new_outcome}", test.name);
+        if old_entry.is_some_and(|e| e.exit_code() == 0)
+            && new_entry.is_some_and(|e| e.exit_code() != 0)
+        {
+            newly_failing = true;
+            line = format!("{line} (newly failing)").red().bold().to_string();
+        }
+        println!("{line}");
+
+        if !args.logs {
+            continue;
+        }
+        let (Some(old_entry), Some(new_entry)) = (old_entry, new_entry) else {
+            continue;
+        };
+        for ((stream, old_path), (_, new_path)) in stored_outputs(test, old_entry)
+            .into_iter()
+            .zip(stored_outputs(test, new_entry))
+        {
+            let label = |commit: &Commit| match stream {
+                Some(stream) => format!("{} {}/{stream}", commit.hash.abbrev(), test.name),
+                None => format!("{} {}", commit.hash.abbrev(), test.name),
+            };
+            let (old_file, new_file) = (NamedTempFile::new()?, NamedTempFile::new()?);
+            fs::write(old_file.path(), read_stripped(&old_path)?).context("writing temp file")?;
+            fs::write(new_file.path(), read_stripped(&new_path)?).context("writing temp file")?;
+            print!(
+                "{}",
+                diff_files(
+                    (&label(old_commit), old_file.path()),
+                    (&label(new_commit), new_file.path())
+                )
+                .await?
+            );
+        }
+    }
+    if !changed {
+        println!(
+            "No test outcomes changed between {} and {}",
+            old_commit.hash.abbrev(),
+            new_commit.hash.abbrev()
+        );
+    }
+    Ok(if newly_failing {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    })
+}
+
+fn stats(env: Env) -> anyhow::Result<ExitCode> {
+    let events = events::read_events(&env.database.base_dir.join(events::EVENTS_FILENAME))?;
+    let mut stats = Vec::new();
+    for test in env.config.all_tests.nodes() {
+        let results = env.database.all_results(&test.name)?;
+        stats.push(TestStats::new(test.name.clone(), &results, &events));
+    }
+    stats.sort_by_key(|s| Reverse(s.machine_time));
+
+    let percent = |n: usize, total: usize| match total {
+        0 => "-".to_string(),
+        _ => format!("{}%", n * 100 / total),
+    };
+    let duration = |d: Option<Duration>| d.map_or("-".to_string(), format_age);
+    let name_width = stats
+        .iter()
+        .map(|s| s.name.to_string().len())
+        .chain(["test".len()])
+        .max()
+        .unwrap_or_default();
+    println!(
+        "{:name_width$}  {:>6}  {:>12}  {:>6}  {:>11}  {:>8}  {:>10}  {:>11}",
+        "test", "runs", "machine time", "mean", "recent mean", "failures", "cache hits", "storage"
+    );
+    for s in stats {
+        let quota = env
+            .config
+            .all_tests
+            .node(&s.name)
+            .and_then(|t| t.storage_quota);
+        let storage = match quota {
+            Some(quota) => format!("{}/{}", format_size(s.storage), format_size(quota)),
+            None => format_size(s.storage),
+        };
+        println!(
+            "{:name_width$}  {:>6}  {:>12}  {:>6}  {:>11}  {:>8}  {:>10}  {:>11}",
+            s.name.to_string(),
+            s.runs,
+            format_age(s.machine_time),
+            duration(s.mean_duration),
+            duration(s.recent_mean_duration),
+            percent(s.failures, s.runs),
+            percent(s.cache_hits, s.finished),
+            storage,
+        );
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+async fn gc(env: Env, args: GcArgs) -> anyhow::Result<ExitCode> {
+    let mut freed = 0;
+    for test in env.config.all_tests.nodes() {
+        let Some(quota) = test.storage_quota else {
+            continue;
+        };
+        let mut results = env.database.all_results(&test.name)?;
+        let mut used: u64 = results.iter().map(|r| r.size).sum();
+        // Results from older versions don't say when they were recorded, they
+        // go first.
+        results.sort_by_key(|r| r.provenance.as_ref().map(|p| p.finished_at));
+        for result in results.iter().filter(|r| !r.pinned) {
+            if used <= quota {
+                break;
+            }
+            println!(
+                "{}: {} ({})",
+                test.name,
+                result.dir.display(),
+                format_size(result.size)
+            );
+            if !args.plan {
+                env.database.remove_result(result).await?;
+            }
+            used -= result.size;
+            freed += result.size;
+        }
+        if used > quota {
+            eprintln!(
+                "Warning: {} is still over its quota, its remaining results are pinned",
+                test.name
+            );
+        }
+    }
+    if let Some(max_age) = args.events_max_age {
+        let events_path = env.database.base_dir.join(events::EVENTS_FILENAME);
+        let cutoff = SystemTime::now()
+            .checked_sub(max_age)
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        let events_freed =
+            events::prune_events(&events_path, env.database.lock_method(), cutoff, args.plan)
+                .await?;
+        if events_freed > 0 {
+            println!(
+                "events older than {}: {} ({})",
+                format_age(max_age),
+                events_path.display(),
+                format_size(events_freed)
+            );
+            freed += events_freed;
+        }
+    }
+    let verb = if args.plan { "Would free" } else { "Freed" };
+    eprintln!("{verb} {}", format_size(freed));
+    Ok(ExitCode::SUCCESS)
+}
+
+fn list(env: Env) -> anyhow::Result<ExitCode> {
+    let mut tests: Vec<_> = env.config.all_tests.nodes().collect();
+    tests.sort_by_key(|test| test.name.to_string());
+    for test in tests {
+        let mut notes = Vec::new();
+        if !test.run_by_default {
+            notes.push("not run by default");
+        }
+        if test.advisory {
+            notes.push("advisory");
+        }
+        if notes.is_empty() {
+            println!("{}", test.name);
+        } else {
+            println!("{} ({})", test.name, notes.join(", "));
+        }
+        for line in test.description.iter().flat_map(|d| d.lines()) {
+            println!("    {line}");
+        }
+        if let Some(owner) = &test.owner {
+            println!("    Owner: {owner}");
+        }
+        if let Some(help_url) = &test.help_url {
+            println!("    Help: {help_url}");
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+async fn artifacts(
+    env: Env,
+    cancellation_token: CancellationToken,
+    lookup_args: DatabaseLookupArgs,
+) -> anyhow::Result<ExitCode> {
+    let db_entry = match lookup(env, cancellation_token, &lookup_args).await? {
+        None => return Ok(ExitCode::from(NO_RESULT_FOUND_EXIT_CODE)),
+        Some(e) => e,
+    };
+    println!("{}", db_entry.artifacts_dir().display());
+    Ok(ExitCode::SUCCESS)
+}
+
+// A file or directory under a test's artifacts, for "artifacts list".
+struct ArtifactNode {
+    name: String,
+    // For directories, the total of everything in them.
+    size: u64,
+    children: Vec<ArtifactNode>,
+}
+
+impl ArtifactNode {
+    fn new(path: &Path) -> anyhow::Result<Self> {
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let metadata =
+            fs::symlink_metadata(path).with_context(|| format!("reading {}", path.display()))?;
+        if !metadata.is_dir() {
+            return Ok(Self {
+                name,
+                size: metadata.len(),
+                children: Vec::new(),
+            });
+        }
+        let mut children = fs::read_dir(path)
+            .with_context(|| format!("reading {}", path.display()))?
+            .map(|entry| {
+                let entry = entry.with_context(|| format!("reading {}", path.display()))?;
+                Self::new(&entry.path())
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        children.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(Self {
+            name: name + "/",
+            size: children.iter().map(|c| c.size).sum(),
+            children,
+        })
+    }
+
+    fn print(&self, depth: usize) {
+        for child in &self.children {
+            let size = format_size(child.size);
+            println!("{:indent$}{}  {size}", "", child.name, indent = depth * 4);
+            child.print(depth + 1);
+        }
+    }
+}
+
+async fn list_artifacts(env: Env, args: ArtifactsListArgs) -> anyhow::Result<ExitCode> {
+    for commit in commits_in(&env, args.rev).await? {
+        for test in env.config.all_tests.nodes() {
+            let test_case = TestCase::new(commit.clone(), test.clone());
+            let Some(db_entry) = env.database.find(&test_case).context("database lookup")? else {
+                continue;
+            };
+            let tree = ArtifactNode::new(&db_entry.artifacts_dir());
+            db_entry.check_unchanged()?;
+            let tree = tree?;
+            // Most tests don't produce any, don't clutter the output with them.
+            if tree.children.is_empty() {
+                continue;
+            }
+            println!(
+                "{} {}  {}",
+                commit.hash.abbrev(),
+                test.name,
+                format_size(tree.size)
+            );
+            tree.print(1);
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+async fn copy_artifacts(
+    env: Env,
+    cancellation_token: CancellationToken,
+    args: ArtifactsCpArgs,
+) -> anyhow::Result<ExitCode> {
+    let db_entry = match lookup(env, cancellation_token, &args.lookup_args).await? {
+        None => return Ok(ExitCode::from(NO_RESULT_FOUND_EXIT_CODE)),
+        Some(e) => e,
+    };
+    fs::create_dir_all(&args.dest).with_context(|| format!("creating {}", args.dest.display()))?;
+    // The trailing "." copies what's in the directory rather than the directory
+    // itself.
+    let copied = tokio::process::Command::new("cp")
+        .arg("-a")
+        .arg(db_entry.artifacts_dir().join("."))
+        .arg(&args.dest)
+        .execute()
+        .await;
+    db_entry.check_unchanged()?;
+    copied.context("copying artifacts")?;
+    Ok(ExitCode::SUCCESS)
+}
+
+async fn repro(
+    env: Env,
+    cancellation_token: CancellationToken,
+    repro_args: ReproArgs,
+) -> anyhow::Result<ExitCode> {
+    let db_entry = match lookup(env, cancellation_token, &repro_args.lookup_args).await? {
+        None => return Ok(ExitCode::from(NO_RESULT_FOUND_EXIT_CODE)),
+        Some(e) => e,
+    };
+    let mut snapshot = db_entry
+        .job_snapshot()?
+        .context("result was recorded by an older version, which didn't record the job")?;
+    // Write to stderr so the output can just be the command, for scripting.
+    if let Some(describe) = &snapshot.git_describe {
+        eprintln!("The job ran with {describe} checked out");
+    }
+    if let Some(cwd) = repro_args.cwd {
+        snapshot.cwd = cwd
+            .canonicalize()
+            .with_context(|| format!("resolving {}", cwd.display()))?;
+    } else if !snapshot.cwd.is_dir() {
+        let message = format!(
+            "{} no longer exists, use --cwd to run somewhere else",
+            snapshot.cwd.display()
+        );
+        if repro_args.exec {
+            bail!(message);
+        }
+        eprintln!("Warning: {message}");
+    }
+    if !repro_args.exec {
+        println!("{}", snapshot.shell_command());
+        return Ok(ExitCode::SUCCESS);
+    }
+    let status = tokio::process::Command::new(&snapshot.argv[0])
+        .args(&snapshot.argv[1..])
+        .current_dir(&snapshot.cwd)
+        .env_clear()
+        .envs(&snapshot.env)
+        .status()
+        .await
+        .context("running job command")?;
+    Ok(ExitCode::from(status.code_not_killed()? as u8))
+}
+
+async fn shell(
+    env: Env,
+    cancellation_token: CancellationToken,
+    shell_args: ShellArgs,
+) -> anyhow::Result<ExitCode> {
+    let test_name = TestName::new(shell_args.test);
+    let commit = env
+        .repo
+        .rev_parse(&shell_args.rev)
+        .await
+        .context("error looking up commit")?
+        .ok_or_else(|| anyhow!("revision {:?} not found", shell_args.rev))?;
+    let tests: Vec<&Arc<Test>> = env
+        .config
+        .tests
+        .top_down_from(&test_name)
+        .ok_or(anyhow!("no such test {:?}", test_name.to_string()))?
+        .collect();
+    let test = tests[0].clone();
+    let dep_tests = tests[1..].to_vec();
+
+    let mut dep_db_entries = HashMap::new();
+    if !dep_tests.is_empty() {
+        eprintln!("Running {} dependency jobs...", dep_tests.len());
+        dep_db_entries =
+            ensure_tests_run(&env, cancellation_token.child_token(), dep_tests, &commit).await?;
+        eprintln!("Dependency jobs complete.");
+    }
+    dep_db_entries.retain(|name, _| !test.stage_gate.contains(name));
+
+    // Even tests that run in the main worktree get one, the user is going to
+    // be messing around in it.
+    for task in worktree_creation_tasks(&env, &cancellation_token, 1, None)? {
+        task.await?;
+    }
+    let mut needs_resources = test.needs_resources.clone();
+    needs_resources.insert(ResourceKey::Worktree, 1);
+    let resources = env
+        .config
+        .resource_pools
+        .get_with_priority(
+            needs_resources,
+            test.resource_alternatives.clone(),
+            &Priority::default(),
+        )
+        .await;
+    let job = TestJobBuilder::new(
+        cancellation_token,
+        TestCase::new(commit, test),
+        Arc::new(base_job_env(env.repo.path(), &env.config.source_path)),
+        Vec::new(), // wait_for
+    )
+    .build();
+    let shell = env::var_os("SHELL").unwrap_or_else(|| "sh".into());
+    eprintln!(
+        "Starting {}, exit it when you're done",
+        shell.to_string_lossy()
+    );
+    let result = job
+        .run_interactive(&shell, &resources, &dep_db_entries)
+        .await;
+    drop(resources);
+    join_all(
+        env.config
+            .resource_pools
+            .try_remove_worktrees()
+            .map(|w| w.cleanup()),
+    )
+    .await;
+    Ok(ExitCode::from(result? as u8))
+}
+
+async fn pin(
+    env: Env,
+    cancellation_token: CancellationToken,
+    pin_args: PinArgs,
+) -> anyhow::Result<ExitCode> {
+    let db_entry = match lookup(env, cancellation_token, &pin_args.lookup_args).await? {
+        None => return Ok(ExitCode::from(NO_RESULT_FOUND_EXIT_CODE)),
+        Some(e) => e,
+    };
+    db_entry.set_pinned(!pin_args.unpin)?;
+    Ok(ExitCode::SUCCESS)
+}
+
+async fn attest(
+    env: Env,
+    cancellation_token: CancellationToken,
+    attest_args: AttestArgs,
+) -> anyhow::Result<ExitCode> {
+    let lookup_args = &attest_args.lookup_args;
+    let commit = env
+        .repo
+        .rev_parse(&lookup_args.rev)
+        .await
+        .context("error looking up commit")?
+        .ok_or_else(|| anyhow!("revision {:?} not found", lookup_args.rev))?;
+    let config_hash = env
+        .config
+        .tests
+        .node(&TestName::new(lookup_args.test.clone()))
+        .ok_or_else(|| anyhow!("no such test {:?}", lookup_args.test))?
+        .config_hash
+        .clone();
+    let db_entry = match lookup(env, cancellation_token, lookup_args).await? {
+        None => return Ok(ExitCode::from(NO_RESULT_FOUND_EXIT_CODE)),
+        Some(e) => e,
+    };
+    if db_entry.exit_code() != 0 {
+        bail!(
+            "test {:?} didn't pass (exit code {})",
+            lookup_args.test,
+            db_entry.exit_code()
+        );
+    }
+    let attestation = attest::Attestation {
+        test: lookup_args.test.clone(),
+        commit: commit.hash.to_string(),
+        tree: commit.tree.to_string(),
+        config_hash,
+        host: hostname(),
+    };
+    attest::write_signed(&attestation, &attest_args.output, &attest_args.key).await?;
+    Ok(ExitCode::SUCCESS)
+}
+
+async fn verify_attestation(env: Env, args: VerifyAttestationArgs) -> anyhow::Result<ExitCode> {
+    let (attestation, principal) =
+        attest::read_verified(&args.attestation, &args.allowed_signers).await?;
+    let test = env
+        .config
+        .tests
+        .node(&TestName::new(attestation.test.clone()))
+        .ok_or_else(|| anyhow!("no such test {:?}", attestation.test))?;
+    if test.config_hash != attestation.config_hash {
+        bail!(
+            "attestation is for a different definition of test {:?}",
+            attestation.test
+        );
+    }
+    if let Some(rev) = &args.rev {
+        let commit = env
+            .repo
+            .rev_parse(rev)
+            .await
+            .context("error looking up commit")?
+            .ok_or_else(|| anyhow!("revision {rev:?} not found"))?;
+        if commit.tree.to_string() != attestation.tree {
+            bail!(
+                "attestation is for tree {}, not {}",
+                attestation.tree,
+                commit.tree
+            );
+        }
+    }
+    println!(
+        "{} passed for tree {} (commit {}) on {}, signed by {principal}",
+        attestation.test, attestation.tree, attestation.commit, attestation.host
+    );
+    Ok(ExitCode::SUCCESS)
+}
+
+async fn explain_cache(env: Env, args: ExplainCacheArgs) -> anyhow::Result<ExitCode> {
+    let test_name = TestName::new(args.test.clone());
+    let test = env
+        .config
+        .all_tests
+        .node(&test_name)
+        .ok_or_else(|| anyhow!("no such test {:?}", args.test))?;
+    let Some(snapshots) = env.database.config_snapshots(&test_name)? else {
+        println!("No config recorded for {test_name}, it hasn't been run yet");
+        return Ok(ExitCode::SUCCESS);
+    };
+    let age =
+        |snapshot: &ConfigSnapshot| format_age(snapshot.recorded_at.elapsed().unwrap_or_default());
+    let (old, new) = if snapshots.current.config_hash != test.config_hash {
+        println!("Config of {test_name} differs from the one it was last run with:");
+        (
+            &snapshots.current.config,
+            &env.config.test_configs[&test_name],
+        )
+    } else if let Some(previous) = &snapshots.previous {
+        println!(
+            "Config of {test_name} changed {} ago:",
+            age(&snapshots.current)
+        );
+        (&previous.config, &snapshots.current.config)
+    } else {
+        println!(
+            "Config of {test_name} hasn't changed since it was first run {} ago",
+            age(&snapshots.current)
+        );
+        return Ok(ExitCode::SUCCESS);
+    };
+    let changes = config::diff_test_configs(old, new);
+    if changes.is_empty() {
+        println!("  No fields changed, so the config of a dependency must have");
+    }
+    for change in changes {
+        println!("  {change}");
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+async fn ctl(socket: &Path, ctl_args: CtlArgs) -> anyhow::Result<ExitCode> {
+    let request = match ctl_args.command {
+        CtlCommand::Status => ctl::Request::Status,
+        CtlCommand::Cancel(CtlCaseArgs { test, rev }) => ctl::Request::Cancel { test, rev },
+        CtlCommand::Rerun(CtlCaseArgs { test, rev }) => ctl::Request::Rerun { test, rev },
+        CtlCommand::Bump(CtlCaseArgs { test, rev }) => ctl::Request::Bump { test, rev },
+        CtlCommand::Run(CtlCaseArgs { test, rev }) => ctl::Request::Run { test, rev },
+        CtlCommand::Release(CtlCaseArgs { test, rev }) => ctl::Request::Release { test, rev },
+    };
+    match ctl::request(socket, &request).await? {
+        ctl::Response::Status { cases } => print_cases(&cases),
+        ctl::Response::Done => (),
+        ctl::Response::Error { message } => bail!("{message}"),
+        ctl::Response::Frame { .. } => bail!("unexpected frame response"),
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn print_cases(cases: &[ctl::CaseStatus]) {
+    for case in cases {
+        println!(
+            "{} {}: {}{}",
+            &case.commit[..min(12, case.commit.len())],
+            case.test,
+            case.status,
+            if case.advisory && !case.succeeded {
+                " (advisory)"
+            } else {
+                ""
+            }
+        );
+    }
+}
+
+async fn wait(
+    socket: &Path,
+    wait_args: WaitArgs,
+    tests: Vec<String>,
+    skip_tests: Vec<String>,
+) -> anyhow::Result<ExitCode> {
+    let request = ctl::Request::Wait {
+        rev: wait_args.rev,
+        tests,
+        skip_tests,
+    };
+    match ctl::request(socket, &request).await? {
+        ctl::Response::Status { cases } => {
+            print_cases(&cases);
+            Ok(if !cases.iter().any(|case| case.failed()) {
+                ExitCode::SUCCESS
+            } else {
+                ExitCode::FAILURE
+            })
+        }
+        ctl::Response::Error { message } => bail!("{message}"),
+        response => bail!("unexpected response {response:?}"),
+    }
+}
+
+async fn attach(socket: &Path, cancellation_token: CancellationToken) -> anyhow::Result<()> {
+    let mut frames = pin!(ctl::attach(socket).await?);
+    let size_watcher = TerminalSizeWatcher::new()?;
+    let mut resizes = pin!(size_watcher.resizes());
+    let mut screen = ui::Screen::new(stdout());
+    let mut frame = None;
+    loop {
+        select! {
+            biased;
+
+            _ = cancellation_token.cancelled() => return Ok(()),
+            next = frames.next() => match next {
+                Some(next) => frame = Some(next?),
+                None => break,
+            },
+            _ = resizes.next() => (),
+        }
+        if let Some(frame) = &frame {
+            screen
+                .paint(frame.text.clone(), &frame.home_url, &size_watcher.size())
+                .context("error painting status to stdout")?;
+        }
+    }
+    // Break out of the TUI.
+    drop(screen);
+    eprintln!("Watch session ended");
+    Ok(())
+}
+
+const MEGABYTE: u64 = 1024 * 1024;
+
+// Hack so we can use anyhow::Result infrastructure for convenient coding but
+// also control the exit code directly in some cases: return a result - if it's
+// an error we just use the default error exit code.
+async fn do_main() -> anyhow::Result<ExitCode> {
+    let mut logger = Logger::try_with_env_or_str("debug")?.format(detailed_format);
+    logger = match env::var_os("LIMMAT_LOGFILE") {
+        Some(path) => logger.log_to_file(
+            FileSpec::try_from(&path)
+                .with_context(|| format!("configuring logging to {:?}", path))?,
+        ),
+        None => {
+            let log_dir = PROJECT_DIRS
+                .state_dir()
+                .unwrap_or(PROJECT_DIRS.data_local_dir());
+            logger
+                .log_to_file(FileSpec::default().directory(log_dir))
+                .create_symlink(log_dir.join("latest.log"))
+                .rotate(
+                    Criterion::Size(64 * MEGABYTE),
+                    Naming::TimestampsDirect,
+                    Cleanup::KeepLogFiles(10),
+                )
+        }
+    };
+    logger.start()?;
+
+    // Set up shutdown first, to ensure we correctly handle early signals.
+    // It seems extremely difficult to use Tokio's ctrl_c API for this correctly
+    // (It only installs the handler the first time the signal gets polled)
+    // so we just go directly for the "interrupt" thing, which might not work on
+    // Windows, not sure. (I don't think this code is likely to work on Windows
+    // anyway).
+    let cancellation_token = CancellationToken::new();
+    let mut sigint = signal(SignalKind::interrupt()).context("registering SIGINT handler")?;
+    // This is how you stop a --daemon.
+    let mut sigterm = signal(SignalKind::terminate()).context("registering SIGTERM handler")?;
+    let token = cancellation_token.clone();
+    tokio::spawn(async move {
+        select! {
+            _ = sigint.recv() => (),
+            _ = sigterm.recv() => (),
+        }
+        token.cancel()
+    });
+
+    let args = Args::parse();
+    debug!("args: {:?}", &args);
+    // This also stops Git from colouring its output.
+    if args.no_color {
+        colored::control::set_override(false);
+    }
+
+    let (repo, git_common_dir) =
+        open_repo(args.repo.to_owned().into(), args.git_binary.clone().into()).await?;
+    let ctl_socket = args
+        .ctl_socket
+        .unwrap_or_else(|| git_common_dir.join("limmat.sock"));
+    match args.command {
+        Command::Ctl(ctl_args) => return ctl(&ctl_socket, ctl_args).await,
+        Command::Rerun(case_args) => {
+            let ctl_args = CtlArgs {
+                command: CtlCommand::Rerun(case_args),
+            };
+            return ctl(&ctl_socket, ctl_args).await;
+        }
+        Command::Attach => {
+            attach(&ctl_socket, cancellation_token).await?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        Command::Wait(wait_args) => {
+            return wait(&ctl_socket, wait_args, args.tests, args.skip_test).await
+        }
+        _ => (),
+    }
+
+    // Only the commands that test ranges of commits care about the history.
+    let tests_ranges = matches!(
+        args.command,
+        Command::Watch(_) | Command::Test(_) | Command::RunAcross(_)
+    );
+    if tests_ranges
+        && repo
+            .is_shallow()
+            .await
+            .context("checking for shallow clone")?
+    {
+        if args.unshallow {
+            eprintln!("Fetching full history of shallow clone...");
+            repo.unshallow().await?;
+        } else {
+            eprintln!(
+                "Warning: repository is a shallow clone, commits at the boundary will look \
+                like root commits. Use --unshallow to fetch the full history."
+            );
+        }
+    }
 
-    let args = Args::parse();
-    debug!("args: {:?}", &args);
     let config_path = find_config(&args.config)?;
-    let config_content = fs::read_to_string(&config_path).context("couldn't read config")?;
-    debug!("config:\n{}", &config_content);
-    let config: Config = toml::from_str(&config_content).context("couldn't parse config")?;
     let config = ParsedConfig::new(
-        config,
+        read_config(&config_path)?,
         config_path,
         args.skip_test.iter().map(|s| s.as_str()),
         args.tests.iter().map(|s| s.as_str()),
     )?;
 
-    let repo = git::PersistentWorktree {
-        path: args.repo.to_owned().into(),
-        git_binary: args.git_binary.clone().into(),
-    };
-    // Check repo is valid.
-    repo.git_common_dir()
-        .await
-        .context(format!("opening repo {}", args.repo))?;
-
+    let mut database = Database::create_or_open(&args.result_db)?;
+    if let Some(method) = config.database_locking {
+        database = database.with_lock_method(method);
+    }
+    let persistent = args.persistent_worktree_dir.is_some();
+    let parent_dir = args
+        .persistent_worktree_dir
+        .or(args.worktree_dir)
+        .or_else(|| config.worktree_dir.clone())
+        .unwrap_or_else(env::temp_dir);
     let env = Env {
         config,
         repo: Arc::new(repo),
-        database: Arc::new(Database::create_or_open(&args.result_db)?),
+        database: Arc::new(database),
         worktree_builder: WorktreeBuilder {
             prefix: args.worktree_prefix.into(),
-            parent_dir: args.worktree_dir.into(),
+            persistent,
+            parent_dir,
         },
+        git_common_dir,
+        ctl_socket,
     };
+    if matches!(
+        args.command,
+        Command::Watch(_) | Command::Test(_) | Command::Gate(_) | Command::RunAcross(_)
+    ) {
+        // This walks the whole database, it shouldn't hold anything up.
+        tokio::spawn(recover_orphans(env.database.clone()));
+        tokio::spawn(prune_job_branches(env.repo.clone()));
+    }
 
     match args.command {
         Command::Get(get_args) => get(env, cancellation_token, get_args).await,
-        Command::Artifacts(lookup_args) => artifacts(env, cancellation_token, lookup_args).await,
+        Command::Logs(logs_args) => logs(env, cancellation_token, logs_args).await,
+        Command::Grep(grep_args) => grep(env, grep_args).await,
+        Command::Diff(diff_args) => diff(env, diff_args).await,
+        Command::Stats => stats(env),
+        Command::Gc(gc_args) => gc(env, gc_args).await,
+        Command::List => list(env),
+        Command::Slowdowns(slowdowns_args) => slowdowns(env, slowdowns_args).await,
+        Command::Metrics(metrics_args) => metrics(env, metrics_args).await,
+        Command::Events(events_args) => {
+            let path = env.database.base_dir.join(events::EVENTS_FILENAME);
+            events::print_events(&path, events_args.follow, cancellation_token).await?;
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::Artifacts(args) => match args.command {
+            Some(ArtifactsCommand::List(list_args)) => list_artifacts(env, list_args).await,
+            Some(ArtifactsCommand::Cp(cp_args)) => {
+                copy_artifacts(env, cancellation_token, cp_args).await
+            }
+            None => {
+                let lookup_args = args
+                    .lookup_args
+                    .context("expected a test and a revision, or a subcommand")?;
+                artifacts(env, cancellation_token, lookup_args).await
+            }
+        },
+        Command::Repro(repro_args) => repro(env, cancellation_token, repro_args).await,
+        Command::Shell(shell_args) => shell(env, cancellation_token, shell_args).await,
+        Command::Pin(pin_args) => pin(env, cancellation_token, pin_args).await,
+        Command::Attest(attest_args) => attest(env, cancellation_token, attest_args).await,
+        Command::VerifyAttestation(args) => verify_attestation(env, args).await,
+        Command::Watch(watch_args) => watch(env, cancellation_token, watch_args).await,
+        Command::Gate(args) => gate(env, cancellation_token, args).await,
+        Command::InstallHooks(args) => install_hooks(env, args).await,
+        Command::RunAcross(args) => run_across(env, cancellation_token, args).await,
+        Command::ExplainCache(args) => explain_cache(env, args).await,
         c => {
             match c {
-                Command::Watch(watch_args) => watch(env, cancellation_token, watch_args).await,
                 Command::Test(ref test_args) => test(env, cancellation_token, test_args).await,
                 _ => panic!("wtf"),
             }?;
@@ -766,13 +3199,49 @@ async fn do_main() -> anyhow::Result<ExitCode> {
     }
 }
 
+// Results that a crashed instance was in the middle of running would otherwise
+// hang around half-written. This runs in the background, so it logs instead of
+// printing over the status view.
+async fn recover_orphans(database: Arc<Database>) {
+    let recovered = database.recover_orphans().await;
+    if recovered.adopted > 0 {
+        info!(
+            "Kept {} results that a crashed instance finished",
+            recovered.adopted
+        );
+    }
+    if recovered.removed > 0 {
+        info!(
+            "Removed {} results that a crashed instance didn't finish, they'll be run again",
+            recovered.removed
+        );
+    }
+}
+
+// Branches that a crashed instance's jobs had checked out would otherwise hang
+// around forever. Like recover_orphans this runs in the background.
+async fn prune_job_branches(repo: Arc<PersistentWorktree>) {
+    match git::prune_job_branches(repo.as_ref()).await {
+        Ok(0) => (),
+        Ok(pruned) => info!("Deleted {pruned} branches that a crashed instance left behind"),
+        Err(e) => warn!("Pruning job branches: {e:#}"),
+    }
+}
+
+// How long child processes get to go away once we're done, before they get
+// killed.
+const CHILD_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
 #[tokio::main]
 async fn main() -> ExitCode {
-    match do_main().await {
+    let code = match do_main().await {
         Err(err) => {
             eprintln!("Fatal error: {:#}", err);
             ExitCode::FAILURE
         }
         Ok(code) => code,
-    }
+    };
+    // Whatever happened, don't leave processes behind.
+    process::reap_children(CHILD_GRACE_PERIOD).await;
+    code
 }
@@ -0,0 +1,93 @@
+// Records results in Git notes, so they show up in "git log --notes=limmat"
+// and can travel along with the commits when the notes ref is pushed.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::Arc,
+};
+
+use itertools::Itertools as _;
+use log::warn;
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+
+use crate::{
+    git::{CommitHash, Worktree},
+    test::{Notification, ReportedTests, TestInconclusive, TestName, TestStatus},
+    util::ResultExt as _,
+};
+
+pub const NOTES_REF: &str = "limmat";
+
+// Write a note for each commit whenever all of its tests have finished.
+// Returns once the channel closes, i.e. when the test manager is dropped,
+// so the last results don't get lost on shutdown.
+pub async fn write_notes(
+    repo: &impl Worktree,
+    reported_tests: ReportedTests,
+    mut notifs: Receiver<Arc<Notification>>,
+) {
+    let mut statuses: HashMap<CommitHash, BTreeMap<TestName, TestStatus>> = HashMap::new();
+    // What was expected for each commit the last time it was in the range. The
+    // range can change before we get round to a notification, e.g. it's
+    // emptied on shutdown right after the last test finishes.
+    let mut expected_tests: HashMap<CommitHash, HashSet<TestName>> = HashMap::new();
+    loop {
+        let notif = match notifs.recv().await {
+            Ok(notif) => notif,
+            Err(RecvError::Lagged(num_dropped)) => {
+                warn!("Dropped {num_dropped} notifications, git notes might be missing results");
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        let commit_hash = &notif.test_case.commit_hash;
+        let commit_statuses = statuses.entry(commit_hash.clone()).or_default();
+        commit_statuses.insert(notif.test_case.test.name.clone(), notif.status.clone());
+        if let Some(expected) = reported_tests.get(commit_hash) {
+            expected_tests.insert(commit_hash.clone(), expected);
+        }
+        let Some(expected) = expected_tests.get(commit_hash) else {
+            continue;
+        };
+        // Canceled tests will get run again, don't clobber the results of an
+        // earlier complete run with them.
+        let complete = expected.iter().all(|name| match commit_statuses.get(name) {
+            Some(TestStatus::Finished(memory)) => {
+                !matches!(memory, Err(TestInconclusive::Canceled))
+            }
+            Some(TestStatus::NotApplicable(_)) => true,
+            _ => false,
+        });
+        if !complete {
+            continue;
+        }
+        // Tests that weren't run don't need mentioning. If that's all of them,
+        // there's no note.
+        let message = commit_statuses
+            .iter()
+            .filter(|(name, _)| expected.contains(*name))
+            .filter(|(_, status)| !matches!(status, TestStatus::NotApplicable(_)))
+            .map(|(name, status)| format!("{name}: {status}"))
+            .join("\n");
+        if message.is_empty() {
+            continue;
+        }
+        update_note(repo, commit_hash, &message)
+            .await
+            .or_log_error("writing git note");
+    }
+}
+
+async fn update_note(
+    repo: &impl Worktree,
+    commit_hash: &CommitHash,
+    message: &str,
+) -> anyhow::Result<()> {
+    // Writing a note creates a commit even if nothing changed, and after a
+    // restart we'll see all the same results again from the cache.
+    let note = repo.note(NOTES_REF, commit_hash).await?;
+    if note.is_some_and(|note| note.trim_end() == message) {
+        return Ok(());
+    }
+    repo.set_note(NOTES_REF, commit_hash, message).await
+}
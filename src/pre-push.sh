@@ -0,0 +1,39 @@
+#!/bin/sh
+# Generated by Limmat. Refuses to push commits that fail the tests in the
+# Limmat config. Set the limmat.gate Git config to "warn" to push anyway after
+# listing the failures, or to "off" to skip this, for example with "git -c
+# limmat.gate=off push".
+
+remote="$1"
+mode=$(git config limmat.gate || echo @MODE@)
+if [ "$mode" = off ]; then
+    exit 0
+fi
+zero=$(git hash-object --stdin </dev/null | tr '0-9a-f' '0')
+
+while read -r local_ref local_sha remote_ref remote_sha; do
+    if [ "$local_sha" = "$zero" ]; then
+        # Deleting a ref, nothing to test.
+        continue
+    fi
+    if [ "$remote_sha" = "$zero" ]; then
+        # New ref, test whatever the remote doesn't have yet.
+        commits=$(git rev-list "$local_sha" --not --remotes="$remote")
+    else
+        commits="$remote_sha..$local_sha"
+    fi
+    if [ -z "$commits" ]; then
+        continue
+    fi
+    echo "limmat: checking $local_ref before pushing to $remote_ref" >&2
+    # stdin is the list of refs being pushed, don't let limmat eat it.
+    # shellcheck disable=SC2086
+    if ! limmat gate @GATE_ARGS@ $commits </dev/null; then
+        if [ "$mode" = warn ]; then
+            echo "limmat: pushing anyway, limmat.gate is \"warn\"" >&2
+        else
+            echo "limmat: not pushing, use \"git -c limmat.gate=off push\" to override" >&2
+            exit 1
+        fi
+    fi
+done
@@ -1,9 +1,27 @@
 use anyhow::{anyhow, Context};
+use itertools::Itertools as _;
+use log::warn;
+use nix::sys::signal::{kill, killpg, Signal};
+use nix::unistd::Pid;
+use parking_lot::Mutex;
+use std::collections::HashMap;
 use std::ffi::OsStr;
 use std::os::unix::ffi::OsStrExt;
 use std::os::unix::process::ExitStatusExt as _;
-use std::process::{Command as SyncCommand, ExitStatus, Output};
-use tokio::process::Command;
+use std::process::{Command as SyncCommand, ExitStatus, Output, Stdio};
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+use std::{fs, io, iter};
+use tokio::process::{Child, ChildStdin, Command};
+use tokio::time::sleep;
+
+use crate::util::shell_quote;
+
+// Every child process we've spawned that hasn't been waited for, so that on
+// shutdown we can make sure none get left behind.
+static CHILDREN: LazyLock<Mutex<HashMap<u32, ChildInfo>>> = LazyLock::new(Default::default);
+
+const REAP_POLL_INTERVAL: Duration = Duration::from_millis(50);
 
 pub trait ExitStatusExt {
     // Returns exit code, fails verbosely if the process was killed by a signal.
@@ -57,14 +75,144 @@ impl OutputExt for Output {
 pub trait CommandExt {
     // Run a command and fail informatively if anything at all goes wrong.
     async fn execute(&mut self) -> anyhow::Result<Output>;
+    // Like output, but the child is a TrackedChild. Unlike output, this
+    // captures stdout and stderr even if they were set to something else, and
+    // stdin is inherited unless the caller set it.
+    async fn tracked_output(&mut self) -> io::Result<Output>;
 }
 
 impl CommandExt for Command {
     async fn execute(&mut self) -> anyhow::Result<Output> {
-        let output = self.output().await.context("couldn't run command")?;
+        let output = self
+            .tracked_output()
+            .await
+            .context("couldn't run command")?;
         output.ok()?;
         Ok(output)
     }
+
+    async fn tracked_output(&mut self) -> io::Result<Output> {
+        self.stdout(Stdio::piped()).stderr(Stdio::piped());
+        TrackedChild::spawn(self)?.wait_with_output().await
+    }
+}
+
+// A child process that's in the registry until it's been waited for. If it's
+// dropped before that it stays there, until the process is gone.
+#[derive(Debug)]
+pub struct TrackedChild {
+    child: Child,
+    pid: Option<u32>,
+}
+
+impl TrackedChild {
+    pub fn spawn(cmd: &mut Command) -> io::Result<Self> {
+        let child = cmd.spawn()?;
+        let pid = child.id();
+        if let Some(pid) = pid {
+            let cmd = cmd.as_std();
+            let command_line = iter::once(cmd.get_program())
+                .chain(cmd.get_args())
+                .map(|arg| shell_quote(&arg.to_string_lossy()))
+                .join(" ");
+            CHILDREN.lock().insert(
+                pid,
+                ChildInfo {
+                    command_line,
+                    start_time: start_time(pid),
+                },
+            );
+        }
+        Ok(Self { child, pid })
+    }
+
+    pub fn id(&self) -> Option<u32> {
+        self.child.id()
+    }
+
+    pub fn take_stdin(&mut self) -> Option<ChildStdin> {
+        self.child.stdin.take()
+    }
+
+    pub async fn wait(&mut self) -> io::Result<ExitStatus> {
+        let status = self.child.wait().await?;
+        if let Some(pid) = self.pid.take() {
+            CHILDREN.lock().remove(&pid);
+        }
+        Ok(status)
+    }
+
+    pub async fn wait_with_output(self) -> io::Result<Output> {
+        let output = self.child.wait_with_output().await?;
+        if let Some(pid) = self.pid {
+            CHILDREN.lock().remove(&pid);
+        }
+        Ok(output)
+    }
+}
+
+#[derive(Debug)]
+struct ChildInfo {
+    command_line: String,
+    // Tells the child apart from a process that reused its PID after it was
+    // reaped.
+    start_time: Option<u64>,
+}
+
+// The fields of /proc/<pid>/stat after the command name, which is in parens
+// and might contain anything. The first one is the state.
+fn stat_fields(pid: u32) -> Option<Vec<String>> {
+    let stat = fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    let (_, rest) = stat.rsplit_once(')')?;
+    Some(rest.split_whitespace().map(str::to_owned).collect())
+}
+
+// When the process started, in clock ticks since boot.
+fn start_time(pid: u32) -> Option<u64> {
+    stat_fields(pid)?.get(19)?.parse().ok()
+}
+
+// Whether the child still exists and hasn't exited. Zombies don't count,
+// somebody will get round to reaping them. Nor does a process that only has
+// the same PID.
+fn running(pid: u32, child: &ChildInfo) -> bool {
+    let Some(fields) = stat_fields(pid) else {
+        return false;
+    };
+    fields.first().is_none_or(|state| state != "Z")
+        && child.start_time.is_some()
+        && fields.get(19).and_then(|t| t.parse().ok()) == child.start_time
+}
+
+// Wait for the child processes to be gone, then SIGKILL any that are left after
+// the grace period and log them.
+pub async fn reap_children(grace_period: Duration) {
+    reap(&CHILDREN, grace_period).await
+}
+
+async fn reap(children: &Mutex<HashMap<u32, ChildInfo>>, grace_period: Duration) {
+    let deadline = Instant::now() + grace_period;
+    loop {
+        children.lock().retain(|&pid, child| running(pid, child));
+        if children.lock().is_empty() || Instant::now() >= deadline {
+            break;
+        }
+        sleep(REAP_POLL_INTERVAL).await;
+    }
+    for (pid, child) in children.lock().drain() {
+        // Check again right before killing, in case it exited since.
+        if !running(pid, &child) {
+            continue;
+        }
+        warn!(
+            "Child process {pid} still running at shutdown, killing it: {}",
+            child.command_line
+        );
+        let pid = Pid::from_raw(pid.try_into().unwrap());
+        // Git and tests get their own process groups.
+        let _ = killpg(pid, Signal::SIGKILL);
+        let _ = kill(pid, Signal::SIGKILL);
+    }
 }
 
 pub trait SyncCommandExt {
@@ -76,3 +224,77 @@ impl SyncCommandExt for SyncCommand {
         self.output().context("couldn't run command")?.ok()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_reap() {
+        let mut exits = Command::new("true").spawn().unwrap();
+        let mut hangs = Command::new("sleep")
+            .arg("60")
+            .process_group(0)
+            .spawn()
+            .unwrap();
+        let info = |pid: u32, command_line: &str| {
+            (
+                pid,
+                ChildInfo {
+                    command_line: command_line.to_owned(),
+                    start_time: start_time(pid),
+                },
+            )
+        };
+        let children = Mutex::new(HashMap::from([
+            info(exits.id().unwrap(), "true"),
+            info(hangs.id().unwrap(), "sleep 60"),
+        ]));
+        exits.wait().await.unwrap();
+
+        reap(&children, Duration::from_millis(200)).await;
+        assert!(children.lock().is_empty());
+        assert_eq!(
+            hangs.wait().await.unwrap().signal(),
+            Some(Signal::SIGKILL as i32)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reap_ignores_reused_pid() {
+        let mut other = Command::new("sleep")
+            .arg("60")
+            .process_group(0)
+            .spawn()
+            .unwrap();
+        let pid = other.id().unwrap();
+        // Like a child of ours that was reaped, and then its PID was reused.
+        let children = Mutex::new(HashMap::from([(
+            pid,
+            ChildInfo {
+                command_line: "true".to_owned(),
+                start_time: start_time(pid).map(|t| t + 1),
+            },
+        )]));
+
+        reap(&children, Duration::from_millis(200)).await;
+        assert!(children.lock().is_empty());
+        assert!(other.try_wait().unwrap().is_none());
+        other.kill().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_tracked_output() {
+        let output = Command::new("echo")
+            .arg("hello")
+            .tracked_output()
+            .await
+            .unwrap();
+        assert_eq!(output.stdout, b"hello\n");
+        // Other tests might be running commands, but not this one.
+        assert!(!CHILDREN
+            .lock()
+            .values()
+            .any(|child| child.command_line == "'echo' 'hello'"));
+    }
+}
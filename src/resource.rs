@@ -1,12 +1,20 @@
-use std::collections::HashMap;
-use std::mem::ManuallyDrop;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::mem::{self, ManuallyDrop};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicI32, AtomicU64, Ordering};
+use std::sync::Arc;
 
 use async_condvar_fair::Condvar;
 #[allow(unused_imports)]
 use log::debug;
 use parking_lot::Mutex;
 
-use crate::git::TempWorktree;
+use crate::{
+    git::{CommitHash, TempWorktree, Worktree as _},
+    gpu::Gpu,
+    test::TestName,
+};
 
 // Key to identify the type of resource that can be put into the pool.
 #[derive(Debug, Hash, PartialEq, Eq, Clone)]
@@ -15,15 +23,30 @@ pub enum ResourceKey {
     // actually care about worktrees so probably we should actually just be
     // generic over the key type.
     Worktree,
+    // CPU cores, for tests that reserve them.
+    Cpu,
+    // Memory in MiB, a quantity.
+    Memory,
+    Gpu,
     UserToken(String), // Resource defined by the user.
 }
 
+impl ResourceKey {
+    // Resources that belong to the machine rather than to a repository, see
+    // Pools::with_machine.
+    fn machine_wide(&self) -> bool {
+        matches!(self, Self::Cpu | Self::Memory | Self::Gpu)
+    }
+}
+
 // Resource that can be put in the pool. This is another thing where we leak the
 // details of the user into this code, we probably shouldn't know about
 // TempWorktree in here.
 #[derive(Debug)]
 pub enum Resource {
     Worktree(TempWorktree),
+    Cpu(usize),
+    Gpu(Gpu),
     UserToken(String),
 }
 
@@ -37,15 +60,143 @@ impl Resource {
     }
 }
 
+// Worktree kept out of its pool after a failed job, so the user can look at
+// what the job left behind.
+#[derive(Debug)]
+struct ParkedWorktree {
+    commit: CommitHash,
+    test: TestName,
+    worktree: TempWorktree,
+}
+
+// Priority of a request for resources, higher values win. This is shared so
+// that it can be raised while the request is already blocked.
+#[derive(Debug, Clone, Default)]
+pub struct Priority {
+    level: Arc<AtomicI32>,
+    // Breaks ties between requests at the same level, lower goes first. This
+    // is how the scheduling policy is implemented.
+    order: (usize, usize),
+}
+
+impl Priority {
+    pub fn with_order(order: (usize, usize)) -> Self {
+        Self {
+            level: Arc::default(),
+            order,
+        }
+    }
+
+    pub fn get(&self) -> i32 {
+        self.level.load(Ordering::Relaxed)
+    }
+
+    // Make this priority one higher than the other one, unless it already is.
+    pub fn raise_above(&self, other: i32) {
+        self.level.fetch_max(other + 1, Ordering::Relaxed);
+    }
+
+    // Sorts before any priority it should be served ahead of. Age is the
+    // final tie-breaker.
+    fn rank(&self, ticket: u64) -> (Reverse<i32>, (usize, usize), u64) {
+        (Reverse(self.get()), self.order, ticket)
+    }
+}
+
+// A request for tokens from whichever of several pools can provide them,
+// preferring the earlier ones.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Alternatives {
+    pub keys: Vec<ResourceKey>,
+    pub count: usize,
+}
+
+// A call to Pools::get that is currently blocked.
+#[derive(Debug)]
+struct Waiter {
+    priority: Priority,
+    wants: Vec<(ResourceKey, usize)>,
+    alternatives: Vec<Alternatives>,
+}
+
+// What's in the pools right now.
+#[derive(Debug, Default)]
+struct Available {
+    tokens: HashMap<ResourceKey, Vec<Resource>>,
+    // Resources where the user only cares how much they get, like memory.
+    // These don't have a token for each unit, there could be millions.
+    quantities: HashMap<ResourceKey, usize>,
+}
+
+impl Available {
+    fn count(&self, key: &ResourceKey) -> usize {
+        match self.quantities.get(key) {
+            Some(quantity) => *quantity,
+            None => self.tokens.get(key).map_or(0, |v| v.len()),
+        }
+    }
+}
+
+// How much to take from each pool to satisfy a request, or None if it can't
+// be satisfied right now.
+fn choose(
+    avail: &Available,
+    wants: &[(ResourceKey, usize)],
+    alternatives: &[Alternatives],
+) -> Option<HashMap<ResourceKey, usize>> {
+    let mut chosen: HashMap<ResourceKey, usize> = HashMap::new();
+    for (key, want) in wants {
+        *chosen.entry(key.clone()).or_default() += want;
+    }
+    for alternatives in alternatives {
+        let key = alternatives
+            .keys
+            .iter()
+            .find(|key| avail.count(key) >= chosen.get(*key).unwrap_or(&0) + alternatives.count)?;
+        *chosen.entry(key.clone()).or_default() += alternatives.count;
+    }
+    chosen
+        .iter()
+        .all(|(key, want)| avail.count(key) >= *want)
+        .then_some(chosen)
+}
+
 // Collection of shared resources, consisting of pools of resources. The
 // user can block until an arbitrary combination of numbers of different tokens
 // becomes available, without any underutilization or deadlocking. Tokens are
 // strings, which is another thing this code doesn't actually care about and
 // probably "should" be generic over.
+//
+// When several blocked getters could be satisfied at once, the one with the
+// highest priority wins, ties are broken by the priority's order and then by
+// age. A getter never waits for a
+// higher-priority one that can't currently be satisfied, so priorities can't
+// cause underutilization.
 #[derive(Debug)]
 pub struct Pools {
     cond: Condvar,
-    resources: Mutex<HashMap<ResourceKey, Vec<Resource>>>,
+    resources: Mutex<Available>,
+    // Keyed by a ticket number that increases with each call to get, so
+    // iteration order is age order. Lock ordering: take resources first.
+    waiters: Mutex<BTreeMap<u64, Waiter>>,
+    next_ticket: AtomicU64,
+    // How many resources of each type exist, whether or not they're in use.
+    // Lock ordering: take resources first.
+    totals: Mutex<HashMap<ResourceKey, usize>>,
+    // Oldest first. These still count towards the total. Lock ordering: take
+    // resources first, then this, then totals.
+    parked: Mutex<VecDeque<ParkedWorktree>>,
+    max_parked: usize,
+    // Where the machine-wide resources come from, if they're shared with
+    // other Pools.
+    machine: Option<Arc<Pools>>,
+}
+
+// How much of a pool is being used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Usage {
+    pub in_use: usize,
+    pub total: usize,
 }
 
 impl Pools {
@@ -54,107 +205,389 @@ impl Pools {
     // TODO: this key/val tuple approach is kinda annoying, maybe we should have
     // a trait object that implements Into<Resource> or something?
     pub fn new(resources: impl IntoIterator<Item = (ResourceKey, Vec<Resource>)>) -> Self {
+        let resources: HashMap<ResourceKey, Vec<Resource>> = resources.into_iter().collect();
+        let totals = resources
+            .iter()
+            .map(|(key, resources)| (key.clone(), resources.len()))
+            .collect();
         Self {
             cond: Condvar::new(),
-            resources: Mutex::new(resources.into_iter().collect()),
+            resources: Mutex::new(Available {
+                tokens: resources,
+                quantities: HashMap::new(),
+            }),
+            waiters: Mutex::new(BTreeMap::new()),
+            next_ticket: AtomicU64::new(0),
+            totals: Mutex::new(totals),
+            parked: Mutex::new(VecDeque::new()),
+            max_parked: 0,
+            machine: None,
         }
     }
 
+    // Allow up to max worktrees to be parked, see park_worktree.
+    pub fn with_parked_worktrees(mut self, max: usize) -> Self {
+        self.max_parked = max;
+        self
+    }
+
+    // Take CPUs, memory and GPUs from the other pools instead of this one.
+    // This is how several repositories watched by the same process avoid
+    // double-booking the machine.
+    pub fn with_machine(mut self, machine: Arc<Pools>) -> Self {
+        self.machine = Some(machine);
+        self
+    }
+
+    // Add a pool of a resource that is just a quantity, see Available.
+    pub fn add_quantity(&self, key: ResourceKey, total: usize) {
+        self.resources.lock().quantities.insert(key.clone(), total);
+        self.totals.lock().insert(key, total);
+        self.cond.notify_all();
+    }
+
     // TODO: As well as being annoying in a similar way to new, this is
     // inconsistent with it for no good reason.
     pub fn add(&self, new_resources: impl IntoIterator<Item = (ResourceKey, Resource)>) {
         // Don't need the condvar since we have a mutable reference to self. We
         // only take the mutex out of a misguided sense of decorum.
         let mut resources = self.resources.lock();
+        let mut totals = self.totals.lock();
         for (key, resource) in new_resources.into_iter() {
-            (*resources).entry(key).or_default().push(resource);
+            *totals.entry(key.clone()).or_default() += 1;
+            resources.tokens.entry(key).or_default().push(resource);
         }
         self.cond.notify_all();
     }
 
+    // Shorthand for tests that don't care about priorities or alternatives.
+    #[cfg(test)]
+    pub async fn get(
+        &self,
+        wants: impl IntoIterator<Item = (ResourceKey, usize)>,
+    ) -> Resources<'_> {
+        self.get_with_priority(wants, Vec::new(), &Priority::default())
+            .await
+    }
+
     // Get the specified number of tokens from each of the pools, keys match
-    // the keys used in new (or this panics).
-    // The tokens are held until you drop the returned value.
-    //
+    // the keys used in new (or this panics), plus the tokens for each of the
+    // alternatives. The tokens are held until you drop the returned value.
+    // See the comment on Pools about priorities. If you raise the priority
+    // while this is blocked, call reprioritized.
+    pub async fn get_with_priority(
+        &self,
+        wants: impl IntoIterator<Item = (ResourceKey, usize)>,
+        alternatives: Vec<Alternatives>,
+        priority: &Priority,
+    ) -> Resources<'_> {
+        let Some(machine) = &self.machine else {
+            return self
+                .get_local(wants.into_iter().collect(), alternatives, priority)
+                .await;
+        };
+        let (machine_wants, wants): (Vec<_>, Vec<_>) =
+            wants.into_iter().partition(|(key, _)| key.machine_wide());
+        // Always taking the local ones first means there's a consistent
+        // ordering so we can't deadlock with the other Pools sharing the
+        // machine. It also means we don't sit on CPUs that another repository
+        // could be using while we wait for a worktree.
+        let mut resources = self.get_local(wants, alternatives, priority).await;
+        if !machine_wants.is_empty() {
+            let machine_resources = machine.get_local(machine_wants, Vec::new(), priority).await;
+            resources.machine = Some(Box::new(machine_resources));
+        }
+        resources
+    }
+
     // https://github.com/rust-lang/rust-clippy/issues/13075
     #[expect(clippy::await_holding_lock)]
-    pub async fn get(
+    async fn get_local(
         &self,
-        wants: impl IntoIterator<Item = (ResourceKey, usize)>,
+        wants: Vec<(ResourceKey, usize)>,
+        alternatives: Vec<Alternatives>,
+        priority: &Priority,
     ) -> Resources<'_> {
-        let wants: Vec<(ResourceKey, usize)> = wants.into_iter().collect();
         let mut guard = self.resources.lock();
+        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+        self.waiters.lock().insert(
+            ticket,
+            Waiter {
+                priority: priority.clone(),
+                wants: wants.clone(),
+                alternatives: alternatives.clone(),
+            },
+        );
+        // If we get cancelled while blocked, we mustn't leave the waiter
+        // around or it would block lower-priority getters forever.
+        let waiter_guard = WaiterGuard {
+            pools: self,
+            ticket,
+        };
         loop {
             let avail_tokens = &mut (*guard);
             // For simplicity we first iterate to check if all the resources we
             // need are available, then if they are we take them out in a
             // separate operation.
-            if wants
-                .iter()
-                .all(|(key, want)| avail_tokens.get(key).unwrap_or(&vec![]).len() >= *want)
-            {
-                return Resources {
-                    resources: ManuallyDrop::new(
-                        wants
-                            .into_iter()
-                            .map(|(key, want_count)| {
-                                let avail =
-                                    avail_tokens.get_mut(&key).expect("invalid resource key");
-                                // Take the last n tokens out of the Vec and
-                                // associated them with the key.
-                                (key, avail.drain((avail.len() - want_count)..).collect())
-                            })
-                            .collect(),
-                    ),
+            let chosen = choose(avail_tokens, &wants, &alternatives)
+                .filter(|_| !self.outranked(avail_tokens, ticket));
+            if let Some(chosen) = chosen {
+                mem::forget(waiter_guard);
+                let mut waiters = self.waiters.lock();
+                waiters.remove(&ticket);
+                let mut taken = HashMap::new();
+                let mut quantities = HashMap::new();
+                for (key, want_count) in chosen {
+                    if let Some(avail) = avail_tokens.quantities.get_mut(&key) {
+                        *avail -= want_count;
+                        quantities.insert(key, want_count);
+                        continue;
+                    }
+                    let avail = avail_tokens
+                        .tokens
+                        .get_mut(&key)
+                        .expect("invalid resource key");
+                    // Take the last n tokens out of the Vec and associated
+                    // them with the key.
+                    taken.insert(key, avail.drain((avail.len() - want_count)..).collect());
+                }
+                let resources = Resources {
+                    resources: ManuallyDrop::new(taken),
+                    quantities,
                     pools: self,
+                    machine: None,
                 };
+                // Other getters might have been waiting for us to go first.
+                if !waiters.is_empty() {
+                    self.cond.notify_all();
+                }
+                return resources;
             }
 
             guard = self.cond.wait(guard).await;
         }
     }
 
+    // Is there another waiter that should go before the one with this ticket?
+    fn outranked(&self, avail: &Available, ticket: u64) -> bool {
+        let waiters = self.waiters.lock();
+        let rank = waiters[&ticket].priority.rank(ticket);
+        waiters.iter().any(|(other_ticket, other)| {
+            other.priority.rank(*other_ticket) < rank
+                && choose(avail, &other.wants, &other.alternatives).is_some()
+        })
+    }
+
+    // Call this after raising the priority of a blocked getter.
+    pub fn reprioritized(&self) {
+        let _guard = self.resources.lock();
+        self.cond.notify_all();
+        if let Some(machine) = &self.machine {
+            machine.reprioritized();
+        }
+    }
+
     // Without blocking, permanently remove all the worktrees that are currently available.
     // specified type that are currently available, up to the specified number.
+    // That includes the parked ones.
     pub fn try_remove_worktrees(&self) -> impl Iterator<Item = TempWorktree> {
         let mut guard = self.resources.lock();
         let avail = &mut (*guard);
-        avail
+        let removed = avail
+            .tokens
             .remove(&ResourceKey::Worktree)
-            .unwrap_or_default()
+            .unwrap_or_default();
+        let parked: Vec<TempWorktree> = self.parked.lock().drain(..).map(|p| p.worktree).collect();
+        if let Some(total) = self.totals.lock().get_mut(&ResourceKey::Worktree) {
+            *total -= removed.len() + parked.len();
+        }
+        removed
             .into_iter()
             .map(|resource| match resource {
                 Resource::Worktree(w) => w,
                 _ => panic!("wrong resource type in worktree pool"),
             })
+            .chain(parked)
+    }
+
+    // Keep the worktree from a failed job out of the pool, instead of letting
+    // the next job clobber it. If that's more than with_parked_worktrees
+    // allows, or would leave no worktrees for jobs to use, the ones that were
+    // parked first are unparked and returned, see unpark_worktree.
+    pub fn park_worktree(
+        &self,
+        commit: CommitHash,
+        test: TestName,
+        worktree: TempWorktree,
+    ) -> Vec<TempWorktree> {
+        let _guard = self.resources.lock();
+        let mut parked = self.parked.lock();
+        parked.push_back(ParkedWorktree {
+            commit,
+            test,
+            worktree,
+        });
+        let total = self.totals.lock()[&ResourceKey::Worktree];
+        let excess = parked.len().saturating_sub(self.max_parked.min(total - 1));
+        parked.drain(..excess).map(|p| p.worktree).collect()
+    }
+
+    // Take out the worktree parked for a test case, if there is one. An
+    // unparked worktree still counts as in use, until it's passed to
+    // return_worktree. In between, whatever was done to it while it was
+    // parked can be undone.
+    pub fn unpark_worktree(&self, commit: &CommitHash, test: &TestName) -> Option<TempWorktree> {
+        let _guard = self.resources.lock();
+        let mut parked = self.parked.lock();
+        let i = parked
+            .iter()
+            .position(|p| &p.commit == commit && &p.test == test)?;
+        Some(parked.remove(i).unwrap().worktree)
+    }
+
+    // Put an unparked worktree back into the pool.
+    pub fn return_worktree(&self, worktree: TempWorktree) {
+        self.resources
+            .lock()
+            .tokens
+            .entry(ResourceKey::Worktree)
+            .or_default()
+            .push(Resource::Worktree(worktree));
+        self.cond.notify_all();
+    }
+
+    // Where each parked worktree is, oldest first.
+    pub fn parked_worktrees(&self) -> Vec<(CommitHash, TestName, PathBuf)> {
+        self.parked
+            .lock()
+            .iter()
+            .map(|p| {
+                (
+                    p.commit.clone(),
+                    p.test.clone(),
+                    p.worktree.path().to_owned(),
+                )
+            })
+            .collect()
     }
 
-    fn put(&self, resources: HashMap<ResourceKey, Vec<Resource>>) {
+    pub fn usage(&self) -> HashMap<ResourceKey, Usage> {
+        let mut usage = self.local_usage();
+        if let Some(machine) = &self.machine {
+            usage.extend(machine.local_usage());
+        }
+        usage
+    }
+
+    fn local_usage(&self) -> HashMap<ResourceKey, Usage> {
+        let avail = self.resources.lock();
+        self.totals
+            .lock()
+            .iter()
+            .map(|(key, &total)| {
+                let avail = avail.count(key);
+                (
+                    key.clone(),
+                    Usage {
+                        in_use: total - avail,
+                        total,
+                    },
+                )
+            })
+            .collect()
+    }
+
+    fn put(
+        &self,
+        resources: HashMap<ResourceKey, Vec<Resource>>,
+        quantities: &HashMap<ResourceKey, usize>,
+    ) {
         let mut guard = self.resources.lock();
         let avail_tokens = &mut (*guard);
         for (key, mut key_resources) in resources.into_iter() {
             avail_tokens
+                .tokens
                 .get_mut(&key)
                 .expect("invalid resource key")
                 .append(&mut key_resources);
         }
+        for (key, quantity) in quantities {
+            *avail_tokens
+                .quantities
+                .get_mut(key)
+                .expect("invalid resource key") += quantity;
+        }
         // Note this is pretty inefficient, we are waking up every getter even though we can satisfy
         // at most one of them.
         self.cond.notify_all();
     }
 }
 
+// Deregisters a Waiter when a blocked get is dropped.
+struct WaiterGuard<'a> {
+    pools: &'a Pools,
+    ticket: u64,
+}
+
+impl Drop for WaiterGuard<'_> {
+    fn drop(&mut self) {
+        self.pools.waiters.lock().remove(&self.ticket);
+        // Anyone who was deferring to us needs to re-evaluate.
+        self.pools.cond.notify_all();
+    }
+}
+
 #[derive(Debug)]
 // Tokens taken from a Pools.
 pub struct Resources<'a> {
     resources: ManuallyDrop<HashMap<ResourceKey, Vec<Resource>>>,
+    quantities: HashMap<ResourceKey, usize>,
     pools: &'a Pools,
+    // What was taken from the machine-wide pools, see Pools::with_machine.
+    machine: Option<Box<Resources<'a>>>,
 }
 
 impl Resources<'_> {
     // Get access to the resources with the given key.
     pub fn resources(&self, key: &ResourceKey) -> Option<&Vec<Resource>> {
-        self.resources.get(key)
+        self.resources
+            .get(key)
+            .or_else(|| self.machine.as_ref()?.resources(key))
+    }
+
+    // The CPU cores that were reserved, in order.
+    pub fn cpus(&self) -> Vec<usize> {
+        let mut cpus: Vec<usize> = self
+            .resources(&ResourceKey::Cpu)
+            .into_iter()
+            .flatten()
+            .map(|r| match r {
+                Resource::Cpu(cpu) => *cpu,
+                _ => panic!("bad resource type for Cpu resource key"),
+            })
+            .collect();
+        cpus.sort();
+        cpus
+    }
+
+    pub fn gpus(&self) -> Vec<&Gpu> {
+        self.resources(&ResourceKey::Gpu)
+            .into_iter()
+            .flatten()
+            .map(|r| match r {
+                Resource::Gpu(gpu) => gpu,
+                _ => panic!("bad resource type for Gpu resource key"),
+            })
+            .collect()
+    }
+
+    // Take the worktree, so it doesn't go back into the pool when this is
+    // dropped.
+    pub fn take_worktree(&mut self) -> Option<TempWorktree> {
+        match self.resources.get_mut(&ResourceKey::Worktree)?.pop()? {
+            Resource::Worktree(w) => Some(w),
+            _ => panic!("bad resource type for Worktree resource key"),
+        }
     }
 
     // Get all the user-configured token values
@@ -182,7 +615,7 @@ impl Drop for Resources<'_> {
     fn drop(&mut self) {
         // SAFETY: This is safe as the fields are never accessed again.
         let resources = unsafe { ManuallyDrop::take(&mut self.resources) };
-        self.pools.put(resources)
+        self.pools.put(resources, &self.quantities)
     }
 }
 
@@ -191,8 +624,10 @@ mod tests {
     use anyhow::bail;
     use std::task::{Context, Poll};
 
-    use futures::{pin_mut, task::noop_waker, Future};
+    use futures::{pin_mut, poll, task::noop_waker, Future};
+    use std::pin::pin;
     use test_case::test_case;
+    use tokio::select;
 
     use super::*;
 
@@ -271,7 +706,183 @@ mod tests {
                 .await;
             check_pending(pools.get([(ResourceKey::UserToken("foo".into()), 3)]))
                 .expect("returned too many tokens");
+            assert_eq!(
+                pools.usage()[&ResourceKey::UserToken("foo".into())],
+                Usage {
+                    in_use: 2,
+                    total: 3
+                }
+            );
         }
         pools.get([(ResourceKey::UserToken("foo".into()), 3)]).await;
+        assert_eq!(
+            pools.usage()[&ResourceKey::UserToken("bar".into())],
+            Usage {
+                in_use: 0,
+                total: 2
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pools_quantity() {
+        let pools = Pools::new([(
+            ResourceKey::UserToken("foo".into()),
+            vec![Resource::UserToken("foo1".into())],
+        )]);
+        pools.add_quantity(ResourceKey::Memory, 1000);
+        let held = pools.get([(ResourceKey::Memory, 600)]).await;
+        check_pending(pools.get([(ResourceKey::Memory, 600)])).expect("overcommitted");
+        {
+            let _both = pools
+                .get([
+                    (ResourceKey::Memory, 400),
+                    (ResourceKey::UserToken("foo".into()), 1),
+                ])
+                .await;
+            assert_eq!(
+                pools.usage()[&ResourceKey::Memory],
+                Usage {
+                    in_use: 1000,
+                    total: 1000
+                }
+            );
+        }
+        drop(held);
+        pools.get([(ResourceKey::Memory, 1000)]).await;
+    }
+
+    #[tokio::test]
+    async fn test_pools_shared_machine() {
+        let machine = Arc::new(Pools::new([(
+            ResourceKey::Cpu,
+            vec![Resource::Cpu(0), Resource::Cpu(1)],
+        )]));
+        machine.add_quantity(ResourceKey::Memory, 1000);
+        let [pools1, pools2] = ["foo", "bar"].map(|name| {
+            Pools::new([(
+                ResourceKey::UserToken(name.into()),
+                vec![Resource::UserToken(format!("{name}1"))],
+            )])
+            .with_machine(machine.clone())
+        });
+        let held = pools1
+            .get([
+                (ResourceKey::Cpu, 1),
+                (ResourceKey::Memory, 600),
+                (ResourceKey::UserToken("foo".into()), 1),
+            ])
+            .await;
+        assert_eq!(held.cpus(), vec![1]);
+        check_pending(pools2.get([(ResourceKey::Cpu, 2)])).expect("double-booked CPU");
+        check_pending(pools2.get([(ResourceKey::Memory, 600)])).expect("overcommitted");
+        assert_eq!(
+            pools2.usage()[&ResourceKey::Cpu],
+            Usage {
+                in_use: 1,
+                total: 2
+            }
+        );
+        let other = pools2
+            .get([
+                (ResourceKey::Cpu, 1),
+                (ResourceKey::UserToken("bar".into()), 1),
+            ])
+            .await;
+        assert_eq!(other.cpus(), vec![0]);
+        drop(held);
+        drop(other);
+        pools2
+            .get([(ResourceKey::Cpu, 2), (ResourceKey::Memory, 1000)])
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_pools_alternatives() {
+        let names = ["fast", "slow", "other"];
+        let pools = Pools::new(names.map(|name| {
+            (
+                ResourceKey::UserToken(name.into()),
+                vec![Resource::UserToken(format!("{name}1"))],
+            )
+        }));
+        let [fast, slow, other] = names.map(|name| ResourceKey::UserToken(name.into()));
+        let alternatives = vec![Alternatives {
+            keys: vec![fast, slow],
+            count: 1,
+        }];
+        let priority = Priority::default();
+        let get = || pools.get_with_priority([], alternatives.clone(), &priority);
+
+        let first = get().await;
+        assert_eq!(
+            first.tokens(),
+            HashMap::from([("fast".into(), vec!["fast1".into()])])
+        );
+        let second = get().await;
+        assert_eq!(
+            second.tokens(),
+            HashMap::from([("slow".into(), vec!["slow1".into()])])
+        );
+        check_pending(get()).expect("got a token that was in use");
+        // Can't use the same token for the fixed want and the alternative.
+        check_pending(pools.get_with_priority(
+            [(other.clone(), 1)],
+            vec![Alternatives {
+                keys: vec![other],
+                count: 1,
+            }],
+            &priority,
+        ))
+        .expect("got a token twice");
+
+        drop(second);
+        assert_eq!(
+            get().await.tokens(),
+            HashMap::from([("slow".into(), vec!["slow1".into()])])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pools_priority() {
+        let key = ResourceKey::UserToken("foo".into());
+        let pools = Pools::new([(key.clone(), vec![Resource::UserToken("foo1".into())])]);
+        let held = pools.get([(key.clone(), 1)]).await;
+
+        let priority = Priority::default();
+        priority.raise_above(0);
+        let mut low = pin!(pools.get([(key.clone(), 1)]));
+        let mut high = pin!(pools.get_with_priority([(key.clone(), 1)], Vec::new(), &priority));
+        assert!(poll!(low.as_mut()).is_pending());
+        assert!(poll!(high.as_mut()).is_pending());
+
+        drop(held);
+        select! {
+            // Give the low-priority getter every chance to jump the queue.
+            biased;
+            _ = &mut low => panic!("low-priority getter was served first"),
+            _ = &mut high => (),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pools_order() {
+        let key = ResourceKey::UserToken("foo".into());
+        let pools = Pools::new([(key.clone(), vec![Resource::UserToken("foo1".into())])]);
+        let held = pools.get([(key.clone(), 1)]).await;
+
+        let (late, early) = (Priority::with_order((1, 0)), Priority::with_order((0, 1)));
+        let mut late = pin!(pools.get_with_priority([(key.clone(), 1)], Vec::new(), &late));
+        let mut early = pin!(pools.get_with_priority([(key.clone(), 1)], Vec::new(), &early));
+        assert!(poll!(late.as_mut()).is_pending());
+        assert!(poll!(early.as_mut()).is_pending());
+
+        drop(held);
+        select! {
+            // The older getter has every chance to jump the queue.
+            biased;
+            _ = &mut late => panic!("later-ordered getter was served first"),
+            _ = &mut early => (),
+        }
     }
 }
@@ -0,0 +1,153 @@
+// Sums up what's in the result database and the event log for each test, to
+// help figure out which tests are worth speeding up, or not running by default.
+
+use std::time::Duration;
+
+use crate::{
+    database::StoredResult,
+    events::{Event, EventKind},
+    test::TestName,
+};
+
+// How many runs count as recent when looking at whether a test is getting
+// slower.
+const RECENT_RUNS: usize = 10;
+
+#[derive(Debug, PartialEq)]
+pub struct TestStats {
+    pub name: TestName,
+    pub runs: usize,
+    pub failures: usize,
+    // Only counts runs where we know how long they took, results from older
+    // versions don't record that.
+    pub machine_time: Duration,
+    pub mean_duration: Option<Duration>,
+    pub recent_mean_duration: Option<Duration>,
+    // Out of the finished events, how many were for results found in the
+    // database.
+    pub cache_hits: usize,
+    pub finished: usize,
+    // Bytes the results take up in the database.
+    pub storage: u64,
+}
+
+fn mean(durations: &[Duration]) -> Option<Duration> {
+    if durations.is_empty() {
+        return None;
+    }
+    Some(durations.iter().sum::<Duration>() / durations.len() as u32)
+}
+
+impl TestStats {
+    pub fn new(name: TestName, results: &[StoredResult], events: &[Event]) -> Self {
+        let mut timed_runs: Vec<_> = results
+            .iter()
+            .filter_map(|result| {
+                let provenance = result.provenance.as_ref()?;
+                let duration = provenance
+                    .finished_at
+                    .duration_since(provenance.started_at?)
+                    .ok()?;
+                Some((provenance.finished_at, duration))
+            })
+            .collect();
+        timed_runs.sort();
+        let durations: Vec<Duration> = timed_runs.into_iter().map(|(_, d)| d).collect();
+        let recent = &durations[durations.len().saturating_sub(RECENT_RUNS)..];
+        let name_str = name.to_string();
+        let cached: Vec<bool> = events
+            .iter()
+            .filter(|event| event.test == name_str)
+            .filter_map(|event| match event.kind {
+                EventKind::Finished { cached, .. } => Some(cached),
+                _ => None,
+            })
+            .collect();
+        Self {
+            name,
+            runs: results.len(),
+            failures: results.iter().filter(|r| r.exit_code != 0).count(),
+            machine_time: durations.iter().sum(),
+            mean_duration: mean(&durations),
+            recent_mean_duration: mean(recent),
+            cache_hits: cached.iter().filter(|&&c| c).count(),
+            finished: cached.len(),
+            storage: results.iter().map(|r| r.size).sum(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, time::SystemTime};
+
+    use crate::database::Provenance;
+
+    use super::*;
+
+    fn result(exit_code: i32, finished_secs: u64, duration_secs: u64) -> StoredResult {
+        let finished_at = SystemTime::UNIX_EPOCH + Duration::from_secs(finished_secs);
+        StoredResult {
+            exit_code,
+            provenance: Some(Provenance {
+                host: "host".into(),
+                started_at: Some(finished_at - Duration::from_secs(duration_secs)),
+                finished_at,
+            }),
+            dir: PathBuf::from("/db"),
+            size: 100,
+            pinned: false,
+        }
+    }
+
+    fn finished(test: &str, cached: bool) -> Event {
+        Event {
+            time: SystemTime::UNIX_EPOCH,
+            pid: 1,
+            repo: PathBuf::from("/repo"),
+            commit: "abc".into(),
+            test: test.into(),
+            kind: EventKind::Finished {
+                exit_code: Some(0),
+                error: None,
+                cached,
+            },
+        }
+    }
+
+    #[test]
+    fn test_stats() {
+        // The last RECENT_RUNS runs take 20s, the ones before them 10s.
+        let mut results: Vec<_> = (0..RECENT_RUNS as u64)
+            .map(|i| result(0, 1000 + i, 20))
+            .collect();
+        results.extend((0..RECENT_RUNS as u64).map(|i| result(1, 100 + i, 10)));
+        results.push(StoredResult {
+            exit_code: 0,
+            provenance: None,
+            dir: PathBuf::from("/db"),
+            size: 50,
+            pinned: false,
+        });
+        let events = [
+            finished("my_test", true),
+            finished("my_test", false),
+            finished("my_test", true),
+            finished("other_test", false),
+        ];
+        assert_eq!(
+            TestStats::new(TestName::new("my_test"), &results, &events),
+            TestStats {
+                name: TestName::new("my_test"),
+                runs: 2 * RECENT_RUNS + 1,
+                failures: RECENT_RUNS,
+                machine_time: Duration::from_secs(30 * RECENT_RUNS as u64),
+                mean_duration: Some(Duration::from_secs(15)),
+                recent_mean_duration: Some(Duration::from_secs(20)),
+                cache_hits: 2,
+                finished: 3,
+                storage: 100 * 2 * RECENT_RUNS as u64 + 50,
+            }
+        );
+    }
+}
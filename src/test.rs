@@ -1,40 +1,65 @@
 use core::{error::Error, fmt, fmt::Display};
 use std::{
     borrow::Borrow,
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    env,
     ffi::{OsStr, OsString},
     fmt::{Debug, Formatter},
+    fs::{OpenOptions, Permissions},
+    os::{
+        fd::{AsRawFd as _, OwnedFd, RawFd},
+        unix::fs::PermissionsExt as _,
+    },
     path::{Path, PathBuf},
     pin::pin,
     process::Stdio,
-    sync::Arc,
-    time::Duration,
+    str::FromStr,
+    sync::{Arc, OnceLock},
+    time::{Duration, Instant, SystemTime},
 };
 
-use anyhow::{anyhow, Context};
-use futures::future::{self, select_all, try_join_all, Either, FutureExt};
+use anyhow::{anyhow, bail, Context};
+use futures::future::{self, select_all, Either, FutureExt};
+use futures::stream::{self, StreamExt as _, TryStreamExt as _};
 use itertools::Itertools;
 #[allow(unused_imports)]
 use log::{debug, error, info, warn};
+use nix::fcntl::{fcntl, FcntlArg, FdFlag, OFlag};
+use nix::sched::{sched_setaffinity, CpuSet};
 use nix::sys::signal::{killpg, Signal};
-use nix::unistd::Pid;
+use nix::sys::stat::{umask, Mode};
+use nix::unistd::{chown, dup2, geteuid, pipe2, Pid, User};
 use parking_lot::Mutex;
+use regex::Regex;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use sha3::{Digest as _, Sha3_256};
 use tokio::{
-    process::{Child, Command},
+    io::{AsyncBufReadExt as _, BufReader},
+    net::unix::pipe,
+    process::Command,
     select,
-    sync::{broadcast, watch, Semaphore},
-    time::sleep,
+    sync::{broadcast, watch, Notify, Semaphore},
+    task::JoinHandle,
+    time::{sleep, timeout},
 };
 use tokio_util::sync::CancellationToken;
 
 use crate::{
+    config::Scheduling,
     dag::{Dag, GraphNode},
-    database::{Database, DatabaseEntry, DatabaseOutput, LookupResult},
-    git::{Commit, CommitHash, Hash, Worktree},
-    process::ExitStatusExt as _,
-    resource::{Pools, ResourceKey, Resources},
+    database::{
+        Database, DatabaseEntry, DatabaseOutput, History, JobSnapshot, LookupResult, Provenance,
+        Slowdown,
+    },
+    git::{
+        self, Commit, CommitHash, Hash, JobBranch, TempWorktree, TreeHash, Worktree,
+        JOB_BRANCH_PREFIX,
+    },
+    gpu::Gpu,
+    process::{ExitStatusExt as _, TrackedChild},
+    resource::{Alternatives, Pools, Priority, ResourceKey, Resources},
+    throttle::Throttle,
     util::ResultExt,
 };
 
@@ -58,9 +83,115 @@ impl CachePolicy {
     }
 }
 
+#[derive(Deserialize, JsonSchema, Serialize, Debug, Clone, Copy, Hash, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckoutMode {
+    #[default]
+    Detached,
+    Branch,
+    // Just the files, extracted into a fresh directory instead of a worktree.
+    Archive,
+}
+
+// Where archive checkouts go. Falls back to the usual temp dir if this doesn't
+// exist.
+const ARCHIVE_PARENT_DIR: &str = "/dev/shm";
+
+#[derive(Deserialize, JsonSchema, Serialize, Debug, Clone, Copy, Hash, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Namespace {
+    Mount,
+    Net,
+    Pid,
+}
+
+impl Namespace {
+    fn unshare_args(&self) -> &'static [&'static str] {
+        match self {
+            Self::Mount => &["--mount"],
+            Self::Net => &["--net"],
+            // Otherwise the job would see the processes of the parent
+            // namespace in /proc.
+            Self::Pid => &["--pid", "--fork", "--mount-proc"],
+        }
+    }
+}
+
+#[derive(Deserialize, JsonSchema, Serialize, Debug, Clone, Copy, Hash, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Network {
+    #[default]
+    Host,
+    // Only loopback.
+    None,
+}
+
+// A new network namespace's loopback interface starts out down.
+const LOOPBACK_UP_SCRIPT: &str = r#"ip link set lo up && exec "$@""#;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Sandbox {
+    // Run as this user instead of ours.
+    pub user: Option<User>,
+    // Give the job its own TMPDIR.
+    pub private_tmp: bool,
+    pub unshare: Vec<Namespace>,
+    pub network: Network,
+}
+
+// Where dependents find one of the test's declared outputs. Characters that
+// can't be in a shell variable name are replaced with underscores.
+pub fn output_env_var(test: &TestName, output: &str) -> String {
+    let output: String = output
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    format!("LIMMAT_ARTIFACT_{test}_{output}")
+}
+
+// Run after the job passes, to copy the artifacts elsewhere.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArtifactUpload {
+    pub argv: Vec<OsString>,
+    pub retries: u32,
+    pub retry_delay: Duration,
+}
+
+// Why a job was canceled, recorded in the database.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CancelReason {
+    // The commit is no longer in the range being tested.
+    LeftRange,
+    Shutdown,
+    // Someone asked via the control socket.
+    Requested,
+    // Replaced by a new job to re-run the test case.
+    Rerun,
+}
+
+impl Display for CancelReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::LeftRange => write!(f, "the commit left the range"),
+            Self::Shutdown => write!(f, "limmat shut down"),
+            Self::Requested => write!(f, "it was canceled via limmat ctl"),
+            Self::Rerun => write!(f, "it was superseded by a rerun"),
+        }
+    }
+}
+
 // Some unspecified hash, don't care too much about stability across builds.
 pub type ConfigHash = String;
 
+// Jobs can report their progress by writing lines to this fd.
+const PROGRESS_FD: RawFd = 3;
+// Minimum time between progress notifications from a single job, so that a
+// chatty job can't flood the notification channel.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+// Git commands run at once to find out what files commits changed.
+const CHANGED_FILES_CONCURRENCY: usize = 16;
+
 #[derive(Clone, Eq, Hash, PartialEq, PartialOrd, Ord)]
 pub struct TestName(String);
 
@@ -97,30 +228,192 @@ impl Debug for TestName {
 // A test task that will need to be repeated for each commit.
 // TODO: this struct is too complex for the plain-old-data (pub fields)
 // approach, it should be constructed with a builder.
+#[derive(Clone)]
 #[cfg_attr(test, derive(PartialEq, Eq))]
 pub struct Test {
     pub name: TestName,
+    // Just for showing to the user.
+    pub description: Option<String>,
+    pub owner: Option<String>,
+    pub help_url: Option<String>,
     // Hash of the configuration that created this Test.
     pub config_hash: ConfigHash,
     pub program: OsString,
     pub args: Vec<OsString>,
+    // Program and arguments of commands run before and after the main one.
+    pub setup: Option<Vec<OsString>>,
+    pub teardown: Option<Vec<OsString>>,
+    pub artifact_upload: Option<ArtifactUpload>,
+    // Paths under the artifacts directory that the test has to produce.
+    pub outputs: Vec<String>,
+    // Bytes its results can take up in the database before "limmat gc"
+    // deletes them.
+    pub storage_quota: Option<u64>,
     // Counts of the resource tokens this test needs a resource-token before it
     // can begin.
     pub needs_resources: HashMap<ResourceKey, usize>,
+    pub resource_alternatives: Vec<Alternatives>,
     pub shutdown_grace_period: Duration,
     pub cache_policy: CachePolicy,
+    // Cached failures older than this are ignored.
+    pub failure_cache_ttl: Option<Duration>,
+    // Passing runs that take this much longer than usual get flagged.
+    pub max_slowdown_percent: Option<u32>,
     // This tests shoudln't start until these other tests have finished.
     // Manager setup will fail if there are cycles in this graph or named tests
     // do not exist.
     pub depends_on: Vec<TestName>,
+    // Position in the configured stages, if the test is in one.
+    pub stage: Option<usize>,
+    // The tests in earlier stages (except those already in depends_on). This
+    // test shouldn't start unless they all succeed, but unlike depends_on
+    // their config hashes don't go into this test's. The stage field itself
+    // is hashed like any other, unless it's in ignore_for_cache.
+    pub stage_gate: Vec<TestName>,
     pub error_exit_codes: HashSet<ExitCode>,
     pub separate_outputs: bool,
+    pub timestamp_output: bool,
+    // Relative to the directory the job is run in.
+    pub cwd: Option<PathBuf>,
+    pub checkout: CheckoutMode,
+    pub umask: Option<Mode>,
+    pub sandbox: Sandbox,
+    // If non-empty, the test is skipped for commits that don't change any
+    // files matching one of these.
+    pub skip_if_unchanged: Vec<glob::Pattern>,
+    pub when: When,
+    // Otherwise it's only run where it's requested with Manager::run.
+    pub run_by_default: bool,
+    // Failures don't count towards the commit's Verdict.
+    pub advisory: bool,
+}
+
+// Conditions a commit has to meet for a test to be run on it at all.
+#[derive(Debug, Clone, Default)]
+pub struct When {
+    // If non-empty, the commit has to change a file matching one of these.
+    pub paths: Vec<glob::Pattern>,
+    pub message: Option<Regex>,
+    pub author: Option<Regex>,
+}
+
+impl When {
+    // If changed_files is None, the paths are assumed to match.
+    fn matches(&self, commit: &Commit, changed_files: Option<&[PathBuf]>) -> bool {
+        if !self.paths.is_empty() && changed_files.is_some_and(|f| !changes_any(f, &self.paths)) {
+            return false;
+        }
+        self.message
+            .as_ref()
+            .is_none_or(|r| r.is_match(&commit.message))
+            && self
+                .author
+                .as_ref()
+                .is_none_or(|r| r.is_match(&commit.author))
+    }
+}
+
+// Regex doesn't implement PartialEq.
+#[cfg(test)]
+impl PartialEq for When {
+    fn eq(&self, other: &Self) -> bool {
+        let source = |regex: &Option<Regex>| regex.as_ref().map(|r| r.as_str().to_owned());
+        self.paths == other.paths
+            && source(&self.message) == source(&other.message)
+            && source(&self.author) == source(&other.author)
+    }
+}
+
+#[cfg(test)]
+impl Eq for When {}
+
+// Whether the commit message says not to run the test, either with a
+// "Limmat-Skip: <test>[, <test>...]" trailer or with "[skip limmat]" anywhere,
+// which skips all the tests.
+fn skipped_by_message(message: &str, test_name: &TestName) -> bool {
+    if message.contains("[skip limmat]") {
+        return true;
+    }
+    // Trailers are in the last paragraph.
+    let trailers = message.trim_end().rsplit("\n\n").next().unwrap_or_default();
+    trailers
+        .lines()
+        .filter_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            key.trim()
+                .eq_ignore_ascii_case("Limmat-Skip")
+                .then_some(value)
+        })
+        .flat_map(|value| value.split(','))
+        .any(|name| name.trim() == test_name.0)
+}
+
+// Whether any of the paths match one of the patterns.
+fn changes_any(changed_files: &[PathBuf], patterns: &[glob::Pattern]) -> bool {
+    let opts = glob::MatchOptions {
+        require_literal_separator: true,
+        ..Default::default()
+    };
+    changed_files.iter().any(|path| {
+        patterns
+            .iter()
+            .any(|pattern| pattern.matches_path_with(path, opts))
+    })
 }
 
 impl Test {
-    fn command(&self) -> Command {
-        let mut cmd = Command::new(&self.program);
-        cmd.args(&self.args);
+    // Command for a process that's run in the job's sandbox.
+    fn sandboxed_command(&self, program: &OsStr, args: &[OsString]) -> Command {
+        let mut unshare = self.sandbox.unshare.clone();
+        if self.sandbox.network == Network::None && !unshare.contains(&Namespace::Net) {
+            unshare.push(Namespace::Net);
+        }
+        let mut cmd = if unshare.is_empty() {
+            Command::new(program)
+        } else {
+            let mut cmd = Command::new("unshare");
+            // Without privileges, creating the other namespaces needs a user
+            // namespace too.
+            if self.sandbox.user.is_some() || !geteuid().is_root() {
+                cmd.arg("--map-current-user");
+                if self.sandbox.network == Network::None {
+                    // Otherwise we can't bring up the loopback interface.
+                    cmd.arg("--keep-caps");
+                }
+            }
+            for namespace in &unshare {
+                cmd.args(namespace.unshare_args());
+            }
+            cmd.arg("--");
+            if self.sandbox.network == Network::None {
+                cmd.args(["bash", "-c", LOOPBACK_UP_SCRIPT, "limmat-network"]);
+            }
+            cmd.arg(program);
+            cmd
+        };
+        cmd.args(args);
+        if let Some(user) = &self.sandbox.user {
+            cmd.uid(user.uid.as_raw())
+                .gid(user.gid.as_raw())
+                .env("HOME", &user.dir)
+                .env("USER", &user.name)
+                .env("LOGNAME", &user.name);
+        }
+        if let Some(mask) = self.umask {
+            // SAFETY: umask is async-signal-safe.
+            unsafe {
+                cmd.pre_exec(move || {
+                    umask(mask);
+                    Ok(())
+                });
+            }
+        }
+        cmd
+    }
+
+    // Command for a process that's run as part of the job.
+    fn job_command(&self, program: &OsStr, args: &[OsString]) -> Command {
+        let mut cmd = self.sandboxed_command(program, args);
         // We want the test process to be its process group leader for two reasons:
         // - We don't want it to get SIGINTed when the user shuts down limmat,
         //   in that case we want our graceful and bugless shutdown procedure to
@@ -142,12 +435,42 @@ impl Test {
         cmd
     }
 
+    fn command(&self) -> Command {
+        self.job_command(&self.program, &self.args)
+    }
+
     pub fn needs_worktree(&self) -> bool {
         self.needs_resources
             .get(&ResourceKey::Worktree)
             .unwrap_or(&0)
             != &0
     }
+
+    // Whether watch would have a result for the test on this commit, rather
+    // than leaving it out or skipping it. Doesn't account for dependencies
+    // being skipped.
+    pub fn runs_on(&self, commit: &Commit, changed_files: &[PathBuf]) -> bool {
+        self.run_by_default
+            && !skipped_by_message(&commit.message, &self.name)
+            && self.when.matches(commit, Some(changed_files))
+            && (self.skip_if_unchanged.is_empty()
+                || changes_any(changed_files, &self.skip_if_unchanged))
+    }
+
+    // Whether the files the commit changes decide if the test is run on it.
+    fn needs_changed_files(&self) -> bool {
+        !self.skip_if_unchanged.is_empty() || !self.when.paths.is_empty()
+    }
+
+    // The description and owner on one line, if the test has either.
+    pub fn about(&self) -> Option<String> {
+        match (&self.description, &self.owner) {
+            (None, None) => None,
+            (Some(description), None) => Some(description.clone()),
+            (None, Some(owner)) => Some(format!("owner: {owner}")),
+            (Some(description), Some(owner)) => Some(format!("{description} (owner: {owner})")),
+        }
+    }
 }
 
 impl Display for Test {
@@ -173,7 +496,7 @@ impl GraphNode for Arc<Test> {
     }
 
     fn child_ids(&self) -> Vec<impl Borrow<TestName>> {
-        self.depends_on.iter().collect()
+        self.depends_on.iter().chain(&self.stage_gate).collect()
     }
 }
 
@@ -195,15 +518,19 @@ pub fn base_job_env(repo_origin: impl Into<PathBuf>, config_path: impl Into<Path
     ]
 }
 
+// Jobs that a Manager lets get past waiting for their dependencies at once.
+// Ought to be enough concurrency for anyone.
+pub const MAX_ACTIVE_JOBS: usize = 64;
+
 // Manages a bunch of worker threads that run tests for the current set of revisions.
 pub struct Manager<W: Worktree> {
     // We hardly need this field, it should be quite easy to remove it.
     repo: Arc<W>,
     // Oops, be extremely careful about mutating this. set_revisions has some
     // pretty strong implicit assumptions about this field.
-    job_cts: Mutex<HashMap<TestCaseId, CancellationToken>>,
+    jobs: Mutex<HashMap<TestCaseId, JobHandle>>,
     job_counter: JobCounter,
-    notif_tx: broadcast::Sender<Arc<Notification>>,
+    notif_tx: NotifSender,
     tests: TestDag,
     // Pools contains sets of intangible arbitrary "resources" that can be used to throttle test
     // jobs, and also tracks access to reused worktrees. The indices of the token-type resources
@@ -213,6 +540,26 @@ pub struct Manager<W: Worktree> {
     job_env: Arc<Vec<(String, String)>>,
     // To avoid spinning up zillions of jobs at once, that can lead to fd exhaustion.
     job_sem: Arc<Semaphore>,
+    // Revision that jobs get the merge base with.
+    upstream: Mutex<Option<OsString>>,
+    // Files changed by each commit we've seen. Commits don't change so this
+    // never needs invalidating.
+    changed_files: Mutex<HashMap<CommitHash, Arc<Vec<PathBuf>>>>,
+    throttle: Option<Arc<Throttle>>,
+    shard: Option<Shard>,
+    scheduling: Scheduling,
+    sample: Option<Sample>,
+    // Cached failures recorded before this are ignored.
+    rerun_failures_before: Option<SystemTime>,
+    // The range as last passed to set_commits.
+    range: Mutex<Range>,
+    // Test cases in the range that we've told listeners aren't going to be
+    // run, so they don't get told again.
+    not_applicable: Mutex<HashMap<TestCaseId, NotApplicable>>,
+    // Same for test cases that are skipped because the commit doesn't change
+    // anything they care about.
+    skipped: Mutex<HashMap<TestCaseId, TestCase>>,
+    reported_tests: ReportedTests,
 }
 
 // We need to specify 'static here. Just because we have an Arc over the
@@ -239,95 +586,295 @@ impl<W: Worktree + Sync + Send + 'static> Manager<W> {
         Self {
             job_env: Arc::new(base_job_env(repo.path(), config_path)),
             repo,
-            notif_tx: result_tx,
-            job_cts: Mutex::new(HashMap::new()),
+            notif_tx: NotifSender {
+                tx: result_tx,
+                coalesced: Arc::new(CoalescedResults::default()),
+            },
+            jobs: Mutex::new(HashMap::new()),
             job_counter: JobCounter::new(),
             tests,
             resource_pools,
             result_db,
-            job_sem: Arc::new(Semaphore::new(64)), // Ough to be enough concurrency for anyone.
+            job_sem: Arc::new(Semaphore::new(MAX_ACTIVE_JOBS)),
+            upstream: Mutex::new(None),
+            changed_files: Mutex::new(HashMap::new()),
+            throttle: None,
+            shard: None,
+            scheduling: Scheduling::default(),
+            sample: None,
+            rerun_failures_before: None,
+            range: Mutex::new(Range::default()),
+            not_applicable: Mutex::new(HashMap::new()),
+            skipped: Mutex::new(HashMap::new()),
+            reported_tests: ReportedTests::default(),
         }
     }
 
-    fn spawn_job(&self, job: TestJob) {
-        job.notifier.notify(&TestStatus::Enqueued);
+    // Tell jobs their commit's merge base with this revision. This only
+    // affects revisions set after it's called.
+    pub fn set_upstream(&self, upstream: impl Into<OsString>) {
+        *self.upstream.lock() = Some(upstream.into());
+    }
 
-        let pools = self.resource_pools.clone();
-        let origin_worktree = self.repo.clone();
-        let db = self.result_db.clone();
-        tokio::spawn(async move {
-            let _ = job.run(db, &pools, origin_worktree.path()).await;
-        });
+    // Don't start jobs unless this throttle admits them.
+    pub fn with_throttle(mut self, throttle: Arc<Throttle>) -> Self {
+        self.throttle = Some(throttle);
+        self
     }
 
-    // Interrupt any revisions that are not in revs, start testing all revisions in revs that are
-    // not already tested or being tested.
-    // It doesn't make sense to call this function if you don't have a receiver
-    // from already having called [[results]].
-    pub async fn set_revisions<I, R>(&self, revs: I) -> anyhow::Result<()>
-    where
-        I: IntoIterator<Item = R>,
-        R: Into<CommitHash> + Debug,
-    {
-        // Build the set test cases we need to kick off.
-        // TODO: this is kinda silly, it looks up the tree from the hash for
-        // every indiviual test, we only need to do that at most once per
-        // revision. But doing this efficiently makes for code that's probably
-        // more complex than necessary.
-        let commits = try_join_all(revs.into_iter().map(|rev| {
-            let commit_hash = rev.into();
-            self.repo
-                .rev_parse(commit_hash.clone())
-                .map(move |result| result?.ok_or(anyhow!("no such revision {commit_hash:?}")))
-        }))
-        .await?;
+    // Only test the test cases in this shard, plus their dependencies.
+    pub fn with_shard(mut self, shard: Shard) -> Self {
+        self.shard = Some(shard);
+        self
+    }
 
-        self.set_commits(commits)
+    // Decides which jobs get resources first when they're contended.
+    pub fn with_scheduling(mut self, scheduling: Scheduling) -> Self {
+        self.scheduling = scheduling;
+        self
     }
 
-    // Inner non-async helper for set_revisions.
-    pub fn set_commits(&self, commits: impl IntoIterator<Item = Commit>) -> anyhow::Result<()> {
-        let mut job_cts = self.job_cts.lock();
+    // Run tests again if their cached result is a failure that was recorded
+    // before this time. Cached passes are still trusted.
+    pub fn with_rerun_failures_before(mut self, time: SystemTime) -> Self {
+        self.rerun_failures_before = Some(time);
+        self
+    }
 
-        let test_cases: HashMap<TestCaseId, TestCase> = commits
-            .into_iter()
-            .cartesian_product(self.tests.nodes())
-            .map(|(commit, test)| {
-                let tc = TestCase::new(commit, test.clone());
-                (tc.id(), tc)
-            })
-            .collect();
+    // Only test every nth commit in the range, plus the newest one. Call
+    // refine_sample as results come in to bisect where they change.
+    pub fn with_sample(mut self, every: usize) -> Self {
+        self.sample = Some(Sample { every });
+        self
+    }
 
-        // Cancel jobs for test cases that we don't care about any more.
-        // https://github.com/rust-lang/rust/issues/59618 would make this more convenient.
-        *job_cts = job_cts
-            .drain()
-            .filter(|(id, cancellation_token)| {
-                if !test_cases.contains_key(id) {
-                    cancellation_token.cancel();
-                    return false;
-                }
-                true
+    // Is test_name needed by one of the tests that pred picks?
+    fn needed_by(&self, test_name: &TestName, pred: impl Fn(&Test) -> bool) -> bool {
+        self.tests.nodes().any(|test| {
+            pred(test)
+                && self
+                    .tests
+                    .top_down_from(&test.name)
+                    .expect("test missing from its own DAG")
+                    .any(|dep| &dep.name == test_name)
+        })
+    }
+
+    // Whether the test should be run at the commit for its own sake, rather
+    // than as a dependency. Commits that aren't in the range are only checked
+    // against the shard.
+    fn wanted(
+        &self,
+        range: &Range,
+        commit_hash: &CommitHash,
+        test: &Test,
+        changed_files: Option<&[PathBuf]>,
+    ) -> bool {
+        let listed = |tests: &HashMap<CommitHash, HashSet<TestName>>| {
+            tests
+                .get(commit_hash)
+                .is_some_and(|names| names.contains(&test.name))
+        };
+        if listed(&range.requested) {
+            return true;
+        }
+        if !test.run_by_default
+            || !self
+                .shard
+                .as_ref()
+                .is_none_or(|shard| shard.contains(commit_hash, &test.name))
+        {
+            return false;
+        }
+        let Some(&index) = range.indices.get(commit_hash) else {
+            return true;
+        };
+        let sampled = self
+            .sample
+            .as_ref()
+            .is_none_or(|sample| sample.contains(index, range.commits.len()))
+            || listed(&range.bisecting);
+        sampled && test.when.matches(&range.commits[index], changed_files)
+    }
+
+    // Why a test that isn't selected for a commit in the range still belongs
+    // there, as opposed to tests that are only run on request or belong to
+    // another shard.
+    fn not_applicable(
+        &self,
+        commit: &Commit,
+        test: &Test,
+        changed_files: Option<&[PathBuf]>,
+    ) -> Option<NotApplicable> {
+        if !test.run_by_default
+            || !self
+                .shard
+                .as_ref()
+                .is_none_or(|shard| shard.contains(&commit.hash, &test.name))
+        {
+            return None;
+        }
+        if !test.when.matches(commit, changed_files) {
+            return Some(NotApplicable::When);
+        }
+        Some(NotApplicable::NotSampled)
+    }
+
+    // True if the test only cares about files that the commit didn't change,
+    // or the same goes for something it depends on. Stage gates don't count,
+    // a skipped earlier stage doesn't stop the test from running.
+    fn unchanged(&self, test: &Test, changed_files: Option<&[PathBuf]>) -> bool {
+        let Some(files) = changed_files else {
+            return false;
+        };
+        (!test.skip_if_unchanged.is_empty() && !changes_any(files, &test.skip_if_unchanged))
+            || test.depends_on.iter().any(|dep| {
+                let dep = self.tests.node(dep).expect("dependency missing from DAG");
+                self.unchanged(dep, changed_files)
             })
-            .collect::<HashMap<_, _>>();
+    }
 
-        // Don't start new jobs for test cases that are already running
-        let test_cases = test_cases.into_iter().filter_map(|(tc_id, tc)| {
-            if job_cts.contains_key(&tc_id) {
-                None
-            } else {
-                Some(tc)
+    // Tell listeners about test cases that won't be run, unless they've
+    // already been told.
+    fn notify_not_applicable(
+        &self,
+        commits: &[Commit],
+        merge_bases: &HashMap<CommitHash, CommitHash>,
+        changed_files: &HashMap<CommitHash, Arc<Vec<PathBuf>>>,
+    ) {
+        let mut not_applicable = HashMap::new();
+        let mut skipped = HashMap::new();
+        for (commit, test) in commits.iter().cartesian_product(self.tests.nodes()) {
+            if self.selected(&commit.hash, &test.name) {
+                continue;
             }
-        });
+            let files = changed_files
+                .get(&commit.hash)
+                .map(|files| files.as_slice());
+            let id = TestCaseId::new(&commit.hash, &test.name);
+            // Reported as skipped where it would otherwise have been run.
+            let skip = self.unchanged(test, files) && {
+                let range = self.range.lock();
+                self.needed_by(&test.name, |test| {
+                    self.wanted(&range, &commit.hash, test, files)
+                })
+            };
+            if skip {
+                let test_case = match self.skipped.lock().get(&id) {
+                    Some(test_case) => test_case.clone(),
+                    None => {
+                        let mut test_case = TestCase::new(commit.clone(), test.clone());
+                        test_case.merge_base = merge_bases.get(&commit.hash).cloned();
+                        test_case.changed_files = changed_files.get(&commit.hash).cloned();
+                        self.notif_tx.send(Arc::new(Notification {
+                            test_case: test_case.clone(),
+                            status: TestStatus::Finished(Err(TestInconclusive::Skipped)),
+                            cached: false,
+                            provenance: None,
+                        }));
+                        test_case
+                    }
+                };
+                skipped.insert(id, test_case);
+                continue;
+            }
+            let Some(reason) = self.not_applicable(commit, test, files) else {
+                continue;
+            };
+            if self.not_applicable.lock().get(&id) != Some(&reason) {
+                self.notif_tx.send(Arc::new(Notification {
+                    test_case: TestCase::new(commit.clone(), test.clone()),
+                    status: TestStatus::NotApplicable(reason),
+                    cached: false,
+                    provenance: None,
+                }));
+            }
+            not_applicable.insert(id, reason);
+        }
+        *self.not_applicable.lock() = not_applicable;
+        *self.skipped.lock() = skipped;
+    }
+
+    // Whether the test case should exist. Other instances won't share their
+    // results with us, and a test case can't be run without its dependencies,
+    // so that's also the case if something that depends on it is wanted. Test
+    // cases that would be skipped don't get a job.
+    fn selected(&self, commit_hash: &CommitHash, test_name: &TestName) -> bool {
+        let range = self.range.lock();
+        let changed_files = self.changed_files.lock().get(commit_hash).cloned();
+        let changed_files = changed_files.as_ref().map(|files| files.as_slice());
+        let test = self.tests.node(test_name).expect("test missing from DAG");
+        !self.unchanged(test, changed_files)
+            && self.needed_by(test_name, |test| {
+                self.wanted(&range, commit_hash, test, changed_files)
+                    && !self.unchanged(test, changed_files)
+            })
+    }
+
+    fn spawn_job(&self, job: TestJob) -> JobHandle {
+        job.notifier.notify(&TestStatus::Enqueued);
+
+        let mut handle = JobHandle {
+            test_case: job.test_case.clone(),
+            ct: job.ct.clone(),
+            cancel_reason: job.cancel_reason.clone(),
+            priority: job.priority.clone(),
+            status: job.notifier.latest.clone(),
+            join: None,
+        };
+        let pools = self.resource_pools.clone();
+        let origin_worktree = self.repo.clone();
+        let db = self.result_db.clone();
+        handle.join = Some(tokio::spawn(async move {
+            let _ = job.run(db, &pools, origin_worktree.as_ref()).await;
+        }));
+        handle
+    }
+
+    // Position of each test in the order the scheduling policy wants to run
+    // them in.
+    fn test_indices(&self) -> HashMap<TestName, usize> {
+        let names: Vec<&TestName> = self.tests.nodes().map(|test| &test.name).collect();
+        let order: Vec<usize> = match self.scheduling {
+            Scheduling::FailFast => fail_fast_order(
+                &names
+                    .iter()
+                    .map(|name| {
+                        self.result_db
+                            .history(name)
+                            .inspect_err(|e| warn!("reading history of {name}: {e:#}"))
+                            .unwrap_or_default()
+                    })
+                    .collect::<Vec<_>>(),
+            ),
+            Scheduling::BreadthFirst | Scheduling::DepthFirst => (0..names.len()).collect(),
+        };
+        order
+            .into_iter()
+            .enumerate()
+            .map(|(pos, idx)| (names[idx].clone(), pos))
+            .collect()
+    }
 
+    // Build jobs for a set of test cases. All the dependencies of each test
+    // case must be included. If rerun is set, that test case ignores any
+    // cached result. commit_indices gives the position of each commit in the
+    // range, for scheduling.
+    fn build_jobs(
+        &self,
+        test_cases: impl IntoIterator<Item = TestCase>,
+        rerun: Option<&TestCaseId>,
+        commit_indices: &HashMap<CommitHash, usize>,
+    ) -> anyhow::Result<HashMap<TestCaseId, TestJob>> {
         // Build the jobs. We do this bottom-up so that depending jobs can refer
         // to the notifier of the jobs they depend on (which we can therefore
         // trust has been constructed already).
         let test_cases = Dag::new(test_cases).expect("failed to build test case DAG");
+        let test_indices = self.test_indices();
         // Note we don't actually need the Dag structure for the jobs, and since
         // we don't have a GraphNode implementation for TestJob, we just collect
         // them into a HashMap instead.
-        let jobs = test_cases.bottom_up().try_fold(
+        test_cases.bottom_up().try_fold(
             HashMap::new(),
             |mut jobs, test_case| -> anyhow::Result<HashMap<TestCaseId, TestJob>> {
                 let wait_for = test_case
@@ -338,7 +885,7 @@ impl<W: Worktree + Sync + Send + 'static> Manager<W> {
                         (dep_job.test_name().clone(), dep_job.subscribe_completion())
                     })
                     .collect();
-                let job = TestJobBuilder::new(
+                let mut builder = TestJobBuilder::new(
                     CancellationToken::new(),
                     // TODO: it would be nice if we had an into_ variant of
                     // the bottom_up so we didn't need this clone.
@@ -349,47 +896,697 @@ impl<W: Worktree + Sync + Send + 'static> Manager<W> {
                 .with_sem(self.job_sem.clone())
                 .with_token(self.job_counter.get())
                 .with_global_notif(self.notif_tx.clone())
-                .build();
-                jobs.insert(test_case.id(), job);
+                .with_priority(Priority::with_order(
+                    self.scheduling.order(
+                        commit_indices
+                            .get(&test_case.commit_hash)
+                            .copied()
+                            .unwrap_or_default(),
+                        test_indices[&test_case.test.name],
+                    ),
+                ));
+                if rerun == Some(&test_case.id()) {
+                    builder = builder.with_rerun();
+                }
+                if let Some(throttle) = &self.throttle {
+                    builder = builder.with_throttle(throttle.clone());
+                }
+                if let Some(time) = self.rerun_failures_before {
+                    builder = builder.with_rerun_failures_before(time);
+                }
+                builder = builder.with_changed_files();
+                jobs.insert(test_case.id(), builder.build());
                 Ok(jobs)
             },
-        )?;
+        )
+    }
+
+    // Interrupt any revisions that are not in revs, start testing all revisions in revs that are
+    // not already tested or being tested.
+    // It doesn't make sense to call this function if you don't have a receiver
+    // from already having called [[results]].
+    pub async fn set_revisions<I, R>(&self, revs: I) -> anyhow::Result<()>
+    where
+        I: IntoIterator<Item = R>,
+        R: Into<CommitHash> + Debug,
+    {
+        // After a rebase most of the range is usually the same as before, only
+        // look up the commits we don't already know about.
+        let revs: Vec<CommitHash> = revs.into_iter().map(|rev| rev.into()).unique().collect();
+        let (mut known, mut merge_bases) = {
+            let range = self.range.lock();
+            let known: HashMap<CommitHash, Commit> = revs
+                .iter()
+                .filter_map(|rev| {
+                    let commit = &range.commits[*range.indices.get(rev)?];
+                    Some((rev.clone(), commit.clone()))
+                })
+                .collect();
+            (known, range.merge_bases.clone())
+        };
+        let new_revs: Vec<CommitHash> = revs
+            .iter()
+            .filter(|rev| !known.contains_key(*rev))
+            .cloned()
+            .collect();
+        let known_hashes: HashSet<CommitHash> = known.keys().cloned().collect();
+        debug!(
+            "Range update: {} new commits, {} already known",
+            new_revs.len(),
+            known.len()
+        );
+        let mut parsed = self
+            .repo
+            .rev_parse_all(&new_revs)
+            .await
+            .context("looking up trees for revisions")?
+            .into_iter();
+        let commits: Vec<Commit> = revs
+            .iter()
+            .map(|rev| match known.remove(rev) {
+                Some(commit) => commit,
+                None => parsed
+                    .next()
+                    .expect("rev_parse_all returned too few commits"),
+            })
+            .collect();
 
-        for (tc_id, job) in jobs.into_iter() {
-            job_cts.insert(tc_id.clone(), job.ct.clone());
-            self.spawn_job(job);
+        // Commits that left the range don't need their merge bases any more,
+        // the ones that joined it need theirs looking up.
+        merge_bases.retain(|hash, _| known_hashes.contains(hash));
+        let joined: Vec<CommitHash> = commits
+            .iter()
+            .filter(|c| !known_hashes.contains(&c.hash))
+            .map(|c| c.hash.clone())
+            .collect();
+        let upstream = self.upstream.lock().clone();
+        if let Some(upstream) = &upstream {
+            merge_bases.extend(
+                self.repo
+                    .merge_bases(upstream, joined)
+                    .await
+                    .context("looking up merge bases")?,
+            );
         }
-        Ok(())
-    }
+        // Jobs that get run list their own changed files, this is only needed
+        // if they decide which jobs there are.
+        let changed_files = if self.tests.nodes().any(|test| test.needs_changed_files()) {
+            self.lookup_changed_files(&commits).await?
+        } else {
+            HashMap::new()
+        };
 
-    pub async fn cancel_running(&self) -> anyhow::Result<()> {
-        self.set_revisions::<_, CommitHash>([]).await
+        self.set_commits(commits, &merge_bases, &changed_files)
     }
 
-    // Streams results back. Note you need to call this _before_ you generate the results you want
-    // to receive.
-    //
-    // I think the "proper" solution for this is to return a Stream. But I don't understand it.
-    pub fn results(&self) -> broadcast::Receiver<Arc<Notification>> {
-        self.notif_tx.subscribe()
+    async fn lookup_changed_files(
+        &self,
+        commits: &[Commit],
+    ) -> anyhow::Result<HashMap<CommitHash, Arc<Vec<PathBuf>>>> {
+        // This is a git command per commit, which for a big range takes a
+        // while if they're run one by one.
+        stream::iter(commits)
+            .map(|commit| async move {
+                let cached = self.changed_files.lock().get(&commit.hash).cloned();
+                let files = match cached {
+                    Some(files) => files,
+                    None => {
+                        let files = git::changed_files(
+                            self.repo.as_ref(),
+                            &commit.hash,
+                            commit.parents.first(),
+                        )
+                        .await?;
+                        let files = Arc::new(files);
+                        self.changed_files
+                            .lock()
+                            .insert(commit.hash.clone(), files.clone());
+                        files
+                    }
+                };
+                anyhow::Ok((commit.hash.clone(), files))
+            })
+            .buffer_unordered(CHANGED_FILES_CONCURRENCY)
+            .try_collect()
+            .await
     }
 
-    // Completes once there are no pending jobs or results.
-    pub async fn settled(&self) {
-        self.job_counter.zero().await;
-    }
+    // Inner non-async helper for set_revisions.
+    fn set_commits(
+        &self,
+        commits: impl IntoIterator<Item = Commit>,
+        merge_bases: &HashMap<CommitHash, CommitHash>,
+        changed_files: &HashMap<CommitHash, Arc<Vec<PathBuf>>>,
+    ) -> anyhow::Result<()> {
+        let mut jobs = self.jobs.lock();
+
+        let commits: Vec<Commit> = commits.into_iter().collect();
+        self.range.lock().set(&commits, merge_bases);
+        self.notify_not_applicable(&commits, merge_bases, changed_files);
+        let commit_indices = match &self.sample {
+            Some(sample) => {
+                // Get a rough picture of the whole range first. Bisection
+                // test cases aren't in here, so they get 0 and go before
+                // everything else.
+                let sampled: Vec<usize> = (0..commits.len())
+                    .filter(|&i| sample.contains(i, commits.len()))
+                    .collect();
+                coarse_to_fine(&sampled)
+                    .into_iter()
+                    .enumerate()
+                    .map(|(rank, i)| (commits[i].hash.clone(), rank + 1))
+                    .collect()
+            }
+            None => commits
+                .iter()
+                .enumerate()
+                .map(|(i, commit)| (commit.hash.clone(), i))
+                .collect(),
+        };
+        let skipped = self.skipped.lock().clone();
+        let test_cases: HashMap<TestCaseId, TestCase> = commits
+            .iter()
+            .cartesian_product(self.tests.nodes())
+            .filter(|(commit, test)| self.selected(&commit.hash, &test.name))
+            .map(|(commit, test)| {
+                let mut tc = TestCase::new(commit.clone(), test.clone());
+                tc.merge_base = merge_bases.get(&tc.commit_hash).cloned();
+                tc.changed_files = changed_files.get(&tc.commit_hash).cloned();
+                tc.skipped_gates = test
+                    .stage_gate
+                    .iter()
+                    .filter(|gate| skipped.contains_key(&TestCaseId::new(&commit.hash, gate)))
+                    .cloned()
+                    .collect();
+                (tc.id(), tc)
+            })
+            .collect();
+        {
+            let not_applicable = self.not_applicable.lock();
+            let mut reported: HashMap<CommitHash, HashSet<TestName>> = HashMap::new();
+            for (commit, test) in commits.iter().cartesian_product(self.tests.nodes()) {
+                let id = TestCaseId::new(&commit.hash, &test.name);
+                if test_cases.contains_key(&id)
+                    || not_applicable.contains_key(&id)
+                    || skipped.contains_key(&id)
+                {
+                    reported
+                        .entry(commit.hash.clone())
+                        .or_default()
+                        .insert(test.name.clone());
+                }
+            }
+            *self.reported_tests.0.lock() = reported;
+        }
 
-    pub fn into_resource_pools(self) -> Arc<Pools> {
-        self.resource_pools
+        // Cancel jobs for test cases that we don't care about any more.
+        // https://github.com/rust-lang/rust/issues/59618 would make this more convenient.
+        jobs.retain(|id, job| {
+            if !test_cases.contains_key(id) {
+                job.cancel(CancelReason::LeftRange);
+                return false;
+            }
+            true
+        });
+
+        // Don't start new jobs for test cases that are already running
+        let test_cases = test_cases
+            .into_iter()
+            .filter_map(|(tc_id, tc)| (!jobs.contains_key(&tc_id)).then_some(tc));
+
+        for (tc_id, job) in self.build_jobs(test_cases, None, &commit_indices)? {
+            jobs.insert(tc_id, self.spawn_job(job));
+        }
+        Ok(())
     }
-}
 
-struct TestStatusNotifier {
-    test_case: TestCase,
-    // Used to feed into the overall notification channel for observers to keep
-    // track of what the whole Manager is doing.
-    global_tx: Option<broadcast::Sender<Arc<Notification>>>,
-    // Used to notify specifically about completion of this job. Only one mesage
+    // Where a test's result differs between two neighbouring commits that were
+    // tested, start testing the commit halfway between them. Calling this
+    // again whenever a test finishes bisects down to the commit where the
+    // result changed. Does nothing if the commits aren't being sampled.
+    pub fn refine_sample(&self) -> anyhow::Result<()> {
+        if self.sample.is_none() {
+            return Ok(());
+        }
+        let (commits, merge_bases) = {
+            let jobs = self.jobs.lock();
+            let mut range = self.range.lock();
+            let mut added = Vec::new();
+            for test in self.tests.nodes() {
+                let id = |index: usize| TestCaseId::new(&range.commits[index].hash, &test.name);
+                // Indices of the commits the test is being run on and whether
+                // it passed, None if it hasn't finished yet. Inconclusive
+                // results are left out.
+                let mut results = Vec::new();
+                for index in 0..range.commits.len() {
+                    let Some(job) = jobs.get(&id(index)) else {
+                        continue;
+                    };
+                    match &*job.status.lock() {
+                        TestStatus::Finished(Ok(result)) => {
+                            results.push((index, Some(result.exit_code == 0)))
+                        }
+                        TestStatus::Finished(Err(_)) => (),
+                        _ => results.push((index, None)),
+                    }
+                }
+                for pair in results.windows(2) {
+                    let [(newer, Some(newer_passed)), (older, Some(older_passed))] = pair else {
+                        continue;
+                    };
+                    if newer_passed == older_passed {
+                        continue;
+                    }
+                    let untested: Vec<usize> = (newer + 1..*older)
+                        .filter(|index| !jobs.contains_key(&id(*index)))
+                        .collect();
+                    if let Some(&index) = untested.get(untested.len() / 2) {
+                        added.push((range.commits[index].hash.clone(), test.name.clone()));
+                    }
+                }
+            }
+            if added.is_empty() {
+                return Ok(());
+            }
+            for (commit_hash, test_name) in added {
+                range
+                    .bisecting
+                    .entry(commit_hash)
+                    .or_default()
+                    .insert(test_name);
+            }
+            (range.commits.clone(), range.merge_bases.clone())
+        };
+        let changed_files = self.changed_files.lock().clone();
+        self.set_commits(commits, &merge_bases, &changed_files)
+    }
+
+    // Start a test case that wouldn't be run otherwise, like one for a test
+    // that isn't run by default. It's kept until its commit leaves the range.
+    // The test case and its dependencies are bumped.
+    pub fn run(&self, commit_hash: &CommitHash, test_name: &TestName) -> anyhow::Result<()> {
+        if self.tests.node(test_name).is_none() {
+            bail!("no such test {test_name}");
+        }
+        let (commits, merge_bases) = {
+            let mut range = self.range.lock();
+            if !range.indices.contains_key(commit_hash) {
+                bail!("{commit_hash} is not in the range being tested");
+            }
+            range
+                .requested
+                .entry(commit_hash.clone())
+                .or_default()
+                .insert(test_name.clone());
+            (range.commits.clone(), range.merge_bases.clone())
+        };
+        let changed_files = self.changed_files.lock().clone();
+        self.set_commits(commits, &merge_bases, &changed_files)?;
+        self.bump(commit_hash, test_name)
+    }
+
+    // Latest status of each test case currently being tested.
+    pub fn statuses(&self) -> Vec<(TestCase, TestStatus)> {
+        self.jobs
+            .lock()
+            .values()
+            .map(|job| (job.test_case.clone(), job.status.lock().clone()))
+            .collect()
+    }
+
+    // Which tests will report a final status at each commit, see
+    // ReportedTests.
+    pub fn reported_tests(&self) -> ReportedTests {
+        self.reported_tests.clone()
+    }
+
+    // Wait until the test cases for these commits, for the tests that match the
+    // filter, have all finished and return their final statuses. Skipped test
+    // cases are included. Fails if any of them isn't being tested, including if
+    // its commit leaves the range while waiting.
+    pub async fn wait(
+        &self,
+        commits: &[CommitHash],
+        filter: impl Fn(&TestName) -> bool,
+    ) -> anyhow::Result<Vec<(TestCase, TestStatus)>> {
+        // Subscribe before checking, so we can't miss the last notification.
+        let mut notifs = self.results();
+        loop {
+            // With a sample, finished jobs can lead to more test cases.
+            self.refine_sample().context("bisecting sampled commits")?;
+            let ids: Vec<TestCaseId> = commits
+                .iter()
+                .cartesian_product(self.tests.nodes().filter(|test| filter(&test.name)))
+                .filter(|(commit_hash, test)| {
+                    self.selected(commit_hash, &test.name)
+                        || self
+                            .skipped
+                            .lock()
+                            .contains_key(&TestCaseId::new(commit_hash, &test.name))
+                })
+                .map(|(commit_hash, test)| TestCaseId::new(commit_hash, &test.name))
+                .collect();
+            let statuses = {
+                let jobs = self.jobs.lock();
+                let skipped = self.skipped.lock();
+                ids.iter()
+                    .map(|id| {
+                        if let Some(test_case) = skipped.get(id) {
+                            let status = TestStatus::Finished(Err(TestInconclusive::Skipped));
+                            return Ok((test_case.clone(), status, true));
+                        }
+                        let job = jobs
+                            .get(id)
+                            .ok_or_else(|| anyhow!("{} is not being tested", id.0))?;
+                        Ok((
+                            job.test_case.clone(),
+                            job.status.lock().clone(),
+                            job.join.is_some(),
+                        ))
+                    })
+                    .collect::<anyhow::Result<Vec<_>>>()?
+            };
+            // Jobs without a join handle are about to be replaced by a rerun.
+            if statuses
+                .iter()
+                .all(|(_, status, live)| *live && matches!(status, TestStatus::Finished(_)))
+            {
+                return Ok(statuses
+                    .into_iter()
+                    .map(|(test_case, status, _)| (test_case, status))
+                    .collect());
+            }
+            // Any notification might be the one we're waiting for. If we
+            // lagged and missed some, that's fine too. We hold the sender so
+            // the channel can't be closed.
+            let _ = notifs.recv().await;
+        }
+    }
+
+    // Cancel the job for a test case. It won't be restarted unless it's
+    // explicitly re-run or it leaves the range and comes back.
+    pub fn cancel(&self, commit_hash: &CommitHash, test_name: &TestName) -> anyhow::Result<()> {
+        let jobs = self.jobs.lock();
+        let job = jobs
+            .get(&TestCaseId::new(commit_hash, test_name))
+            .ok_or_else(|| anyhow!("{test_name} is not being tested at {commit_hash}"))?;
+        job.cancel(CancelReason::Requested);
+        Ok(())
+    }
+
+    // Make a test case and its dependencies the next in line for resources.
+    // This has no effect on jobs that have already started.
+    pub fn bump(&self, commit_hash: &CommitHash, test_name: &TestName) -> anyhow::Result<()> {
+        {
+            let jobs = self.jobs.lock();
+            let max = jobs.values().map(|job| job.priority.get()).max();
+            for test in self
+                .tests
+                .top_down_from(test_name)
+                .ok_or_else(|| anyhow!("no such test {test_name}"))?
+            {
+                let job = jobs
+                    .get(&TestCaseId::new(commit_hash, &test.name))
+                    .ok_or_else(|| anyhow!("{} is not being tested at {commit_hash}", test.name))?;
+                job.priority.raise_above(max.unwrap_or_default());
+            }
+        }
+        self.resource_pools.reprioritized();
+        Ok(())
+    }
+
+    // Give back the worktree that was kept after the test case failed, see
+    // Pools::park_worktree.
+    pub async fn release_worktree(
+        &self,
+        commit_hash: &CommitHash,
+        test_name: &TestName,
+    ) -> anyhow::Result<()> {
+        let Some(worktree) = self.resource_pools.unpark_worktree(commit_hash, test_name) else {
+            bail!("no worktree was kept for {test_name} at {commit_hash}");
+        };
+        return_parked_worktree(&self.resource_pools, worktree).await
+    }
+
+    // Run a test case again, ignoring any cached result. Anything that depends
+    // on it is restarted too. The test case and its dependencies are bumped.
+    pub async fn rerun(
+        &self,
+        commit_hash: &CommitHash,
+        test_name: &TestName,
+    ) -> anyhow::Result<()> {
+        let dep_names: HashSet<&TestName> = self
+            .tests
+            .top_down_from(test_name)
+            .ok_or_else(|| anyhow!("no such test {test_name}"))?
+            .map(|test| &test.name)
+            .collect();
+        // Shut down the old jobs first. Otherwise their final notifications
+        // could arrive after the new jobs have been enqueued.
+        let (restart_ids, joins) = {
+            let mut jobs = self.jobs.lock();
+            if !jobs.contains_key(&TestCaseId::new(commit_hash, test_name)) {
+                bail!("{test_name} is not being tested at {commit_hash}");
+            }
+            // Everything that depends on the test, plus everything those
+            // depend on, since new jobs can only wait for other new jobs.
+            // Dependents that weren't selected for the commit have no job, and
+            // the dependencies of the ones that were all have jobs too.
+            let mut restart_names = HashSet::new();
+            for test in self.tests.nodes() {
+                if jobs.contains_key(&TestCaseId::new(commit_hash, &test.name))
+                    && self
+                        .tests
+                        .top_down_from(&test.name)
+                        .unwrap()
+                        .any(|dep| dep.name == *test_name)
+                {
+                    restart_names.extend(
+                        self.tests
+                            .top_down_from(&test.name)
+                            .unwrap()
+                            .map(|dep| &dep.name),
+                    );
+                }
+            }
+            // Skipped stage gates have no job either.
+            let skipped = self.skipped.lock();
+            let restart_ids: Vec<TestCaseId> = restart_names
+                .iter()
+                .map(|name| TestCaseId::new(commit_hash, name))
+                .filter(|id| !skipped.contains_key(id))
+                .collect();
+            // Check everything is there before canceling anything, so an error
+            // can't leave jobs behind that were canceled but not restarted.
+            if let Some(id) = restart_ids.iter().find(|id| !jobs.contains_key(*id)) {
+                bail!("{} is not being tested", id.0);
+            }
+            let mut joins: Vec<JoinHandle<()>> = Vec::new();
+            for id in &restart_ids {
+                let job = jobs.get_mut(id).unwrap();
+                job.cancel(CancelReason::Rerun);
+                joins.extend(job.join.take());
+            }
+            (restart_ids, joins)
+        };
+        for join in joins {
+            let _ = join.await;
+        }
+
+        let mut jobs = self.jobs.lock();
+        let mut test_cases = Vec::new();
+        for id in &restart_ids {
+            match jobs.get(id) {
+                Some(job) if job.join.is_none() => test_cases.push(job.test_case.clone()),
+                // Either the commit left the range or someone else already
+                // restarted the job, in both cases we have nothing to do.
+                _ => return Ok(()),
+            }
+        }
+        let target_id = TestCaseId::new(commit_hash, test_name);
+        let max = jobs.values().map(|job| job.priority.get()).max();
+        for (tc_id, job) in self.build_jobs(test_cases, Some(&target_id), &HashMap::new())? {
+            if dep_names.contains(job.test_name()) {
+                job.priority.raise_above(max.unwrap_or_default());
+            }
+            jobs.insert(tc_id, self.spawn_job(job));
+        }
+        Ok(())
+    }
+
+    // Rerun every test case that failed or hit an error. Test cases that
+    // were canceled or skipped because of an earlier stage aren't included.
+    pub async fn rerun_failed(&self) -> anyhow::Result<()> {
+        let failed: Vec<(CommitHash, TestName)> = self
+            .statuses()
+            .into_iter()
+            .filter(|(_, status)| {
+                matches!(
+                    status,
+                    TestStatus::Finished(Err(
+                        TestInconclusive::Error(_) | TestInconclusive::ErrorExitCode(_)
+                    ))
+                ) || matches!(status, TestStatus::Finished(Ok(result)) if result.exit_code != 0)
+            })
+            .map(|(test_case, _)| (test_case.commit_hash, test_case.test.name.clone()))
+            .collect();
+        for (commit_hash, test_name) in failed {
+            self.rerun(&commit_hash, &test_name).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn cancel_running(&self) -> anyhow::Result<()> {
+        for job in self.jobs.lock().values() {
+            job.cancel(CancelReason::Shutdown);
+        }
+        self.set_revisions::<_, CommitHash>([]).await
+    }
+
+    // Streams results back. Note you need to call this _before_ you generate the results you want
+    // to receive.
+    //
+    // I think the "proper" solution for this is to return a Stream. But I don't understand it.
+    pub fn results(&self) -> broadcast::Receiver<Arc<Notification>> {
+        self.notif_tx.tx.subscribe()
+    }
+
+    // Like results, but bursts get merged instead of queued, see
+    // CoalescedResults. There can only be one of these listeners.
+    pub fn coalesced_results(&self) -> Arc<CoalescedResults> {
+        self.notif_tx.coalesced.listen();
+        self.notif_tx.coalesced.clone()
+    }
+
+    // Completes once there are no pending jobs or results.
+    pub async fn settled(&self) {
+        self.job_counter.zero().await;
+    }
+
+    pub fn into_resource_pools(self) -> Arc<Pools> {
+        self.resource_pools
+    }
+}
+
+// Put a worktree that was parked back into use. Whoever was looking at it
+// might have changed things, which would get in the way of checking out the
+// next commit.
+async fn return_parked_worktree(pools: &Pools, worktree: TempWorktree) -> anyhow::Result<()> {
+    let result = worktree
+        .reset()
+        .await
+        .with_context(|| format!("resetting {:?}", worktree.path()));
+    // It's still usable if the reset failed, the checkout might fix it.
+    pools.return_worktree(worktree);
+    result
+}
+
+// Indices of the tests in the order that's expected to hit a failure soonest.
+// That means sorting by expected duration divided by the chance of failing.
+// Tests with no history are assumed to be average, tests that never fail go
+// last. Otherwise the original order is kept.
+fn fail_fast_order(histories: &[Option<History>]) -> Vec<usize> {
+    let known: Vec<&History> = histories.iter().flatten().collect();
+    let mean = |field: fn(&History) -> f64| {
+        known.iter().map(|h| field(h)).sum::<f64>() / known.len().max(1) as f64
+    };
+    let average = History {
+        failure_rate: mean(|h| h.failure_rate),
+        duration_secs: mean(|h| h.duration_secs),
+    };
+    let cost = |idx: &usize| {
+        let history = histories[*idx].unwrap_or(average);
+        if history.failure_rate > 0.0 {
+            history.duration_secs / history.failure_rate
+        } else {
+            f64::INFINITY
+        }
+    };
+    let mut order: Vec<usize> = (0..histories.len()).collect();
+    order.sort_by(|a, b| cost(a).total_cmp(&cost(b)));
+    order
+}
+
+// The Manager's view of a job it spawned.
+struct JobHandle {
+    test_case: TestCase,
+    ct: CancellationToken,
+    cancel_reason: Arc<OnceLock<CancelReason>>,
+    priority: Priority,
+    status: Arc<Mutex<TestStatus>>,
+    // None once someone has started shutting the job down to replace it.
+    join: Option<JoinHandle<()>>,
+}
+
+impl JobHandle {
+    // If the job was already canceled, the original reason sticks.
+    fn cancel(&self, reason: CancelReason) {
+        let _ = self.cancel_reason.set(reason);
+        self.ct.cancel();
+    }
+}
+
+// For listeners that only care where each test case has got to, like the status
+// view. Each notification replaces any pending one for the same test case, so
+// a burst like the thousands of cache hits at startup can't pile up, and the
+// listener can't fall behind and lose statuses like a broadcast receiver does.
+#[derive(Default)]
+pub struct CoalescedResults {
+    // None until someone listens, until then there's no point keeping these.
+    pending: Mutex<Option<HashMap<TestCaseId, Arc<Notification>>>>,
+    ready: Notify,
+}
+
+impl CoalescedResults {
+    fn listen(&self) {
+        self.pending.lock().get_or_insert_with(HashMap::new);
+    }
+
+    fn push(&self, notif: Arc<Notification>) {
+        let mut pending = self.pending.lock();
+        if let Some(pending) = pending.as_mut() {
+            pending.insert(notif.test_case.id(), notif);
+            self.ready.notify_one();
+        }
+    }
+
+    // Waits for something to happen, then returns the latest notification for
+    // each test case that had any since the last call.
+    pub async fn recv(&self) -> Vec<Arc<Notification>> {
+        loop {
+            if let Some(pending) = self.pending.lock().as_mut() {
+                if !pending.is_empty() {
+                    return pending.drain().map(|(_, notif)| notif).collect();
+                }
+            }
+            self.ready.notified().await;
+        }
+    }
+}
+
+// Where the jobs' notifications go for the Manager's listeners.
+#[derive(Clone)]
+pub struct NotifSender {
+    tx: broadcast::Sender<Arc<Notification>>,
+    coalesced: Arc<CoalescedResults>,
+}
+
+impl NotifSender {
+    fn send(&self, notif: Arc<Notification>) {
+        // Inner failure means nobody is listening. This is expected when running unit tests.
+        let _ = self.tx.send(notif.clone());
+        self.coalesced.push(notif);
+    }
+}
+
+struct TestStatusNotifier {
+    test_case: TestCase,
+    // Whatever was most recently passed to notify.
+    latest: Arc<Mutex<TestStatus>>,
+    // Used to feed into the overall notification channel for observers to keep
+    // track of what the whole Manager is doing.
+    global_tx: Option<NotifSender>,
+    // Used to notify specifically about completion of this job. Only one mesage
     // should be sent on this channel. This is done via a separate channel so
     // that you can get notified about one job without having to wake up for a
     // bunch of other unrelated events.
@@ -401,10 +1598,11 @@ struct TestStatusNotifier {
 }
 
 impl TestStatusNotifier {
-    fn new(test_case: TestCase, global_tx: Option<broadcast::Sender<Arc<Notification>>>) -> Self {
+    fn new(test_case: TestCase, global_tx: Option<NotifSender>) -> Self {
         let completion_tx = broadcast::Sender::new(1);
         Self {
             test_case,
+            latest: Arc::new(Mutex::new(TestStatus::Enqueued)),
             global_tx,
             completion_tx,
         }
@@ -417,14 +1615,20 @@ impl TestStatusNotifier {
 
     // Report a general update to the status of the test job.
     pub fn notify(&self, status: &TestStatus) {
+        self.send(status, None);
+    }
+
+    fn send(&self, status: &TestStatus, db_entry: Option<&DatabaseEntry>) {
         debug!("{:?}: {}", self.test_case, status);
-        // Inner failure means nobody is listening. This is expected when running unit tests.
+        *self.latest.lock() = status.clone();
         let notif = Arc::new(Notification {
             test_case: self.test_case.clone(),
             status: status.clone(),
+            cached: db_entry.is_some_and(|e| e.cached()),
+            provenance: db_entry.and_then(|e| e.provenance().cloned()),
         });
         if let Some(tx) = &self.global_tx {
-            let _ = tx.send(notif);
+            tx.send(notif);
         }
     }
 
@@ -434,9 +1638,10 @@ impl TestStatusNotifier {
     // got that wrong the results would be confusing to debug, so that's why
     // sending the message consumes the JobDebNotifier.
     fn notify_completion(self, outcome: TestOutcome) {
-        self.notify(&TestStatus::Finished(
-            outcome.clone().map(|db_entry| db_entry.result().clone()),
-        ));
+        self.send(
+            &TestStatus::Finished(outcome.clone().map(|db_entry| db_entry.result().clone())),
+            outcome.as_ref().ok().map(|db_entry| db_entry.as_ref()),
+        );
         // Inner failure means nobody is listening. This is fine and normal.
         let _ = self.completion_tx.send(outcome);
     }
@@ -506,7 +1711,7 @@ impl Drop for JobToken {
 // jiggery pokery but it produced just godawful nonsense verbosity so... just be
 // careful yeah?
 #[derive(Debug)]
-struct ChildDropGuard(Child);
+struct ChildDropGuard(TrackedChild);
 
 impl Drop for ChildDropGuard {
     fn drop(&mut self) {
@@ -524,8 +1729,14 @@ pub struct TestJobBuilder {
     token: Option<JobToken>,
     env: Arc<Vec<(String, String)>>,
     wait_for: Vec<(TestName, broadcast::Receiver<TestOutcome>)>,
-    global_tx: Option<broadcast::Sender<Arc<Notification>>>,
+    global_tx: Option<NotifSender>,
     sem: Option<Arc<Semaphore>>,
+    throttle: Option<Arc<Throttle>>,
+    priority: Priority,
+    rerun: bool,
+    rerun_failures_before: Option<SystemTime>,
+    echo_prefix: Option<String>,
+    list_changed_files: bool,
 }
 
 impl TestJobBuilder {
@@ -545,6 +1756,12 @@ impl TestJobBuilder {
             token: None,
             global_tx: None,
             sem: None,
+            throttle: None,
+            priority: Priority::default(),
+            rerun: false,
+            rerun_failures_before: None,
+            echo_prefix: None,
+            list_changed_files: false,
         }
     }
 
@@ -555,6 +1772,18 @@ impl TestJobBuilder {
         self
     }
 
+    // Have this job wait for the throttle to admit it before starting.
+    fn with_throttle(mut self, throttle: Arc<Throttle>) -> Self {
+        self.throttle = Some(throttle);
+        self
+    }
+
+    // Use this priority when getting resources from the pools.
+    fn with_priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
     // Have this job also report when it's done back to this weird token counter
     // mechanism that probably shouldn't exist.
     fn with_token(mut self, token: JobToken) -> Self {
@@ -563,11 +1792,38 @@ impl TestJobBuilder {
     }
 
     // Have this job also report notifications about its status to this channel.
-    pub fn with_global_notif(mut self, tx: broadcast::Sender<Arc<Notification>>) -> Self {
+    pub fn with_global_notif(mut self, tx: NotifSender) -> Self {
         self.global_tx = Some(tx);
         self
     }
 
+    // Have this job ignore any existing result in the database.
+    fn with_rerun(mut self) -> Self {
+        self.rerun = true;
+        self
+    }
+
+    // Have this job ignore an existing failure in the database if it was
+    // recorded before this time.
+    fn with_rerun_failures_before(mut self, time: SystemTime) -> Self {
+        self.rerun_failures_before = Some(time);
+        self
+    }
+
+    // Have this job copy its output to our stderr, with this at the start of
+    // each line.
+    pub fn with_echo_prefix(mut self, prefix: String) -> Self {
+        self.echo_prefix = Some(prefix);
+        self
+    }
+
+    // Have this job list the files its commit changes before running, if the
+    // test case doesn't already know them.
+    fn with_changed_files(mut self) -> Self {
+        self.list_changed_files = true;
+        self
+    }
+
     pub fn build(self) -> TestJob {
         TestJob {
             ct: self.ct,
@@ -577,6 +1833,15 @@ impl TestJobBuilder {
             wait_for: self.wait_for,
             notifier: TestStatusNotifier::new(self.test_case, self.global_tx),
             sem: self.sem,
+            throttle: self.throttle,
+            priority: self.priority,
+            rerun: self.rerun,
+            rerun_failures_before: self.rerun_failures_before,
+            echo_prefix: self.echo_prefix,
+            list_changed_files: self.list_changed_files,
+            cancel_reason: Arc::new(OnceLock::new()),
+            claimed: false,
+            started_at: None,
         }
     }
 }
@@ -598,12 +1863,33 @@ pub struct TestJob {
     notifier: TestStatusNotifier,
     // Take a permit from this semaphore before doing any real work.
     sem: Option<Arc<Semaphore>>,
+    // Once we have resources, wait for this to admit us before starting.
+    throttle: Option<Arc<Throttle>>,
+    // Used when getting resources from the pools.
+    priority: Priority,
+    // Ignore any existing result in the database.
+    rerun: bool,
+    // Ignore an existing failure in the database from before this.
+    rerun_failures_before: Option<SystemTime>,
+    // Copy output to our stderr with this prefix.
+    echo_prefix: Option<String>,
+    // List the commit's changed files for the job if they aren't known.
+    list_changed_files: bool,
+    // Set before ct is canceled, unless it was canceled via a parent token.
+    cancel_reason: Arc<OnceLock<CancelReason>>,
+    // Whether the database gave us the test case to run, rather than a result.
+    claimed: bool,
+    // When the test command was spawned, if it was.
+    started_at: Option<Instant>,
 }
 
 pub type DepDatabaseEntries = HashMap<TestName, Arc<DatabaseEntry>>;
 
 enum DepWaitError {
     DependencyFailed(TestName),
+    StageFailed(TestName),
+    // Says why the dependency was skipped.
+    Skipped(TestInconclusive),
     Canceled,
 }
 
@@ -625,9 +1911,46 @@ impl<'a> TestJob {
         mut self,
         database: Arc<Database>,
         pools: &Pools,
-        origin_worktree_path: &Path,
+        origin_worktree: &impl Worktree,
     ) -> TestOutcome {
-        let outcome = self.do_run(database, pools, origin_worktree_path).await;
+        let outcome = self.do_run(database.clone(), pools, origin_worktree).await;
+        // Record why it was canceled, or forget why an earlier attempt was. If
+        // the job never took on the test case there's no attempt to record,
+        // the reason is only kept in memory.
+        if self.claimed {
+            let cancel_reason = match &outcome {
+                // No reason means it was canceled via a parent token, that only
+                // happens when shutting down.
+                Err(TestInconclusive::Canceled) => Some(
+                    self.cancel_reason
+                        .get()
+                        .copied()
+                        .unwrap_or(CancelReason::Shutdown),
+                ),
+                _ => None,
+            };
+            database
+                .set_cancel_reason(&self.test_case, cancel_reason)
+                .or_log_error("recording cancellation in database");
+        }
+        if let (Ok(entry), Some(started_at)) = (&outcome, self.started_at) {
+            let test = &self.test_case.test;
+            let duration = started_at.elapsed();
+            let failed = entry.exit_code() != 0;
+            // This has to happen before the run goes into the history.
+            let slowdown = match test.max_slowdown_percent {
+                Some(threshold) if !failed => database.history(&test.name).map(|history| {
+                    history.and_then(|history| Slowdown::check(duration, &history, threshold))
+                }),
+                _ => Ok(None),
+            };
+            slowdown
+                .and_then(|slowdown| database.set_slowdown(&self.test_case, slowdown))
+                .or_log_error("recording slowdown");
+            database
+                .record_run(&test.name, duration, failed)
+                .or_log_error("recording test history");
+        }
         self.notifier.notify_completion(outcome.clone());
         outcome
     }
@@ -636,8 +1959,12 @@ impl<'a> TestJob {
         &mut self,
         database: Arc<Database>,
         pools: &Pools,
-        origin_worktree_path: &Path,
+        origin_worktree: &impl Worktree,
     ) -> TestOutcome {
+        if self.test_case.skipped_by_commit {
+            return Err(TestInconclusive::SkippedByCommit);
+        }
+
         // Wait for dependencies do be done, bail early if they do anything
         // but terminate successfully.
         let dep_db_entries = match self.await_dep_success().await {
@@ -645,6 +1972,10 @@ impl<'a> TestJob {
             Err(DepWaitError::DependencyFailed(test_name)) => {
                 return Err(anyhow!("dependency job {test_name} failed").into())
             }
+            Err(DepWaitError::StageFailed(test_name)) => {
+                return Err(TestInconclusive::StageFailed(test_name))
+            }
+            Err(DepWaitError::Skipped(inconclusive)) => return Err(inconclusive),
             Err(DepWaitError::Canceled) => return Err(TestInconclusive::Canceled),
         };
 
@@ -664,16 +1995,43 @@ impl<'a> TestJob {
             None => None,
         };
 
-        let output = match database
-            .lookup(&self.test_case)
-            .await
-            .context("database lookup")?
-        {
+        let lookup = if self.rerun {
+            database.lookup_rerun(&self.test_case).await
+        } else {
+            database.lookup(&self.test_case).await
+        };
+        let stale = matches!(&lookup, Ok(LookupResult::FoundResult(entry)) if self.is_stale(entry));
+        let lookup = if stale {
+            debug!("ignoring stale failure for {:?}", self.test_case);
+            // Release our lock on the entry first.
+            drop(lookup);
+            database.lookup_rerun(&self.test_case).await
+        } else {
+            lookup
+        };
+        let mut output = match lookup.context("database lookup")? {
             LookupResult::FoundResult(db_entry) => {
                 return Ok(Arc::new(db_entry));
             }
             LookupResult::YouRunIt(output) => output,
         };
+        self.claimed = true;
+        if self.list_changed_files && self.test_case.changed_files.is_none() {
+            let files = git::changed_files(
+                origin_worktree,
+                &self.test_case.commit_hash,
+                self.test_case.parents.first(),
+            )
+            .await
+            .context("listing changed files")?;
+            self.test_case.changed_files = Some(Arc::new(files));
+        }
+        if let Some(prefix) = &self.echo_prefix {
+            output.echo_with_prefix(prefix.clone());
+        }
+        if self.test_case.test.timestamp_output {
+            output.timestamp_lines();
+        }
 
         select! {
             // This "biased" is here because otherwise when we cancel a bunch of jobs all at once,
@@ -685,16 +2043,58 @@ impl<'a> TestJob {
             biased;
 
             _ = self.ct.cancelled() => Err(TestInconclusive::Canceled),
-            resources = pools.get(self.test_case.test.needs_resources.clone()) =>  {
-                self.notifier.notify(&TestStatus::Started);
+            // Wait for the throttle after getting resources, so we don't start
+            // a job that was queued for resources while paused.
+            (mut resources, _permit) = async {
+                let test = &self.test_case.test;
+                let resources = pools
+                    .get_with_priority(
+                        test.needs_resources.clone(),
+                        test.resource_alternatives.clone(),
+                        &self.priority,
+                    )
+                    .await;
+                let permit = match &self.throttle {
+                    Some(throttle) => Some(throttle.admit().await),
+                    None => None,
+                };
+                (resources, permit)
+            } =>  {
+                self.notifier.notify(&TestStatus::Started(None));
                 if let Some(worktrees) = resources.resources(&ResourceKey::Worktree) {
                     // We "own" this worktree.
-                    let worktree = worktrees[0].as_worktree();
-                    worktree.checkout(&self.test_case.commit_hash).await.context("failed to check out revision")?;
-                    self.execute_child(worktree.path(), &resources, output, dep_db_entries).await
+                    let outcome = {
+                        let worktree = worktrees[0].as_worktree();
+                        // If anything goes wrong this deletes the branch when it's dropped.
+                        let branch = self.branch().map(|name| JobBranch::new(worktree, name));
+                        worktree.checkout(&self.test_case.commit_hash, branch.as_ref().map(JobBranch::name)).await.context("failed to check out revision")?;
+                        let git_describe = self.describe(worktree).await;
+                        let outcome = self.execute_child(worktree.path(), git_describe, &resources, output, dep_db_entries).await;
+                        if let Some(branch) = branch {
+                            if let Err(e) = branch.delete().await {
+                                warn!("{:?}: failed to clean up branch: {e:#}", self.test_case);
+                            }
+                        }
+                        outcome
+                    };
+                    if matches!(&outcome, Ok(entry) if entry.exit_code() != 0) {
+                        let worktree = resources.take_worktree().unwrap();
+                        let test_case = &self.test_case;
+                        let evicted = pools.park_worktree(test_case.commit_hash.clone(), test_case.test.name.clone(), worktree);
+                        for worktree in evicted {
+                            if let Err(e) = return_parked_worktree(pools, worktree).await {
+                                warn!("{test_case:?}: {e:#}");
+                            }
+                        }
+                    }
+                    outcome
+                } else if self.test_case.test.checkout == CheckoutMode::Archive {
+                    let dir = self.extract_archive(origin_worktree).await?;
+                    self.execute_child(dir.path(), None, &resources, output, dep_db_entries).await
                 } else {
                     // We don't "own" the "main" worktree so the job shouldn't mess with it.
-                    self.execute_child(origin_worktree_path, &resources, output, dep_db_entries).await
+                    let git_describe = self.describe(origin_worktree).await;
+                    self.execute_child(origin_worktree.path(), git_describe, &resources, output, dep_db_entries).await
                 }
             }
         }
@@ -734,29 +2134,172 @@ impl<'a> TestJob {
                         "{:?}: Dependency {:?} succeeded",
                         self.test_case.test.name, test_name
                     );
-                    ret.insert(test_name.clone(), db_entry.clone());
+                    // Tests from earlier stages don't share their artifacts.
+                    if !self.test_case.test.stage_gate.contains(test_name) {
+                        ret.insert(test_name.clone(), db_entry.clone());
+                    }
+                    continue;
+                }
+            }
+            // A skipped dependency means there's nothing for us to do either,
+            // but a skipped test from an earlier stage didn't fail.
+            if let Ok(Err(
+                inconclusive @ (TestInconclusive::Skipped | TestInconclusive::SkippedByCommit),
+            )) = &outcome
+            {
+                if self.test_case.test.stage_gate.contains(test_name) {
                     continue;
                 }
+                return Err(DepWaitError::Skipped(inconclusive.clone()));
             }
             info!(
                 "Dependency {:?} of {:?} failed: {:?}",
                 test_name, self.test_case.test.name, outcome
             );
+            if self.test_case.test.stage_gate.contains(test_name) {
+                return Err(DepWaitError::StageFailed(test_name.clone()));
+            }
             return Err(DepWaitError::DependencyFailed(test_name.clone()));
         }
         debug!("{:?}: Dependencies succeeded", self.test_case);
         Ok(ret)
     }
 
+    // For CheckoutMode::Archive. The directory is deleted when the TempDir is
+    // dropped.
+    async fn extract_archive(
+        &self,
+        origin_worktree: &impl Worktree,
+    ) -> anyhow::Result<tempfile::TempDir> {
+        let parent = Path::new(ARCHIVE_PARENT_DIR);
+        let parent = if parent.is_dir() {
+            parent.to_owned()
+        } else {
+            env::temp_dir()
+        };
+        let dir = tempfile::Builder::new()
+            .prefix("limmat-archive-")
+            // In case the job runs as another user.
+            .permissions(Permissions::from_mode(0o755))
+            .tempdir_in(&parent)
+            .with_context(|| format!("creating archive directory in {}", parent.display()))?;
+        git::extract_commit(origin_worktree, &self.test_case.commit_hash, dir.path()).await?;
+        Ok(dir)
+    }
+
+    // The branch to check out the commit on, if the test wants one. This only
+    // makes sense for tests that get their own worktree.
+    fn branch(&self) -> Option<String> {
+        let test = &self.test_case.test;
+        if test.checkout != CheckoutMode::Branch
+            || !test.needs_resources.contains_key(&ResourceKey::Worktree)
+        {
+            return None;
+        }
+        let name: String = test
+            .name
+            .0
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || "._-".contains(c) {
+                    c
+                } else {
+                    '-'
+                }
+            })
+            .collect();
+        Some(format!(
+            "{JOB_BRANCH_PREFIX}/{name}/{}",
+            self.test_case.commit_hash
+        ))
+    }
+
+    // Where the job runs, given where the commit is checked out.
+    fn job_dir(&self, checkout_dir: &Path) -> PathBuf {
+        match &self.test_case.test.cwd {
+            Some(cwd) => checkout_dir.join(cwd),
+            None => checkout_dir.to_owned(),
+        }
+    }
+
+    // Returns the private TMPDIR, if the test wants one. If the job runs as
+    // another user, that and the artifacts directory are given to them.
+    fn prepare_dirs(&self, artifacts_dir: &Path) -> anyhow::Result<Option<tempfile::TempDir>> {
+        let sandbox = &self.test_case.test.sandbox;
+        let tmp_dir = if sandbox.private_tmp {
+            Some(
+                tempfile::Builder::new()
+                    .prefix("limmat-job-")
+                    .tempdir()
+                    .context("creating private TMPDIR")?,
+            )
+        } else {
+            None
+        };
+        if let Some(user) = &sandbox.user {
+            for dir in tmp_dir.iter().map(|d| d.path()).chain([artifacts_dir]) {
+                chown(dir, Some(user.uid), Some(user.gid))
+                    .with_context(|| format!("giving {} to {:?}", dir.display(), user.name))?;
+            }
+        }
+        Ok(tmp_dir)
+    }
+
+    // For the JobSnapshot, it's not worth failing the job over.
+    async fn describe(&self, worktree: &impl Worktree) -> Option<String> {
+        git::describe(worktree)
+            .await
+            .inspect_err(|e| warn!("{:?}: {e:#}", self.test_case))
+            .ok()
+    }
+
     fn set_env(
         &self,
         cmd: &mut Command,
         resources: &Resources<'a>,
         artifacts_dir: &Path,
+        failed_step_path: &Path,
+        changed_files_path: Option<&Path>,
         dep_db_entries: &DepDatabaseEntries,
     ) {
         cmd.env("LIMMAT_COMMIT", &self.test_case.commit_hash);
+        cmd.env("LIMMAT_TREE", &self.test_case.tree_hash);
+        cmd.env("LIMMAT_PARENTS", self.test_case.parents.iter().join(" "));
+        cmd.env(
+            "LIMMAT_IS_MERGE",
+            if self.test_case.parents.len() > 1 {
+                "1"
+            } else {
+                "0"
+            },
+        );
+        if let Some(merge_base) = &self.test_case.merge_base {
+            cmd.env("LIMMAT_MERGE_BASE", merge_base);
+        }
         cmd.env("LIMMAT_ARTIFACTS", artifacts_dir);
+        cmd.env("LIMMAT_FAILED_STEP_FILE", failed_step_path);
+        cmd.env("LIMMAT_STATUS_FD", PROGRESS_FD.to_string());
+        if let Some(path) = changed_files_path {
+            cmd.env("LIMMAT_CHANGED_FILES", path);
+        }
+        if let Some(branch) = self.branch() {
+            cmd.env("LIMMAT_BRANCH", branch);
+        }
+        let cpus = resources.cpus();
+        if !cpus.is_empty() {
+            cmd.env("LIMMAT_CPUS", cpus.iter().join(","));
+        }
+        let gpus = resources.gpus();
+        if !gpus.is_empty() {
+            cmd.env("LIMMAT_GPUS", gpus.iter().join(","));
+        }
+        let cuda_devices: Vec<_> = gpus
+            .iter()
+            .filter(|gpu| matches!(gpu, Gpu::Nvidia(_)))
+            .collect();
+        if !cuda_devices.is_empty() {
+            cmd.env("CUDA_VISIBLE_DEVICES", cuda_devices.iter().join(","));
+        }
         for (k, v) in self.base_env.iter() {
             cmd.env(k, v);
         }
@@ -774,41 +2317,270 @@ impl<'a> TestJob {
                 format!("LIMMAT_ARTIFACTS_{}", test_name),
                 db_entry.artifacts_dir(),
             );
+            for output in db_entry.outputs() {
+                cmd.env(
+                    output_env_var(test_name, output),
+                    db_entry.artifacts_dir().join(output),
+                );
+            }
         }
     }
 
-    // The core part of the job - runs the actual process and returns its result.
+    // The core part of the job - runs the actual process, between the setup and
+    // teardown commands, and returns its result. git_describe is what's checked
+    // out in current_dir, for the JobSnapshot.
     async fn execute_child(
         &mut self,
         current_dir: &Path,
+        git_describe: Option<String>,
         resources: &Resources<'a>,
         mut output: DatabaseOutput,
         dep_db_entries: DepDatabaseEntries,
     ) -> TestOutcome {
         info!("Starting {:?}", self.test_case);
 
+        let current_dir = &self.job_dir(current_dir);
+        let changed_files_path = match &self.test_case.changed_files {
+            Some(files) => Some(output.write_changed_files(files)?),
+            None => None,
+        };
+        let artifacts_dir = output.artifacts_dir().to_owned();
+        // Deleted when the job is done.
+        let tmp_dir = self.prepare_dirs(&artifacts_dir)?;
+        let prepare = |cmd: &mut Command, failed_step_path: &Path, metrics_path: &Path| {
+            cmd.current_dir(current_dir);
+            if let Some(tmp_dir) = &tmp_dir {
+                cmd.env("TMPDIR", tmp_dir.path());
+            }
+            self.set_env(
+                cmd,
+                resources,
+                &artifacts_dir,
+                failed_step_path,
+                changed_files_path.as_deref(),
+                &dep_db_entries,
+            );
+            cmd.env("LIMMAT_METRICS", metrics_path);
+        };
         let mut cmd = self.test_case.test.command();
-        cmd.current_dir(current_dir)
-            .stdout(output.stdout().context("no stdout handle available")?)
+        cmd.stdout(output.stdout().context("no stdout handle available")?)
             .stderr(output.stderr().context("no stdout handle available")?);
-        self.set_env(&mut cmd, resources, output.artifacts_dir(), &dep_db_entries);
-        // It would be really confusing and annoying if we exited this function
-        // without ensuring the child is dead. So we wrap it in this sketchy
-        // drop guard thing.
-        let mut child = ChildDropGuard(cmd.spawn().context("spawning test command")?);
-        // Grab the PID now if we can, since it's a pain to look it up later for
-        // silly Rust reasons. If no PID is found we just carry on assuming the
-        // process has already shut down.
-        let pid = child
-            .0
-            .id()
-            .map(|raw| Pid::from_raw(raw.try_into().unwrap()));
-        // Await the child, or cancellation. Because the "right" branch still needs to do work on
-        // the "left" future, tokio::select doesn't grant us any clarity or concision here so we
-        // drop down to the raw function call.
-        let child_fut = pin!(child.0.wait());
-        let cancel_fut = pin!(self.ct.cancelled());
-        match future::select(child_fut, cancel_fut).await {
+        prepare(&mut cmd, &output.failed_step_path(), &output.metrics_path());
+        output.write_job_snapshot(&job_snapshot(cmd.as_std(), resources, git_describe))?;
+        // These can be made of steps too, they mustn't clobber the test
+        // command's failed step. Metrics are only for the test command too.
+        let hook_cmd = |argv: &Vec<OsString>| {
+            let mut cmd = self.test_case.test.job_command(&argv[0], &argv[1..]);
+            prepare(&mut cmd, Path::new("/dev/null"), Path::new("/dev/null"));
+            cmd
+        };
+        let setup_cmd = self.test_case.test.setup.as_ref().map(hook_cmd);
+        let teardown_cmd = self.test_case.test.teardown.as_ref().map(hook_cmd);
+        let upload_cmd = self
+            .test_case
+            .test
+            .artifact_upload
+            .as_ref()
+            .map(|upload| (upload.clone(), hook_cmd(&upload.argv)));
+
+        self.started_at = Some(Instant::now());
+        let started_at = SystemTime::now();
+        let setup_result = match setup_cmd {
+            Some(mut setup_cmd) => {
+                self.run_hook("setup", &mut setup_cmd, &output, &self.ct)
+                    .await
+            }
+            None => Ok(Some(0)),
+        };
+        let result = match setup_result {
+            Ok(Some(0)) => self.run_test_command(cmd, resources).await,
+            Ok(Some(code)) => Err(anyhow!("setup command failed with exit code {code}").into()),
+            Ok(None) => Err(TestInconclusive::Canceled),
+            Err(e) => Err(e.context("running setup command").into()),
+        };
+        // Before the teardown command, in case that cleans up the artifacts.
+        let result = match result {
+            Ok(0) => self.check_outputs(&artifacts_dir).map(|()| 0),
+            result => result,
+        };
+        if let Some(mut teardown_cmd) = teardown_cmd {
+            // Never canceled, it's what cleans up after cancellation.
+            let ct = CancellationToken::new();
+            let error = match self
+                .run_hook("teardown", &mut teardown_cmd, &output, &ct)
+                .await
+            {
+                Ok(Some(0)) => None,
+                Ok(Some(code)) => Some(format!("exit code {code}")),
+                Ok(None) => unreachable!("teardown canceled"),
+                Err(e) => Some(format!("{e:#}")),
+            };
+            if let Some(error) = error {
+                warn!("teardown for {:?} failed: {error}", self.test_case);
+                output.set_teardown_error(error);
+            }
+        }
+        if let (Ok(0), Some((upload, mut upload_cmd))) = (&result, upload_cmd) {
+            if let Some(error) = self
+                .upload_artifacts(&upload, &mut upload_cmd, &output)
+                .await
+            {
+                warn!("artifact upload for {:?} failed: {error}", self.test_case);
+                output.set_upload_error(error);
+            }
+        }
+        let exit_code = result?;
+        if exit_code == 0 {
+            output.set_outputs(self.test_case.test.outputs.clone());
+        }
+        Ok(Arc::new(
+            output
+                .set_result(&TestResult { exit_code }, started_at)
+                .await?,
+        ))
+    }
+
+    fn check_outputs(&self, artifacts_dir: &Path) -> Result<(), TestInconclusive> {
+        for output in &self.test_case.test.outputs {
+            if !artifacts_dir.join(output).exists() {
+                return Err(anyhow!(
+                    "test passed but didn't produce its declared output {output:?} in \
+                     $LIMMAT_ARTIFACTS"
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    // Tries the artifact_upload command until it succeeds or runs out of
+    // retries. Returns why the last attempt failed.
+    async fn upload_artifacts(
+        &self,
+        upload: &ArtifactUpload,
+        cmd: &mut Command,
+        output: &DatabaseOutput,
+    ) -> Option<String> {
+        let mut attempt = 0;
+        loop {
+            let error = match self.run_hook("upload", cmd, output, &self.ct).await {
+                Ok(Some(0)) => return None,
+                Ok(Some(code)) => format!("exit code {code}"),
+                Ok(None) => return Some("canceled".into()),
+                Err(e) => format!("{e:#}"),
+            };
+            if attempt == upload.retries {
+                return Some(error);
+            }
+            attempt += 1;
+            debug!(
+                "artifact upload for {:?} failed ({error}), retrying",
+                self.test_case
+            );
+            select! {
+                _ = sleep(upload.retry_delay) => (),
+                _ = self.ct.cancelled() => return Some("canceled".into()),
+            }
+        }
+    }
+
+    // Runs a setup, teardown or upload command, with its output going to
+    // name.txt in the result directory. Returns None if it was canceled.
+    async fn run_hook(
+        &self,
+        name: &str,
+        cmd: &mut Command,
+        output: &DatabaseOutput,
+        ct: &CancellationToken,
+    ) -> anyhow::Result<Option<ExitCode>> {
+        let file = output.hook_output(name)?;
+        cmd.stdout(file.try_clone().context("duplicating output file")?)
+            .stderr(file);
+        let mut child = ChildDropGuard(
+            TrackedChild::spawn(cmd).with_context(|| format!("spawning {name} command"))?,
+        );
+        select! {
+            wait_result = child.0.wait() => {
+                Ok(Some(wait_result.context("awaiting child")?.code_not_killed()?))
+            }
+            _ = ct.cancelled() => {
+                if let Some(pid) = child.0.id() {
+                    killpg(Pid::from_raw(pid.try_into().unwrap()), Signal::SIGTERM)
+                        .or_log_error("SIGTERMing child process");
+                }
+                // If this times out, the drop guard SIGKILLs it.
+                let _ = timeout(self.test_case.test.shutdown_grace_period, child.0.wait()).await;
+                Ok(None)
+            }
+        }
+    }
+
+    // Runs the test command itself.
+    async fn run_test_command(
+        &mut self,
+        mut cmd: Command,
+        resources: &Resources<'a>,
+    ) -> Result<ExitCode, TestInconclusive> {
+        let (progress_rx, progress_tx) =
+            pipe2(OFlag::O_CLOEXEC).context("creating progress pipe")?;
+        let progress_tx_fd = progress_tx.as_raw_fd();
+        let cpus = resources.cpus();
+        let cpu_set = if cpus.is_empty() {
+            None
+        } else {
+            let mut cpu_set = CpuSet::new();
+            for cpu in cpus {
+                cpu_set.set(cpu).context("building CPU set")?;
+            }
+            Some(cpu_set)
+        };
+        // SAFETY: dup2, fcntl and sched_setaffinity are async-signal-safe.
+        unsafe {
+            cmd.pre_exec(move || {
+                if progress_tx_fd == PROGRESS_FD {
+                    // dup2 would be a no-op, leaving O_CLOEXEC set.
+                    fcntl(progress_tx_fd, FcntlArg::F_SETFD(FdFlag::empty()))?;
+                } else {
+                    dup2(progress_tx_fd, PROGRESS_FD)?;
+                }
+                if let Some(cpu_set) = &cpu_set {
+                    sched_setaffinity(Pid::from_raw(0), cpu_set)?;
+                }
+                Ok(())
+            });
+        }
+        // It would be really confusing and annoying if we exited this function
+        // without ensuring the child is dead. So we wrap it in this sketchy
+        // drop guard thing.
+        let mut child =
+            ChildDropGuard(TrackedChild::spawn(&mut cmd).context("spawning test command")?);
+        // Otherwise we'd never see EOF on the read ends. The Command holds the
+        // write ends of any output pipes.
+        drop(progress_tx);
+        drop(cmd);
+        // Grab the PID now if we can, since it's a pain to look it up later for
+        // silly Rust reasons. If no PID is found we just carry on assuming the
+        // process has already shut down.
+        let pid = child
+            .0
+            .id()
+            .map(|raw| Pid::from_raw(raw.try_into().unwrap()));
+        // Await the child, or cancellation. Because the "right" branch still needs to do work on
+        // the "left" future, tokio::select doesn't grant us any clarity or concision here so we
+        // drop down to the raw function call.
+        let child_fut = pin!(async {
+            // The child can close the pipe before it exits, in that case
+            // just keep waiting for it.
+            let progress = self
+                .report_progress(progress_rx)
+                .then(|()| future::pending::<()>());
+            select! {
+                wait_result = child.0.wait() => wait_result,
+                _ = progress => unreachable!(),
+            }
+        });
+        let cancel_fut = pin!(self.ct.cancelled());
+        match future::select(child_fut, cancel_fut).await {
             Either::Left((wait_result, _)) =>
             // Test completed, figure out the result. I think maybe a true Rustacean would
             // write this block as a single chain of methods? But it seems ridiculous to me.
@@ -817,9 +2589,7 @@ impl<'a> TestJob {
                 if self.test_case.test.error_exit_codes.contains(&exit_code) {
                     return Err(TestInconclusive::ErrorExitCode(exit_code));
                 }
-                Ok(Arc::new(
-                    output.set_result(&TestResult { exit_code }).await?,
-                ))
+                Ok(exit_code)
             }
             Either::Right((_, child_fut)) => {
                 // Canceled. Shut down the process if necessary.
@@ -851,6 +2621,49 @@ impl<'a> TestJob {
         }
     }
 
+    // Is this a failure that we've been asked to run again, or that has
+    // expired?
+    fn is_stale(&self, entry: &DatabaseEntry) -> bool {
+        if entry.exit_code() == 0 {
+            return false;
+        }
+        // Results from versions that didn't record when they started are
+        // assumed to be old.
+        let started_at = entry.provenance().and_then(|p| p.started_at);
+        let before = |time: SystemTime| started_at.is_none_or(|started_at| started_at < time);
+        self.rerun_failures_before.is_some_and(before)
+            || self
+                .test_case
+                .test
+                .failure_cache_ttl
+                .and_then(|ttl| SystemTime::now().checked_sub(ttl))
+                .is_some_and(before)
+    }
+
+    // Notify the latest line the job wrote to the pipe as its progress, until
+    // the pipe is closed.
+    async fn report_progress(&self, pipe: OwnedFd) {
+        let rx = match pipe::Receiver::from_owned_fd(pipe) {
+            Ok(rx) => rx,
+            Err(e) => {
+                warn!("{:?}: can't read progress pipe: {e}", self.test_case);
+                return;
+            }
+        };
+        let mut lines = BufReader::new(rx).lines();
+        while let Ok(Some(mut line)) = lines.next_line().await {
+            // Skip straight to the latest line if the job has written several.
+            while let Some(Ok(Some(next))) = lines.next_line().now_or_never() {
+                line = next;
+            }
+            let progress = line.trim();
+            self.notifier.notify(&TestStatus::Started(
+                (!progress.is_empty()).then(|| progress.to_owned()),
+            ));
+            sleep(PROGRESS_INTERVAL).await;
+        }
+    }
+
     // This is a specialised entry point for when you already have the necessary
     // resources from the pools and you need direct control over where the job
     // runs and where its output goes. This API is wack and I hate it but I
@@ -858,23 +2671,130 @@ impl<'a> TestJob {
     // weeknights.
     pub async fn run_with(
         mut self,
-        current_dir: &Path,
+        worktree: &impl Worktree,
         resources: &Resources<'a>,
         output: DatabaseOutput,
         dep_db_entries: DepDatabaseEntries,
     ) -> TestOutcome {
+        let git_describe = self.describe(worktree).await;
         let outcome = self
-            .execute_child(current_dir, resources, output, dep_db_entries)
+            .execute_child(
+                worktree.path(),
+                git_describe,
+                resources,
+                output,
+                dep_db_entries,
+            )
             .await;
         self.notifier.notify_completion(outcome.clone());
         outcome
     }
 
+    // Runs program attached to the terminal in place of the test command, with
+    // the environment the job would get, in the worktree from resources with
+    // the commit checked out. The setup and teardown commands aren't run,
+    // nothing goes in the database and the artifacts directory is a temporary
+    // one.
+    pub async fn run_interactive(
+        &self,
+        program: &OsStr,
+        resources: &Resources<'a>,
+        dep_db_entries: &DepDatabaseEntries,
+    ) -> anyhow::Result<ExitCode> {
+        let worktree = resources
+            .resources(&ResourceKey::Worktree)
+            .context("no worktree to run in")?[0]
+            .as_worktree();
+        let branch = self.branch().map(|name| JobBranch::new(worktree, name));
+        worktree
+            .checkout(
+                &self.test_case.commit_hash,
+                branch.as_ref().map(JobBranch::name),
+            )
+            .await
+            .context("failed to check out revision")?;
+        let artifacts_dir = tempfile::Builder::new()
+            .prefix("limmat-artifacts-")
+            .tempdir()
+            .context("creating artifacts directory")?;
+        let tmp_dir = self.prepare_dirs(artifacts_dir.path())?;
+        let mut cmd = self.test_case.test.sandboxed_command(program, &[]);
+        cmd.current_dir(self.job_dir(worktree.path()));
+        if let Some(tmp_dir) = &tmp_dir {
+            cmd.env("TMPDIR", tmp_dir.path());
+        }
+        self.set_env(
+            &mut cmd,
+            resources,
+            artifacts_dir.path(),
+            Path::new("/dev/null"),
+            None,
+            dep_db_entries,
+        );
+        cmd.env("LIMMAT_METRICS", "/dev/null");
+        // Progress reports go nowhere, but they shouldn't fail.
+        let dev_null = OpenOptions::new()
+            .write(true)
+            .open("/dev/null")
+            .context("opening /dev/null")?;
+        let dev_null_fd = dev_null.as_raw_fd();
+        // SAFETY: dup2 and fcntl are async-signal-safe.
+        unsafe {
+            cmd.pre_exec(move || {
+                if dev_null_fd == PROGRESS_FD {
+                    fcntl(dev_null_fd, FcntlArg::F_SETFD(FdFlag::empty()))?;
+                } else {
+                    dup2(dev_null_fd, PROGRESS_FD)?;
+                }
+                Ok(())
+            });
+        }
+        let status = cmd.status().await.context("running command")?;
+        if let Some(branch) = branch {
+            if let Err(e) = branch.delete().await {
+                warn!("{:?}: failed to clean up branch: {e:#}", self.test_case);
+            }
+        }
+        status.code_not_killed()
+    }
+
     pub fn test_name(&self) -> &TestName {
         &self.test_case.test.name
     }
 }
 
+// The command is what the job is about to run, with its environment all set up.
+fn job_snapshot(
+    cmd: &std::process::Command,
+    resources: &Resources,
+    git_describe: Option<String>,
+) -> JobSnapshot {
+    let mut env: BTreeMap<String, String> = env::vars_os()
+        .map(|(k, v)| (k.to_string_lossy().into(), v.to_string_lossy().into()))
+        .collect();
+    for (k, v) in cmd.get_envs() {
+        let k = k.to_string_lossy().into_owned();
+        match v {
+            Some(v) => env.insert(k, v.to_string_lossy().into()),
+            None => env.remove(&k),
+        };
+    }
+    JobSnapshot {
+        argv: [cmd.get_program()]
+            .into_iter()
+            .chain(cmd.get_args())
+            .map(|arg| arg.to_string_lossy().into())
+            .collect(),
+        cwd: cmd
+            .get_current_dir()
+            .map(Path::to_owned)
+            .unwrap_or_default(),
+        env,
+        resource_tokens: resources.tokens().into_iter().collect(),
+        git_describe,
+    }
+}
+
 // An identifier that uniquely identifies a TestCase among all that can exist for a given Manager.
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct TestCaseId(String);
@@ -890,10 +2810,19 @@ impl TestCaseId {
 pub struct TestCase {
     // Commit that will be checked out to run the test.
     pub commit_hash: CommitHash,
+    pub tree_hash: TreeHash,
     // Hash that will be used to identify the test result, if caching is
     // enabled. Might be a tree hash, otherwise it matches the commit hash.
     pub cache_hash: Option<Hash>,
     pub test: Arc<Test>,
+    pub parents: Vec<CommitHash>,
+    // Merge base with the upstream, if the Manager has one.
+    pub merge_base: Option<CommitHash>,
+    // Files changed relative to the first parent, if known.
+    pub changed_files: Option<Arc<Vec<PathBuf>>>,
+    pub skipped_by_commit: bool,
+    // Stage gates that were skipped, so there's nothing to wait for.
+    pub skipped_gates: Vec<TestName>,
 }
 
 impl Debug for TestCase {
@@ -907,12 +2836,137 @@ impl Debug for TestCase {
     }
 }
 
+// Only some of the commits in the range get tested.
+struct Sample {
+    every: usize,
+}
+
+impl Sample {
+    // Counting from the oldest commit, so that the sample stays put when new
+    // commits are added.
+    fn contains(&self, index: usize, len: usize) -> bool {
+        index == 0 || (len - 1 - index) % self.every == 0
+    }
+}
+
+// The tests that will report a final status at each commit in the range: the
+// ones that are being tested there and the ones that were reported as not
+// applicable. This is shared with the Manager, but doesn't keep it alive, and
+// keeps the last range it saw after the Manager is gone.
+#[derive(Clone, Default)]
+pub struct ReportedTests(Arc<Mutex<HashMap<CommitHash, HashSet<TestName>>>>);
+
+impl ReportedTests {
+    // None if the commit isn't in the range.
+    pub fn get(&self, commit_hash: &CommitHash) -> Option<HashSet<TestName>> {
+        self.0.lock().get(commit_hash).cloned()
+    }
+}
+
+#[derive(Default)]
+struct Range {
+    commits: Vec<Commit>,
+    indices: HashMap<CommitHash, usize>,
+    merge_bases: HashMap<CommitHash, CommitHash>,
+    // When sampling, tests added on commits outside the sample to find where
+    // a test's result changes.
+    bisecting: HashMap<CommitHash, HashSet<TestName>>,
+    // Tests added with Manager::run.
+    requested: HashMap<CommitHash, HashSet<TestName>>,
+}
+
+impl Range {
+    fn set(&mut self, commits: &[Commit], merge_bases: &HashMap<CommitHash, CommitHash>) {
+        self.commits = commits.to_vec();
+        self.indices = commits
+            .iter()
+            .enumerate()
+            .map(|(i, commit)| (commit.hash.clone(), i))
+            .collect();
+        self.merge_bases = merge_bases.clone();
+        self.bisecting
+            .retain(|hash, _| self.indices.contains_key(hash));
+        self.requested
+            .retain(|hash, _| self.indices.contains_key(hash));
+    }
+}
+
+// The items reordered so that the ends come first, then the middle, then the
+// middles of each half, and so on.
+fn coarse_to_fine(items: &[usize]) -> Vec<usize> {
+    let Some(last) = items.len().checked_sub(1) else {
+        return Vec::new();
+    };
+    let mut order = vec![0];
+    if last > 0 {
+        order.push(last);
+    }
+    let mut spans = VecDeque::from([(0, last)]);
+    while let Some((start, end)) = spans.pop_front() {
+        if end - start < 2 {
+            continue;
+        }
+        let mid = (start + end) / 2;
+        order.push(mid);
+        spans.extend([(start, mid), (mid, end)]);
+    }
+    order.into_iter().map(|i| items[i]).collect()
+}
+
+// One of several roughly equal parts of the test cases, so that instances on
+// different machines can divide the work between them without talking to each
+// other. Written as "k/n", for the kth of n shards counting from 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Shard {
+    index: u64, // Counting from 0.
+    count: u64,
+}
+
+impl Shard {
+    // This has to give the same answer on every machine, so it can't use the
+    // std Hasher.
+    fn contains(&self, commit_hash: &CommitHash, test_name: &TestName) -> bool {
+        let digest = Sha3_256::new()
+            .chain_update(commit_hash.to_string())
+            .chain_update([0])
+            .chain_update(&test_name.0)
+            .finalize();
+        let n = u64::from_le_bytes(digest[..8].try_into().unwrap());
+        n % self.count == self.index
+    }
+}
+
+impl FromStr for Shard {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (k, n) = s
+            .split_once('/')
+            .ok_or_else(|| anyhow!("expected k/n, e.g. 1/2"))?;
+        let k: u64 = k.parse().context("parsing shard number")?;
+        let count: u64 = n.parse().context("parsing shard count")?;
+        if k == 0 || k > count {
+            bail!("shard number must be between 1 and {count}");
+        }
+        Ok(Self {
+            index: k - 1,
+            count,
+        })
+    }
+}
+
 impl TestCase {
     pub fn new(commit: Commit, test: Arc<Test>) -> Self {
         Self {
             cache_hash: test.cache_policy.cache_hash(&commit),
+            skipped_by_commit: skipped_by_message(&commit.message, &test.name),
             test,
             commit_hash: commit.hash,
+            tree_hash: commit.tree,
+            parents: commit.parents,
+            merge_base: None,
+            changed_files: None,
+            skipped_gates: Vec::new(),
         }
     }
 
@@ -942,6 +2996,8 @@ impl GraphNode for TestCase {
         self.test
             .depends_on
             .iter()
+            .chain(&self.test.stage_gate)
+            .filter(|test_name| !self.skipped_gates.contains(test_name))
             .map(|test_name| TestCaseId::new(&self.commit_hash, test_name))
             .collect()
     }
@@ -955,17 +3011,95 @@ pub type ExitCode = i32;
 #[derive(Debug, Clone)]
 pub enum TestStatus {
     Enqueued,
-    Started,
+    // With the latest progress reported by the job, if any.
+    Started(Option<String>),
     Finished(TestMemory),
+    // There's no job for the test case, this is only ever sent by the Manager.
+    NotApplicable(NotApplicable),
+}
+
+// Why a test isn't run on a commit in the range at all.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotApplicable {
+    When,       // The commit doesn't meet the test's "when" conditions.
+    NotSampled, // The commit isn't in the sample, see Manager::with_sample.
+}
+
+impl Display for NotApplicable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::When => write!(
+                f,
+                "Not applicable, the commit doesn't meet the test's conditions"
+            ),
+            Self::NotSampled => write!(f, "Not applicable, the commit isn't in the sample"),
+        }
+    }
+}
+
+impl TestStatus {
+    // Finished without anything going wrong. Skipped tests count, since there
+    // was nothing for them to check.
+    pub fn succeeded(&self) -> bool {
+        match self {
+            Self::Finished(Ok(result)) => result.exit_code == 0,
+            Self::Finished(Err(TestInconclusive::Skipped | TestInconclusive::SkippedByCommit)) => {
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+// What the statuses of the tests on a commit add up to, apart from advisory
+// ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Pass,
+    Fail,
+    Pending,
+}
+
+impl Verdict {
+    // A failure decides it straight away, even if other tests haven't finished.
+    pub fn of<'a>(statuses: impl IntoIterator<Item = (&'a Test, &'a TestStatus)>) -> Self {
+        let mut verdict = Self::Pass;
+        for (test, status) in statuses {
+            if test.advisory {
+                continue;
+            }
+            match status {
+                TestStatus::NotApplicable(_) => (),
+                TestStatus::Enqueued
+                | TestStatus::Started(_)
+                | TestStatus::Finished(Err(TestInconclusive::Canceled)) => verdict = Self::Pending,
+                status if status.succeeded() => (),
+                _ => return Self::Fail,
+            }
+        }
+        verdict
+    }
+}
+
+impl Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Pass => write!(f, "pass"),
+            Self::Fail => write!(f, "fail"),
+            Self::Pending => write!(f, "pending"),
+        }
+    }
 }
 
 impl Display for TestStatus {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::Enqueued => write!(f, "Enqueued"),
-            Self::Started => write!(f, "Started"),
+            Self::Started(None) => write!(f, "Started"),
+            Self::Started(Some(progress)) => write!(f, "Started ({progress})"),
             Self::Finished(Err(inconclusive)) => write!(f, "{}", inconclusive),
             Self::Finished(Ok(result)) => write!(f, "{}", result),
+            Self::NotApplicable(reason) => write!(f, "{}", reason),
         }
     }
 }
@@ -989,6 +3123,9 @@ pub enum TestInconclusive {
     // anyhow::Error doesn't implement Clone.
     Error(String),           // This includes the test getting terminated by a signal.
     ErrorExitCode(ExitCode), // The test exited with one of its configured error_exit_codes.
+    StageFailed(TestName),   // This test from an earlier stage didn't succeed.
+    Skipped,                 // No files that the test (or a dependency) cares about changed.
+    SkippedByCommit,         // The commit message said to skip the test (or a dependency).
 }
 
 impl Display for TestInconclusive {
@@ -999,6 +3136,9 @@ impl Display for TestInconclusive {
             Self::ErrorExitCode(code) => {
                 write!(f, "Exited with {}, which is in error_exit_codes", code)
             }
+            Self::StageFailed(name) => write!(f, "Not run, {} from an earlier stage failed", name),
+            Self::Skipped => write!(f, "Skipped, no relevant files changed"),
+            Self::SkippedByCommit => write!(f, "Skipped, as requested in the commit message"),
         }
     }
 }
@@ -1029,6 +3169,9 @@ impl Display for TestResult {
 pub struct Notification {
     pub test_case: TestCase,
     pub status: TestStatus,
+    // Whether a finished result came from the database instead of being run.
+    pub cached: bool,
+    pub provenance: Option<Provenance>,
 }
 
 #[cfg(test)]
@@ -1042,7 +3185,19 @@ pub mod test_utils {
         args: Vec<OsString>,
         needs_resources: HashMap<ResourceKey, usize>,
         cache_policy: CachePolicy,
+        failure_cache_ttl: Option<Duration>,
+        max_slowdown_percent: Option<u32>,
         depends_on: Vec<TestName>,
+        skip_if_unchanged: Vec<glob::Pattern>,
+        setup: Option<Vec<OsString>>,
+        teardown: Option<Vec<OsString>>,
+        artifact_upload: Option<ArtifactUpload>,
+        outputs: Vec<String>,
+        umask: Option<Mode>,
+        sandbox: Sandbox,
+        checkout: CheckoutMode,
+        separate_outputs: bool,
+        advisory: bool,
     }
 
     impl TestBuilder {
@@ -1057,7 +3212,19 @@ pub mod test_utils {
                 args: args.into_iter().map(|a| a.into()).collect(),
                 needs_resources: HashMap::new(),
                 cache_policy: CachePolicy::ByCommit,
+                failure_cache_ttl: None,
+                max_slowdown_percent: None,
                 depends_on: vec![],
+                skip_if_unchanged: vec![],
+                setup: None,
+                teardown: None,
+                artifact_upload: None,
+                outputs: Vec::new(),
+                umask: None,
+                sandbox: Sandbox::default(),
+                checkout: CheckoutMode::Detached,
+                separate_outputs: false,
+                advisory: false,
             }
         }
 
@@ -1078,23 +3245,115 @@ pub mod test_utils {
             self
         }
 
+        pub fn setup(mut self, script: &str) -> Self {
+            self.setup = Some(vec!["bash".into(), "-c".into(), script.into()]);
+            self
+        }
+
+        pub fn teardown(mut self, script: &str) -> Self {
+            self.teardown = Some(vec!["bash".into(), "-c".into(), script.into()]);
+            self
+        }
+
+        pub fn artifact_upload(mut self, script: &str, retries: u32) -> Self {
+            self.artifact_upload = Some(ArtifactUpload {
+                argv: vec!["bash".into(), "-c".into(), script.into()],
+                retries,
+                retry_delay: Duration::ZERO,
+            });
+            self
+        }
+
+        pub fn outputs(mut self, outputs: impl IntoIterator<Item = &'static str>) -> Self {
+            self.outputs = outputs.into_iter().map(String::from).collect();
+            self
+        }
+
+        pub fn umask(mut self, umask: Mode) -> Self {
+            self.umask = Some(umask);
+            self
+        }
+
+        pub fn sandbox(mut self, sandbox: Sandbox) -> Self {
+            self.sandbox = sandbox;
+            self
+        }
+
+        pub fn checkout(mut self, checkout: CheckoutMode) -> Self {
+            self.checkout = checkout;
+            self
+        }
+
+        pub fn separate_outputs(mut self) -> Self {
+            self.separate_outputs = true;
+            self
+        }
+
         pub fn cache_policy(mut self, cache_policy: CachePolicy) -> Self {
             self.cache_policy = cache_policy;
             self
         }
 
+        pub fn failure_cache_ttl(mut self, ttl: Duration) -> Self {
+            self.failure_cache_ttl = Some(ttl);
+            self
+        }
+
+        pub fn max_slowdown_percent(mut self, percent: u32) -> Self {
+            self.max_slowdown_percent = Some(percent);
+            self
+        }
+
+        pub fn advisory(mut self) -> Self {
+            self.advisory = true;
+            self
+        }
+
+        pub fn skip_if_unchanged(
+            mut self,
+            patterns: impl IntoIterator<Item = &'static str>,
+        ) -> Self {
+            for pattern in patterns {
+                self.skip_if_unchanged
+                    .push(glob::Pattern::new(pattern).expect("bad glob"));
+            }
+            self
+        }
+
         pub fn build(self) -> Test {
             Test {
                 name: self.name,
+                description: None,
+                owner: None,
+                help_url: None,
                 program: self.program,
                 args: self.args,
+                setup: self.setup,
+                teardown: self.teardown,
+                artifact_upload: self.artifact_upload,
+                outputs: self.outputs,
+                storage_quota: None,
                 needs_resources: self.needs_resources,
+                resource_alternatives: Vec::new(),
                 shutdown_grace_period: Duration::from_secs(5),
                 cache_policy: self.cache_policy,
+                failure_cache_ttl: self.failure_cache_ttl,
+                max_slowdown_percent: self.max_slowdown_percent,
                 depends_on: self.depends_on,
+                stage: None,
+                stage_gate: Vec::new(),
                 config_hash: "fake_config_hash".into(),
                 error_exit_codes: HashSet::new(),
-                separate_outputs: false,
+                separate_outputs: self.separate_outputs,
+                timestamp_output: false,
+                cwd: None,
+                checkout: self.checkout,
+                umask: self.umask,
+                sandbox: self.sandbox,
+                skip_if_unchanged: self.skip_if_unchanged,
+                when: When::default(),
+                run_by_default: true,
+                advisory: self.advisory,
             }
         }
     }
@@ -1115,7 +3374,7 @@ mod tests {
     };
 
     use anyhow::bail;
-    use future::{join_all, select_all};
+    use future::{join_all, select_all, try_join_all};
     use googletest::{
         description::Description,
         fail,
@@ -1135,7 +3394,7 @@ mod tests {
     use crate::{
         git::{
             test_utils::{TempRepo, WorktreeExt},
-            CommitHash, TempWorktree,
+            CommitHash, TempWorktree, WorktreeOptions,
         },
         resource::Resource,
         test_utils::{path_exists, timeout_5s},
@@ -1410,7 +3669,7 @@ mod tests {
         fn matches(&self, actual: &TestStatus) -> MatcherResult {
             match (self, actual) {
                 (Self::Enqueued, TestStatus::Enqueued) => MatcherResult::Match,
-                (Self::Started, TestStatus::Started) => MatcherResult::Match,
+                (Self::Started, TestStatus::Started(_)) => MatcherResult::Match,
                 (Self::Completed(exit_code), TestStatus::Finished(Ok(result))) => {
                     if result.exit_code == *exit_code {
                         MatcherResult::Match
@@ -1488,6 +3747,13 @@ mod tests {
         cache_policies: Vec<CachePolicy>,
         needs_worktree: Vec<bool>,
         dependencies: Vec<(usize, usize)>,
+        stage_gates: Vec<(usize, usize)>,
+        sample_every: Option<usize>,
+        whens: Vec<(usize, When)>,
+        not_run_by_default: Vec<usize>,
+        // Used instead of the scripted tests if set.
+        tests: Vec<Test>,
+        parked_worktrees: usize,
     }
 
     impl TestScriptFixtureBuilder {
@@ -1538,6 +3804,49 @@ mod tests {
                 .unwrap_or(0);
             self.extend(max_idx + 1)
         }
+
+        // Like dependencies, but via stage_gate instead of depends_on.
+        pub fn stage_gates(mut self, gates: impl IntoIterator<Item = (usize, usize)>) -> Self {
+            self.stage_gates = gates.into_iter().collect();
+            let max_idx = self
+                .stage_gates
+                .iter()
+                .map(|&(x, y)| max(x, y))
+                .max()
+                .unwrap_or(0);
+            self.extend(max_idx + 1)
+        }
+
+        pub fn sample_every(mut self, every: usize) -> Self {
+            self.sample_every = Some(every);
+            self
+        }
+
+        // Set the when conditions of the test with this index.
+        pub fn when(mut self, idx: usize, when: When) -> Self {
+            self.whens.push((idx, when));
+            self.extend(idx + 1)
+        }
+
+        // Set run_by_default = false for the tests with these indexes.
+        pub fn not_run_by_default(mut self, idxs: impl IntoIterator<Item = usize>) -> Self {
+            self.not_run_by_default.extend(idxs);
+            let max_idx = self.not_run_by_default.iter().copied().max().unwrap_or(0);
+            self.extend(max_idx + 1)
+        }
+
+        // Run these tests instead of TestScripts, for checking things the
+        // scripts don't do. The other per-test options don't apply to them.
+        pub fn tests(mut self, tests: impl IntoIterator<Item = Test>) -> Self {
+            self.tests = tests.into_iter().collect();
+            let len = self.tests.len();
+            self.num_tests(len)
+        }
+
+        pub fn parked_worktrees(mut self, max: usize) -> Self {
+            self.parked_worktrees = max;
+            self
+        }
     }
 
     async fn nonempty_temp_repo() -> Arc<TempRepo> {
@@ -1554,26 +3863,49 @@ mod tests {
         try_join_all((0..n).map(|_| async {
             let t = TempDir::with_prefix("worktree").context("creating tempdir")?;
             Ok::<_, anyhow::Error>(Resource::Worktree(
-                TempWorktree::new(&CancellationToken::new(), origin, t)
-                    .await
-                    .context("creating worktree")?,
+                TempWorktree::new(
+                    &CancellationToken::new(),
+                    origin,
+                    t,
+                    &WorktreeOptions::default(),
+                )
+                .await
+                .context("creating worktree")?,
             ))
         }))
         .await
         .unwrap()
     }
 
+    // Sandboxing without root needs unprivileged user namespaces, which lots
+    // of environments (containers, hardened kernels) don't allow. Tests that
+    // need them are ignored unless you ask for them with --include-ignored,
+    // and check this first so that it's clear why they fail.
+    fn can_unshare() -> bool {
+        std::process::Command::new("unshare")
+            .args(["--user", "--map-current-user", "--mount", "--net", "true"])
+            .output()
+            .is_ok_and(|output| output.status.success())
+    }
+
     struct TestScriptFixture {
         db_dir: ManuallyDrop<TempDir>,
         repo: Arc<TempRepo>,
         scripts: Vec<TestScript>,
+        db: Arc<Database>,
+        pools: Arc<Pools>,
         manager: Manager<TempRepo>,
     }
 
     impl TestScriptFixtureBuilder {
         pub async fn build(&self) -> TestScriptFixture {
             let repo = nonempty_temp_repo().await;
-            let scripts: Vec<TestScript> = (0..self.num_tests)
+            let num_scripts = if self.tests.is_empty() {
+                self.num_tests
+            } else {
+                0
+            };
+            let scripts: Vec<TestScript> = (0..num_scripts)
                 // Here we pass needs_worktree[i] to configure whether the script should
                 // try to detect sharing a worktree with another test run. If it
                 // doesn't a worktree then that sharing is harmless and
@@ -1596,24 +3928,47 @@ mod tests {
                     .iter()
                     .filter(|(from_idx, _)| *from_idx == i)
                     .map(|(_, to_idx)| TestName::new(format!("test_{to_idx}")));
-                script.as_test(cache_policy, needs_worktree, dep_names)
-            });
+                let mut test = script.as_test(cache_policy, needs_worktree, dep_names);
+                test.stage_gate = self
+                    .stage_gates
+                    .iter()
+                    .filter(|(from_idx, _)| *from_idx == i)
+                    .map(|(_, to_idx)| TestName::new(format!("test_{to_idx}")))
+                    .collect();
+                if let Some((_, when)) = self.whens.iter().find(|(idx, _)| *idx == i) {
+                    test.when = when.clone();
+                }
+                test.run_by_default = !self.not_run_by_default.contains(&i);
+                test
+            })
+            .chain(self.tests.iter().cloned());
+            let db = Arc::new(
+                Database::create_or_open(db_dir.path()).expect("couldn't setup result DB"),
+            );
+            let pools = Arc::new(
+                Pools::new([(
+                    ResourceKey::Worktree,
+                    worktree_resources(&repo, self.num_worktrees).await,
+                )])
+                .with_parked_worktrees(self.parked_worktrees),
+            );
             let manager = Manager::new(
                 repo.clone(),
                 "/fake/config/path",
-                Arc::new(
-                    Database::create_or_open(db_dir.path()).expect("couldn't setup result DB"),
-                ),
-                Arc::new(Pools::new([(
-                    ResourceKey::Worktree,
-                    worktree_resources(&repo, self.num_worktrees).await,
-                )])),
+                db.clone(),
+                pools.clone(),
                 Dag::new(tests.map(Arc::new)).expect("couldn't build test DAG"),
             );
+            let manager = match self.sample_every {
+                Some(every) => manager.with_sample(every),
+                None => manager,
+            };
             TestScriptFixture {
                 manager,
                 scripts,
                 repo,
+                db,
+                pools,
                 db_dir: ManuallyDrop::new(db_dir),
             }
         }
@@ -1627,9 +3982,27 @@ mod tests {
                 cache_policies: vec![CachePolicy::ByCommit; 2],
                 needs_worktree: vec![true; 2],
                 dependencies: vec![],
+                stage_gates: vec![],
+                sample_every: None,
+                whens: vec![],
+                not_run_by_default: vec![],
+                tests: vec![],
+                parked_worktrees: 0,
             }
         }
 
+        // Another manager for the same tests and database, as if limmat had
+        // been restarted.
+        fn restart(&self) -> Manager<TempRepo> {
+            Manager::new(
+                self.repo.clone(),
+                "/fake/config/path",
+                self.db.clone(),
+                self.pools.clone(),
+                Dag::new(self.manager.tests.nodes().cloned()).expect("couldn't build test DAG"),
+            )
+        }
+
         // Convenience helper to construct a TestCase referring to this fixture's configuration.
         // yes this function is O(test_idx). you got a porblem with that?? is that a porblem?
         fn test_case(&self, commit: impl Borrow<Commit>, test_idx: usize) -> TestCase {
@@ -1650,7 +4023,7 @@ mod tests {
             // SAFETY: The field is never accessed again.
             let db_dir = unsafe { ManuallyDrop::take(&mut self.db_dir) };
             if env::var("LIMMAT_TESTS_LEAK_RESULT_DB").unwrap_or("0".to_owned()) != "0" {
-                let db_dir_path = db_dir.into_path(); // Stops it from being deleted.
+                let db_dir_path = db_dir.keep(); // Stops it from being deleted.
                 info!("Leaking database directory {:?}", db_dir_path);
             }
         }
@@ -1687,7 +4060,67 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn should_cancel_running() {
+    async fn should_keep_merge_bases_of_known_commits() {
+        let f = TestScriptFixture::builder().num_tests(1).build().await;
+        let base = f.repo.commit("base").await.unwrap();
+        let commit1 = f.repo.commit("hello,").await.unwrap();
+        let commit2 = f.repo.commit("hello,").await.unwrap();
+        f.manager.set_upstream(&base.hash);
+        f.manager
+            .set_revisions([base.clone(), commit1.clone()])
+            .await
+            .unwrap();
+        f.manager
+            .set_revisions([commit1.clone(), commit2.clone()])
+            .await
+            .unwrap();
+        let merge_bases = f.manager.range.lock().merge_bases.clone();
+        assert_eq!(
+            merge_bases,
+            HashMap::from([
+                (commit1.hash.clone(), base.hash.clone()),
+                (commit2.hash.clone(), base.hash.clone()),
+            ])
+        );
+        f.manager.settled().await;
+    }
+
+    #[tokio::test]
+    async fn should_ignore_duplicate_revisions() {
+        let f = TestScriptFixture::builder().num_tests(1).build().await;
+        let mut results = f.manager.results();
+        let commit = f
+            .repo
+            .commit("hello,")
+            .await
+            .expect("couldn't create test commit");
+        f.manager.set_revisions(vec![commit.clone()]).await.unwrap();
+        // Now it's a known commit, not one that has to be looked up.
+        f.manager
+            .set_revisions(vec![commit.clone(), commit.clone()])
+            .await
+            .unwrap();
+        expect_notifs_20s(
+            &mut results,
+            [(
+                f.test_case(&commit, 0),
+                vec![
+                    TestStatusMatcher::Enqueued,
+                    TestStatusMatcher::Started,
+                    TestStatusMatcher::Completed(0),
+                ]
+                .into(),
+            )],
+        )
+        .await
+        .expect("bad test result");
+        expect_no_more_results(&mut results, &f.manager)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn should_cancel_running() {
         let f = TestScriptFixture::builder().num_tests(2).build().await;
         // First commit's test will block forever.
         let commit1 = f
@@ -1775,6 +4208,189 @@ mod tests {
             .unwrap()
     }
 
+    #[tokio::test]
+    async fn should_record_cancel_reason() {
+        let f = TestScriptFixture::builder().num_tests(1).build().await;
+        let db = Database::create_or_open(f.db_dir.path()).unwrap();
+        let commit1 = f
+            .repo
+            .commit(TestScript::BLOCK_COMMIT_MSG_TAG)
+            .await
+            .unwrap();
+        let commit2 = f
+            .repo
+            .commit(TestScript::BLOCK_COMMIT_MSG_TAG)
+            .await
+            .unwrap();
+        f.manager
+            .set_revisions([commit1.clone(), commit2.clone()])
+            .await
+            .unwrap();
+        let started2 = timeout_5s(join_all([
+            f.scripts[0].started(&commit1.hash),
+            f.scripts[0].started(&commit2.hash),
+        ]))
+        .await
+        .expect("tests didn't start")
+        .pop()
+        .unwrap();
+
+        f.manager
+            .cancel(&commit1.hash, &f.scripts[0].test_name)
+            .unwrap();
+        f.manager.set_revisions([commit1.clone()]).await.unwrap();
+        f.manager.settled().await;
+        assert_eq!(
+            db.cancel_reason(&f.test_case(&commit1, 0)).unwrap(),
+            Some(CancelReason::Requested)
+        );
+        assert_eq!(
+            db.cancel_reason(&f.test_case(&commit2, 0)).unwrap(),
+            Some(CancelReason::LeftRange)
+        );
+
+        // An attempt that doesn't get canceled forgets about the cancellation.
+        started2.reset_started();
+        f.manager.set_revisions([commit2.clone()]).await.unwrap();
+        timeout_5s(f.scripts[0].started(&commit2.hash))
+            .await
+            .expect("test didn't restart")
+            .sigurs1();
+        f.manager.settled().await;
+        assert_eq!(db.cancel_reason(&f.test_case(&commit2, 0)).unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn should_not_record_cancel_reason_for_queued_job() {
+        let f = TestScriptFixture::builder()
+            .dependencies([(1, 0)])
+            .build()
+            .await;
+        let db = Database::create_or_open(f.db_dir.path()).unwrap();
+        let commit = f
+            .repo
+            .commit(TestScript::BLOCK_COMMIT_MSG_TAG)
+            .await
+            .unwrap();
+        f.manager.set_revisions([commit.clone()]).await.unwrap();
+        timeout_5s(f.scripts[0].started(&commit.hash))
+            .await
+            .expect("test didn't start");
+
+        f.manager.set_revisions(Vec::<Commit>::new()).await.unwrap();
+        f.manager.settled().await;
+        assert_eq!(
+            db.cancel_reason(&f.test_case(&commit, 0)).unwrap(),
+            Some(CancelReason::LeftRange)
+        );
+        // It was still waiting for its dependency.
+        assert_eq!(db.cancel_reason(&f.test_case(&commit, 1)).unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn should_keep_jobs_when_tip_replaced() {
+        let f = TestScriptFixture::builder().num_tests(1).build().await;
+        let db = Database::create_or_open(f.db_dir.path()).unwrap();
+        let mut commits = Vec::new();
+        for _ in 0..3 {
+            commits.push(
+                f.repo
+                    .commit(TestScript::BLOCK_COMMIT_MSG_TAG)
+                    .await
+                    .unwrap(),
+            );
+        }
+        f.manager
+            .set_revisions([commits[0].clone(), commits[1].clone()])
+            .await
+            .unwrap();
+        let started = timeout_5s(join_all([
+            f.scripts[0].started(&commits[0].hash),
+            f.scripts[0].started(&commits[1].hash),
+        ]))
+        .await
+        .expect("tests didn't start");
+
+        // Like amending the tip commit, the job on the commit below it should
+        // carry on undisturbed.
+        f.manager
+            .set_revisions([commits[0].clone(), commits[2].clone()])
+            .await
+            .unwrap();
+        timeout_5s(f.scripts[0].started(&commits[2].hash))
+            .await
+            .expect("test didn't start on new tip")
+            .sigurs1();
+        timeout_5s(started[1].sigtermed())
+            .await
+            .expect("replaced commit's test wasn't terminated");
+        started[0].sigurs1();
+        f.manager.settled().await;
+        assert_eq!(f.scripts[0].num_runs(&commits[0].hash), 1);
+        assert_eq!(
+            db.cancel_reason(&f.test_case(&commits[1], 0)).unwrap(),
+            Some(CancelReason::LeftRange)
+        );
+        assert_eq!(
+            db.cancel_reason(&f.test_case(&commits[0], 0)).unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn should_coalesce_results() {
+        let results = CoalescedResults::default();
+        let test_case = |name| {
+            TestCase::new(
+                Commit::arbitrary(),
+                Arc::new(TestBuilder::new(name, "", [""]).build()),
+            )
+        };
+        let (test1, test2) = (test_case("test1"), test_case("test2"));
+        let push = |test_case: &TestCase, status| {
+            results.push(Arc::new(Notification {
+                test_case: test_case.clone(),
+                status,
+                cached: false,
+                provenance: None,
+            }))
+        };
+
+        // Nothing is kept until someone listens.
+        push(&test1, TestStatus::Enqueued);
+        results.listen();
+        push(&test1, TestStatus::Started(None));
+        push(&test2, TestStatus::Enqueued);
+        push(
+            &test1,
+            TestStatus::Finished(Ok(TestResult { exit_code: 0 })),
+        );
+        let mut got: Vec<(String, String)> = timeout_5s(results.recv())
+            .await
+            .expect("no notifications")
+            .iter()
+            .map(|notif| {
+                (
+                    notif.test_case.test.name.to_string(),
+                    notif.status.to_string(),
+                )
+            })
+            .collect();
+        got.sort();
+        let finished = TestStatus::Finished(Ok(TestResult { exit_code: 0 }));
+        assert_eq!(
+            got,
+            vec![
+                ("test1".to_string(), finished.to_string()),
+                ("test2".to_string(), TestStatus::Enqueued.to_string()),
+            ]
+        );
+        select! {
+            _ = sleep(Duration::from_millis(100)) => (),
+            _ = results.recv() => panic!("got notifications twice"),
+        }
+    }
+
     // This is not actually testing functionality, this is a meta-test, yikes this is
     // over-engineered.
     #[tokio::test]
@@ -1841,26 +4457,561 @@ mod tests {
         assert_eq!(f.scripts[1].num_runs(&orig_commit.hash), 1);
         assert_eq!(f.scripts[2].num_runs(&orig_commit.hash), 1);
 
-        f.manager
-            .set_revisions(vec![same_tree.clone()])
-            .await
-            .unwrap();
-        f.manager.settled().await;
-        // Without caching the script should get run every time.
-        assert_eq!(f.scripts[0].num_runs(&same_tree.hash), 1);
-        // Commit has has changed so new commit should get retested for ByCommit.
-        assert_eq!(f.scripts[1].num_runs(&same_tree.hash), 1);
-        // But not for ByTree
-        assert_eq!(f.scripts[2].num_runs(&same_tree.hash), 0);
+        f.manager
+            .set_revisions(vec![same_tree.clone()])
+            .await
+            .unwrap();
+        f.manager.settled().await;
+        // Without caching the script should get run every time.
+        assert_eq!(f.scripts[0].num_runs(&same_tree.hash), 1);
+        // Commit has has changed so new commit should get retested for ByCommit.
+        assert_eq!(f.scripts[1].num_runs(&same_tree.hash), 1);
+        // But not for ByTree
+        assert_eq!(f.scripts[2].num_runs(&same_tree.hash), 0);
+
+        f.manager
+            .set_revisions(vec![orig_commit.clone()])
+            .await
+            .unwrap();
+        f.manager.settled().await;
+        assert_eq!(f.scripts[0].num_runs(&orig_commit.hash), 2);
+        assert_eq!(f.scripts[1].num_runs(&orig_commit.hash), 1);
+        assert_eq!(f.scripts[2].num_runs(&orig_commit.hash), 1);
+    }
+
+    #[tokio::test]
+    async fn should_rerun() {
+        let f = TestScriptFixture::builder()
+            .num_tests(3)
+            .dependencies([(1, 0)])
+            .build()
+            .await;
+        let commit = f
+            .repo
+            .commit("yarp")
+            .await
+            .expect("couldn't create test commit");
+        f.manager.set_revisions([commit.clone()]).await.unwrap();
+        f.manager.settled().await;
+
+        f.manager
+            .rerun(&commit.hash, &f.scripts[0].test_name)
+            .await
+            .expect("rerun failed");
+        f.manager.settled().await;
+        assert_eq!(f.scripts[0].num_runs(&commit.hash), 2);
+        // The dependent was restarted but its result is still cached.
+        assert_eq!(f.scripts[1].num_runs(&commit.hash), 1);
+        assert_eq!(f.scripts[2].num_runs(&commit.hash), 1);
+        for (_, status) in f.manager.statuses() {
+            verify_that!(&status, TestStatusMatcher::Completed(0)).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn should_rerun_with_unselected_dependent() {
+        let f = TestScriptFixture::builder()
+            .num_tests(2)
+            .dependencies([(1, 0)])
+            .not_run_by_default([1])
+            .build()
+            .await;
+        let commit = f
+            .repo
+            .commit("yarp")
+            .await
+            .expect("couldn't create test commit");
+        f.manager.set_revisions([commit.clone()]).await.unwrap();
+        f.manager.settled().await;
+
+        f.manager
+            .rerun(&commit.hash, &f.scripts[0].test_name)
+            .await
+            .expect("rerun failed");
+        f.manager.settled().await;
+        assert_eq!(f.scripts[0].num_runs(&commit.hash), 2);
+        assert_eq!(f.scripts[1].num_runs(&commit.hash), 0);
+        // Nothing was left canceled without being restarted.
+        let statuses = f.manager.statuses();
+        assert_eq!(statuses.len(), 1);
+        for (_, status) in statuses {
+            verify_that!(&status, TestStatusMatcher::Completed(0)).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn should_rerun_failed() {
+        let f = TestScriptFixture::builder().num_tests(2).build().await;
+        let good = f.repo.commit("good").await.unwrap();
+        let bad = f.repo.commit("exit_code(1)").await.unwrap();
+        f.manager
+            .set_revisions([bad.clone(), good.clone()])
+            .await
+            .unwrap();
+        f.manager.settled().await;
+
+        f.manager.rerun_failed().await.expect("rerun failed");
+        f.manager.settled().await;
+        for script in &f.scripts {
+            assert_eq!(script.num_runs(&good.hash), 1);
+            assert_eq!(script.num_runs(&bad.hash), 2);
+        }
+    }
+
+    #[tokio::test]
+    async fn should_rerun_stale_failures() {
+        let temp_dir = TempDir::new().unwrap();
+        let log_path = temp_dir.path().join("log");
+        let tests = [0, 1].map(|exit_code| {
+            TestBuilder::new(
+                format!("exit_{exit_code}"),
+                "bash",
+                [
+                    "-c".into(),
+                    OsString::from(format!(
+                        "echo {exit_code} >> {log_path:?}; exit {exit_code}"
+                    )),
+                ],
+            )
+            .build()
+        });
+        let f = TestScriptFixture::builder()
+            .num_worktrees(0)
+            .tests(tests)
+            .build()
+            .await;
+        let commit = f.repo.commit("hello").await.unwrap();
+        let num_runs = |exit_code: &str| {
+            let log = fs::read_to_string(&log_path).unwrap();
+            log.lines().filter(|l| *l == exit_code).count()
+        };
+
+        f.manager.set_revisions([commit.clone()]).await.unwrap();
+        f.manager.settled().await;
+        assert_eq!((num_runs("0"), num_runs("1")), (1, 1));
+
+        // Restarting with the option runs the failure again, but only once.
+        let m = f.restart().with_rerun_failures_before(SystemTime::now());
+        m.set_revisions([commit.clone()]).await.unwrap();
+        m.settled().await;
+        m.set_revisions(Vec::<Commit>::new()).await.unwrap();
+        m.set_revisions([commit.clone()]).await.unwrap();
+        m.settled().await;
+        assert_eq!((num_runs("0"), num_runs("1")), (1, 2));
+    }
+
+    #[test_case(Duration::ZERO, 2 ; "expired")]
+    #[test_case(Duration::from_secs(60 * 60), 1 ; "fresh")]
+    #[tokio::test]
+    async fn should_expire_failures(ttl: Duration, want_failure_runs: usize) {
+        let temp_dir = TempDir::new().unwrap();
+        let log_path = temp_dir.path().join("log");
+        let tests = [0, 1].map(|exit_code| {
+            TestBuilder::new(
+                format!("exit_{exit_code}"),
+                "bash",
+                [
+                    "-c".into(),
+                    OsString::from(format!(
+                        "echo {exit_code} >> {log_path:?}; exit {exit_code}"
+                    )),
+                ],
+            )
+            .failure_cache_ttl(ttl)
+            .build()
+        });
+        let f = TestScriptFixture::builder()
+            .num_worktrees(0)
+            .tests(tests)
+            .build()
+            .await;
+        let commit = f.repo.commit("hello").await.unwrap();
+        for _ in 0..2 {
+            f.manager.set_revisions([commit.clone()]).await.unwrap();
+            f.manager.settled().await;
+            f.manager.set_revisions(Vec::<Commit>::new()).await.unwrap();
+        }
+        let log = fs::read_to_string(&log_path).unwrap();
+        let num_runs = |exit_code: &str| log.lines().filter(|l| *l == exit_code).count();
+        assert_eq!((num_runs("0"), num_runs("1")), (1, want_failure_runs));
+    }
+
+    #[test_case(100, true ; "slow")]
+    #[test_case(100_000, false ; "not slow")]
+    #[tokio::test]
+    async fn should_flag_slowdowns(max_slowdown_percent: u32, want_slow: bool) {
+        let f = TestScriptFixture::builder()
+            .num_worktrees(0)
+            .tests([TestBuilder::new("my_test", "sleep", ["0.2"])
+                .max_slowdown_percent(max_slowdown_percent)
+                .build()])
+            .build()
+            .await;
+        let commit = f.repo.commit("hello").await.unwrap();
+        let test_case = f.test_case(&commit, 0);
+        f.db.record_run(&test_case.test.name, Duration::from_millis(10), false)
+            .unwrap();
+        f.manager.set_revisions([commit.clone()]).await.unwrap();
+        f.manager.settled().await;
+        let slowdown = f.db.slowdown(&test_case).unwrap();
+        assert_eq!(slowdown.is_some(), want_slow);
+        if let Some(slowdown) = slowdown {
+            assert_eq!(slowdown.baseline_secs, 0.01);
+            assert!(slowdown.duration_secs >= 0.2);
+        }
+    }
+
+    #[tokio::test]
+    async fn should_wait() {
+        let f = TestScriptFixture::builder().num_tests(2).build().await;
+        let commit = f
+            .repo
+            .commit(TestScript::BLOCK_COMMIT_MSG_TAG)
+            .await
+            .expect("couldn't create test commit");
+        f.manager.set_revisions([commit.clone()]).await.unwrap();
+        let started = timeout_5s(f.scripts[0].started(&commit.hash))
+            .await
+            .expect("script not started");
+
+        // Only waiting for the first test, the second one never finishes.
+        let commits = [commit.hash.clone()];
+        let mut wait = pin!(f
+            .manager
+            .wait(&commits, |name| *name == f.scripts[0].test_name));
+        assert!((&mut wait).now_or_never().is_none());
+        started.sigurs1();
+        let statuses = timeout_5s(wait)
+            .await
+            .expect("wait didn't return")
+            .expect("wait failed");
+        assert_eq!(statuses.len(), 1);
+        assert_eq!(statuses[0].0.id(), f.test_case(&commit, 0).id());
+        assert!(!statuses[0].1.succeeded());
+
+        let other = f
+            .repo
+            .commit("not in range")
+            .await
+            .expect("couldn't create test commit");
+        assert!(f.manager.wait(&[other.hash], |_| true).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn should_bisect_sample() {
+        let f = TestScriptFixture::builder()
+            .num_tests(1)
+            .sample_every(4)
+            .build()
+            .await;
+        let mut commits = Vec::new();
+        for i in 0..9 {
+            let msg = if i < 3 { "good" } else { "exit_code(1)" };
+            commits.push(
+                f.repo
+                    .commit(msg)
+                    .await
+                    .expect("couldn't create test commit"),
+            );
+        }
+        // Newest first, like rev-list.
+        commits.reverse();
+        f.manager.set_revisions(commits.clone()).await.unwrap();
+        let hashes: Vec<CommitHash> = commits.iter().map(|c| c.hash.clone()).collect();
+        timeout_5s(f.manager.wait(&hashes, |_| true))
+            .await
+            .expect("wait didn't return")
+            .expect("wait failed");
+
+        // Sampled 0, 4 and 8, then bisected between 4 and 8 to find that 5 is
+        // the oldest failure.
+        let tested: Vec<usize> = (0..commits.len())
+            .filter(|&i| f.scripts[0].num_runs(&commits[i].hash) > 0)
+            .collect();
+        assert_eq!(tested, vec![0, 4, 5, 6, 8]);
+        let not_applicable = f.manager.not_applicable.lock();
+        let not_sampled: Vec<_> = [1, 2, 3, 7]
+            .into_iter()
+            .filter_map(|i| {
+                not_applicable.get(&TestCaseId::new(&commits[i].hash, &f.scripts[0].test_name))
+            })
+            .collect();
+        assert_eq!(not_sampled, vec![&NotApplicable::NotSampled; 4]);
+        assert_eq!(not_applicable.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn should_only_create_test_cases_when_conditions_hold() {
+        let f = TestScriptFixture::builder()
+            .num_tests(3)
+            // test_2 depends on test_1, which only exists for docs commits.
+            .dependencies([(2, 1)])
+            .when(
+                1,
+                When {
+                    message: Some(Regex::new("^docs:").unwrap()),
+                    ..Default::default()
+                },
+            )
+            .when(
+                2,
+                When {
+                    author: Some(Regex::new("nobody").unwrap()),
+                    ..Default::default()
+                },
+            )
+            .build()
+            .await;
+        let docs = f.repo.commit("docs: fix typo").await.unwrap();
+        let code = f.repo.commit("code: fix bug").await.unwrap();
+        let mut notifs = f.manager.results();
+        f.manager
+            .set_revisions([code.clone(), docs.clone()])
+            .await
+            .unwrap();
+        // The test cases that weren't created are reported straight away, and
+        // only once.
+        f.manager
+            .set_revisions([code.clone(), docs.clone()])
+            .await
+            .unwrap();
+        let mut not_applicable = Vec::new();
+        while let Ok(notif) = notifs.try_recv() {
+            if let TestStatus::NotApplicable(reason) = notif.status {
+                not_applicable.push((notif.test_case.id().0, reason));
+            }
+        }
+        not_applicable.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut expected = vec![
+            (f.test_case(&code, 1).id().0, NotApplicable::When),
+            (f.test_case(&code, 2).id().0, NotApplicable::When),
+            (f.test_case(&docs, 2).id().0, NotApplicable::When),
+        ];
+        expected.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(not_applicable, expected);
+        let hashes = [code.hash.clone(), docs.hash.clone()];
+        timeout_5s(f.manager.wait(&hashes, |_| true))
+            .await
+            .expect("wait didn't return")
+            .expect("wait failed");
+
+        let mut ids: Vec<TestCaseId> = f
+            .manager
+            .statuses()
+            .into_iter()
+            .map(|(test_case, _)| test_case.id())
+            .collect();
+        ids.sort_by_key(|id| id.0.clone());
+        let mut expected = vec![
+            f.test_case(&docs, 0).id(),
+            f.test_case(&docs, 1).id(),
+            f.test_case(&code, 0).id(),
+        ];
+        expected.sort_by_key(|id| id.0.clone());
+        assert_eq!(ids, expected);
+    }
+
+    #[tokio::test]
+    async fn should_run_requested_test_cases() {
+        let f = TestScriptFixture::builder()
+            .num_tests(3)
+            // Neither test_1 nor its dependency test_2 are run by default.
+            .dependencies([(1, 2)])
+            .not_run_by_default([1, 2])
+            .build()
+            .await;
+        let commit1 = f.repo.commit("1").await.unwrap();
+        let commit2 = f.repo.commit("2").await.unwrap();
+        f.manager
+            .set_revisions([commit2.clone(), commit1.clone()])
+            .await
+            .unwrap();
+        let hashes = [commit2.hash.clone(), commit1.hash.clone()];
+        timeout_5s(f.manager.wait(&hashes, |_| true))
+            .await
+            .expect("wait didn't return")
+            .expect("wait failed");
+        assert_eq!(f.manager.statuses().len(), 2);
+
+        f.manager
+            .run(&commit1.hash, &f.scripts[1].test_name)
+            .expect("couldn't request test case");
+        let statuses = timeout_5s(f.manager.wait(&hashes, |_| true))
+            .await
+            .expect("wait didn't return")
+            .expect("wait failed");
+        assert_eq!(statuses.len(), 4);
+        assert!(statuses.iter().all(|(_, status)| status.succeeded()));
+        for script in &f.scripts[1..] {
+            assert_eq!(script.num_runs(&commit1.hash), 1);
+            assert_eq!(script.num_runs(&commit2.hash), 0);
+        }
+
+        assert!(f
+            .manager
+            .run(&commit1.hash, &TestName::new("nonexistent"))
+            .is_err());
+        let other = f.repo.commit("not in range").await.unwrap();
+        assert!(f.manager.run(&other.hash, &f.scripts[1].test_name).is_err());
+    }
+
+    #[test]
+    fn test_when() {
+        let commit = Commit {
+            message: "docs: fix typo\n\nMore words.\n".into(),
+            author: "Jo Bloggs <jo@example.com>".into(),
+            ..Commit::arbitrary()
+        };
+        let changed = [PathBuf::from("docs/index.md")];
+        let when = |paths: &[&str], message: Option<&str>, author: Option<&str>| When {
+            paths: paths
+                .iter()
+                .map(|p| glob::Pattern::new(p).unwrap())
+                .collect(),
+            message: message.map(|r| Regex::new(r).unwrap()),
+            author: author.map(|r| Regex::new(r).unwrap()),
+        };
+        assert!(when(&[], None, None).matches(&commit, Some(&changed)));
+        assert!(when(&["docs/**"], Some("^docs:"), Some("@example\\.com"))
+            .matches(&commit, Some(&changed)));
+        assert!(!when(&["src/**"], None, None).matches(&commit, Some(&changed)));
+        // Without the changed files, only the other conditions count.
+        assert!(when(&["src/**"], None, None).matches(&commit, None));
+        assert!(!when(&[], Some("^fix"), None).matches(&commit, None));
+        assert!(!when(&[], None, Some("Alice")).matches(&commit, None));
+    }
+
+    #[test]
+    fn test_skipped_by_message() {
+        let name = TestName::new("build");
+        assert!(!skipped_by_message("Fix the build", &name));
+        assert!(skipped_by_message("wip [skip limmat]", &name));
+        assert!(skipped_by_message(
+            "wip\n\nMore words.\n\nLimmat-Skip: lint, build\nSigned-off-by: Me\n",
+            &name
+        ));
+        assert!(skipped_by_message("wip\n\nlimmat-skip:build", &name));
+        assert!(!skipped_by_message("wip\n\nLimmat-Skip: build_all", &name));
+        // Only the last paragraph has trailers.
+        assert!(!skipped_by_message(
+            "wip\n\nLimmat-Skip: build\n\nSigned-off-by: Me",
+            &name
+        ));
+    }
+
+    #[test]
+    fn test_runs_on() {
+        let commit = Commit::arbitrary();
+        let mut test = TestBuilder::new("my_test", "", [""])
+            .skip_if_unchanged(["src/**"])
+            .build();
+        assert!(test.runs_on(&commit, &[PathBuf::from("src/main.rs")]));
+        assert!(!test.runs_on(&commit, &[PathBuf::from("README.md")]));
+        test.when.message = Some(Regex::new("^docs:").unwrap());
+        assert!(!test.runs_on(&commit, &[PathBuf::from("src/main.rs")]));
+        test.when.message = None;
+        let wip = Commit {
+            message: "wip [skip limmat]".into(),
+            ..Commit::arbitrary()
+        };
+        assert!(!test.runs_on(&wip, &[PathBuf::from("src/main.rs")]));
+        test.run_by_default = false;
+        assert!(!test.runs_on(&commit, &[PathBuf::from("src/main.rs")]));
+    }
+
+    #[test]
+    fn test_about() {
+        let mut test = TestBuilder::new("my_test", "", [""]).build();
+        assert_eq!(test.about(), None);
+        test.owner = Some("Alice".into());
+        assert_eq!(test.about().as_deref(), Some("owner: Alice"));
+        test.description = Some("Checks stuff".into());
+        assert_eq!(test.about().as_deref(), Some("Checks stuff (owner: Alice)"));
+    }
+
+    #[test]
+    fn test_verdict() {
+        let required = TestBuilder::new("required", "", [""]).build();
+        let advisory = TestBuilder::new("advisory", "", [""]).advisory().build();
+        let passed = TestStatus::Finished(Ok(TestResult { exit_code: 0 }));
+        let failed = TestStatus::Finished(Ok(TestResult { exit_code: 1 }));
+        let skipped = TestStatus::Finished(Err(TestInconclusive::SkippedByCommit));
+        let not_applicable = TestStatus::NotApplicable(NotApplicable::When);
+        let canceled = TestStatus::Finished(Err(TestInconclusive::Canceled));
+        let error = TestStatus::Finished(Err(TestInconclusive::ErrorExitCode(2)));
+
+        assert_eq!(Verdict::of([]), Verdict::Pass);
+        assert_eq!(
+            Verdict::of([(&required, &passed), (&advisory, &failed)]),
+            Verdict::Pass
+        );
+        assert_eq!(
+            Verdict::of([(&required, &skipped), (&required, &not_applicable)]),
+            Verdict::Pass
+        );
+        assert_eq!(
+            Verdict::of([(&required, &passed), (&required, &canceled)]),
+            Verdict::Pending
+        );
+        assert_eq!(
+            Verdict::of([(&required, &TestStatus::Enqueued), (&required, &failed)]),
+            Verdict::Fail
+        );
+        assert_eq!(Verdict::of([(&required, &error)]), Verdict::Fail);
+    }
+
+    #[test]
+    fn test_coarse_to_fine() {
+        assert_eq!(coarse_to_fine(&[]), Vec::<usize>::new());
+        assert_eq!(coarse_to_fine(&[7]), vec![7]);
+        assert_eq!(coarse_to_fine(&[0, 4, 8, 12, 16]), vec![0, 16, 8, 4, 12]);
+    }
 
-        f.manager
-            .set_revisions(vec![orig_commit.clone()])
-            .await
-            .unwrap();
-        f.manager.settled().await;
-        assert_eq!(f.scripts[0].num_runs(&orig_commit.hash), 2);
-        assert_eq!(f.scripts[1].num_runs(&orig_commit.hash), 1);
-        assert_eq!(f.scripts[2].num_runs(&orig_commit.hash), 1);
+    #[test]
+    fn test_shard() {
+        assert!("0/2".parse::<Shard>().is_err());
+        assert!("3/2".parse::<Shard>().is_err());
+        assert!("1".parse::<Shard>().is_err());
+        let shards: Vec<Shard> = ["1/3", "2/3", "3/3"]
+            .iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+        let mut counts = [0; 3];
+        for i in 0..100 {
+            let commit_hash = CommitHash::new(format!("{i:040x}"));
+            for name in ["build", "test"] {
+                let test_name = TestName::new(name);
+                let matching: Vec<usize> = (0..3)
+                    .filter(|&j| shards[j].contains(&commit_hash, &test_name))
+                    .collect();
+                assert_eq!(matching.len(), 1, "{commit_hash} {name} in {matching:?}");
+                counts[matching[0]] += 1;
+            }
+        }
+        // Should be roughly even.
+        assert!(counts.iter().all(|&count| count > 40), "{counts:?}");
+    }
+
+    #[test]
+    fn test_fail_fast_order() {
+        let history = |failure_rate, duration_secs| {
+            Some(History {
+                failure_rate,
+                duration_secs,
+            })
+        };
+        assert_eq!(fail_fast_order(&[None, None, None]), vec![0, 1, 2]);
+        assert_eq!(
+            fail_fast_order(&[
+                history(0.0, 1.0),  // Never fails.
+                history(0.5, 60.0), // Slow.
+                None,               // Average: 0.5 and 18.2s.
+                history(1.0, 10.0), // Flaky and quick.
+                history(0.5, 10.0),
+                history(0.5, 10.0),
+            ]),
+            vec![3, 4, 5, 2, 1, 0]
+        );
     }
 
     #[test_case(1, 1 ; "single worktree, one test")]
@@ -1960,6 +5111,46 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_keep_failed_worktrees() {
+        let f = TestScriptFixture::builder()
+            .tests([TestBuilder::new(
+                "my_test",
+                "bash",
+                ["-c".into(), OsString::from("touch junk; false")],
+            )
+            .needs_resources([(ResourceKey::Worktree, 1)])
+            .build()])
+            // More than we have worktrees for, only one of them can be kept.
+            .parked_worktrees(5)
+            .build()
+            .await;
+        let (repo, pools, m) = (&f.repo, &f.pools, &f.manager);
+        fs::write(repo.path().join("file"), "committed").unwrap();
+        repo.add_all().await.unwrap();
+        let mut commits = Vec::new();
+        for _ in 0..3 {
+            commits.push(repo.commit("hello,").await.unwrap());
+        }
+        m.set_revisions(commits.clone()).await.unwrap();
+        m.settled().await;
+
+        let parked = pools.parked_worktrees();
+        assert_eq!(parked.len(), 1);
+        let (commit, test, path) = &parked[0];
+        assert!(path.join("junk").exists());
+        assert_eq!(pools.usage()[&ResourceKey::Worktree].in_use, 1);
+
+        fs::write(path.join("file"), "scribbled on").unwrap();
+        m.release_worktree(commit, test).await.unwrap();
+        assert!(pools.parked_worktrees().is_empty());
+        assert_eq!(pools.usage()[&ResourceKey::Worktree].in_use, 0);
+        // Whatever the user did to it is gone.
+        assert!(!path.join("junk").exists());
+        assert_eq!(fs::read_to_string(path.join("file")).unwrap(), "committed");
+        assert!(m.release_worktree(commit, test).await.is_err());
+    }
+
     #[tokio::test]
     async fn test_job_env() {
         let temp_dir = TempDir::new().unwrap();
@@ -2047,6 +5238,7 @@ mod tests {
             Arc::new(resource_pools),
             tests,
         );
+        m.set_upstream(&commit1.hash);
 
         m.set_revisions([commit1.clone(), commit2.clone()])
             .await
@@ -2117,6 +5309,546 @@ mod tests {
         );
         assert_eq!(env.get("LIMMAT_ARTIFACTS_notdep"), None);
         assert_eq!(env.get("LIMMAT_CONFIG"), Some("/fake/config/path").as_ref());
+        let commit1_hash = commit1.hash.to_string();
+        assert_eq!(env.get("LIMMAT_PARENTS"), Some(&commit1_hash.as_str()));
+        assert_eq!(env.get("LIMMAT_IS_MERGE"), Some(&"0"));
+        assert_eq!(env.get("LIMMAT_MERGE_BASE"), Some(&commit1_hash.as_str()));
+    }
+
+    #[tokio::test]
+    async fn should_report_progress() {
+        let temp_dir = TempDir::new().unwrap();
+        let proceed_path = temp_dir.path().join("proceed");
+        let f = TestScriptFixture::builder()
+            .num_worktrees(0)
+            .tests([TestBuilder::new(
+                "my_test",
+                "bash",
+                [
+                    "-c".into(),
+                    OsString::from(format!(
+                        "echo '  compiling 45% ' >&$LIMMAT_STATUS_FD
+                         while [ ! -e {proceed_path:?} ]; do sleep 0.01; done"
+                    )),
+                ],
+            )
+            .build()])
+            .build()
+            .await;
+        let commit = f.repo.commit("hello").await.unwrap();
+        let m = &f.manager;
+        let mut results = m.results();
+        m.set_revisions([commit.clone()]).await.unwrap();
+
+        timeout_5s(async {
+            loop {
+                let notif = results.recv().await.unwrap();
+                if let TestStatus::Started(Some(progress)) = &notif.status {
+                    assert_eq!(progress, "compiling 45%");
+                    break;
+                }
+                assert!(
+                    !matches!(notif.status, TestStatus::Finished(_)),
+                    "finished without reporting progress: {notif:?}"
+                );
+            }
+        })
+        .await
+        .expect("didn't get progress notification");
+
+        File::create(&proceed_path).unwrap();
+        m.settled().await;
+    }
+
+    #[tokio::test]
+    async fn should_run_setup_and_teardown() {
+        let temp_dir = TempDir::new().unwrap();
+        let log_path = temp_dir.path().join("log");
+        let proceed_path = temp_dir.path().join("proceed");
+        let f = TestScriptFixture::builder()
+            .num_worktrees(0)
+            .tests([TestBuilder::new(
+                "my_test",
+                "bash",
+                [
+                    "-c".into(),
+                    OsString::from(format!(
+                        "echo test >> {log_path:?}
+                         while [ ! -e {proceed_path:?} ]; do sleep 0.01; done
+                         exit 1"
+                    )),
+                ],
+            )
+            .setup(&format!("echo setup >> {log_path:?}"))
+            .teardown(&format!("echo teardown >> {log_path:?}; echo oops; exit 3"))
+            .build()])
+            .build()
+            .await;
+        let commit = f.repo.commit("hello").await.unwrap();
+        let m = &f.manager;
+        let log = || fs::read_to_string(&log_path).unwrap_or_default();
+
+        // Teardown still happens when the test is canceled.
+        m.set_revisions([commit.clone()]).await.unwrap();
+        timeout_5s(async {
+            while !log().contains("test") {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("test didn't start");
+        m.cancel_running().await.unwrap();
+        m.settled().await;
+        assert_eq!(log(), "setup\ntest\nteardown\n");
+
+        // A failed teardown doesn't affect the result.
+        fs::remove_file(&log_path).unwrap();
+        File::create(&proceed_path).unwrap();
+        m.set_revisions([commit.clone()]).await.unwrap();
+        m.settled().await;
+        assert_eq!(log(), "setup\ntest\nteardown\n");
+        let LookupResult::FoundResult(entry) = f.db.lookup(&f.test_case(&commit, 0)).await.unwrap()
+        else {
+            panic!("no result recorded");
+        };
+        assert_eq!(entry.exit_code(), 1);
+        assert_eq!(entry.teardown_error(), Some("exit code 3"));
+        assert_eq!(
+            fs::read_to_string(entry.hook_output_path("teardown")).unwrap(),
+            "oops\n"
+        );
+    }
+
+    // The upload fails the first fail_attempts times it's run.
+    #[test_case(0, 0, 0, 1, None ; "passes")]
+    #[test_case(0, 2, 2, 3, None ; "passes after retries")]
+    #[test_case(0, 2, 1, 2, Some("exit code 1") ; "runs out of retries")]
+    #[test_case(1, 0, 0, 0, None ; "not run for failures")]
+    #[tokio::test]
+    async fn should_upload_artifacts(
+        exit_code: ExitCode,
+        fail_attempts: usize,
+        retries: u32,
+        want_attempts: usize,
+        want_error: Option<&str>,
+    ) {
+        let temp_dir = TempDir::new().unwrap();
+        let log_path = temp_dir.path().join("log");
+        let f = TestScriptFixture::builder()
+            .num_worktrees(0)
+            .tests([TestBuilder::new(
+                "my_test",
+                "bash",
+                [
+                    "-c",
+                    &format!("touch $LIMMAT_ARTIFACTS/foo; exit {exit_code}"),
+                ],
+            )
+            .artifact_upload(
+                &format!(
+                    "echo attempt >> {log_path:?}
+                     ls $LIMMAT_ARTIFACTS
+                     [ $(wc -l < {log_path:?}) -gt {fail_attempts} ]"
+                ),
+                retries,
+            )
+            .build()])
+            .build()
+            .await;
+        let commit = f.repo.commit("hello").await.unwrap();
+        f.manager.set_revisions([commit.clone()]).await.unwrap();
+        f.manager.settled().await;
+
+        let log = fs::read_to_string(&log_path).unwrap_or_default();
+        assert_eq!(log.lines().count(), want_attempts);
+        let LookupResult::FoundResult(entry) = f.db.lookup(&f.test_case(&commit, 0)).await.unwrap()
+        else {
+            panic!("no result recorded");
+        };
+        // A failed upload doesn't affect the result.
+        assert_eq!(entry.exit_code(), exit_code);
+        assert_eq!(entry.upload_error(), want_error);
+        if want_attempts > 0 {
+            assert_eq!(
+                fs::read_to_string(entry.hook_output_path("upload")).unwrap(),
+                "foo\n"
+            );
+        }
+    }
+
+    #[test_case(true ; "present")]
+    #[test_case(false ; "missing")]
+    #[tokio::test]
+    async fn should_check_outputs(write_report: bool) {
+        let temp_dir = TempDir::new().unwrap();
+        let log_path = temp_dir.path().join("log");
+        let report = if write_report {
+            "touch $LIMMAT_ARTIFACTS/report.json"
+        } else {
+            ""
+        };
+        let build = TestBuilder::new(
+            "build",
+            "bash",
+            [
+                "-c".into(),
+                format!("mkdir $LIMMAT_ARTIFACTS/out; touch $LIMMAT_ARTIFACTS/out/bin; {report}"),
+            ],
+        )
+        .outputs(["out/bin", "report.json"])
+        .build();
+        let user = TestBuilder::new(
+            "user",
+            "bash",
+            [
+                "-c".into(),
+                format!(
+                    "echo $LIMMAT_ARTIFACT_build_out_bin $LIMMAT_ARTIFACT_build_report_json \
+                     > {log_path:?}"
+                ),
+            ],
+        )
+        .depends_on(["build"])
+        .build();
+        let f = TestScriptFixture::builder()
+            .num_worktrees(0)
+            .tests([build, user])
+            .build()
+            .await;
+        let commit = f.repo.commit("hello").await.unwrap();
+        f.manager.set_revisions([commit.clone()]).await.unwrap();
+        f.manager.settled().await;
+
+        let build_status = f
+            .manager
+            .statuses()
+            .into_iter()
+            .find(|(test_case, _)| test_case.test.name.to_string() == "build")
+            .unwrap()
+            .1;
+        if write_report {
+            let artifacts = f
+                .db_dir
+                .path()
+                .join(commit.hash.to_string())
+                .join("build/artifacts");
+            assert_eq!(
+                fs::read_to_string(&log_path).unwrap(),
+                format!(
+                    "{} {}\n",
+                    artifacts.join("out/bin").display(),
+                    artifacts.join("report.json").display()
+                )
+            );
+            assert!(matches!(build_status, TestStatus::Finished(Ok(_))));
+        } else {
+            assert!(!log_path.exists(), "dependent ran");
+            let TestStatus::Finished(Err(TestInconclusive::Error(error))) = build_status else {
+                panic!("unexpected status {build_status:?}");
+            };
+            assert!(
+                error.contains("\"report.json\""),
+                "unexpected error {error:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs user namespaces"]
+    async fn should_sandbox() {
+        assert!(can_unshare(), "can't create user namespaces");
+        let temp_dir = TempDir::new().unwrap();
+        let out_path = temp_dir.path().join("out");
+        let f = TestScriptFixture::builder()
+            .num_worktrees(0)
+            .tests([TestBuilder::new(
+                "my_test",
+                "bash",
+                [
+                    "-c".into(),
+                    OsString::from(format!(
+                        "umask > {out_path:?}
+                         echo $TMPDIR >> {out_path:?}
+                         sed -n 's/ *\\(.*\\):.*/\\1/p' /proc/net/dev >> {out_path:?}"
+                    )),
+                ],
+            )
+            .umask(Mode::from_bits_truncate(0o027))
+            .sandbox(Sandbox {
+                user: None,
+                private_tmp: true,
+                unshare: vec![Namespace::Mount, Namespace::Net],
+                network: Network::Host,
+            })
+            .build()])
+            .build()
+            .await;
+        let commit = f.repo.commit("hello").await.unwrap();
+        f.manager.set_revisions([commit.clone()]).await.unwrap();
+        f.manager.settled().await;
+
+        let out = fs::read_to_string(&out_path).unwrap();
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(lines[0], "0027");
+        assert_ne!(lines[1], "");
+        assert!(!Path::new(lines[1]).exists(), "TMPDIR not cleaned up");
+        assert_eq!(lines[2..], ["lo"]);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs user namespaces and iproute2"]
+    async fn should_isolate_network() {
+        assert!(can_unshare(), "can't create user namespaces");
+        // Both the sandbox and the test itself need iproute2.
+        assert!(
+            std::process::Command::new("ip").arg("-V").output().is_ok(),
+            "ip isn't installed"
+        );
+        let temp_dir = TempDir::new().unwrap();
+        let out_path = temp_dir.path().join("out");
+        let f = TestScriptFixture::builder()
+            .num_worktrees(0)
+            .tests([TestBuilder::new(
+                "my_test",
+                "bash",
+                [
+                    "-c".into(),
+                    OsString::from(format!("ip -o link > {out_path:?}")),
+                ],
+            )
+            .sandbox(Sandbox {
+                network: Network::None,
+                ..Sandbox::default()
+            })
+            .build()])
+            .build()
+            .await;
+        let commit = f.repo.commit("hello").await.unwrap();
+        f.manager.set_revisions([commit.clone()]).await.unwrap();
+        f.manager.settled().await;
+
+        let out = fs::read_to_string(&out_path).unwrap();
+        let links: Vec<_> = out.lines().collect();
+        assert_eq!(links.len(), 1, "unexpected interfaces: {out}");
+        assert!(links[0].starts_with("1: lo: <LOOPBACK,UP"), "{out}");
+    }
+
+    #[tokio::test]
+    async fn should_run_in_archive() {
+        let temp_dir = TempDir::new().unwrap();
+        let out_path = temp_dir.path().join("out");
+        let f = TestScriptFixture::builder()
+            // No worktrees in the pool, the job shouldn't need one.
+            .num_worktrees(0)
+            .tests([TestBuilder::new(
+                "my_test",
+                "bash",
+                [
+                    "-c".into(),
+                    OsString::from(format!(
+                        "cat file > {out_path:?}
+                         echo >> {out_path:?}
+                         pwd >> {out_path:?}
+                         ls -A >> {out_path:?}"
+                    )),
+                ],
+            )
+            .checkout(CheckoutMode::Archive)
+            .build()])
+            .build()
+            .await;
+        let repo = &f.repo;
+        fs::write(repo.path().join("file"), "committed").unwrap();
+        repo.add_all().await.unwrap();
+        let commit = repo.commit("file").await.unwrap();
+        fs::write(repo.path().join("file"), "uncommitted").unwrap();
+        f.manager.set_revisions([commit.clone()]).await.unwrap();
+        f.manager.settled().await;
+
+        let out = fs::read_to_string(&out_path).unwrap();
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(lines[0], "committed");
+        assert_ne!(Path::new(lines[1]), repo.path());
+        assert!(!Path::new(lines[1]).exists(), "archive dir not cleaned up");
+        assert_eq!(lines[2..], ["file"]);
+    }
+
+    #[tokio::test]
+    async fn should_skip_unchanged() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut deploy = TestBuilder::new("deploy", "true", Vec::<OsString>::new()).build();
+        deploy.stage_gate = vec![TestName::new("build")];
+        let f = TestScriptFixture::builder()
+            .num_worktrees(0)
+            .tests([
+                TestBuilder::new("build", "true", Vec::<OsString>::new())
+                    .skip_if_unchanged(["src/**"])
+                    .build(),
+                TestBuilder::new("run", "true", Vec::<OsString>::new())
+                    .depends_on(["build"])
+                    .build(),
+                deploy,
+                TestBuilder::new(
+                    "lint",
+                    "bash",
+                    [
+                        "-c".into(),
+                        OsString::from(format!(
+                            "cp $LIMMAT_CHANGED_FILES {0:?}/${{LIMMAT_COMMIT}}",
+                            temp_dir.path()
+                        )),
+                    ],
+                )
+                .build(),
+            ])
+            .build()
+            .await;
+        let repo = &f.repo;
+        fs::create_dir(repo.path().join("src")).unwrap();
+        fs::write(repo.path().join("src/main.c"), "1").unwrap();
+        repo.add_all().await.unwrap();
+        let src_commit = repo.commit("src").await.unwrap();
+        fs::write(repo.path().join("README"), "1").unwrap();
+        repo.add_all().await.unwrap();
+        let docs_commit = repo.commit("docs").await.unwrap();
+
+        let mut results = f.manager.results();
+        f.manager
+            .set_revisions([src_commit.clone(), docs_commit.clone()])
+            .await
+            .expect("set_revisions failed");
+        // Skipped test cases are decided up front, they don't get a job.
+        let statuses = f.manager.statuses();
+        assert!(
+            !statuses
+                .iter()
+                .any(|(tc, _)| tc.commit_hash == docs_commit.hash
+                    && ["build", "run"].contains(&tc.test.name.0.as_str())),
+            "{statuses:?}"
+        );
+        let notif = results.recv().await.unwrap();
+        assert_eq!(notif.test_case.commit_hash, docs_commit.hash);
+        assert!(matches!(
+            notif.status,
+            TestStatus::Finished(Err(TestInconclusive::Skipped))
+        ));
+
+        let commits = [src_commit.hash.clone(), docs_commit.hash.clone()];
+        let statuses: HashMap<(CommitHash, String), String> = f
+            .manager
+            .wait(&commits, |_| true)
+            .await
+            .expect("wait failed")
+            .into_iter()
+            .map(|(tc, status)| {
+                (
+                    (tc.commit_hash, tc.test.name.to_string()),
+                    status.to_string(),
+                )
+            })
+            .collect();
+        let status =
+            |commit: &Commit, test: &str| statuses[&(commit.hash.clone(), test.to_owned())].clone();
+        let skipped = TestInconclusive::Skipped.to_string();
+        assert_eq!(status(&src_commit, "build"), "exit code 0");
+        assert_eq!(status(&src_commit, "run"), "exit code 0");
+        assert_eq!(status(&docs_commit, "build"), skipped);
+        assert_eq!(status(&docs_commit, "run"), skipped);
+        assert_eq!(status(&docs_commit, "deploy"), "exit code 0");
+        assert_eq!(status(&docs_commit, "lint"), "exit code 0");
+
+        assert_eq!(
+            fs::read_to_string(temp_dir.path().join(src_commit.hash.to_string())).unwrap(),
+            "src/main.c\n"
+        );
+        assert_eq!(
+            fs::read_to_string(temp_dir.path().join(docs_commit.hash.to_string())).unwrap(),
+            "README\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn should_list_changed_files_for_job() {
+        // No test decides whether to run based on changed files, so the
+        // Manager doesn't list them, but the job still gets them.
+        let temp_dir = TempDir::new().unwrap();
+        let f = TestScriptFixture::builder()
+            .num_worktrees(0)
+            .tests([TestBuilder::new(
+                "lint",
+                "bash",
+                [
+                    "-c".into(),
+                    OsString::from(format!(
+                        "cp $LIMMAT_CHANGED_FILES {0:?}/${{LIMMAT_COMMIT}}",
+                        temp_dir.path()
+                    )),
+                ],
+            )
+            .build()])
+            .build()
+            .await;
+        let repo = &f.repo;
+        fs::write(repo.path().join("README"), "1").unwrap();
+        repo.add_all().await.unwrap();
+        let commit = repo.commit("docs").await.unwrap();
+
+        f.manager
+            .set_revisions([commit.clone()])
+            .await
+            .expect("set_revisions failed");
+        f.manager.settled().await;
+
+        assert!(f.manager.changed_files.lock().is_empty());
+        assert_eq!(
+            fs::read_to_string(temp_dir.path().join(commit.hash.to_string())).unwrap(),
+            "README\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn should_skip_by_commit_message() {
+        let f = TestScriptFixture::builder()
+            .num_worktrees(0)
+            .tests([
+                TestBuilder::new("build", "true", Vec::<OsString>::new()).build(),
+                TestBuilder::new("run", "true", Vec::<OsString>::new())
+                    .depends_on(["build"])
+                    .build(),
+                TestBuilder::new("lint", "true", Vec::<OsString>::new()).build(),
+                TestBuilder::new("doc", "true", Vec::<OsString>::new()).build(),
+            ])
+            .build()
+            .await;
+        let wip_commit = f
+            .repo
+            .commit("wip\n\nLimmat-Skip: lint, build")
+            .await
+            .unwrap();
+        let all_commit = f.repo.commit("more wip [skip limmat]").await.unwrap();
+        f.manager
+            .set_revisions([wip_commit.clone(), all_commit.clone()])
+            .await
+            .expect("set_revisions failed");
+        f.manager.settled().await;
+
+        let statuses: HashMap<(CommitHash, String), TestStatus> = f
+            .manager
+            .statuses()
+            .into_iter()
+            .map(|(tc, status)| ((tc.commit_hash, tc.test.name.to_string()), status))
+            .collect();
+        let status =
+            |commit: &Commit, test: &str| &statuses[&(commit.hash.clone(), test.to_owned())];
+        let skipped = TestInconclusive::SkippedByCommit.to_string();
+        for test in ["build", "run", "lint"] {
+            assert_eq!(status(&wip_commit, test).to_string(), skipped, "{test}");
+            assert!(status(&wip_commit, test).succeeded());
+        }
+        assert_eq!(status(&wip_commit, "doc").to_string(), "exit code 0");
+        for test in ["build", "run", "lint", "doc"] {
+            assert_eq!(status(&all_commit, test).to_string(), skipped, "{test}");
+        }
     }
 
     #[tokio::test]
@@ -2386,6 +6118,44 @@ mod tests {
         // result cache.
     }
 
+    #[test_case(0, true ; "succeeded¸ should start")]
+    #[test_case(1, false ; "failed¸ shouldn't start")]
+    #[tokio::test]
+    async fn should_gate_stages(exit_code: u32, should_start: bool) {
+        let f = TestScriptFixture::builder()
+            .stage_gates([(1, 0)])
+            .num_worktrees(2)
+            .build()
+            .await;
+        let mut results = f.manager.results();
+        let commit = f
+            .repo
+            .commit(TestScript::exit_code_tag(exit_code))
+            .await
+            .unwrap();
+        f.manager.set_revisions(vec![commit.clone()]).await.unwrap();
+        timeout_5s(f.manager.settled())
+            .await
+            .expect("jobs didn't settle");
+        assert_eq!(f.scripts[1].was_started(&commit.hash), should_start);
+        if !should_start {
+            let mut final_status = None;
+            while let Ok(notif) = results.try_recv() {
+                if notif.test_case.test.name == TestName::new("test_1") {
+                    final_status = Some(notif.status.clone());
+                }
+            }
+            assert!(
+                matches!(
+                    final_status,
+                    Some(TestStatus::Finished(Err(TestInconclusive::StageFailed(ref name))))
+                        if *name == TestName::new("test_0")
+                ),
+                "unexpected status {final_status:?}"
+            );
+        }
+    }
+
     #[tokio::test]
     async fn should_cancel_waiting_for_deps() {
         let f = TestScriptFixture::builder()
@@ -17,11 +17,14 @@ use std::{
 
 use colored::{ColoredString, Colorize as _};
 use indoc::indoc;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 use unicode_segmentation::UnicodeSegmentation as _;
 
 // Represents a block of text, potentially with styling. Note this always
 // represents a block, you can't represent a string without a newline at the
 // end (I'm not sure if that's the same in Ratatui).
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Text<'a> {
     pub lines: Vec<Line<'a>>,
 }
@@ -60,6 +63,13 @@ impl<'a> Text<'a> {
     pub fn into_lines(self) -> impl Iterator<Item = Line<'a>> {
         self.lines.into_iter()
     }
+
+    // Copy any borrowed content so the text can outlive what it came from.
+    pub fn into_owned(self) -> Text<'static> {
+        Text {
+            lines: self.lines.into_iter().map(Line::into_owned).collect(),
+        }
+    }
 }
 
 pub struct RenderAnsi<'a> {
@@ -89,6 +99,10 @@ impl RenderHtmlPre<'_> {
             background: rgba(242, 121, 53, 0.3);
         }
 
+        .advisory-failure {
+            background: rgba(255, 215, 0, 0.3);
+        }
+
         .success {
             background: rgba(0, 255, 0, 0.3);
         }
@@ -113,6 +127,7 @@ impl Display for RenderHtmlPre<'_> {
     }
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Line<'a> {
     pub spans: Vec<Span<'a>>,
 }
@@ -129,24 +144,36 @@ where
 }
 
 impl Line<'_> {
-    // Truncate to the given number of unicode graphemej clusters, disregarding styling.
-    pub fn truncate_graphemes(mut self, length: usize) -> Self {
-        let mut remaining_length = length;
+    // Number of terminal columns the line takes up.
+    pub fn width(&self) -> usize {
+        self.spans.iter().map(Span::width).sum()
+    }
+
+    // Truncate to fit in the given number of terminal columns. Escape
+    // sequences in the content don't count towards the width.
+    pub fn truncate_width(mut self, width: usize) -> Self {
+        let mut remaining_width = width;
         let mut last_span_idx = None;
         for (i, span) in self.spans.iter_mut().enumerate() {
-            let n = span.num_graphemes();
-            if n >= remaining_length {
-                span.truncate_graphemes(remaining_length);
+            let n = span.width();
+            if n >= remaining_width {
+                span.truncate_width(remaining_width);
                 last_span_idx = Some(i);
                 break;
             }
-            remaining_length -= n;
+            remaining_width -= n;
         }
         if let Some(i) = last_span_idx {
             self.spans.truncate(i + 1);
         }
         self
     }
+
+    fn into_owned(self) -> Line<'static> {
+        Line {
+            spans: self.spans.into_iter().map(Span::into_owned).collect(),
+        }
+    }
 }
 
 struct RenderAnsiLine<'a> {
@@ -185,12 +212,17 @@ impl<'a, T: Into<Span<'a>>> From<T> for Line<'a> {
     }
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Span<'a> {
     pub class: Option<Class>,
+    // Overrides the colours that come from the class.
+    pub style: Option<Style>,
     // The cow is copied from Ratatui. My understanding is that this is there to
     // be generic across ownership or reference.
     pub content: Cow<'a, str>,
     pub url: Option<Cow<'a, str>>,
+    // Only shown in HTML, when hovering over the span.
+    pub tooltip: Option<Cow<'a, str>>,
 }
 
 impl<'a, T: Into<Cow<'a, str>>> From<T> for Span<'a> {
@@ -204,7 +236,9 @@ impl<'a> Span<'a> {
         Self {
             content: content.into(),
             class: None,
+            style: None,
             url: None,
+            tooltip: None,
         }
     }
 
@@ -213,28 +247,135 @@ impl<'a> Span<'a> {
         self
     }
 
+    pub fn with_style(mut self, style: Style) -> Self {
+        self.style = Some(style);
+        self
+    }
+
     pub fn with_url(mut self, url: impl Into<Cow<'a, str>>) -> Self {
         self.url = Some(url.into());
         self
     }
 
-    fn num_graphemes(&self) -> usize {
-        self.content.graphemes(true).count()
+    pub fn with_tooltip(mut self, tooltip: impl Into<Cow<'a, str>>) -> Self {
+        self.tooltip = Some(tooltip.into());
+        self
+    }
+
+    fn into_owned(self) -> Span<'static> {
+        Span {
+            class: self.class,
+            style: self.style,
+            content: Cow::Owned(self.content.into_owned()),
+            url: self.url.map(|url| Cow::Owned(url.into_owned())),
+            tooltip: self.tooltip.map(|tooltip| Cow::Owned(tooltip.into_owned())),
+        }
+    }
+
+    fn width(&self) -> usize {
+        grapheme_widths(&self.content).map(|(_, width)| width).sum()
     }
 
     // If this was to become public, the API should be made consistent with
-    // Lines::truncate_graphemes. But at the moment it just has whatever is most convenient.
-    fn truncate_graphemes(&mut self, len: usize) {
-        if let Some((byte_idx, _)) = self.content.grapheme_indices(true).nth(len) {
-            match self.content {
-                Cow::Borrowed(s) => {
-                    self.content = Cow::Borrowed(&s[..byte_idx]);
-                }
-                Cow::Owned(ref mut s) => {
-                    s.truncate(byte_idx);
-                }
-            };
+    // Lines::truncate_width. But at the moment it just has whatever is most convenient.
+    fn truncate_width(&mut self, width: usize) {
+        let mut total = 0;
+        let mut escaped = false;
+        let mut cut = None;
+        for (byte_idx, grapheme_width) in grapheme_widths(&self.content) {
+            if total + grapheme_width > width {
+                cut = Some(byte_idx);
+                break;
+            }
+            total += grapheme_width;
+            escaped |= grapheme_width == 0;
+        }
+        let Some(byte_idx) = cut else {
+            return;
+        };
+        let mut content = self.content[..byte_idx].to_owned();
+        // Don't let a color from Git leak into the rest of the output.
+        if escaped {
+            content.push_str("\x1b[0m");
         }
+        self.content = Cow::Owned(content);
+    }
+}
+
+#[derive(Clone, Copy)]
+enum EscapeState {
+    None,
+    Start,
+    Csi,
+}
+
+// The byte index of each grapheme cluster in the string, and how many terminal
+// columns it takes up. ANSI escape sequences (like the colors that Git outputs)
+// take up none.
+fn grapheme_widths(s: &str) -> impl Iterator<Item = (usize, usize)> + '_ {
+    let mut state = EscapeState::None;
+    s.grapheme_indices(true).map(move |(byte_idx, grapheme)| {
+        let width = match (state, grapheme) {
+            (_, "\x1b") => {
+                state = EscapeState::Start;
+                0
+            }
+            (EscapeState::Start, "[") => {
+                state = EscapeState::Csi;
+                0
+            }
+            (EscapeState::Start, _) => {
+                state = EscapeState::None;
+                0
+            }
+            (EscapeState::Csi, _) => {
+                // Parameter bytes are 0x30-0x3f, the final byte comes after.
+                if grapheme.chars().all(|c| ('\x40'..='\x7e').contains(&c)) {
+                    state = EscapeState::None;
+                }
+                0
+            }
+            (EscapeState::None, _) => grapheme_width(grapheme),
+        };
+        (byte_idx, width)
+    })
+}
+
+// There's no table of character widths in our dependencies, so this is a guess
+// covering the emoji and CJK characters that are likely to show up.
+fn grapheme_width(grapheme: &str) -> usize {
+    let Some(c) = grapheme.chars().next() else {
+        return 0;
+    };
+    let wide = grapheme.contains('\u{fe0f}')
+        || matches!(c as u32,
+            0x1100..=0x115f
+            | 0x231a..=0x231b
+            | 0x23e9..=0x23ec
+            | 0x23f0
+            | 0x23f3
+            | 0x2614..=0x2615
+            | 0x26a1
+            | 0x26d4
+            | 0x2705
+            | 0x274c
+            | 0x2b50
+            | 0x2e80..=0xa4cf
+            | 0xac00..=0xd7a3
+            | 0xf900..=0xfaff
+            | 0xfe30..=0xfe4f
+            | 0xff00..=0xff60
+            | 0xffe0..=0xffe6
+            | 0x1f300..=0x1f64f
+            | 0x1f680..=0x1f6ff
+            | 0x1f900..=0x1faff
+            | 0x20000..=0x3fffd);
+    if wide {
+        2
+    } else if c.is_control() {
+        0
+    } else {
+        1
     }
 }
 
@@ -245,13 +386,24 @@ struct RenderAnsiSpan<'a> {
 impl Display for RenderAnsiSpan<'_> {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         let output = self.span.content.as_ref();
-        let output = match self.span.class {
+        let output = match (self.span.style, self.span.class) {
+            (Some(style), _) => {
+                let mut output = ColoredString::from(output);
+                if let Some(color) = style.color {
+                    output = output.color(color.ansi());
+                }
+                if let Some(background) = style.background {
+                    output = output.on_color(background.ansi());
+                }
+                output
+            }
             // TODO: ColoredString is not very useful here any more.
-            None => ColoredString::from(output),
-            Some(Class::Failure) => output.on_red(),
-            Some(Class::Success) => output.on_green(),
-            Some(Class::Error) => output.on_bright_red(),
-            Some(Class::TestName) => output.bold(),
+            (None, None) => ColoredString::from(output),
+            (None, Some(Class::Failure)) => output.on_red(),
+            (None, Some(Class::AdvisoryFailure)) => output.on_yellow(),
+            (None, Some(Class::Success)) => output.on_green(),
+            (None, Some(Class::Error)) => output.on_bright_red(),
+            (None, Some(Class::TestName)) => output.bold(),
         };
         // Renders a hyperlink like in
         // https://gist.github.com/egmontkob/eb114294efbcd5adb1944c9f3cb5feda.
@@ -269,24 +421,49 @@ struct RenderHtmlSpan<'a> {
 
 impl Display for RenderHtmlSpan<'_> {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if let Some(tooltip) = &self.span.tooltip {
+            // Unlike the content, this comes from the config so it's likely to
+            // have quotes in it.
+            let tooltip = tooltip
+                .replace('&', "&amp;")
+                .replace('"', "&quot;")
+                .replace('<', "&lt;");
+            write!(f, r#"<span title="{tooltip}">"#)?;
+        }
         if let Some(ref url) = &self.span.url {
             write!(f, r#"<a href="{}">"#, url)?;
         }
-        write!(
-            f,
-            r#"<span class="{}">{}</span>"#,
-            match self.span.class {
-                None => "",
-                Some(Class::Error) => "error",
-                Some(Class::Success) => "success",
-                Some(Class::Failure) => "failure",
-                Some(Class::TestName) => "test-name",
-            },
-            self.span.content.as_ref()
-        )?;
+        // Like for ANSI, an explicit style replaces the class.
+        if let Some(style) = self.span.style {
+            write!(f, r#"<span style=""#)?;
+            if let Some(color) = style.color {
+                write!(f, "color: {};", color.css())?;
+            }
+            if let Some(background) = style.background {
+                write!(f, "background: {};", background.css())?;
+            }
+            write!(f, r#"">{}</span>"#, self.span.content.as_ref())?;
+        } else {
+            write!(
+                f,
+                r#"<span class="{}">{}</span>"#,
+                match self.span.class {
+                    None => "",
+                    Some(Class::Error) => "error",
+                    Some(Class::Success) => "success",
+                    Some(Class::Failure) => "failure",
+                    Some(Class::AdvisoryFailure) => "advisory-failure",
+                    Some(Class::TestName) => "test-name",
+                },
+                self.span.content.as_ref()
+            )?;
+        }
         if self.span.url.is_some() {
             write!(f, "</a>")?;
         }
+        if self.span.tooltip.is_some() {
+            write!(f, "</span>")?;
+        }
         Ok(())
     }
 }
@@ -294,9 +471,90 @@ impl Display for RenderHtmlSpan<'_> {
 // This is like a CSS class. For ANSI output this will produce a hard-coded
 // style. For HTML it outputs a CSS class name, some CSS is provided  to
 // make use of these classes.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
 pub enum Class {
     Error,
     Success,
     Failure,
+    AdvisoryFailure,
     TestName,
 }
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+pub struct Style {
+    pub color: Option<Color>,
+    pub background: Option<Color>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Color {
+    Black,
+    Red,
+    Green,
+    Yellow,
+    Blue,
+    Magenta,
+    Cyan,
+    White,
+}
+
+impl Color {
+    fn ansi(self) -> colored::Color {
+        match self {
+            Self::Black => colored::Color::Black,
+            Self::Red => colored::Color::Red,
+            Self::Green => colored::Color::Green,
+            Self::Yellow => colored::Color::Yellow,
+            Self::Blue => colored::Color::Blue,
+            Self::Magenta => colored::Color::Magenta,
+            Self::Cyan => colored::Color::Cyan,
+            Self::White => colored::Color::White,
+        }
+    }
+
+    fn css(self) -> &'static str {
+        match self {
+            Self::Black => "black",
+            Self::Red => "red",
+            Self::Green => "green",
+            Self::Yellow => "yellow",
+            Self::Blue => "blue",
+            Self::Magenta => "magenta",
+            Self::Cyan => "cyan",
+            Self::White => "white",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_truncate_width() {
+        let line = Line::from_iter(["\x1b[31mabc\x1b[m", "✅de"]);
+        assert_eq!(line.width(), 7);
+        let truncated = line.clone().truncate_width(4);
+        assert_eq!(truncated.spans.len(), 2);
+        assert_eq!(truncated.spans[0].content, "\x1b[31mabc\x1b[m");
+        assert_eq!(truncated.spans[1].content, "");
+        let truncated = line.truncate_width(2);
+        assert_eq!(truncated.spans.len(), 1);
+        // The color gets reset.
+        assert_eq!(truncated.spans[0].content, "\x1b[31mab\x1b[0m");
+    }
+
+    #[test]
+    fn test_html_tooltip() {
+        let text = Text::from(Span::new("build").with_tooltip(r#"Runs "make""#));
+        assert_eq!(
+            text.html_pre().to_string(),
+            concat!(
+                "<pre>\n",
+                r#"<span title="Runs &quot;make&quot;"><span class="">build</span></span>"#,
+                "\n</pre>\n"
+            )
+        );
+    }
+}
@@ -0,0 +1,412 @@
+// Holds back new jobs while the machine is busy with other things, so that
+// Limmat doesn't fight the user's interactive work for CPU, when it's running
+// on battery, or when the disk is nearly full.
+
+use std::{
+    fmt::{self, Display},
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context as _};
+use tokio::{select, sync::watch, time::sleep};
+use tokio_util::sync::CancellationToken;
+
+use crate::{config, util::free_space};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+const POWER_SUPPLY_DIR: &str = "/sys/class/power_supply";
+
+// A limit on how many jobs can run at once, and why.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Restriction {
+    pub max_jobs: usize,
+    pub reason: String,
+}
+
+impl Restriction {
+    fn pause(reason: String) -> Self {
+        Self {
+            max_jobs: 0,
+            reason,
+        }
+    }
+}
+
+impl Display for Restriction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.max_jobs {
+            0 => write!(f, "Not starting jobs: {}", self.reason),
+            n => write!(f, "Running at most {n} jobs: {}", self.reason),
+        }
+    }
+}
+
+pub struct Throttle {
+    config: config::Throttle,
+    database_dir: PathBuf,
+    worktree_dir: PathBuf,
+    restriction: watch::Sender<Option<Restriction>>,
+    // Applies on top of the restriction, for when the throttle is shared by
+    // several test managers.
+    max_jobs: Option<usize>,
+    // Jobs that have been admitted and haven't finished yet.
+    running: watch::Sender<usize>,
+}
+
+// A job is allowed to run for as long as it holds this.
+pub struct ThrottlePermit(Arc<Throttle>);
+
+impl Drop for ThrottlePermit {
+    fn drop(&mut self) {
+        self.0.running.send_modify(|running| *running -= 1);
+    }
+}
+
+impl Throttle {
+    // Checks the system state once up front, so that nothing gets started
+    // before the first restriction is known.
+    pub fn new(
+        config: config::Throttle,
+        database_dir: PathBuf,
+        worktree_dir: PathBuf,
+    ) -> anyhow::Result<Self> {
+        let mut throttle = Self {
+            config,
+            database_dir,
+            worktree_dir,
+            restriction: watch::Sender::new(None),
+            max_jobs: None,
+            running: watch::Sender::new(0),
+        };
+        throttle.restriction = watch::Sender::new(throttle.check()?);
+        Ok(throttle)
+    }
+
+    pub fn with_max_jobs(mut self, max_jobs: usize) -> Self {
+        self.max_jobs = Some(max_jobs);
+        self
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<Option<Restriction>> {
+        self.restriction.subscribe()
+    }
+
+    // Blocks until the restriction allows another job to run.
+    pub async fn admit(self: &Arc<Self>) -> ThrottlePermit {
+        let mut restriction = self.restriction.subscribe();
+        let mut running = self.running.subscribe();
+        loop {
+            let max_jobs = restriction
+                .borrow_and_update()
+                .as_ref()
+                .map(|r| r.max_jobs)
+                .into_iter()
+                .chain(self.max_jobs)
+                .min();
+            running.mark_unchanged();
+            let admitted = self.running.send_if_modified(|running| {
+                if max_jobs.is_some_and(|max| *running >= max) {
+                    return false;
+                }
+                *running += 1;
+                true
+            });
+            if admitted {
+                return ThrottlePermit(self.clone());
+            }
+            // Can't fail, we hold the senders.
+            select! {
+                _ = restriction.changed() => (),
+                _ = running.changed() => (),
+            }
+        }
+    }
+
+    // For things that aren't jobs but shouldn't happen while jobs are paused,
+    // like creating worktrees.
+    pub async fn wait_unpaused(&self) {
+        let mut restriction = self.restriction.subscribe();
+        // Can't fail, we hold the sender.
+        let _ = restriction
+            .wait_for(|r| r.as_ref().is_none_or(|r| r.max_jobs > 0))
+            .await;
+    }
+
+    // Keep checking the system state until the token is cancelled.
+    pub async fn monitor(&self, ct: CancellationToken) -> anyhow::Result<()> {
+        loop {
+            let new = self.check()?;
+            self.restriction.send_if_modified(|restriction| {
+                let changed = *restriction != new;
+                *restriction = new;
+                changed
+            });
+            select! {
+                _ = ct.cancelled() => return Ok(()),
+                _ = sleep(POLL_INTERVAL) => (),
+            }
+        }
+    }
+
+    fn check(&self) -> anyhow::Result<Option<Restriction>> {
+        // Running out of space mid-job can leave a mangled database entry, so
+        // this comes first.
+        for (dir, min_mb) in [
+            (&self.database_dir, self.config.min_free_database_mb),
+            (&self.worktree_dir, self.config.min_free_worktree_mb),
+        ] {
+            let Some(min_mb) = min_mb else {
+                continue;
+            };
+            let free_mb = free_space(dir)? / (1024 * 1024);
+            if free_mb < min_mb {
+                return Ok(Some(Restriction::pause(format!(
+                    "only {free_mb} MiB free in {}, need {min_mb}",
+                    dir.display()
+                ))));
+            }
+        }
+        if let Some(max_load) = self.config.max_load {
+            let load = load_average()?;
+            if load > max_load {
+                return Ok(Some(Restriction::pause(format!(
+                    "load average {load:.1} is above {max_load}"
+                ))));
+            }
+        }
+        if let Some(max_pressure) = self.config.max_cpu_pressure {
+            let pressure = cpu_pressure()?;
+            if pressure > max_pressure {
+                return Ok(Some(Restriction::pause(format!(
+                    "CPU pressure {pressure:.1}% is above {max_pressure}%"
+                ))));
+            }
+        }
+        if self.config.battery_max_jobs.is_none() && self.config.min_battery_percent.is_none() {
+            return Ok(None);
+        }
+        let Some(charge) = battery_charge(Path::new(POWER_SUPPLY_DIR))? else {
+            return Ok(None);
+        };
+        if let Some(min_percent) = self.config.min_battery_percent {
+            if charge < min_percent {
+                return Ok(Some(Restriction::pause(format!(
+                    "battery at {charge:.0}%, below {min_percent}%"
+                ))));
+            }
+        }
+        Ok(self.config.battery_max_jobs.map(|max_jobs| Restriction {
+            max_jobs,
+            reason: "on battery".into(),
+        }))
+    }
+}
+
+// Over the last minute.
+fn load_average() -> anyhow::Result<f64> {
+    let content = fs::read_to_string("/proc/loadavg").context("reading /proc/loadavg")?;
+    content
+        .split_whitespace()
+        .next()
+        .ok_or_else(|| anyhow!("empty /proc/loadavg"))?
+        .parse()
+        .context("parsing /proc/loadavg")
+}
+
+// Percentage of the last 10 seconds that some task was waiting for a CPU.
+fn cpu_pressure() -> anyhow::Result<f64> {
+    let path = "/proc/pressure/cpu";
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("reading {path}, does the kernel have CONFIG_PSI?"))?;
+    parse_pressure(&content).with_context(|| format!("parsing {path}"))
+}
+
+fn parse_pressure(content: &str) -> anyhow::Result<f64> {
+    let line = content
+        .lines()
+        .find(|line| line.starts_with("some "))
+        .ok_or_else(|| anyhow!("no \"some\" line"))?;
+    let avg10 = line
+        .split_whitespace()
+        .find_map(|field| field.strip_prefix("avg10="))
+        .ok_or_else(|| anyhow!("no avg10 field"))?;
+    avg10.parse().context("parsing avg10")
+}
+
+// If we're running on battery, the average charge percentage of the batteries
+// in a sysfs power_supply directory.
+fn battery_charge(dir: &Path) -> anyhow::Result<Option<f64>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        // No power supply class, so presumably no batteries.
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("reading {}", dir.display())),
+    };
+    let mut discharging = false;
+    let mut charges = Vec::new();
+    for entry in entries {
+        let path = entry
+            .with_context(|| format!("reading {}", dir.display()))?
+            .path();
+        // Not every driver provides every attribute.
+        let read = |name: &str| match fs::read_to_string(path.join(name)) {
+            Ok(s) => Ok(Some(s.trim().to_owned())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("reading {}", path.join(name).display())),
+        };
+        // Devices like wireless mice show up here too, but they don't power
+        // this machine.
+        if read("type")?.as_deref() != Some("Battery")
+            || read("scope")?.as_deref() == Some("Device")
+        {
+            continue;
+        }
+        let (Some(status), Some(capacity)) = (read("status")?, read("capacity")?) else {
+            continue;
+        };
+        discharging |= status == "Discharging";
+        charges.push(capacity.parse::<f64>().context("parsing capacity")?);
+    }
+    if !discharging {
+        return Ok(None);
+    }
+    Ok(Some(charges.iter().sum::<f64>() / charges.len() as f64))
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_pressure() {
+        let content = "some avg10=12.50 avg60=3.00 avg300=1.00 total=123456\n\
+                       full avg10=0.00 avg60=0.00 avg300=0.00 total=0\n";
+        assert_eq!(parse_pressure(content).unwrap(), 12.5);
+        assert!(parse_pressure("full avg10=1.00\n").is_err());
+    }
+
+    fn add_supply(dir: &Path, name: &str, files: &[(&str, &str)]) {
+        let path = dir.join(name);
+        fs::create_dir(&path).unwrap();
+        for (file, content) in files {
+            fs::write(path.join(file), format!("{content}\n")).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_battery_charge() {
+        let dir = TempDir::new().unwrap();
+        assert_eq!(
+            battery_charge(&dir.path().join("nonexistent")).unwrap(),
+            None
+        );
+        add_supply(dir.path(), "AC", &[("type", "Mains"), ("online", "1")]);
+        add_supply(
+            dir.path(),
+            "BAT0",
+            &[
+                ("type", "Battery"),
+                ("status", "Charging"),
+                ("capacity", "40"),
+            ],
+        );
+        assert_eq!(battery_charge(dir.path()).unwrap(), None);
+        add_supply(
+            dir.path(),
+            "BAT1",
+            &[
+                ("type", "Battery"),
+                ("status", "Discharging"),
+                ("capacity", "80"),
+            ],
+        );
+        assert_eq!(battery_charge(dir.path()).unwrap(), Some(60.0));
+        add_supply(
+            dir.path(),
+            "hid-mouse-battery",
+            &[
+                ("type", "Battery"),
+                ("scope", "Device"),
+                ("status", "Discharging"),
+                ("capacity", "5"),
+            ],
+        );
+        add_supply(dir.path(), "BAT2", &[("type", "Battery")]);
+        assert_eq!(battery_charge(dir.path()).unwrap(), Some(60.0));
+    }
+
+    #[tokio::test]
+    async fn should_limit_running_jobs() {
+        let dir = TempDir::new().unwrap();
+        let throttle = Arc::new(
+            Throttle::new(
+                config::Throttle::default(),
+                dir.path().to_owned(),
+                dir.path().to_owned(),
+            )
+            .unwrap(),
+        );
+        throttle.restriction.send_replace(Some(Restriction {
+            max_jobs: 1,
+            reason: "testing".into(),
+        }));
+        let permit = throttle.admit().await;
+        let mut second = Box::pin(throttle.admit());
+        assert!(futures::poll!(&mut second).is_pending());
+        drop(permit);
+        let _permit = second.await;
+        throttle
+            .restriction
+            .send_replace(Some(Restriction::pause("testing".into())));
+        assert!(futures::poll!(Box::pin(throttle.admit())).is_pending());
+        throttle.restriction.send_replace(None);
+        throttle.admit().await;
+    }
+
+    #[tokio::test]
+    async fn should_cap_running_jobs() {
+        let dir = TempDir::new().unwrap();
+        let throttle = Arc::new(
+            Throttle::new(
+                config::Throttle::default(),
+                dir.path().to_owned(),
+                dir.path().to_owned(),
+            )
+            .unwrap()
+            .with_max_jobs(2),
+        );
+        let _permit1 = throttle.admit().await;
+        let permit2 = throttle.admit().await;
+        let mut third = Box::pin(throttle.admit());
+        assert!(futures::poll!(&mut third).is_pending());
+        // The restriction can only tighten the cap.
+        throttle.restriction.send_replace(Some(Restriction {
+            max_jobs: 3,
+            reason: "testing".into(),
+        }));
+        assert!(futures::poll!(&mut third).is_pending());
+        drop(permit2);
+        third.await;
+    }
+
+    #[tokio::test]
+    async fn should_pause_when_disk_full() {
+        let dir = TempDir::new().unwrap();
+        let config = config::Throttle {
+            min_free_worktree_mb: Some(u64::MAX),
+            ..Default::default()
+        };
+        let throttle =
+            Throttle::new(config, dir.path().to_owned(), dir.path().join("worktrees")).unwrap();
+        let restriction = throttle.subscribe().borrow().clone().unwrap();
+        assert_eq!(restriction.max_jobs, 0);
+        assert!(futures::poll!(Box::pin(throttle.wait_unpaused())).is_pending());
+        throttle.restriction.send_replace(None);
+        throttle.wait_unpaused().await;
+    }
+}
@@ -1,26 +1,42 @@
-use std::{collections::HashMap, ffi::OsStr, io::Write, mem, sync::Arc};
+use std::{
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    io::Write,
+    iter,
+    ops::Range,
+    str::FromStr,
+    sync::Arc,
+};
 
 use ansi_control_codes::control_sequences::{CUP, ED};
 use anyhow::{self, bail, Context as _};
 use colored::Colorize;
-use futures::future::try_join;
 use lazy_static::lazy_static;
 #[allow(unused_imports)]
 use log::debug;
+use parking_lot::Mutex;
 use regex::Regex;
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    database::Database,
-    git::{CommitHash, LogStyle, Worktree},
+    config::{StatusStyle, StatusTheme},
+    database::{Database, Provenance},
+    git::{CommitHash, Worktree},
     http::UiState,
-    test::{Notification, TestCase, TestInconclusive, TestName, TestStatus},
-    text::{Class, Line, Span, Text},
+    resource::{Pools, ResourceKey, Usage},
+    test::{Notification, TestCase, TestInconclusive, TestName, TestStatus, Verdict},
+    text::{Class, Line, Span, Style, Text},
     util::{Rect, ResultExt as _},
 };
 
 struct TrackedTestCase {
     test_case: TestCase,
     status: TestStatus,
+    pinned: bool,
+    // Ran slower than usual, see max_slowdown_percent.
+    slow: bool,
+    cached: bool,
+    provenance: Option<Provenance>,
 }
 
 // Inner string key is test name. Here we awkwardly store this as a
@@ -31,7 +47,12 @@ type TrackedCases = HashMap<CommitHash, HashMap<TestName, TrackedTestCase>>;
 
 // Updates the awkward nested hashmap to reflect a new notification coming in.
 // Standalone function for convenient use in tests.
-fn update_tracked_cases(tracked_cases: &mut TrackedCases, notif: Arc<Notification>) {
+fn update_tracked_cases(
+    tracked_cases: &mut TrackedCases,
+    notif: Arc<Notification>,
+    pinned: bool,
+    slow: bool,
+) {
     let commit_statuses = tracked_cases
         .entry(notif.test_case.commit_hash.clone())
         .or_default();
@@ -40,22 +61,198 @@ fn update_tracked_cases(tracked_cases: &mut TrackedCases, notif: Arc<Notificatio
         TrackedTestCase {
             test_case: notif.test_case.clone(),
             status: notif.status.clone(),
+            pinned,
+            slow,
+            cached: notif.cached,
+            provenance: notif.provenance.clone(),
         },
     );
 }
 
+// Whether the notification is the first failure on the tip of the range, i.e.
+// none of the tip's other tests are currently failing. Must be called before
+// the notification is passed to update_tracked_cases.
+fn is_first_tip_failure(
+    tracked_cases: &TrackedCases,
+    tip: Option<&CommitHash>,
+    notif: &Notification,
+) -> bool {
+    let failed = |status| StatusKind::of(status) == StatusKind::Failure;
+    tip == Some(&notif.test_case.commit_hash)
+        && failed(&notif.status)
+        && !tracked_cases
+            .get(&notif.test_case.commit_hash)
+            .is_some_and(|cases| cases.values().any(|c| failed(&c.status)))
+}
+
+// A complete rendering of the status view, as shared with other terminals
+// attached to the same session.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Frame {
+    pub text: Text<'static>,
+    pub home_url: String,
+}
+
+// Owns a terminal while a status view is being shown on it.
+pub struct Screen<O: Write> {
+    output: O,
+}
+
+impl<O: Write> Screen<O> {
+    pub fn new(output: O) -> Self {
+        Self { output }
+    }
+
+    // Write the text to the output with fancy terminal escape codes to
+    // overwrite what was previously written.
+    pub fn paint(&mut self, text: Text, home_url: &str, term_size: &Rect) -> anyhow::Result<()> {
+        // Enter alternate screen. Dunno why ansi-control-codes doesn't have
+        // this. This isn't really how I wanted this UI to work. But
+        // implementing what I really wanted turns out to be really fucking
+        // fiddly and unsatisfying and boring, I just don't care enough.
+        // This is idempotent so we just do it every time.
+        writeln!(self.output, "\x1B[?1049h")?;
+        // Move cursor to top left and erase the display.
+        write!(&mut self.output, "{}{}", CUP(Some(0), Some(0)), ED(None))?;
+        let truncated = Text::from_iter(
+            text.into_lines()
+                // I'm not sure why we need to subtract 3 here instead of 1 (for
+                // the line we print below). Something causes the cursor to
+                // bounce around and leave two empty lines at the bottom. Don't
+                // care, it's too boring to figure this stuff out, lmao.
+                .take(term_size.rows.saturating_sub(3))
+                .map(|l| l.truncate_width(term_size.cols)),
+        );
+        write!(&mut self.output, "{}", truncated.ansi())?;
+        writeln!(&mut self.output, "Web UI: {}", home_url.bold().on_blue())?;
+
+        Ok(())
+    }
+
+    // Send a desktop notification via OSC 9 (terminals that don't understand
+    // it should ignore it) and ring the bell.
+    pub fn alert(&mut self, message: &str) -> anyhow::Result<()> {
+        write!(&mut self.output, "\x1B]9;{message}\x1B\\\x07")?;
+        self.output.flush()?;
+        Ok(())
+    }
+}
+
+impl<O: Write> Drop for Screen<O> {
+    fn drop(&mut self) {
+        writeln!(self.output, "\x1B[?1049l").or_log_error("Couldn't exit alternate screen");
+    }
+}
+
+// Lets several status views share the terminal and the web UI, each one
+// showing in its own section. Sections only get a heading when there's more
+// than one of them.
+pub struct SharedScreen<O: Write> {
+    screen: Screen<O>,
+    web_ui: Arc<UiState>,
+    home_url: String,
+    // Heading and latest rendering of each section.
+    sections: Vec<(String, Text<'static>)>,
+    // Shown above the sections, only in the terminal.
+    help: Option<Text<'static>>,
+    // As of the last paint.
+    term_size: Option<Rect>,
+}
+
+impl<O: Write> SharedScreen<O> {
+    pub fn new(output: O, web_ui: Arc<UiState>, home_url: impl Into<String>) -> Self {
+        Self {
+            screen: Screen::new(output),
+            web_ui,
+            home_url: home_url.into(),
+            sections: Vec::new(),
+            help: None,
+            term_size: None,
+        }
+    }
+
+    // Returns the index to paint the section with.
+    fn add_section(&mut self, heading: String) -> usize {
+        self.sections.push((heading, Text { lines: Vec::new() }));
+        self.sections.len() - 1
+    }
+
+    fn render(&self) -> Text<'static> {
+        if let [(_, text)] = self.sections.as_slice() {
+            return text.clone();
+        }
+        Text::from_iter(self.sections.iter().flat_map(|(heading, text)| {
+            let heading = Span::new(format!("── {heading} ──")).with_class(Class::TestName);
+            iter::once(Line::from(heading)).chain(text.lines.iter().cloned())
+        }))
+    }
+
+    fn paint(
+        &mut self,
+        section: usize,
+        text: Text<'static>,
+        term_size: &Rect,
+    ) -> anyhow::Result<()> {
+        self.sections[section].1 = text;
+        self.term_size = Some(term_size.clone());
+        self.show()
+    }
+
+    // Show the help if it's hidden, or hide it.
+    pub fn toggle_help(&mut self, help: Text<'static>) -> anyhow::Result<()> {
+        self.help = match self.help {
+            Some(_) => None,
+            None => Some(help),
+        };
+        self.show()
+    }
+
+    fn show(&mut self) -> anyhow::Result<()> {
+        // Nothing's been painted yet.
+        let Some(term_size) = &self.term_size else {
+            return Ok(());
+        };
+        let render = self.render();
+        self.web_ui.set_log_buf(render.html_pre());
+        self.web_ui.set_frame(Frame {
+            text: render.clone(),
+            home_url: self.home_url.clone(),
+        });
+        let render = match &self.help {
+            Some(help) => Text::from_iter(
+                help.clone()
+                    .into_lines()
+                    .chain(iter::once(Line::from("")))
+                    .chain(render.into_lines()),
+            ),
+            None => render,
+        };
+        self.screen.paint(render, &self.home_url, term_size)
+    }
+}
+
 // Tracks the status of the tests being run by observing the notification
 // stream.
 pub struct StatusViewer<W: Worktree, O: Write> {
     repo: Arc<W>,
+    database: Arc<Database>,
     tracked_cases: TrackedCases,
     output_buf: OutputBuffer,
-    output: O,
-    web_ui: Arc<UiState>,
-    result_url_base: String,
-    home_url: String,
+    screen: Arc<Mutex<SharedScreen<O>>>,
+    section: usize,
+    render_options: RenderOptions,
+    log_format: String,
+    pools: Option<Arc<Pools>>,
+    // Why jobs are being held back, if they are.
+    restriction: Option<String>,
+    bell: bool,
+    // Message for the next repaint to alert the user with.
+    pending_alert: Option<String>,
 }
 
+const DEFAULT_LOG_FORMAT: &str =
+    "%Cred%h%Creset -%C(yellow)%d%Creset %s %Cgreen(%cr) %C(bold blue)<%an>%Creset";
+
 // This ought to be private to StatusViewer::reset, rust just doesn't seem to
 // let you do that.
 lazy_static! {
@@ -64,181 +261,317 @@ lazy_static! {
 }
 
 impl<W: Worktree, O: Write> StatusViewer<W, O> {
-    // Construct a UI that writes to to the given outut. The URL
-    // base is used to generate hyperlinks to test results.
+    // Construct a UI that shows in a new section of the screen, under the
+    // given heading. The URL base is used to generate hyperlinks to test
+    // results.
     pub fn new(
         repo: Arc<W>,
-        output: O,
-        web_ui: Arc<UiState>,
+        database: Arc<Database>,
+        screen: Arc<Mutex<SharedScreen<O>>>,
+        heading: impl Into<String>,
         result_url_base: impl Into<String>,
-        home_url: impl Into<String>,
+        theme: StatusTheme,
     ) -> Self {
+        let section = screen.lock().add_section(heading.into());
         Self {
             repo,
+            database,
             tracked_cases: HashMap::new(),
             output_buf: OutputBuffer::empty(),
-            output,
-            web_ui,
-            result_url_base: result_url_base.into(),
-            home_url: home_url.into(),
+            screen,
+            section,
+            render_options: RenderOptions {
+                result_url_base: result_url_base.into(),
+                theme,
+                ..Default::default()
+            },
+            log_format: DEFAULT_LOG_FORMAT.to_owned(),
+            pools: None,
+            restriction: None,
+            bell: false,
+            pending_alert: None,
         }
     }
 
-    // Informs the UI of the range of tests that we expect to be testing.
-    pub async fn set_range(&mut self, range_spec: &OsStr) -> anyhow::Result<()> {
-        // This should eventually be configurable.
-        let log_format =
-            "%Cred%h%Creset -%C(yellow)%d%Creset %s %Cgreen(%cr) %C(bold blue)<%an>%Creset";
+    // Format for the commit info, as for git log --format.
+    pub fn with_log_format(mut self, log_format: String) -> Self {
+        self.log_format = log_format;
+        self
+    }
+
+    // Collapse runs of passing commits, see OutputBuffer::render.
+    pub fn with_compact(mut self, compact: bool) -> Self {
+        self.render_options.compact = compact;
+        self
+    }
+
+    pub fn with_filter(mut self, filter: Filter) -> Self {
+        self.render_options.filter = filter;
+        self
+    }
+
+    // Alert the user when a test starts failing on the tip of the range.
+    pub fn with_bell(mut self, bell: bool) -> Self {
+        self.bell = bell;
+        self
+    }
 
-        self.output_buf = OutputBuffer::new(&self.repo, range_spec, log_format).await?;
+    // Show how much of the resources are in use.
+    pub fn with_resource_pools(mut self, pools: Arc<Pools>) -> Self {
+        self.pools = Some(pools);
+        self
+    }
+
+    // Informs the UI of the range of tests that we expect to be testing.
+    pub async fn set_range(&mut self, range: &[OsString]) -> anyhow::Result<()> {
+        self.output_buf = OutputBuffer::new(&self.repo, range, &self.log_format).await?;
         Ok(())
     }
 
-    // Absorb a notification.
+    // Absorb a notification. Pinning is only checked here, so a result pinned
+    // while we're running doesn't show up until the test case is next updated.
     pub fn update(&mut self, notif: Arc<Notification>) {
-        update_tracked_cases(&mut self.tracked_cases, notif);
+        let finished = matches!(notif.status, TestStatus::Finished(_));
+        let pinned = finished && {
+            let pinned = self.database.is_pinned(&notif.test_case);
+            pinned.or_log_error("checking for pinned result");
+            pinned.unwrap_or(false)
+        };
+        let slow = finished && {
+            let slowdown = self.database.slowdown(&notif.test_case);
+            slowdown.or_log_error("checking for slowdown");
+            slowdown.is_ok_and(|s| s.is_some())
+        };
+        if self.bell
+            && is_first_tip_failure(
+                &self.tracked_cases,
+                self.output_buf.commits().next(),
+                &notif,
+            )
+        {
+            let test = &notif.test_case.test;
+            let mut alert = format!(
+                "limmat: {} failed on {}",
+                test.name, notif.test_case.commit_hash
+            );
+            if let Some(about) = test.about() {
+                alert = format!("{alert} - {about}");
+            }
+            self.pending_alert = Some(alert);
+        }
+        update_tracked_cases(&mut self.tracked_cases, notif, pinned, slow);
+    }
+
+    // For annotating the stack of branches being tested.
+    pub fn set_branches(&mut self, branches: HashMap<CommitHash, Vec<String>>) {
+        self.render_options.branches = branches;
+    }
+
+    pub fn set_restriction(&mut self, restriction: Option<String>) {
+        self.restriction = restriction;
     }
 
     // Update the UI by writing it to the output with fancy terminal escape
     // codes to overwrite what was previously written.
     pub fn repaint(&mut self, term_size: &Rect) -> anyhow::Result<()> {
-        let render = self
-            .output_buf
-            .render(&self.tracked_cases, &self.result_url_base);
-
-        self.web_ui.set_log_buf(render.html_pre());
-
-        // Enter alternate screen. Dunno why ansi-control-codes doesn't have
-        // this. This isn't really how I wanted this UI to work. But
-        // implementing what I really wanted turns out to be really fucking
-        // fiddly and unsatisfying and boring, I just don't care enough.
-        // This is idempotent so we just do it every time.
-        writeln!(self.output, "\x1B[?1049h")?;
-        // Move cursor to top left and erase the display.
-        write!(&mut self.output, "{}{}", CUP(Some(0), Some(0)), ED(None))?;
-        let truncated = Text::from_iter(
-            render
-                .into_lines()
-                // I'm not sure why we need to subtract 3 here instead of 1 (for
-                // the line we print below). Something causes the cursor to
-                // bounce around and leave two empty lines at the bottom. Don't
-                // care, it's too boring to figure this stuff out, lmao.
-                .take(term_size.rows.saturating_sub(3))
-                .map(|l| l.truncate_graphemes(term_size.cols)),
+        let mut render = self.output_buf.render(
+            &self.tracked_cases,
+            &self.render_options,
+            // This is 0 if stdout isn't a terminal.
+            (term_size.cols > 0).then_some(term_size.cols),
         );
-        write!(&mut self.output, "{}", truncated.ansi())?;
-        writeln!(
-            &mut self.output,
-            "Web UI: {}",
-            self.home_url.bold().on_blue()
-        )?;
+        let parked = self
+            .pools
+            .as_ref()
+            .map(|p| p.parked_worktrees())
+            .unwrap_or_default();
+        if !parked.is_empty() {
+            let lines = parked.into_iter().map(|(commit, test, path)| {
+                Line::from(Span::new(format!(
+                    "📌 {test} failed at {}, its worktree is kept at {}",
+                    commit.abbrev(),
+                    path.display()
+                )))
+            });
+            render = Text::from_iter(lines.chain(render.into_lines()));
+        }
+        if let Some(restriction) = &self.restriction {
+            let banner = Span::new(format!("⏸ {restriction}")).with_class(Class::Error);
+            render = Text::from_iter(iter::once(Line::from(banner)).chain(render.into_lines()));
+        }
+        let usage = self.pools.as_ref().map(|p| p.usage()).unwrap_or_default();
+        let summary = render_summary(self.output_buf.commits(), &self.tracked_cases, &usage);
+        render = Text::from_iter(iter::once(summary).chain(render.into_lines()));
 
+        let mut screen = self.screen.lock();
+        screen.paint(self.section, render.into_owned(), term_size)?;
+        if let Some(message) = self.pending_alert.take() {
+            screen.screen.alert(&message)?;
+        }
         Ok(())
     }
 }
 
-impl<W: Worktree, O: Write> Drop for StatusViewer<W, O> {
-    fn drop(&mut self) {
-        writeln!(self.output, "\x1B[?1049l").or_log_error("Couldn't exit alternate screen");
+// What a test case's status looks like to the user, i.e. just what matters
+// for how it's displayed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatusKind {
+    Enqueued,
+    Started,
+    Success,
+    Failure,
+    Canceled,
+    Error,
+    StageFailed,
+    Skipped,
+    SkippedByCommit,
+    NotApplicable,
+}
+
+impl StatusKind {
+    fn of(status: &TestStatus) -> Self {
+        match status {
+            TestStatus::Enqueued => Self::Enqueued,
+            TestStatus::Started(_) => Self::Started,
+            TestStatus::Finished(Ok(result)) if result.exit_code == 0 => Self::Success,
+            TestStatus::Finished(Ok(_)) => Self::Failure,
+            TestStatus::Finished(Err(inconclusive)) => match inconclusive {
+                // Note - cancellation is an "error" in the type system but we
+                // don't treat it as an error in the UI.
+                TestInconclusive::Canceled => Self::Canceled,
+                TestInconclusive::Error(_) | TestInconclusive::ErrorExitCode(_) => Self::Error,
+                TestInconclusive::StageFailed(_) => Self::StageFailed,
+                TestInconclusive::Skipped => Self::Skipped,
+                TestInconclusive::SkippedByCommit => Self::SkippedByCommit,
+            },
+            TestStatus::NotApplicable(_) => Self::NotApplicable,
+        }
     }
 }
 
-// Helper for OutputBuffer - just the graph bit of the git log --graph output.
-struct GraphBuffer {
-    raw_buf: String, // Output straight from Git.
+// Named like the fields of config::StatusTheme.
+impl FromStr for StatusKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Ok(match s {
+            "enqueued" => Self::Enqueued,
+            "started" => Self::Started,
+            "success" => Self::Success,
+            "failure" => Self::Failure,
+            "canceled" => Self::Canceled,
+            "error" => Self::Error,
+            "stage_failed" => Self::StageFailed,
+            "skipped" => Self::Skipped,
+            "skipped_by_commit" => Self::SkippedByCommit,
+            "not_applicable" => Self::NotApplicable,
+            _ => bail!("unknown status {s:?}"),
+        })
+    }
 }
 
-impl GraphBuffer {
-    pub async fn new(
-        repo: &Arc<impl Worktree>,
-        range_spec: impl AsRef<OsStr>,
-    ) -> anyhow::Result<Self> {
-        // Get the raw buffer.
-        let raw_buf = repo
-            .log(range_spec.as_ref(), "%H\n", LogStyle::WithGraph)
-            .await?;
-        // OsStr doesn't have a proper API, luckily we can expect utf-8.
-        let raw_buf = String::from_utf8(raw_buf)
-            .map_err(|_err| anyhow::anyhow!("got non-utf8 output from git log"))?;
-        Ok(Self { raw_buf })
-    }
-
-    // Parse pairs of commit hash and chunks from the buffer, split by line.
-    pub fn chunks(&self) -> anyhow::Result<Vec<(CommitHash, Vec<&str>)>> {
-        // Separate out chunks into separate vecs of lines.
-        let mut cur_chunk = Vec::<&str>::new();
-        let mut raw_chunks = Vec::<Vec<&str>>::new();
-        for line in self.raw_buf.trim().split('\n') {
-            // --graph uses * to represent a node in the DAG.
-            if line.contains('*') && !cur_chunk.is_empty() {
-                raw_chunks.push(mem::take(&mut cur_chunk));
-            }
-            if !line.is_empty() {
-                cur_chunk.push(line);
-            }
-        }
-        if !cur_chunk.is_empty() {
-            raw_chunks.push(cur_chunk);
-        }
+// One term of a Filter, in the form "status=<status>" or "test=<regex>".
+#[derive(Debug, Clone)]
+pub enum FilterTerm {
+    Status(StatusKind),
+    Test(Regex),
+}
 
-        // Now each chunk looks something like this:
-        //
-        // | * |   e96277a570cd32432fjklfef
-        // | |\ \
-        // | | |/
-        // | |/|
-
-        // Parse out and remove the commit hashes.
-        let mut chunks: Vec<(CommitHash, Vec<&str>)> = Vec::new();
-        for mut raw_chunk in raw_chunks {
-            // The commit hash should be the only alphanumeric sequence in
-            // the chunk and it should be in the first line.
-            let matches: Vec<_> = COMMIT_HASH_REGEX.find_iter(raw_chunk[0]).collect();
-            if matches.len() != 1 {
-                bail!(
-                    "matched {} commit hashes in graph chunk:\n{:?}",
-                    matches.len(),
-                    raw_chunk
-                );
+impl FromStr for FilterTerm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.split_once('=') {
+            Some(("status", status)) => Ok(Self::Status(status.parse()?)),
+            Some(("test", regex)) => {
+                Ok(Self::Test(Regex::new(regex).with_context(|| {
+                    format!("parsing test regex {regex:?}")
+                })?))
             }
-            let mattch = matches.first().unwrap();
-            let hash = CommitHash::new(mattch.as_str());
+            _ => bail!("expected \"status=<status>\" or \"test=<regex>\", got {s:?}"),
+        }
+    }
+}
 
-            // We only want the graph bit, strip out the commit hash which we
-            // only put in there as an anchor for this parsing.
-            raw_chunk[0] = &raw_chunk[0][..mattch.range().start];
+// Which test cases to show in the status view, this doesn't affect which ones
+// get run. A case is shown if it matches any of the status terms (if there are
+// any) and any of the test terms (if there are any).
+#[derive(Debug, Default)]
+pub struct Filter {
+    statuses: Vec<StatusKind>,
+    tests: Vec<Regex>,
+}
 
-            chunks.push((hash, raw_chunk));
+impl Filter {
+    pub fn new(terms: impl IntoIterator<Item = FilterTerm>) -> Self {
+        let mut filter = Self::default();
+        for term in terms {
+            match term {
+                FilterTerm::Status(status) => filter.statuses.push(status),
+                FilterTerm::Test(regex) => filter.tests.push(regex),
+            }
         }
-        Ok(chunks)
+        filter
+    }
+
+    fn shows(&self, tracked_case: &TrackedTestCase) -> bool {
+        let name = tracked_case.test_case.test.name.to_string();
+        (self.statuses.is_empty()
+            || self
+                .statuses
+                .contains(&StatusKind::of(&tracked_case.status)))
+            && (self.tests.is_empty() || self.tests.iter().any(|r| r.is_match(&name)))
     }
 }
 
-// Helper for OutputBuffer - a way to grab log info for a bunch of commits with
-// a single git command. It's important that we don't do N git commands, that
-// can really slow things down when the range is large.
+// Settings for OutputBuffer::render that don't change between repaints.
+#[derive(Default)]
+struct RenderOptions {
+    // Used to generate hyperlinks to test results.
+    result_url_base: String,
+    theme: StatusTheme,
+    compact: bool,
+    filter: Filter,
+    // Names of branches, by their tips, to show next to the commits.
+    branches: HashMap<CommitHash, Vec<String>>,
+}
 
-struct CommitInfoBuffer {
+// Helper for OutputBuffer - a way to grab the graph and the log info for a
+// bunch of commits with a single git command. It's important that we don't do N
+// git commands, that can really slow things down when the range is large.
+struct LogBuffer {
     raw_buf: String, // Output straight from Git.
 }
 
-impl CommitInfoBuffer {
+// One commit's lines from the log.
+struct LogChunk<'a> {
+    hash: CommitHash,
+    // The graph bit of the first line, the one with the '*' in it.
+    commit_graph: &'a str,
+    // Lines with the commit's log info in them, starting with the first line.
+    info_lines: Vec<String>,
+    // The line after the info, which just has graph stuff in it. Empty if Git
+    // didn't draw any graph there.
+    status_line: &'a str,
+    // Any more lines that Git printed before the next commit.
+    graph_lines: Vec<&'a str>,
+}
+
+impl LogBuffer {
     // log_format must not contain %x00 as that's used internally for splitting
     // up the raw buffer.
     pub async fn new(
         repo: &Arc<impl Worktree>,
-        range_spec: impl AsRef<OsStr>,
+        range: &[impl AsRef<OsStr> + Sync],
         log_format: &str,
     ) -> anyhow::Result<Self> {
         if log_format.contains("%x00") {
             bail!("NUL bytes not allowed in log format");
         }
+        // The extra newline at the end gets Git to draw a line of graph
+        // after the info, that's where the status goes.
         let raw_buf = repo
-            .log(
-                range_spec.as_ref(),
-                format!("%H {log_format}%x00"),
-                LogStyle::NoGraph,
-            )
+            .graph_log(range, format!("%H {log_format}%x00%n"))
             .await?;
         // Hack: OsStr doesn't have a proper API, so just squash to utf-8, sorry
         // users.
@@ -247,24 +580,59 @@ impl CommitInfoBuffer {
         })
     }
 
-    pub fn info(&self) -> anyhow::Result<HashMap<CommitHash, &str>> {
-        let b = self.raw_buf.trim();
-        let b = b.strip_suffix('\0').unwrap_or(b);
-        if b.is_empty() {
-            return Ok(HashMap::new());
-        }
-        b.split('\0')
-            .map(|raw_chunk| {
-                let [hash, chunk] = raw_chunk
-                    .splitn(2, ' ')
-                    .collect::<Vec<_>>()
-                    .try_into()
-                    .map_err(|v: Vec<_>| {
-                        anyhow::anyhow!("expected chunk split len 2, got {}", v.len())
-                    })?;
-                Ok((CommitHash::new(hash.trim()), chunk))
-            })
-            .collect()
+    // Each commit comes out looking something like this:
+    //
+    // | * |   e96277a570cd32432fjklfef first line of info
+    // | | |   second line of info\0
+    // | | |
+    // | |\ \
+    // | | |/
+    //
+    // The commit hash should be the only alphanumeric sequence in the graph
+    // and it should be in the first line. We only put it in there as an anchor
+    // for this parsing.
+    pub fn chunks(&self) -> anyhow::Result<Vec<LogChunk<'_>>> {
+        let mut chunks: Vec<LogChunk> = Vec::new();
+        // Whether we're still in the log info of the last chunk.
+        let mut in_info = false;
+        // Whether the next line is the last chunk's status line.
+        let mut at_status = false;
+        for line in self.raw_buf.split('\n') {
+            if in_info {
+                let chunk = chunks.last_mut().unwrap();
+                let info = line.strip_suffix('\0');
+                chunk.info_lines.push(info.unwrap_or(line).to_owned());
+                in_info = info.is_none();
+                at_status = !in_info;
+            } else if at_status {
+                chunks.last_mut().unwrap().status_line = line;
+                at_status = false;
+            } else if let Some(mattch) = COMMIT_HASH_REGEX.find(line) {
+                let graph = &line[..mattch.start()];
+                let info = &line[mattch.end()..];
+                let info = info.strip_prefix(' ').unwrap_or(info);
+                let end = info.strip_suffix('\0');
+                chunks.push(LogChunk {
+                    hash: CommitHash::new(mattch.as_str()),
+                    commit_graph: graph,
+                    info_lines: vec![graph.to_owned() + end.unwrap_or(info)],
+                    status_line: "",
+                    graph_lines: Vec::new(),
+                });
+                in_info = end.is_none();
+                at_status = !in_info;
+            } else if !line.is_empty() {
+                chunks
+                    .last_mut()
+                    .with_context(|| format!("graph line {line:?} before first commit"))?
+                    .graph_lines
+                    .push(line);
+            }
+        }
+        if in_info {
+            bail!("git log output ended in the middle of a commit");
+        }
+        Ok(chunks)
     }
 }
 
@@ -274,6 +642,8 @@ struct OutputBuffer {
     lines: Vec<String>,
     // lines[i] should be appended with the live status information of tests for status_commit[i].
     status_commits: HashMap<usize, CommitHash>,
+    // The range of lines for each commit, in the order they're shown.
+    chunks: Vec<(CommitHash, Range<usize>)>,
 }
 
 impl OutputBuffer {
@@ -281,12 +651,17 @@ impl OutputBuffer {
         Self {
             lines: Vec::new(),
             status_commits: HashMap::new(),
+            chunks: Vec::new(),
         }
     }
 
-    pub async fn new<W: Worktree, S: AsRef<OsStr>>(
+    pub fn commits(&self) -> impl Iterator<Item = &CommitHash> {
+        self.chunks.iter().map(|(hash, _)| hash)
+    }
+
+    pub async fn new<W: Worktree, S: AsRef<OsStr> + Sync>(
         repo: &Arc<W>,
-        range_spec: S,
+        range: &[S],
         log_format: &str,
     ) -> anyhow::Result<Self> {
         // All right this is gonna seem pretty hacky. We're gonna get the --graph log
@@ -303,129 +678,212 @@ impl OutputBuffer {
         // the graph logic can still sometimes occupy more more lines when
         // history is very complex.
         //
-        // So here's the idea: we just get git to dump out the graph, we divide
-        // it into chunks that start with the '*' that identifies each commit.
-        // Then we insert the extra information to the graph chunks.
-        //
-        // We want to display a) some more human-readable information about the
-        // commit (i.e. what you get from logging with a more informative
-        // --format) and b) our injected test status data. Overall this will
-        // produce some other buffer. If it has less lines than the graph buffer
-        // chunk, we can just append those lines onto the lines of the graph
-        // buffer pairwise. If it has more lines then we will need to stretch
-        // out the graph vertically to make space first.
-
-        let (graph_buf, info_buf) = try_join(
-            GraphBuffer::new(repo, range_spec.as_ref()),
-            CommitInfoBuffer::new(repo, range_spec.as_ref(), log_format),
-        )
-        .await?;
+        // So here's the idea: we get git to dump out the graph along with the
+        // more human-readable information about each commit (i.e. what you get
+        // from logging with a more informative --format), with the commit hash
+        // at the start and an extra line at the end where we'll inject our
+        // test status data. Git takes care of stretching out the graph
+        // vertically to fit all that in, we just need to find where each
+        // commit starts and where its status line is.
+
+        let log_buf = LogBuffer::new(repo, range, log_format).await?;
 
-        let commit_info = info_buf.info()?;
         let mut lines = Vec::new();
         let mut status_commits = HashMap::new();
-        for (hash, mut chunk) in graph_buf.chunks()? {
-            let log_info = commit_info
-                .get(&hash)
-                .with_context(|| format!("missing commit info for {:?}", hash))?;
-
-            // We're gonna add our own newlines in so we don't need the one that
-            // Git printed.
-            let log_info = log_info.strip_suffix('\n').unwrap_or(log_info);
-            let mut info_lines: Vec<&str> = log_info.split('\n').collect();
-
-            // Here's where we'll inject the live status
-            status_commits.insert(lines.len() + info_lines.len(), hash);
-            info_lines.push("");
-
-            let graph_line_deficit = info_lines.len() as isize - chunk.len() as isize;
-            let extension_line;
-            if graph_line_deficit > 0 {
-                // We assume that the first line of the chunk will contain an
-                // asterisk identifying the current commit, and some vertical
-                // lines continuing up to the previous chunk. We just copy those
-                // vertical lines and then add a new vertical lines pointing up
-                // to the asterisk.
+        let mut chunks = Vec::new();
+        for mut chunk in log_buf.chunks()? {
+            let start = lines.len();
+            if chunk.status_line.trim().is_empty() {
+                // Git doesn't draw anything under the last commit, but we want
+                // a line from it to its info and status. We assume that the
+                // first line of the chunk will contain an asterisk identifying
+                // the current commit, and some vertical lines continuing up to
+                // the previous chunk. We just copy those vertical lines and
+                // then add a new vertical lines pointing up to the asterisk.
                 //
                 // I checked and it is in fact possible to have non-vertical
                 // lines on the same line as the asterisk. E.g. check the linux
                 // kernel history, search back to commit 578cc98b66f5a5 and you
                 // will see it. So we need to replace diagnoals with verticals
                 // too.
-                extension_line = GRAPH_COMPONENT_REGEX.replace_all(chunk[0], "|");
-                for _ in 0..graph_line_deficit {
-                    chunk.insert(1, &extension_line);
+                let extension = GRAPH_COMPONENT_REGEX.replace_all(chunk.commit_graph, "|");
+                let blank = " ".repeat(strip_ansi_escapes::strip_str(chunk.commit_graph).len());
+                for line in chunk.info_lines.iter_mut().skip(1) {
+                    if let Some(info) = line.strip_prefix(&blank) {
+                        *line = format!("{extension}{info}");
+                    }
                 }
+                chunk.info_lines.push(extension.into_owned());
             } else {
-                // Append empty entries to the info lines so that the zip below works nicely.
-                info_lines.append(&mut vec![""; -graph_line_deficit as usize]);
+                chunk.info_lines.push(chunk.status_line.to_owned());
             }
-            assert_eq!(info_lines.len(), chunk.len());
-
-            lines.append(
-                &mut chunk
-                    .iter()
-                    .zip(info_lines.iter())
-                    .map(|(graph, info)| (*graph).to_owned() + *info)
-                    // TODO: can we get rid of the collect and just call .join on the map iterator?
-                    .collect::<Vec<_>>(),
-            );
+            // Here's where we'll inject the live status
+            status_commits.insert(start + chunk.info_lines.len() - 1, chunk.hash.clone());
+            lines.extend(chunk.info_lines);
+            lines.extend(chunk.graph_lines.into_iter().map(str::to_owned));
+            chunks.push((chunk.hash, start..lines.len()));
         }
         Ok(Self {
             lines,
             status_commits,
+            chunks,
         })
     }
 
+    // If there's a width, test statuses that don't fit on the line are wrapped
+    // onto extra lines. Anything else that's too wide is left for the caller to
+    // truncate. In compact mode, runs of commits where all the tests passed are
+    // collapsed into a single line, except for the tip of the range and commits
+    // next to ones that didn't pass.
     fn render<'a>(
         &'a self,
         statuses: &'a HashMap<CommitHash, HashMap<TestName, TrackedTestCase>>,
-        result_url_base: &str,
+        options: &RenderOptions,
+        width: Option<usize>,
     ) -> Text<'a> {
         if self.lines.is_empty() {
             return "[range empty]".into();
         }
-        self.lines
-            .iter()
-            .enumerate()
-            .map(|(i, log_line)| -> Line {
-                let mut spans = vec![Span::from(log_line)];
-                if let Some(hash) = self.status_commits.get(&i) {
-                    if let Some(tracked_cases) = statuses.get(hash) {
-                        spans.extend(self.render_cases(tracked_cases.values(), result_url_base));
-                    }
-                }
-                Line::from_iter(spans)
+        let passing = |idx: usize| {
+            self.chunks.get(idx).is_none_or(|(hash, _)| {
+                statuses.get(hash).is_some_and(|cases| {
+                    // Tests that don't apply to the commit don't stop it
+                    // being collapsed, unless none of them do.
+                    let kinds: Vec<StatusKind> = cases
+                        .values()
+                        .map(|tc| StatusKind::of(&tc.status))
+                        .filter(|&kind| kind != StatusKind::NotApplicable)
+                        .collect();
+                    !kinds.is_empty() && kinds.iter().all(|&kind| kind == StatusKind::Success)
+                })
             })
-            .collect::<Text>()
+        };
+        let collapsible = |idx: usize| {
+            options.compact
+                && idx > 0
+                && !options.branches.contains_key(&self.chunks[idx].0)
+                && passing(idx - 1)
+                && passing(idx)
+                && passing(idx + 1)
+        };
+
+        let mut lines = Vec::new();
+        let mut idx = 0;
+        while idx < self.chunks.len() {
+            let run = (idx..self.chunks.len())
+                .take_while(|&i| collapsible(i))
+                .count();
+            // Collapsing a single commit wouldn't save much.
+            if run > 1 {
+                // Like for wrapped statuses, the status line has just the graph.
+                let status_line = self.chunks[idx]
+                    .1
+                    .clone()
+                    .find(|i| self.status_commits.contains_key(i))
+                    .expect("no status line for commit");
+                lines.push(Line::from_iter([
+                    Span::from(GRAPH_COMPONENT_REGEX.replace_all(&self.lines[status_line], "|")),
+                    Span::new(format!("… {run} commits all passing …")),
+                ]));
+                idx += run;
+                continue;
+            }
+            for i in self.chunks[idx].1.clone() {
+                lines.extend(self.render_line(i, statuses, options, width));
+            }
+            idx += 1;
+        }
+        Text::from_iter(lines)
+    }
+
+    fn render_line<'a>(
+        &'a self,
+        i: usize,
+        statuses: &'a HashMap<CommitHash, HashMap<TestName, TrackedTestCase>>,
+        options: &RenderOptions,
+        width: Option<usize>,
+    ) -> Vec<Line<'a>> {
+        let log_line = &self.lines[i];
+        let Some(hash) = self.status_commits.get(&i) else {
+            return vec![Line::from(log_line)];
+        };
+        let mut line = Line::from(log_line);
+        let tracked_cases = statuses.get(hash);
+        if let Some(tracked_cases) = tracked_cases {
+            line.spans.extend(Self::render_verdict(tracked_cases));
+        }
+        if let Some(branches) = options.branches.get(hash) {
+            line.spans
+                .push(Span::new(format!("[{}] ", branches.join(", "))));
+        }
+        let Some(tracked_cases) = tracked_cases else {
+            return vec![line];
+        };
+        // The status line only has graph on it, continue that downwards.
+        let continuation = GRAPH_COMPONENT_REGEX.replace_all(log_line, "|");
+        let mut lines = vec![line];
+        let mut cases_on_line = 0;
+        for case in self.render_cases(tracked_cases.values(), options) {
+            let line = lines.last_mut().unwrap();
+            if cases_on_line > 0 && width.is_some_and(|w| line.width() + case.width() > w) {
+                lines.push(Line::from(continuation.clone()));
+                cases_on_line = 0;
+            }
+            lines.last_mut().unwrap().spans.extend(case.spans);
+            cases_on_line += 1;
+        }
+        lines
+    }
+
+    // Goes by all the commit's test cases, even ones the filter hides.
+    fn render_verdict(tracked_cases: &HashMap<TestName, TrackedTestCase>) -> [Span<'static>; 2] {
+        let verdict = Verdict::of(
+            tracked_cases
+                .values()
+                .map(|tc| (&*tc.test_case.test, &tc.status)),
+        );
+        let mut span = Span::new(verdict.to_string());
+        match verdict {
+            Verdict::Pass => span = span.with_class(Class::Success),
+            Verdict::Fail => span = span.with_class(Class::Failure),
+            Verdict::Pending => (),
+        }
+        [span, Span::new(" ")]
     }
 
     fn render_case<'a>(
-        test_case: &'a TestCase,
-        status: &'a TestStatus,
-        result_url_base: &str,
+        tracked_case: &'a TrackedTestCase,
+        options: &RenderOptions,
     ) -> Vec<Span<'a>> {
-        let status_part = match status {
-            TestStatus::Enqueued => Span::new("⏳"),
-            TestStatus::Started => Span::new("🏃"),
-            TestStatus::Finished(Ok(result)) => {
-                if result.exit_code == 0 {
-                    Span::new("✅").with_class(Class::Success)
-                } else {
-                    Span::new("❌").with_class(Class::Failure)
-                }
+        let TrackedTestCase {
+            test_case, status, ..
+        } = tracked_case;
+        let theme = &options.theme;
+        let (label, class, style) = match StatusKind::of(status) {
+            StatusKind::Enqueued => ("⏳", None, &theme.enqueued),
+            StatusKind::Started => ("🏃", None, &theme.started),
+            StatusKind::Success => ("✅", Some(Class::Success), &theme.success),
+            StatusKind::Failure if test_case.test.advisory => {
+                ("❌", Some(Class::AdvisoryFailure), &theme.failure)
             }
-            TestStatus::Finished(Err(inconclusive)) => match inconclusive {
-                // Note - cancellation is an "error" in the type system but we
-                // don't treat it as an error in the UI.
-                TestInconclusive::Canceled => Span::new("🚫"),
-                TestInconclusive::Error(_) => Span::new("💥").with_class(Class::Error),
-                TestInconclusive::ErrorExitCode(_) => Span::new("💥").with_class(Class::Error),
-            },
+            StatusKind::Failure => ("❌", Some(Class::Failure), &theme.failure),
+            StatusKind::Canceled => ("🚫", None, &theme.canceled),
+            StatusKind::Error => ("💥", Some(Class::Error), &theme.error),
+            StatusKind::StageFailed => ("⛔", None, &theme.stage_failed),
+            StatusKind::Skipped => ("💤", None, &theme.skipped),
+            StatusKind::SkippedByCommit => ("🚧", None, &theme.skipped_by_commit),
+            StatusKind::NotApplicable => ("➖", None, &theme.not_applicable),
+        };
+        let mut status_part = Span::new(label);
+        if let Some(class) = class {
+            status_part = status_part.with_class(class);
+        }
+        if let Some(style) = style {
+            status_part = themed(status_part, style);
         }
-        .with_url(format!(
+        let status_part = status_part.with_url(format!(
             "{}/{}/{}",
-            result_url_base,
+            options.result_url_base,
             Database::result_relpath(test_case).to_string_lossy(),
             if test_case.test.separate_outputs {
                 "stdout.txt"
@@ -433,46 +891,159 @@ impl OutputBuffer {
                 "output.txt"
             }
         ));
-        vec![
-            Span::new(test_case.test.name.to_string()).with_class(Class::TestName),
-            Span::new(": "),
-            status_part,
-            Span::new(" "),
-        ]
+        let mut name = Span::new(test_case.test.name.to_string()).with_class(Class::TestName);
+        if let Some(about) = test_case.test.about() {
+            name = name.with_tooltip(about);
+        }
+        let mut spans = vec![name, Span::new(": "), status_part];
+        if tracked_case.pinned {
+            spans.push(Span::new("📌"));
+        }
+        if tracked_case.slow {
+            spans.push(Span::new("🐢"));
+        }
+        if let (StatusKind::Failure | StatusKind::Error, Some(help_url)) =
+            (StatusKind::of(status), &test_case.test.help_url)
+        {
+            spans.push(Span::new("📖").with_url(help_url.as_str()));
+        }
+        // So that stale results from before some change in the environment
+        // don't get mistaken for fresh ones. It's also useful to know if a
+        // failure is from before or after the last time something was fixed.
+        if tracked_case.cached {
+            spans.push(Span::new(match &tracked_case.provenance {
+                Some(provenance) => format!(" (cached {})", provenance.describe()),
+                None => " (cached)".to_owned(),
+            }));
+        } else if let (StatusKind::Failure, Some(provenance)) =
+            (StatusKind::of(status), &tracked_case.provenance)
+        {
+            spans.push(Span::new(format!(" ({})", provenance.describe())));
+        }
+        spans.push(Span::new(" "));
+        if let TestStatus::Started(Some(progress)) = status {
+            spans.extend([Span::new(progress.as_str()), Span::new(" ")]);
+        }
+        spans
     }
 
     fn render_cases<'a>(
         &self,
         tracked_cases: impl IntoIterator<Item = &'a TrackedTestCase>,
-        result_url_base: &str,
-    ) -> Vec<Span<'a>> {
-        let mut tracked_cases: Vec<_> = tracked_cases.into_iter().collect();
-        tracked_cases.sort_by_key(|tc| &tc.test_case.test.name);
-        let mut spans = Vec::new();
+        options: &RenderOptions,
+    ) -> Vec<Line<'a>> {
+        let mut tracked_cases: Vec<_> = tracked_cases
+            .into_iter()
+            .filter(|tc| options.filter.shows(tc))
+            .collect();
+        tracked_cases.sort_by_key(|tc| (tc.test_case.test.stage, &tc.test_case.test.name));
+        let mut cases = Vec::new();
+        let mut prev_stage = None;
         for tracked_case in tracked_cases {
-            spans.extend(Self::render_case(
-                &tracked_case.test_case,
-                &tracked_case.status,
-                result_url_base,
-            ));
+            let mut spans = Vec::new();
+            let stage = tracked_case.test_case.test.stage;
+            if prev_stage.is_some_and(|prev| prev != stage) {
+                spans.push(Span::new("» "));
+            }
+            prev_stage = Some(stage);
+            spans.extend(Self::render_case(tracked_case, options));
+            cases.push(Line::from_iter(spans));
         }
-        spans
+        cases
+    }
+}
+
+// Totals for the test cases of the given commits, and how busy the resource
+// pools are.
+fn render_summary<'a>(
+    commits: impl IntoIterator<Item = &'a CommitHash>,
+    tracked_cases: &TrackedCases,
+    usage: &HashMap<ResourceKey, Usage>,
+) -> Line<'static> {
+    let (mut num_commits, mut queued, mut running, mut passed, mut failed, mut errored) =
+        (0, 0, 0, 0, 0, 0);
+    for commit in commits {
+        num_commits += 1;
+        for tracked_case in tracked_cases
+            .get(commit)
+            .into_iter()
+            .flat_map(|c| c.values())
+        {
+            match &tracked_case.status {
+                TestStatus::Enqueued => queued += 1,
+                TestStatus::Started(_) => running += 1,
+                TestStatus::Finished(Ok(result)) if result.exit_code == 0 => passed += 1,
+                TestStatus::Finished(Ok(_)) => failed += 1,
+                TestStatus::Finished(Err(
+                    TestInconclusive::Error(_) | TestInconclusive::ErrorExitCode(_),
+                )) => errored += 1,
+                TestStatus::Finished(Err(_)) | TestStatus::NotApplicable(_) => (),
+            }
+        }
+    }
+    let mut summary = format!(
+        "{num_commits} commits: {queued} queued, {running} running, {passed} passed, \
+         {failed} failed, {errored} errored"
+    );
+    let mut usage: Vec<_> = usage
+        .iter()
+        .map(|(key, usage)| match key {
+            ResourceKey::Worktree => ("worktrees".to_owned(), usage),
+            ResourceKey::Cpu => ("cpus".to_owned(), usage),
+            ResourceKey::Memory => ("memory MiB".to_owned(), usage),
+            ResourceKey::Gpu => ("gpus".to_owned(), usage),
+            ResourceKey::UserToken(name) => (name.clone(), usage),
+        })
+        .collect();
+    // Worktrees first, then the user's resources.
+    usage.sort_by_key(|(name, _)| (name != "worktrees", name.clone()));
+    for (i, (name, usage)) in usage.iter().enumerate() {
+        let sep = if i == 0 { " | " } else { ", " };
+        summary += &format!("{sep}{name} {}/{}", usage.in_use, usage.total);
+    }
+    Line::from(summary)
+}
+
+fn themed<'a>(span: Span<'a>, style: &StatusStyle) -> Span<'a> {
+    let span = match &style.label {
+        Some(label) => Span {
+            content: label.clone().into(),
+            ..span
+        },
+        None => span,
+    };
+    if style.color.is_none() && style.background.is_none() {
+        return span;
     }
+    span.with_style(Style {
+        color: style.color,
+        background: style.background,
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use core::str;
-    use std::sync::Arc;
+    use std::{
+        fs,
+        os::unix::fs::PermissionsExt as _,
+        sync::Arc,
+        time::{Duration, SystemTime},
+    };
 
-    use googletest::{expect_that, prelude::eq};
+    use googletest::{
+        expect_that,
+        prelude::{contains_substring, eq},
+    };
+    use tempfile::TempDir;
 
     use crate::{
         git::{
             test_utils::{TempRepo, WorktreeExt},
-            Commit,
+            Commit, TreeHash,
         },
         test::{test_utils::TestBuilder, CachePolicy, ExitCode, Test, TestResult},
+        text::Color,
     };
 
     use super::*;
@@ -489,10 +1060,19 @@ mod tests {
         Notification {
             test_case: TestCase {
                 commit_hash: commit_hash.clone(),
+                // Not used by the UI, so this doesn't need to be realistic.
+                tree_hash: TreeHash::new(commit_hash.to_string()),
                 cache_hash: Some(commit_hash.clone().into()),
                 test: test.clone(),
+                parents: Vec::new(),
+                merge_base: None,
+                changed_files: None,
+                skipped_by_commit: false,
+                skipped_gates: Vec::new(),
             },
             status,
+            cached: false,
+            provenance: None,
         }
     }
 
@@ -517,7 +1097,7 @@ mod tests {
         let test1 = fake_test("my_test1", CachePolicy::ByCommit);
         let test2 = fake_test("my_test2", CachePolicy::ByCommit);
 
-        let ob = OutputBuffer::new(&repo, format!("{}^..HEAD", commit2.hash), "%h %s")
+        let ob = OutputBuffer::new(&repo, &[format!("{}^..HEAD", commit2.hash)], "%h %s")
             .await
             .expect("failed to build OutputBuffer");
         let mut tracked_cases = HashMap::new();
@@ -529,12 +1109,18 @@ mod tests {
                 &test1,
                 TestStatus::Finished(Err(TestInconclusive::Error("oh no".to_owned()))),
             ),
-            fake_notif(&commit2.hash, &test2, TestStatus::Started),
+            fake_notif(&commit2.hash, &test2, TestStatus::Started(None)),
         ] {
-            update_tracked_cases(&mut tracked_cases, Arc::new(notif));
+            update_tracked_cases(&mut tracked_cases, Arc::new(notif), false, false);
         }
+        let pinned = fake_notif(&commit3.hash, &test2, fake_completion(0).await);
+        update_tracked_cases(&mut tracked_cases, Arc::new(pinned), true, true);
 
-        let buf = format!("{}", ob.render(&tracked_cases, "myhost").ansi());
+        let buf = format!(
+            "{}",
+            ob.render(&tracked_cases, &RenderOptions::default(), None)
+                .ansi()
+        );
         expect_that!(
             // The colored crate does not have any useful way to disable it from
             // this test code, only globally. This clashes with parallel testing.
@@ -545,9 +1131,9 @@ mod tests {
             *strip_ansi_escapes::strip_str(str::from_utf8(buf.as_bytes()).unwrap()),
             eq(format!(
                 "* {commit3} 3\n\
-                | my_test1: ⏳ my_test2: ✅ \n\
+                | pending my_test1: ⏳ my_test2: ✅📌🐢 \n\
                 * {commit2} 2\n\
-                | my_test1: 💥 my_test2: 🏃 \n",
+                | fail my_test1: 💥 my_test2: 🏃 \n",
                 commit3 = abbrev(&commit3),
                 commit2 = abbrev(&commit2)
             ))
@@ -561,9 +1147,9 @@ mod tests {
         let base_commit = repo.commit("base").await.unwrap();
         let join_commit = repo.commit("join").await.unwrap();
         let commit1 = repo.commit("1").await.unwrap();
-        repo.checkout(&base_commit.hash).await.unwrap();
+        repo.checkout(&base_commit.hash, None).await.unwrap();
         let commit2 = repo.commit("2").await.unwrap();
-        repo.checkout(&base_commit.hash).await.unwrap();
+        repo.checkout(&base_commit.hash, None).await.unwrap();
         let commit3 = repo.commit("3").await.unwrap();
         let merge = repo
             .merge(&[
@@ -576,7 +1162,7 @@ mod tests {
         let test1 = fake_test("my_test1", CachePolicy::ByCommit);
         let test2 = fake_test("my_test2", CachePolicy::ByCommit);
 
-        let ob = OutputBuffer::new(&repo, format!("{}..HEAD", base_commit.hash), "%h %s")
+        let ob = OutputBuffer::new(&repo, &[format!("{}..HEAD", base_commit.hash)], "%h %s")
             .await
             .expect("failed to build OutputBuffer");
 
@@ -589,12 +1175,16 @@ mod tests {
                 &test1,
                 TestStatus::Finished(Err(TestInconclusive::Error("oh no".to_owned()))),
             ),
-            fake_notif(&commit2.hash, &test2, TestStatus::Started),
+            fake_notif(&commit2.hash, &test2, TestStatus::Started(None)),
         ] {
-            update_tracked_cases(&mut tracked_cases, Arc::new(notif));
+            update_tracked_cases(&mut tracked_cases, Arc::new(notif), false, false);
         }
 
-        let buf = format!("{}", ob.render(&tracked_cases, "myhost").ansi());
+        let buf = format!(
+            "{}",
+            ob.render(&tracked_cases, &RenderOptions::default(), None)
+                .ansi()
+        );
 
         // Note this is a kinda weird log. We excluded the common ancestor of all the commits.
         // Also note it's a kinda weird input because we haven't provided any
@@ -606,13 +1196,13 @@ mod tests {
                 |\\ \\  \n\
                 | | | \n\
                 | | * {commit2} 2\n\
-                | |   my_test1: 💥 my_test2: 🏃 \n\
+                | |   fail my_test1: 💥 my_test2: 🏃 \n\
                 | * {commit1} 1\n\
                 | | \n\
                 | * {join} join\n\
                 |   \n\
                 * {commit3} 3\n\
-                | my_test1: ⏳ my_test2: ✅ \n",
+                | pending my_test1: ⏳ my_test2: ✅ \n",
                 merge = abbrev(&merge),
                 commit3 = abbrev(&commit3),
                 commit2 = abbrev(&commit2),
@@ -629,9 +1219,9 @@ mod tests {
         let base_commit = repo.commit("base").await.unwrap();
         repo.commit("join").await.unwrap();
         let commit1 = repo.commit("1").await.unwrap();
-        repo.checkout(&base_commit.hash).await.unwrap();
+        repo.checkout(&base_commit.hash, None).await.unwrap();
         let commit2 = repo.commit("2").await.unwrap();
-        repo.checkout(&base_commit.hash).await.unwrap();
+        repo.checkout(&base_commit.hash, None).await.unwrap();
         let commit3 = repo.commit("3").await.unwrap();
         repo.merge(&[commit1.hash, commit2.hash.clone(), commit3.hash.clone()])
             .await
@@ -639,7 +1229,7 @@ mod tests {
         let test1 = fake_test("my_test1", CachePolicy::ByCommit);
         let test2 = fake_test("my_test2", CachePolicy::ByCommit);
 
-        let ob = OutputBuffer::new(&repo, format!("{0}..{0}", base_commit.hash), "%h %s")
+        let ob = OutputBuffer::new(&repo, &[format!("{0}..{0}", base_commit.hash)], "%h %s")
             .await
             .expect("failed to build OutputBuffer");
         let mut tracked_cases = HashMap::new();
@@ -651,15 +1241,441 @@ mod tests {
                 &test2,
                 TestStatus::Finished(Err(TestInconclusive::Error("oh no".to_owned()))),
             ),
-            fake_notif(&commit2.hash, &test2, TestStatus::Started),
+            fake_notif(&commit2.hash, &test2, TestStatus::Started(None)),
         ] {
-            update_tracked_cases(&mut tracked_cases, Arc::new(notif));
+            update_tracked_cases(&mut tracked_cases, Arc::new(notif), false, false);
         }
 
-        let buf = format!("{}", ob.render(&tracked_cases, "myhost").ansi());
+        let buf = format!(
+            "{}",
+            ob.render(&tracked_cases, &RenderOptions::default(), None)
+                .ansi()
+        );
         expect_that!(
             *strip_ansi_escapes::strip_str(str::from_utf8(buf.as_bytes()).unwrap()),
             eq("[range empty]\n".to_owned())
         );
     }
+
+    #[googletest::test]
+    #[tokio::test]
+    async fn output_buffer_theme() {
+        let repo = Arc::new(TempRepo::new().await.unwrap());
+        let commit = repo.commit("1").await.unwrap();
+        let test1 = fake_test("my_test1", CachePolicy::ByCommit);
+        let test2 = fake_test("my_test2", CachePolicy::ByCommit);
+        let options = RenderOptions {
+            theme: StatusTheme {
+                success: Some(StatusStyle {
+                    label: Some("PASS".into()),
+                    ..Default::default()
+                }),
+                failure: Some(StatusStyle {
+                    label: Some("FAIL".into()),
+                    color: Some(Color::Blue),
+                    background: None,
+                }),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let ob = OutputBuffer::new(&repo, &["HEAD"], "%h %s")
+            .await
+            .expect("failed to build OutputBuffer");
+        let mut tracked_cases = HashMap::new();
+        for notif in [
+            fake_notif(&commit.hash, &test1, fake_completion(0).await),
+            fake_notif(&commit.hash, &test2, fake_completion(1).await),
+        ] {
+            update_tracked_cases(&mut tracked_cases, Arc::new(notif), false, false);
+        }
+
+        let buf = format!("{}", ob.render(&tracked_cases, &options, None).ansi());
+        expect_that!(
+            *strip_ansi_escapes::strip_str(str::from_utf8(buf.as_bytes()).unwrap()),
+            eq(format!(
+                "* {commit} 1\n| fail my_test1: PASS my_test2: FAIL \n",
+                commit = abbrev(&commit)
+            ))
+        );
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    async fn output_buffer_advisory() {
+        let repo = Arc::new(TempRepo::new().await.unwrap());
+        let commit = repo.commit("1").await.unwrap();
+        let test = fake_test("my_test", CachePolicy::ByCommit);
+        let lint = Arc::new(TestBuilder::new("lint", "", [""]).advisory().build());
+
+        let ob = OutputBuffer::new(&repo, &["HEAD"], "%h %s")
+            .await
+            .expect("failed to build OutputBuffer");
+        let mut tracked_cases = HashMap::new();
+        for notif in [
+            fake_notif(&commit.hash, &test, fake_completion(0).await),
+            fake_notif(&commit.hash, &lint, fake_completion(1).await),
+        ] {
+            update_tracked_cases(&mut tracked_cases, Arc::new(notif), false, false);
+        }
+
+        let html = ob
+            .render(&tracked_cases, &RenderOptions::default(), None)
+            .html_pre()
+            .to_string();
+        expect_that!(
+            html,
+            contains_substring(r#"<span class="success">pass</span>"#)
+        );
+        expect_that!(
+            html,
+            contains_substring(r#"class="advisory-failure">❌</span>"#)
+        );
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    async fn output_buffer_multiline() {
+        // Count the git commands.
+        let bin_dir = TempDir::new().unwrap();
+        let count_path = bin_dir.path().join("count");
+        let git = bin_dir.path().join("git");
+        fs::write(
+            &git,
+            format!("#!/bin/sh\necho >> {count_path:?}\nexec git \"$@\"\n"),
+        )
+        .unwrap();
+        fs::set_permissions(&git, fs::Permissions::from_mode(0o755)).unwrap();
+        let repo = Arc::new(TempRepo::with_git_binary(git).await.unwrap());
+        repo.commit("base").await.unwrap();
+        let commit1 = repo.commit("1").await.unwrap();
+        let commit2 = repo.commit("2").await.unwrap();
+        let test = fake_test("my_test", CachePolicy::ByCommit);
+        let count = || fs::read_to_string(&count_path).unwrap().lines().count();
+
+        let count_before = count();
+        let ob = OutputBuffer::new(&repo, &["HEAD~2..HEAD"], "%h%n  %s")
+            .await
+            .expect("failed to build OutputBuffer");
+        expect_that!(count() - count_before, eq(1));
+        let mut tracked_cases = HashMap::new();
+        for notif in [
+            fake_notif(&commit1.hash, &test, fake_completion(0).await),
+            fake_notif(&commit2.hash, &test, fake_completion(1).await),
+        ] {
+            update_tracked_cases(&mut tracked_cases, Arc::new(notif), false, false);
+        }
+
+        let text = ob.render(&tracked_cases, &RenderOptions::default(), None);
+        expect_that!(
+            *strip_ansi_escapes::strip_str(text.ansi().to_string()),
+            eq(format!(
+                "* {commit2}\n|   2\n| fail my_test: ❌ \n* {commit1}\n|   1\n| pass my_test: ✅ \n",
+                commit2 = abbrev(&commit2),
+                commit1 = abbrev(&commit1)
+            ))
+        );
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    async fn output_buffer_help_url() {
+        let repo = Arc::new(TempRepo::new().await.unwrap());
+        repo.commit("base").await.unwrap();
+        let commit1 = repo.commit("1").await.unwrap();
+        let commit2 = repo.commit("2").await.unwrap();
+        let mut test = TestBuilder::new("my_test", "", [""]).build();
+        test.help_url = Some("https://example.com/my_test".into());
+        let test = Arc::new(test);
+
+        let ob = OutputBuffer::new(&repo, &["HEAD~2..HEAD"], "%h %s")
+            .await
+            .expect("failed to build OutputBuffer");
+        let mut tracked_cases = HashMap::new();
+        for notif in [
+            fake_notif(&commit1.hash, &test, fake_completion(0).await),
+            fake_notif(&commit2.hash, &test, fake_completion(1).await),
+        ] {
+            update_tracked_cases(&mut tracked_cases, Arc::new(notif), false, false);
+        }
+
+        let text = ob.render(&tracked_cases, &RenderOptions::default(), None);
+        // Only the failure links to the help.
+        expect_that!(
+            *strip_ansi_escapes::strip_str(text.ansi().to_string()),
+            eq(format!(
+                "* {commit2} 2\n| fail my_test: ❌📖 \n* {commit1} 1\n| pass my_test: ✅ \n",
+                commit2 = abbrev(&commit2),
+                commit1 = abbrev(&commit1)
+            ))
+        );
+        expect_that!(
+            text.html_pre().to_string(),
+            contains_substring(r#"<a href="https://example.com/my_test"><span class="">📖"#)
+        );
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    async fn output_buffer_wrap() {
+        let repo = Arc::new(TempRepo::new().await.unwrap());
+        let commit = repo.commit("1").await.unwrap();
+        let tests =
+            ["my_test1", "my_test2", "my_test3"].map(|name| fake_test(name, CachePolicy::ByCommit));
+
+        let ob = OutputBuffer::new(&repo, &["HEAD"], "%h %s")
+            .await
+            .expect("failed to build OutputBuffer");
+        let mut tracked_cases = HashMap::new();
+        for test in &tests {
+            let notif = fake_notif(&commit.hash, test, fake_completion(0).await);
+            update_tracked_cases(&mut tracked_cases, Arc::new(notif), false, false);
+        }
+
+        // Each status takes 13 columns, so only two fit after the graph and
+        // the verdict.
+        let text = ob.render(&tracked_cases, &RenderOptions::default(), Some(35));
+        let buf = format!("{}", text.ansi());
+        expect_that!(
+            *strip_ansi_escapes::strip_str(str::from_utf8(buf.as_bytes()).unwrap()),
+            eq(format!(
+                "* {commit} 1\n| pass my_test1: ✅ my_test2: ✅ \n| my_test3: ✅ \n",
+                commit = abbrev(&commit)
+            ))
+        );
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    async fn output_buffer_compact() {
+        let repo = Arc::new(TempRepo::new().await.unwrap());
+        let mut commits = Vec::new();
+        for i in 1..=6 {
+            commits.push(repo.commit(i.to_string()).await.unwrap());
+        }
+        let test = fake_test("my_test", CachePolicy::ByCommit);
+
+        let ob = OutputBuffer::new(&repo, &["HEAD"], "%h %s")
+            .await
+            .expect("failed to build OutputBuffer");
+        let mut tracked_cases = HashMap::new();
+        for (i, commit) in commits.iter().enumerate() {
+            let exit_code = if i == 1 { 1 } else { 0 };
+            let notif = fake_notif(&commit.hash, &test, fake_completion(exit_code).await);
+            update_tracked_cases(&mut tracked_cases, Arc::new(notif), false, false);
+        }
+
+        let options = RenderOptions {
+            compact: true,
+            ..Default::default()
+        };
+        let text = ob.render(&tracked_cases, &options, None);
+        let buf = format!("{}", text.ansi());
+        expect_that!(
+            *strip_ansi_escapes::strip_str(str::from_utf8(buf.as_bytes()).unwrap()),
+            eq(format!(
+                "* {commit6} 6\n\
+                | pass my_test: ✅ \n\
+                | … 2 commits all passing …\n\
+                * {commit3} 3\n\
+                | pass my_test: ✅ \n\
+                * {commit2} 2\n\
+                | fail my_test: ❌ \n\
+                * {commit1} 1\n\
+                | pass my_test: ✅ \n",
+                commit6 = abbrev(&commits[5]),
+                commit3 = abbrev(&commits[2]),
+                commit2 = abbrev(&commits[1]),
+                commit1 = abbrev(&commits[0]),
+            ))
+        );
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    async fn output_buffer_filter() {
+        let repo = Arc::new(TempRepo::new().await.unwrap());
+        let commit = repo.commit("1").await.unwrap();
+        let tests =
+            ["build", "build_docs", "lint"].map(|name| fake_test(name, CachePolicy::ByCommit));
+
+        let ob = OutputBuffer::new(&repo, &["HEAD"], "%h %s")
+            .await
+            .expect("failed to build OutputBuffer");
+        let mut tracked_cases = HashMap::new();
+        for (test, exit_code) in tests.iter().zip([1, 0, 1]) {
+            let notif = fake_notif(&commit.hash, test, fake_completion(exit_code).await);
+            update_tracked_cases(&mut tracked_cases, Arc::new(notif), false, false);
+        }
+
+        let terms = ["status=failure", "test=^build"].map(|term| term.parse().unwrap());
+        let options = RenderOptions {
+            filter: Filter::new(terms),
+            ..Default::default()
+        };
+        let buf = format!("{}", ob.render(&tracked_cases, &options, None).ansi());
+        expect_that!(
+            *strip_ansi_escapes::strip_str(str::from_utf8(buf.as_bytes()).unwrap()),
+            eq(format!(
+                "* {commit} 1\n| fail build: ❌ \n",
+                commit = abbrev(&commit)
+            ))
+        );
+        expect_that!("status=bogus".parse::<FilterTerm>().is_err(), eq(true));
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    async fn output_buffer_branches() {
+        let repo = Arc::new(TempRepo::new().await.unwrap());
+        let commit1 = repo.commit("1").await.unwrap();
+        let commit2 = repo.commit("2").await.unwrap();
+        let test = fake_test("my_test", CachePolicy::ByCommit);
+
+        let ob = OutputBuffer::new(&repo, &["HEAD"], "%h %s")
+            .await
+            .expect("failed to build OutputBuffer");
+        let mut tracked_cases = HashMap::new();
+        let notif = fake_notif(&commit1.hash, &test, fake_completion(0).await);
+        update_tracked_cases(&mut tracked_cases, Arc::new(notif), false, false);
+        let options = RenderOptions {
+            branches: HashMap::from([
+                (commit1.hash.clone(), vec!["bottom".to_owned()]),
+                (
+                    commit2.hash.clone(),
+                    vec!["top".to_owned(), "other".to_owned()],
+                ),
+            ]),
+            ..Default::default()
+        };
+
+        let buf = format!("{}", ob.render(&tracked_cases, &options, None).ansi());
+        expect_that!(
+            *strip_ansi_escapes::strip_str(str::from_utf8(buf.as_bytes()).unwrap()),
+            eq(format!(
+                "* {commit2} 2\n| [top, other] \n* {commit1} 1\n| pass [bottom] my_test: ✅ \n",
+                commit1 = abbrev(&commit1),
+                commit2 = abbrev(&commit2),
+            ))
+        );
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    async fn output_buffer_cached() {
+        let repo = Arc::new(TempRepo::new().await.unwrap());
+        let commit = repo.commit("1").await.unwrap();
+        let [test1, test2, test3] =
+            ["my_test1", "my_test2", "my_test3"].map(|name| fake_test(name, CachePolicy::ByCommit));
+
+        let ob = OutputBuffer::new(&repo, &["HEAD"], "%h %s")
+            .await
+            .expect("failed to build OutputBuffer");
+        let mut tracked_cases = HashMap::new();
+        let provenance = Provenance {
+            host: "elsewhere".into(),
+            started_at: None,
+            finished_at: SystemTime::now() - Duration::from_secs(3 * 86400),
+        };
+        for notif in [
+            fake_notif(&commit.hash, &test1, fake_completion(0).await),
+            Notification {
+                cached: true,
+                provenance: Some(provenance),
+                ..fake_notif(&commit.hash, &test2, fake_completion(0).await)
+            },
+            Notification {
+                cached: true,
+                ..fake_notif(&commit.hash, &test3, fake_completion(0).await)
+            },
+        ] {
+            update_tracked_cases(&mut tracked_cases, Arc::new(notif), false, false);
+        }
+
+        let buf = format!(
+            "{}",
+            ob.render(&tracked_cases, &RenderOptions::default(), None)
+                .ansi()
+        );
+        expect_that!(
+            *strip_ansi_escapes::strip_str(str::from_utf8(buf.as_bytes()).unwrap()),
+            eq(format!(
+                "* {commit} 1\n| pass my_test1: ✅ my_test2: ✅ (cached 3d ago on elsewhere) \
+                 my_test3: ✅ (cached) \n",
+                commit = abbrev(&commit)
+            ))
+        );
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    async fn first_tip_failure() {
+        let tip = CommitHash::new("1111");
+        let parent = CommitHash::new("2222");
+        let test1 = fake_test("my_test1", CachePolicy::ByCommit);
+        let test2 = fake_test("my_test2", CachePolicy::ByCommit);
+        let mut tracked_cases = HashMap::new();
+
+        let notif = fake_notif(&parent, &test1, fake_completion(1).await);
+        expect_that!(
+            is_first_tip_failure(&tracked_cases, Some(&tip), &notif),
+            eq(false)
+        );
+        for notif in [
+            fake_notif(&tip, &test1, TestStatus::Enqueued),
+            fake_notif(&tip, &test1, fake_completion(0).await),
+            fake_notif(&tip, &test2, fake_completion(1).await),
+            fake_notif(&tip, &test1, fake_completion(1).await),
+        ] {
+            let first = is_first_tip_failure(&tracked_cases, Some(&tip), &notif);
+            expect_that!(first, eq(Arc::ptr_eq(&notif.test_case.test, &test2)));
+            update_tracked_cases(&mut tracked_cases, Arc::new(notif), false, false);
+        }
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    async fn summary() {
+        let commit1 = CommitHash::new("1111");
+        let commit2 = CommitHash::new("2222");
+        let outside_range = CommitHash::new("3333");
+        let test1 = fake_test("my_test1", CachePolicy::ByCommit);
+        let test2 = fake_test("my_test2", CachePolicy::ByCommit);
+        let mut tracked_cases = HashMap::new();
+        for notif in [
+            fake_notif(&commit1, &test1, fake_completion(0).await),
+            fake_notif(&commit1, &test2, fake_completion(1).await),
+            fake_notif(&commit2, &test1, TestStatus::Started(None)),
+            fake_notif(&commit2, &test2, TestStatus::Enqueued),
+            fake_notif(&outside_range, &test1, fake_completion(0).await),
+        ] {
+            update_tracked_cases(&mut tracked_cases, Arc::new(notif), false, false);
+        }
+        let usage = HashMap::from([
+            (
+                ResourceKey::UserToken("gpu".into()),
+                Usage {
+                    in_use: 0,
+                    total: 1,
+                },
+            ),
+            (
+                ResourceKey::Worktree,
+                Usage {
+                    in_use: 1,
+                    total: 4,
+                },
+            ),
+        ]);
+
+        let line = render_summary([&commit1, &commit2], &tracked_cases, &usage);
+        expect_that!(
+            Text::from(line).ansi().to_string(),
+            eq(
+                "2 commits: 1 queued, 1 running, 1 passed, 1 failed, 0 errored \
+                | worktrees 1/4, gpu 0/1\n"
+            )
+        );
+    }
 }
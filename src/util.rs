@@ -1,19 +1,176 @@
 use core::fmt;
 use std::{
     fmt::{Display, Formatter},
+    fs::{self, File},
     future::Future,
-    io,
+    io::{self, Write as _},
     ops::Deref,
-    path::PathBuf,
+    os::unix::fs::MetadataExt as _,
+    path::{Path, PathBuf},
     str::FromStr,
+    time::Duration,
 };
 
+use anyhow::{anyhow, Context as _};
 #[allow(unused_imports)]
 use log::{debug, error};
+use nix::{
+    sched::{sched_getaffinity, CpuSet},
+    sys::{statvfs::statvfs, utsname::uname},
+    unistd::Pid,
+};
 use sha3::digest;
+use tempfile::NamedTempFile;
 use tokio::task::JoinSet;
 use tokio_util::sync::CancellationToken;
 
+// Empty if it can't be determined.
+pub fn hostname() -> String {
+    uname()
+        .map(|u| u.nodename().to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+// Rough, human-friendly version of a duration, like "3m" or "2d".
+pub fn format_age(age: Duration) -> String {
+    let secs = age.as_secs();
+    match secs {
+        0..60 => format!("{secs}s"),
+        60..3600 => format!("{}m", secs / 60),
+        3600..172800 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    }
+}
+
+// Quote an argument so that the shell passes it through as-is.
+pub fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', r"'\''"))
+}
+
+// Bytes available to unprivileged users on the filesystem that path is, or
+// would be, created on.
+pub fn free_space(path: &Path) -> anyhow::Result<u64> {
+    let dir = path.ancestors().find(|p| p.exists()).unwrap_or(path);
+    let stat = statvfs(dir).with_context(|| format!("getting free space in {dir:?}"))?;
+    Ok(stat.blocks_available() as u64 * stat.fragment_size() as u64)
+}
+
+// The CPUs this process is allowed to run on.
+pub fn available_cpus() -> anyhow::Result<Vec<usize>> {
+    let set = sched_getaffinity(Pid::from_raw(0)).context("getting CPU affinity")?;
+    Ok((0..CpuSet::count())
+        .filter(|&cpu| set.is_set(cpu).unwrap_or(false))
+        .collect())
+}
+
+// Total RAM, in bytes.
+pub fn total_memory() -> anyhow::Result<u64> {
+    let content = fs::read_to_string("/proc/meminfo").context("reading /proc/meminfo")?;
+    let kib: u64 = content
+        .lines()
+        .find_map(|line| line.strip_prefix("MemTotal:"))
+        .ok_or_else(|| anyhow!("no MemTotal in /proc/meminfo"))?
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .context("parsing MemTotal")?;
+    Ok(kib * 1024)
+}
+
+// Parse a size in bytes, like "512M" or "8G". The suffixes are powers of 1024.
+pub fn parse_size(s: &str) -> anyhow::Result<u64> {
+    let shift = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => 10,
+        Some('M') => 20,
+        Some('G') => 30,
+        Some('T') => 40,
+        _ => 0,
+    };
+    let num = if shift == 0 { s } else { &s[..s.len() - 1] };
+    let num: u64 = num
+        .parse()
+        .with_context(|| format!("invalid size {s:?}, expected e.g. \"8G\""))?;
+    num.checked_mul(1 << shift)
+        .ok_or_else(|| anyhow!("size {s:?} is too big"))
+}
+
+// Replace the file's content so that a crash leaves either the old or the new
+// content, never something in between.
+pub fn write_atomic(path: &Path, content: &[u8]) -> anyhow::Result<()> {
+    let dir = path.parent().context("path has no parent")?;
+    let mut file = NamedTempFile::new_in(dir).context("creating temp file")?;
+    file.write_all(content)
+        .with_context(|| format!("writing {}", path.display()))?;
+    file.as_file()
+        .sync_all()
+        .with_context(|| format!("syncing {}", path.display()))?;
+    file.persist(path)
+        .with_context(|| format!("writing {}", path.display()))?;
+    sync_dir(dir)
+}
+
+// Make renames into the directory survive a crash.
+pub fn sync_dir(dir: &Path) -> anyhow::Result<()> {
+    File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .with_context(|| format!("syncing {}", dir.display()))
+}
+
+// Space taken up on disk by a file, or by everything under a directory,
+// without following symlinks. Files that disappear while this is running are
+// skipped.
+pub fn disk_usage(path: &Path) -> anyhow::Result<u64> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e).with_context(|| format!("reading {}", path.display())),
+    };
+    let mut total = metadata.blocks() * 512;
+    if metadata.is_dir() {
+        let entries = match fs::read_dir(path) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(total),
+            Err(e) => return Err(e).with_context(|| format!("reading {}", path.display())),
+        };
+        for entry in entries {
+            let entry = entry.with_context(|| format!("reading {}", path.display()))?;
+            total += disk_usage(&entry.path())?;
+        }
+    }
+    Ok(total)
+}
+
+// Inverse of parse_size, rounded to one decimal place, like "1.5M".
+pub fn format_size(bytes: u64) -> String {
+    let mut size = bytes as f64;
+    for suffix in ["", "K", "M", "G"] {
+        if size < 1024.0 {
+            return if suffix.is_empty() {
+                format!("{bytes}")
+            } else {
+                format!("{size:.1}{suffix}")
+            };
+        }
+        size /= 1024.0;
+    }
+    format!("{size:.1}T")
+}
+
+// Parse a duration like "90s", "30m", "12h" or "7d".
+pub fn parse_duration(s: &str) -> anyhow::Result<Duration> {
+    let (num, unit_secs) = [("s", 1), ("m", 60), ("h", 60 * 60), ("d", 24 * 60 * 60)]
+        .into_iter()
+        .find_map(|(suffix, secs)| Some((s.strip_suffix(suffix)?, secs)))
+        .ok_or_else(|| anyhow!("invalid duration {s:?}, expected e.g. \"12h\""))?;
+    let num: u64 = num
+        .parse()
+        .with_context(|| format!("invalid duration {s:?}, expected e.g. \"12h\""))?;
+    num.checked_mul(unit_secs)
+        .map(Duration::from_secs)
+        .ok_or_else(|| anyhow!("duration {s:?} is too long"))
+}
+
 #[derive(Clone, Debug)]
 pub struct DisplayablePathBuf(pub PathBuf);
 
@@ -3,7 +3,7 @@ use std::{
     ffi::{OsStr, OsString},
     fs::{self, create_dir, create_dir_all, remove_file, File},
     io::{BufRead as _, BufReader},
-    os::unix::process::ExitStatusExt as _,
+    os::unix::{fs::PermissionsExt as _, process::ExitStatusExt as _},
     path::{Path, PathBuf},
     process::{ExitStatus, Stdio},
     result,
@@ -681,251 +681,1399 @@ async fn should_find_output(want_stdout: &str, want_stderr: &str) {
 
 #[googletest::test]
 #[tokio::test]
-async fn should_find_not_race() {
-    let tmp_dir = TempDir::new().unwrap();
+async fn should_list_and_copy_artifacts() {
+    let db_dir = TempDir::with_prefix("result-db").unwrap();
+    let builder = LimmatChildBuilder::new(
+        r##"
+            [[tests]]
+            name = "build"
+            command = """
+            mkdir $LIMMAT_ARTIFACTS/out
+            printf hello > $LIMMAT_ARTIFACTS/out/bin
+            echo hi > $LIMMAT_ARTIFACTS/log.txt
+            """
+        "##,
+    )
+    .await
+    .unwrap()
+    .db_dir(db_dir.path().to_owned());
+
+    let dest_dir = TempDir::with_prefix("artifacts-dest").unwrap();
+    let dest = dest_dir.path().join("copied");
+    let mut child = builder
+        .start([
+            "artifacts",
+            "cp",
+            "--run",
+            "build",
+            "HEAD",
+            dest.to_str().unwrap(),
+        ])
+        .await
+        .unwrap();
+    timeout(Duration::from_secs(5), child.expect_exit_code(0))
+        .await
+        .expect("child didn't shut down")
+        .unwrap();
+    expect_that!(fs::read_to_string(dest.join("out/bin")), ok(eq("hello")));
+    expect_that!(fs::read_to_string(dest.join("log.txt")), ok(eq("hi\n")));
+
+    let mut child = builder.start(["artifacts", "list", "HEAD"]).await.unwrap();
+    timeout(Duration::from_secs(5), child.expect_exit_code(0))
+        .await
+        .expect("child didn't shut down")
+        .unwrap();
+    expect_that!(
+        child.stdout(),
+        ok(ends_with(
+            " build  8\n    log.txt  3\n    out/  5\n        bin  5\n"
+        ))
+    );
+
+    let mut child = builder
+        .start(["--skip-test", "build", "artifacts", "list", "HEAD"])
+        .await
+        .unwrap();
+    timeout(Duration::from_secs(5), child.expect_exit_code(0))
+        .await
+        .expect("child didn't shut down")
+        .unwrap();
+    expect_that!(child.stdout(), ok(eq("")));
+}
+
+#[test_case(&[] , "\x1b[31mred\x1b[0m\nplain\n" ; "raw")]
+#[test_case(&["--no-ansi"] , "red\nplain\n" ; "no ansi")]
+#[googletest::test]
+#[tokio::test]
+async fn should_print_logs(extra_args: &[&str], want_output: &str) {
+    let db_dir = TempDir::with_prefix("result-db").unwrap();
+    let builder = LimmatChildBuilder::new(
+        r##"
+            num_worktrees = 1
+            [[tests]]
+            name = "my_test"
+            command = "printf '\\033[31mred\\033[0m\\n'; echo plain >&2"
+        "##,
+    )
+    .await
+    .unwrap()
+    .db_dir(db_dir.path().to_owned());
+    let mut child = builder
+        .start(
+            ["logs", "--run", "my_test", "HEAD^"]
+                .into_iter()
+                .chain(extra_args.iter().copied()),
+        )
+        .await
+        .unwrap();
+    timeout(Duration::from_secs(5), child.expect_exit_code(0))
+        .await
+        .expect("child didn't shut down")
+        .unwrap();
+    expect_that!(child.stdout(), ok(eq(want_output)));
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn should_grep_logs() {
+    let db_dir = TempDir::with_prefix("result-db").unwrap();
+    let builder = LimmatChildBuilder::new(
+        r##"
+            num_worktrees = 1
+            [[tests]]
+            name = "my_test"
+            command = "echo hay; printf '\\033[31mneedle\\033[0m\\n'"
+        "##,
+    )
+    .await
+    .unwrap()
+    .db_dir(db_dir.path().to_owned());
+    let mut child = builder.start(["grep", "^needle$", "HEAD^"]).await.unwrap();
+    timeout(Duration::from_secs(5), child.expect_exit_code(1))
+        .await
+        .expect("child didn't shut down")
+        .unwrap();
+
+    let mut child = builder
+        .start(["get", "--run", "my_test", "HEAD^"])
+        .await
+        .unwrap();
+    timeout(Duration::from_secs(5), child.expect_exit_code(0))
+        .await
+        .expect("child didn't shut down")
+        .unwrap();
+
+    let mut child = builder.start(["grep", "^needle$", "HEAD^"]).await.unwrap();
+    timeout(Duration::from_secs(5), child.expect_exit_code(0))
+        .await
+        .expect("child didn't shut down")
+        .unwrap();
+    expect_that!(child.stdout(), ok(ends_with(" my_test:2: needle\n")));
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn should_diff_results() {
+    let db_dir = TempDir::with_prefix("result-db").unwrap();
+    let builder = LimmatChildBuilder::new(
+        r##"
+            num_worktrees = 1
+            [[tests]]
+            name = "my_test"
+            command = "n=$(git rev-list --count HEAD); echo commits: $n; [ $n -lt 4 ]"
+        "##,
+    )
+    .await
+    .unwrap()
+    .db_dir(db_dir.path().to_owned());
+    // Running the failing one is an error, but the result is still stored.
+    for (rev, want_code) in [("HEAD~2", 0), ("HEAD^", 1)] {
+        let mut child = builder
+            .start(["get", "--run", "my_test", rev])
+            .await
+            .unwrap();
+        timeout(Duration::from_secs(5), child.expect_exit_code(want_code))
+            .await
+            .expect("child didn't shut down")
+            .unwrap();
+    }
+
+    let mut child = builder
+        .start(["diff", "--logs", "HEAD~2", "HEAD^"])
+        .await
+        .unwrap();
+    timeout(Duration::from_secs(5), child.expect_exit_code(1))
+        .await
+        .expect("child didn't shut down")
+        .unwrap();
+    let stdout = child.stdout().unwrap();
+    expect_that!(
+        stdout,
+        contains_substring("my_test: passed -> failed (exit code 1) (newly failing)\n")
+    );
+    expect_that!(stdout, contains_substring("\n-commits: 3\n+commits: 4\n"));
+
+    // The other way round, nothing broke.
+    let mut child = builder.start(["diff", "HEAD^", "HEAD~2"]).await.unwrap();
+    timeout(Duration::from_secs(5), child.expect_exit_code(0))
+        .await
+        .expect("child didn't shut down")
+        .unwrap();
+    expect_that!(
+        child.stdout(),
+        ok(eq("my_test: failed (exit code 1) -> passed\n"))
+    );
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn should_gate_range() {
+    let db_dir = TempDir::with_prefix("result-db").unwrap();
+    let builder = LimmatChildBuilder::new(
+        r##"
+            num_worktrees = 2
+            [[tests]]
+            name = "my_test"
+            command = "[ $(git rev-list --count HEAD) -lt 4 ]"
+        "##,
+    )
+    .await
+    .unwrap()
+    .db_dir(db_dir.path().to_owned());
+    let output = Command::new("git")
+        .current_dir(&builder.repo_dir)
+        .args(["rev-parse", "HEAD^"])
+        .output()
+        .await
+        .unwrap();
+    let bad_commit = String::from_utf8(output.stdout).unwrap().trim().to_owned();
+
+    let mut child = builder.start(["gate", "HEAD~3..HEAD^"]).await.unwrap();
+    timeout(Duration::from_secs(10), child.expect_exit_code(1))
+        .await
+        .expect("child didn't shut down")
+        .unwrap();
+    expect_that!(
+        child.stdout(),
+        ok(ends_with(format!(
+            "\nCommits with failing tests:\n  {} lohs geht's buebe\n",
+            &bad_commit[..12]
+        )))
+    );
+
+    // The results were stored, so gating again on a passing commit doesn't
+    // need to run anything.
+    let mut child = builder.start(["gate", "HEAD~2"]).await.unwrap();
+    timeout(Duration::from_secs(10), child.expect_exit_code(0))
+        .await
+        .expect("child didn't shut down")
+        .unwrap();
+
+    let mut child = builder.start(["gate", "--print-hook"]).await.unwrap();
+    timeout(Duration::from_secs(5), child.expect_exit_code(0))
+        .await
+        .expect("child didn't shut down")
+        .unwrap();
+    expect_that!(child.stdout(), ok(starts_with("#!/bin/sh\n")));
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn should_get_verdict() {
+    let db_dir = TempDir::with_prefix("result-db").unwrap();
+    let builder = LimmatChildBuilder::new(
+        r##"
+            num_worktrees = 2
+            [[tests]]
+            name = "build"
+            command = "[ $(git rev-list --count HEAD) -lt 5 ]"
+            help_url = "https://example.com/build"
+            [[tests]]
+            name = "lint"
+            command = "false"
+            advisory = true
+        "##,
+    )
+    .await
+    .unwrap()
+    .db_dir(db_dir.path().to_owned());
+
+    let mut child = builder.start(["get", "--verdict", "HEAD^"]).await.unwrap();
+    timeout(Duration::from_secs(5), child.expect_exit_code(50))
+        .await
+        .expect("child didn't shut down")
+        .unwrap();
+    expect_that!(child.stdout(), ok(eq("pending\n")));
+
+    let mut child = builder.start(["gate", "HEAD~2..HEAD"]).await.unwrap();
+    timeout(Duration::from_secs(10), child.expect_exit_code(1))
+        .await
+        .expect("child didn't shut down")
+        .unwrap();
+
+    // The advisory test failed, but that doesn't count.
+    let mut child = builder.start(["get", "--verdict", "HEAD^"]).await.unwrap();
+    timeout(Duration::from_secs(5), child.expect_exit_code(0))
+        .await
+        .expect("child didn't shut down")
+        .unwrap();
+    expect_that!(child.stdout(), ok(eq("pass\n")));
+    let mut child = builder.start(["gate", "HEAD^"]).await.unwrap();
+    timeout(Duration::from_secs(10), child.expect_exit_code(0))
+        .await
+        .expect("child didn't shut down")
+        .unwrap();
+    expect_that!(child.stdout(), ok(contains_substring(" lint: ")));
+    expect_that!(child.stdout(), ok(contains_substring(" (advisory)\n")));
+
+    let mut child = builder.start(["get", "--verdict", "HEAD"]).await.unwrap();
+    timeout(Duration::from_secs(5), child.expect_exit_code(1))
+        .await
+        .expect("child didn't shut down")
+        .unwrap();
+    expect_that!(child.stdout(), ok(eq("fail\n")));
+
+    let mut child = builder.start(["get", "build", "HEAD"]).await.unwrap();
+    timeout(Duration::from_secs(5), child.expect_exit_code(0))
+        .await
+        .expect("child didn't shut down")
+        .unwrap();
+    expect_that!(
+        child.stderr(),
+        ok(contains_substring("see https://example.com/build for help"))
+    );
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn should_repro() {
     let db_dir = TempDir::with_prefix("result-db").unwrap();
+    let builder = LimmatChildBuilder::new(
+        r##"
+            [[resources]]
+            name = "pokemon"
+            tokens = ["moltres"]
+            [[tests]]
+            name = "my_test"
+            resources = ["pokemon"]
+            command = "echo $LIMMAT_RESOURCE_pokemon $(git rev-parse HEAD) $LIMMAT_COMMIT"
+        "##,
+    )
+    .await
+    .unwrap()
+    .db_dir(db_dir.path().to_owned());
+
+    let mut child = builder.start(["repro", "my_test", "HEAD"]).await.unwrap();
+    timeout(Duration::from_secs(5), child.expect_exit_code(50))
+        .await
+        .expect("child didn't shut down")
+        .unwrap();
+
+    let mut child = builder
+        .start(["repro", "--run", "my_test", "HEAD"])
+        .await
+        .unwrap();
+    timeout(Duration::from_secs(10), child.expect_exit_code(0))
+        .await
+        .expect("child didn't shut down")
+        .unwrap();
+    let command = child.stdout().unwrap();
+    expect_that!(command, contains_substring(" env -i "));
+    expect_that!(
+        command,
+        contains_substring("'LIMMAT_RESOURCE_pokemon=moltres'")
+    );
+    expect_that!(child.stderr(), ok(contains_substring(" checked out")));
+
+    // The job ran in a temporary worktree, which is gone now.
+    expect_that!(child.stderr(), ok(contains_substring("no longer exists")));
+    let mut child = builder
+        .start(["repro", "--exec", "my_test", "HEAD"])
+        .await
+        .unwrap();
+    timeout(Duration::from_secs(5), child.expect_exit_code(1))
+        .await
+        .expect("child didn't shut down")
+        .unwrap();
+
+    let repo_dir = builder.repo_dir.to_str().unwrap();
+    let mut child = builder
+        .start(["repro", "--exec", "--cwd", repo_dir, "my_test", "HEAD"])
+        .await
+        .unwrap();
+    timeout(Duration::from_secs(5), child.expect_exit_code(0))
+        .await
+        .expect("child didn't shut down")
+        .unwrap();
+    let output = Command::new("git")
+        .current_dir(&builder.repo_dir)
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .await
+        .unwrap();
+    let head = String::from_utf8(output.stdout).unwrap().trim().to_owned();
+    let expected = format!("moltres {head} {head}\n");
+    expect_that!(child.stdout(), ok(eq(&expected)));
+
+    // The printed command does the same thing.
+    let mut child = builder
+        .start(["repro", "--cwd", repo_dir, "my_test", "HEAD"])
+        .await
+        .unwrap();
+    timeout(Duration::from_secs(5), child.expect_exit_code(0))
+        .await
+        .expect("child didn't shut down")
+        .unwrap();
+    let output = Command::new("sh")
+        .args(["-c", &child.stdout().unwrap()])
+        .output()
+        .await
+        .unwrap();
+    expect_that!(String::from_utf8(output.stdout), ok(eq(&expected)));
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn should_start_shell() {
+    let temp_dir = TempDir::new().unwrap();
+    let shell_path = temp_dir.path().join("shell.sh");
+    let env_path = temp_dir.path().join("env.txt");
+    fs::write(
+        &shell_path,
+        format!(
+            "#!/bin/sh\n{{ pwd; git rev-parse HEAD; echo $LIMMAT_RESOURCE_pokemon; \
+             cat $LIMMAT_ARTIFACTS_build/out; }} > {env_path:?}\nexit 3\n"
+        ),
+    )
+    .unwrap();
+    fs::set_permissions(&shell_path, fs::Permissions::from_mode(0o755)).unwrap();
+    let builder = LimmatChildBuilder::new(
+        r##"
+            [[resources]]
+            name = "pokemon"
+            tokens = ["moltres"]
+            [[tests]]
+            name = "build"
+            command = "echo built > $LIMMAT_ARTIFACTS/out"
+            [[tests]]
+            name = "my_test"
+            resources = ["pokemon"]
+            depends_on = ["build"]
+            command = "false"
+        "##,
+    )
+    .await
+    .unwrap()
+    .env("SHELL", shell_path.as_os_str());
+
+    let mut child = builder.start(["shell", "my_test", "HEAD^"]).await.unwrap();
+    timeout(Duration::from_secs(10), child.expect_exit_code(3))
+        .await
+        .expect("child didn't shut down")
+        .unwrap();
+    let output = Command::new("git")
+        .current_dir(&builder.repo_dir)
+        .args(["rev-parse", "HEAD^"])
+        .output()
+        .await
+        .unwrap();
+    let commit = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<String> = fs::read_to_string(&env_path)
+        .unwrap()
+        .lines()
+        .map(str::to_owned)
+        .collect();
+    assert_that!(lines.len(), eq(4));
+    // It's in a worktree, not the main repo.
+    expect_that!(lines[0], not(eq(builder.repo_dir.to_str().unwrap())));
+    expect_that!(lines[1], eq(commit.trim()));
+    expect_that!(lines[2], eq("moltres"));
+    expect_that!(lines[3], eq("built"));
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn should_list_tests() {
+    let builder = LimmatChildBuilder::new(
+        r##"
+            [[tests]]
+            name = "lint"
+            command = "true"
+            advisory = true
+            run_by_default = false
+            [[tests]]
+            name = "build"
+            command = "true"
+            description = "Checks that it compiles.\nAsk on the list if it's broken."
+            owner = "Alice <alice@example.com>"
+            help_url = "https://example.com/build"
+        "##,
+    )
+    .await
+    .unwrap();
+    let mut child = builder.start(["list"]).await.unwrap();
+    timeout(Duration::from_secs(5), child.expect_exit_code(0))
+        .await
+        .expect("child didn't shut down")
+        .unwrap();
+    expect_that!(
+        child.stdout(),
+        ok(eq("build\n    \
+            Checks that it compiles.\n    \
+            Ask on the list if it's broken.\n    \
+            Owner: Alice <alice@example.com>\n    \
+            Help: https://example.com/build\n\
+            lint (not run by default, advisory)\n"))
+    );
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn should_gc() {
+    let db_dir = TempDir::with_prefix("result-db").unwrap();
+    let builder = LimmatChildBuilder::new(
+        r##"
+            [[tests]]
+            name = "my_test"
+            command = "head -c 100000 /dev/zero > $LIMMAT_ARTIFACTS/blob"
+            storage_quota = "150K"
+        "##,
+    )
+    .await
+    .unwrap()
+    .db_dir(db_dir.path().to_owned());
+    // Each result is a bit over 100K, so only the newest fits in the quota.
+    for rev in ["HEAD^", "HEAD"] {
+        let mut child = builder
+            .start(["artifacts", "--run", "my_test", rev])
+            .await
+            .unwrap();
+        timeout(Duration::from_secs(5), child.expect_exit_code(0))
+            .await
+            .expect("child didn't shut down")
+            .unwrap();
+    }
+
+    let mut child = builder.start(["gc", "--plan"]).await.unwrap();
+    timeout(Duration::from_secs(5), child.expect_exit_code(0))
+        .await
+        .expect("child didn't shut down")
+        .unwrap();
+    let stdout = child.stdout().unwrap();
+    expect_that!(stdout.lines().count(), eq(1));
+    expect_that!(child.stderr(), ok(contains_substring("Would free")));
+    let planned = stdout
+        .strip_prefix("my_test: ")
+        .and_then(|line| line.split(" (").next())
+        .unwrap();
+    expect_true!(Path::new(planned).exists());
+
+    let mut child = builder.start(["gc"]).await.unwrap();
+    timeout(Duration::from_secs(5), child.expect_exit_code(0))
+        .await
+        .expect("child didn't shut down")
+        .unwrap();
+    for (rev, want_exit_code) in [("HEAD^", 50), ("HEAD", 0)] {
+        let mut child = builder.start(["artifacts", "my_test", rev]).await.unwrap();
+        timeout(
+            Duration::from_secs(5),
+            child.expect_exit_code(want_exit_code),
+        )
+        .await
+        .expect("child didn't shut down")
+        .unwrap();
+    }
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn should_install_hooks() {
+    let db_dir = TempDir::with_prefix("result-db").unwrap();
+    let builder = LimmatChildBuilder::new(
+        r##"
+            num_worktrees = 1
+            [[tests]]
+            name = "my_test"
+            command = "true"
+        "##,
+    )
+    .await
+    .unwrap()
+    .db_dir(db_dir.path().to_owned());
+    let mut child = builder.start(["install-hooks"]).await.unwrap();
+    timeout(Duration::from_secs(5), child.expect_exit_code(0))
+        .await
+        .expect("child didn't shut down")
+        .unwrap();
+    let hook_path = builder.repo_dir.join(".git/hooks/pre-push");
+    let hook = fs::read_to_string(&hook_path).unwrap();
+    expect_that!(hook, contains_substring("limmat gate --no-run "));
+    expect_that!(
+        fs::metadata(&hook_path).unwrap().permissions().mode() & 0o111,
+        eq(0o111)
+    );
+    // Doesn't clobber an existing hook.
+    let mut child = builder.start(["install-hooks"]).await.unwrap();
+    timeout(Duration::from_secs(5), child.expect_exit_code(1))
+        .await
+        .expect("child didn't shut down")
+        .unwrap();
+
+    // Nothing has been run yet, so the hook would refuse to push.
+    let mut child = builder.start(["gate", "--no-run", "HEAD"]).await.unwrap();
+    timeout(Duration::from_secs(5), child.expect_exit_code(1))
+        .await
+        .expect("child didn't shut down")
+        .unwrap();
+    expect_that!(
+        child.stdout(),
+        ok(contains_substring(" my_test: no result\n"))
+    );
+    let mut child = builder.start(["gate", "HEAD"]).await.unwrap();
+    timeout(Duration::from_secs(10), child.expect_exit_code(0))
+        .await
+        .expect("child didn't shut down")
+        .unwrap();
+    let mut child = builder.start(["gate", "--no-run", "HEAD"]).await.unwrap();
+    timeout(Duration::from_secs(5), child.expect_exit_code(0))
+        .await
+        .expect("child didn't shut down")
+        .unwrap();
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn should_show_metrics() {
+    let db_dir = TempDir::with_prefix("result-db").unwrap();
+    let builder = LimmatChildBuilder::new(
+        r##"
+            num_worktrees = 1
+            [[tests]]
+            name = "my_test"
+            command = "echo commits=$(git rev-list --count HEAD) > $LIMMAT_METRICS"
+        "##,
+    )
+    .await
+    .unwrap()
+    .db_dir(db_dir.path().to_owned());
+    for rev in ["HEAD^", "HEAD"] {
+        let mut child = builder
+            .start(["get", "--run", "my_test", rev])
+            .await
+            .unwrap();
+        timeout(Duration::from_secs(5), child.expect_exit_code(0))
+            .await
+            .expect("child didn't shut down")
+            .unwrap();
+    }
+
+    let mut child = builder
+        .start(["metrics", "export", "HEAD~2..HEAD"])
+        .await
+        .unwrap();
+    timeout(Duration::from_secs(5), child.expect_exit_code(0))
+        .await
+        .expect("child didn't shut down")
+        .unwrap();
+    let stdout = child.stdout().unwrap();
+    let rows: Vec<_> = stdout
+        .lines()
+        .map(|line| line.split(',').skip(1).collect::<Vec<_>>().join(","))
+        .collect();
+    expect_that!(
+        rows,
+        elements_are![
+            eq("test,metric,value"),
+            eq("my_test,commits,4"),
+            eq("my_test,commits,5")
+        ]
+    );
+
+    let mut child = builder
+        .start(["metrics", "plot", "HEAD~2..HEAD"])
+        .await
+        .unwrap();
+    timeout(Duration::from_secs(5), child.expect_exit_code(0))
+        .await
+        .expect("child didn't shut down")
+        .unwrap();
+    let stdout = child.stdout().unwrap();
+    expect_that!(stdout, starts_with("my_test: commits\n"));
+    // Scaled to the biggest value.
+    expect_that!(
+        stdout,
+        contains_substring(format!(" {:40} 4\n", "█".repeat(32)))
+    );
+    expect_that!(
+        stdout,
+        contains_substring(format!(" {} 5\n", "█".repeat(40)))
+    );
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn should_find_not_race() {
+    let tmp_dir = TempDir::new().unwrap();
+    let db_dir = TempDir::with_prefix("result-db").unwrap();
+
+    let builder = LimmatChildBuilder::new(format!(
+        r##"
+            num_worktrees = 1
+            [[tests]]
+            name = "my_test"
+            command = "touch {}/started.$$.$LIMMAT_COMMIT && sleep 1"
+            shutdown_grace_period_s = 1
+        "##,
+        tmp_dir.path().display()
+    ))
+    .await
+    .unwrap()
+    .db_dir(db_dir.path().to_owned())
+    // Hack - this is making for annoying log spam when it fails at the moment.
+    .dump_output_on_panic(false);
+    let mut watch_children = Vec::new();
+    for _ in 0..16 {
+        let child = builder.start(["watch", "HEAD^"]).await.unwrap();
+        watch_children.push(child);
+    }
+    let mut get_children: Vec<LimmatChild> = Vec::new();
+    for _ in 0..16 {
+        let child = builder
+            .start(["get", "--run", "my_test", "HEAD"])
+            .await
+            .unwrap();
+        get_children.push(child);
+    }
+
+    for mut child in get_children.into_iter() {
+        timeout(Duration::from_secs(5), child.expect_exit_code(0))
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    // Even though we ran loads of instances of Limmat, only one of them should
+    // have ran the job.
+    let pattern = tmp_dir.path().join("started.*").to_owned();
+    expect_that!(
+        glob(pattern.to_string_lossy().as_ref())
+            .unwrap()
+            .collect::<Vec<_>>(),
+        len(eq(1))
+    );
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn limmat_artifacts_test_cmd() {
+    let temp_dir = TempDir::new().unwrap();
+
+    let builder = LimmatChildBuilder::new(
+        r##"
+            num_worktrees = 1
+            [[tests]]
+            name = "my_test"
+            command = "echo hwat >> $LIMMAT_ARTIFACTS/foo"
+        "##,
+    )
+    .await
+    .unwrap()
+    .env("TMPDIR", temp_dir.path().as_os_str());
+    let mut child = builder.start(["test", "my_test"]).await.unwrap();
+    timeout(Duration::from_secs(5), child.expect_exit_code(0))
+        .await
+        .expect("child didn't shut down")
+        .unwrap();
+
+    // The "test" command dumps artifacts into a temp directory, there should be
+    // a single file in the temp directory (in a subdir) which should be a
+    // single file.
+    let mut artifacts_dir_files: Vec<PathBuf> = glob(
+        &temp_dir
+            .path()
+            .join("**")
+            .join("artifacts")
+            .join("*")
+            .to_string_lossy(),
+    )
+    .expect("failed to glob")
+    .collect::<result::Result<Vec<PathBuf>, GlobError>>()
+    .expect("error in glob result")
+    .into_iter()
+    .filter(|p| !p.is_dir())
+    .collect();
+    assert_that!(artifacts_dir_files, len(eq(1)));
+    let artifact_path = artifacts_dir_files.pop().unwrap();
+    expect_that!(artifact_path.file_name(), some(eq("foo")));
+    expect_that!(
+        artifact_path
+            .parent()
+            .and_then(|p| p.parent())
+            .and_then(|p| p.parent()),
+        some(eq(temp_dir.path()))
+    );
+    expect_that!(fs::read_to_string(artifact_path), ok(eq("hwat\n")));
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn artifacts_cmd() {
+    let temp_dir = TempDir::new().unwrap();
+
+    let builder = LimmatChildBuilder::new(
+        r##"
+            num_worktrees = 1
+            [[tests]]
+            name = "my_test"
+            command = "echo hwat >> $LIMMAT_ARTIFACTS/foo"
+        "##,
+    )
+    .await
+    .unwrap()
+    .env("TMPDIR", temp_dir.path().as_os_str());
+    let mut child = builder.start(["test", "my_test"]).await.unwrap();
+    timeout(Duration::from_secs(5), child.expect_exit_code(0))
+        .await
+        .expect("child didn't shut down")
+        .unwrap();
+
+    // The "test" command dumps artifacts into a temp directory, there should be
+    // a single file in the temp directory (in a subdir) which should be a
+    // single file.
+    let mut artifacts_files: Vec<PathBuf> = glob(
+        &temp_dir
+            .path()
+            .join("**")
+            .join("artifacts")
+            .join("*")
+            .to_string_lossy(),
+    )
+    .expect("failed to glob")
+    .collect::<result::Result<Vec<PathBuf>, GlobError>>()
+    .expect("error in glob result")
+    .into_iter()
+    .filter(|p| !p.is_dir())
+    .collect();
+    assert_that!(artifacts_files, len(eq(1)));
+    let artifact_path = artifacts_files.pop().unwrap();
+    expect_that!(artifact_path.file_name(), some(eq("foo")));
+    expect_that!(
+        artifact_path
+            .parent()
+            .and_then(|p| p.parent())
+            .and_then(|p| p.parent()),
+        some(eq(temp_dir.path()))
+    );
+    expect_that!(fs::read_to_string(artifact_path), ok(eq("hwat\n")));
+}
+static DEP_CONFIG: &str = r##"
+    num_worktrees = 1
+
+    [[tests]]
+    name = "dep"
+    command = "echo 'ye mighty' >> $LIMMAT_ARTIFACTS/ozy"
+
+    [[tests]]
+    name = "dep2"
+    command = "echo 'and dispair' >> $LIMMAT_ARTIFACTS/trunkless"
+
+    [[tests]]
+    name = "main"
+    depends_on = ["dep", "dep2"]
+    command = "cat $LIMMAT_ARTIFACTS_dep/ozy $LIMMAT_ARTIFACTS_dep2/trunkless"
+    separate_outputs = true
+"##;
+
+#[googletest::test]
+#[tokio::test]
+async fn dependency_artifacts_get_cmd() {
+    let builder = LimmatChildBuilder::new(DEP_CONFIG.to_string())
+        .await
+        .unwrap();
+    let mut child = builder
+        .start(["get", "--run", "main", "HEAD^", "stdout"])
+        .await
+        .unwrap();
+    timeout(Duration::from_secs(5), child.expect_exit_code(0))
+        .await
+        .expect("child didn't shut down")
+        .unwrap();
+    expect_that!(
+        fs::read_to_string(child.stdout().unwrap().trim()),
+        ok(eq("ye mighty\nand dispair\n"))
+    );
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn dependency_artifacts_test_cmd() {
+    let builder = LimmatChildBuilder::new(DEP_CONFIG.to_string())
+        .await
+        .unwrap();
+    let mut child = builder.start(["test", "main"]).await.unwrap();
+    timeout(Duration::from_secs(5), child.expect_exit_code(0))
+        .await
+        .expect("child didn't shut down")
+        .unwrap();
+    expect_that!(child.stdout().unwrap(), eq("ye mighty\nand dispair\n"));
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn test_cmd_prefixes_dep_output() {
+    let builder = LimmatChildBuilder::new(
+        r##"
+            num_worktrees = 2
+            [[tests]]
+            name = "dep"
+            command = "echo to stdout; echo to stderr >&2"
+            [[tests]]
+            name = "other_dep"
+            command = "printf 'no newline'"
+            [[tests]]
+            name = "main"
+            depends_on = ["dep", "other_dep"]
+            command = "echo main"
+        "##,
+    )
+    .await
+    .unwrap();
+    let mut child = builder.start(["test", "main"]).await.unwrap();
+    timeout(Duration::from_secs(5), child.expect_exit_code(0))
+        .await
+        .expect("child didn't shut down")
+        .unwrap();
+    expect_that!(child.stdout().unwrap(), eq("main\n"));
+    let stderr = child.stderr().unwrap();
+    expect_that!(stderr, contains_substring("      dep | to stdout\n"));
+    expect_that!(stderr, contains_substring("      dep | to stderr\n"));
+    expect_that!(stderr, contains_substring("other_dep | no newline\n"));
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn test_cmd_rev() {
+    let temp_dir = TempDir::new().unwrap();
+    let builder = LimmatChildBuilder::new(format!(
+        r##"
+            [[tests]]
+            name = "test"
+            command = "echo $LIMMAT_COMMIT >> {}/ran"
+        "##,
+        temp_dir.path().display(),
+    ))
+    .await
+    .unwrap();
+    let output = Command::new("git")
+        .current_dir(&builder.repo_dir)
+        .args(["rev-parse", "HEAD^"])
+        .output()
+        .await
+        .unwrap();
+    let want_commit = String::from_utf8(output.stdout).unwrap();
+
+    // Second time around the result should come from the cache.
+    for _ in 0..2 {
+        let mut child = builder
+            .start(["test", "test", "--rev", "HEAD^"])
+            .await
+            .unwrap();
+        timeout(Duration::from_secs(5), child.expect_exit_code(0))
+            .await
+            .expect("child didn't shut down")
+            .unwrap();
+    }
+    expect_that!(
+        fs::read_to_string(temp_dir.path().join("ran")),
+        ok(eq(&want_commit))
+    );
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn run_across() {
+    let builder = LimmatChildBuilder::new(
+        r##"
+            [[tests]]
+            name = "unused"
+            command = "false"
+        "##,
+    )
+    .await
+    .unwrap();
+    let output = Command::new("git")
+        .current_dir(&builder.repo_dir)
+        .args(["rev-parse", "HEAD^"])
+        .output()
+        .await
+        .unwrap();
+    let bad_commit = String::from_utf8(output.stdout).unwrap().trim().to_owned();
+
+    let check = format!("[ $LIMMAT_COMMIT != {bad_commit} ]");
+    let mut child = builder
+        .start(["run-across", "HEAD~3..HEAD", "--", "sh", "-c", &check])
+        .await
+        .unwrap();
+    timeout(Duration::from_secs(10), child.expect_exit_code(1))
+        .await
+        .expect("child didn't shut down")
+        .unwrap();
+    let stdout = child.stdout().unwrap();
+    expect_that!(stdout.lines().count(), eq(3));
+    expect_that!(
+        stdout,
+        contains_substring(format!("{} run-across: exit code 1\n", &bad_commit[..12]))
+    );
+    expect_that!(stdout.matches("exit code 0").count(), eq(2));
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn error_exit_code_tests() {
+    let temp_dir = TempDir::new().unwrap();
+    let builder = LimmatChildBuilder::new(format!(
+        r##"
+            num_worktrees = 1
+            [[tests]]
+            name = "test"
+            error_exit_codes = [2]
+            command = "echo >> {}/ran; exit 2"
+        "##,
+        temp_dir.path().display(),
+    ))
+    .await
+    .unwrap();
+
+    let mut child = builder.start(["test", "test"]).await.unwrap();
+    // `limmat test` exits with 1 for any error.
+    timeout(Duration::from_secs(5), child.expect_exit_code(1))
+        .await
+        .expect("child didn't shut down")
+        .unwrap();
+    assert_that!(
+        fs::read_to_string(temp_dir.path().join("ran")),
+        ok(eq("\n"))
+    );
+
+    // Run it a second time, it should get run again.
+    let mut child = builder.start(["test", "test"]).await.unwrap();
+    timeout(Duration::from_secs(5), child.expect_exit_code(1))
+        .await
+        .expect("child didn't shut down")
+        .unwrap();
+    expect_that!(
+        fs::read_to_string(temp_dir.path().join("ran")),
+        ok(eq("\n\n")),
+        "Erroring test not re-ran?"
+    );
+}
+
+#[test_case(0, 0, "1/1 tests succeeded" ; "success")]
+#[test_case(3, 1, "0/1 tests succeeded" ; "failure")]
+#[googletest::test]
+#[tokio::test]
+async fn watch_once(test_exit_code: i32, want_exit_code: i32, want_summary: &str) {
+    let builder = LimmatChildBuilder::new(format!(
+        r##"
+            [[tests]]
+            name = "test"
+            command = "exit {test_exit_code}"
+        "##
+    ))
+    .await
+    .unwrap();
+
+    let mut child = builder.start(["watch", "--once", "HEAD^"]).await.unwrap();
+    timeout(
+        Duration::from_secs(10),
+        child.expect_exit_code(want_exit_code),
+    )
+    .await
+    .expect("child didn't shut down")
+    .unwrap();
+    expect_that!(child.stdout().unwrap(), contains_substring(want_summary));
+}
+
+#[googletest::test]
+#[tokio::test]
+async fn watch_default_upstream() {
+    let builder = LimmatChildBuilder::new(
+        r##"
+            [[tests]]
+            name = "test"
+            command = "true"
+        "##,
+    )
+    .await
+    .unwrap();
+
+    let mut child = builder.start(["watch", "--once"]).await.unwrap();
+    timeout(Duration::from_secs(10), child.expect_exit_code(1))
+        .await
+        .expect("child didn't shut down")
+        .unwrap();
+
+    for args in [
+        &["branch", "upstream", "HEAD^"][..],
+        &["branch", "--set-upstream-to=upstream"],
+    ] {
+        Command::new("git")
+            .current_dir(&builder.repo_dir)
+            .args(args)
+            .stdout(Stdio::null())
+            .status()
+            .await
+            .unwrap()
+            .check_exit_ok()
+            .unwrap();
+    }
+    let mut child = builder.start(["watch", "--once"]).await.unwrap();
+    timeout(Duration::from_secs(10), child.expect_exit_code(0))
+        .await
+        .expect("child didn't shut down")
+        .unwrap();
+    expect_that!(
+        child.stdout().unwrap(),
+        contains_substring("1/1 tests succeeded")
+    );
+}
 
-    let builder = LimmatChildBuilder::new(format!(
+#[googletest::test]
+#[tokio::test]
+async fn watch_stack() {
+    let builder = LimmatChildBuilder::new(
         r##"
-            num_worktrees = 1
             [[tests]]
-            name = "my_test"
-            command = "touch {}/started.$$.$LIMMAT_COMMIT && sleep 1"
-            shutdown_grace_period_s = 1
+            name = "test"
+            command = "true"
         "##,
-        tmp_dir.path().display()
-    ))
+    )
     .await
-    .unwrap()
-    .db_dir(db_dir.path().to_owned())
-    // Hack - this is making for annoying log spam when it fails at the moment.
-    .dump_output_on_panic(false);
-    let mut watch_children = Vec::new();
-    for _ in 0..16 {
-        let child = builder.start(["watch", "HEAD^"]).await.unwrap();
-        watch_children.push(child);
-    }
-    let mut get_children: Vec<LimmatChild> = Vec::new();
-    for _ in 0..16 {
-        let child = builder
-            .start(["get", "--run", "my_test", "HEAD"])
-            .await
-            .unwrap();
-        get_children.push(child);
-    }
-
-    for mut child in get_children.into_iter() {
-        timeout(Duration::from_secs(5), child.expect_exit_code(0))
+    .unwrap();
+    for args in [
+        &["checkout", "-q", "-b", "upper"][..],
+        &["commit", "-q", "--allow-empty", "-m", "upper"],
+        &["checkout", "-q", "-"],
+    ] {
+        Command::new("git")
+            .current_dir(&builder.repo_dir)
+            .args(args)
+            .status()
             .await
             .unwrap()
+            .check_exit_ok()
             .unwrap();
     }
 
-    // Even though we ran loads of instances of Limmat, only one of them should
-    // have ran the job.
-    let pattern = tmp_dir.path().join("started.*").to_owned();
+    let mut child = builder
+        .start(["watch", "--once", "--stack", "HEAD^"])
+        .await
+        .unwrap();
+    timeout(Duration::from_secs(10), child.expect_exit_code(0))
+        .await
+        .expect("child didn't shut down")
+        .unwrap();
     expect_that!(
-        glob(pattern.to_string_lossy().as_ref())
-            .unwrap()
-            .collect::<Vec<_>>(),
-        len(eq(1))
+        child.stdout().unwrap(),
+        contains_substring("2/2 tests succeeded")
     );
 }
 
 #[googletest::test]
 #[tokio::test]
-async fn limmat_artifacts_test_cmd() {
-    let temp_dir = TempDir::new().unwrap();
-
+async fn watch_also_watch() {
     let builder = LimmatChildBuilder::new(
         r##"
-            num_worktrees = 1
             [[tests]]
-            name = "my_test"
-            command = "echo hwat >> $LIMMAT_ARTIFACTS/foo"
+            name = "test"
+            command = "true"
         "##,
     )
     .await
-    .unwrap()
-    .env("TMPDIR", temp_dir.path().as_os_str());
-    let mut child = builder.start(["test", "my_test"]).await.unwrap();
-    timeout(Duration::from_secs(5), child.expect_exit_code(0))
+    .unwrap();
+    let other_dir = builder.temp_dir.path().join("other");
+    create_dir(&other_dir).unwrap();
+    LimmatChildBuilder::init_test_repo(&other_dir)
         .await
-        .expect("child didn't shut down")
         .unwrap();
-
-    // The "test" command dumps artifacts into a temp directory, there should be
-    // a single file in the temp directory (in a subdir) which should be a
-    // single file.
-    let mut artifacts_dir_files: Vec<PathBuf> = glob(
-        &temp_dir
-            .path()
-            .join("**")
-            .join("artifacts")
-            .join("*")
-            .to_string_lossy(),
+    fs::write(
+        other_dir.join("limmat.toml"),
+        r##"
+            [[tests]]
+            name = "other_test"
+            command = "false"
+        "##,
     )
-    .expect("failed to glob")
-    .collect::<result::Result<Vec<PathBuf>, GlobError>>()
-    .expect("error in glob result")
-    .into_iter()
-    .filter(|p| !p.is_dir())
-    .collect();
-    assert_that!(artifacts_dir_files, len(eq(1)));
-    let artifact_path = artifacts_dir_files.pop().unwrap();
-    expect_that!(artifact_path.file_name(), some(eq("foo")));
+    .unwrap();
+
+    let also_watch = format!("{}:HEAD~2", other_dir.display());
+    let mut child = builder
+        .start(["watch", "--once", "HEAD^", "--also-watch", &also_watch])
+        .await
+        .unwrap();
+    timeout(Duration::from_secs(10), child.expect_exit_code(1))
+        .await
+        .expect("child didn't shut down")
+        .unwrap();
     expect_that!(
-        artifact_path
-            .parent()
-            .and_then(|p| p.parent())
-            .and_then(|p| p.parent()),
-        some(eq(temp_dir.path()))
+        child.stdout().unwrap(),
+        contains_substring("1/3 tests succeeded")
     );
-    expect_that!(fs::read_to_string(artifact_path), ok(eq("hwat\n")));
 }
 
 #[googletest::test]
 #[tokio::test]
-async fn artifacts_cmd() {
-    let temp_dir = TempDir::new().unwrap();
-
+async fn cpus() {
     let builder = LimmatChildBuilder::new(
         r##"
-            num_worktrees = 1
             [[tests]]
-            name = "my_test"
-            command = "echo hwat >> $LIMMAT_ARTIFACTS/foo"
+            name = "test"
+            cpus = 1
+            command = '''
+                allowed=$(grep Cpus_allowed_list /proc/self/status | cut -f2)
+                [[ "$LIMMAT_CPUS" =~ ^[0-9]+$ ]] && [ "$allowed" = "$LIMMAT_CPUS" ]
+            '''
         "##,
     )
     .await
-    .unwrap()
-    .env("TMPDIR", temp_dir.path().as_os_str());
-    let mut child = builder.start(["test", "my_test"]).await.unwrap();
-    timeout(Duration::from_secs(5), child.expect_exit_code(0))
+    .unwrap();
+
+    let mut child = builder.start(["watch", "--once", "HEAD^"]).await.unwrap();
+    timeout(Duration::from_secs(10), child.expect_exit_code(0))
         .await
         .expect("child didn't shut down")
         .unwrap();
+}
 
-    // The "test" command dumps artifacts into a temp directory, there should be
-    // a single file in the temp directory (in a subdir) which should be a
-    // single file.
-    let mut artifacts_files: Vec<PathBuf> = glob(
-        &temp_dir
-            .path()
-            .join("**")
-            .join("artifacts")
-            .join("*")
-            .to_string_lossy(),
+#[googletest::test]
+#[tokio::test]
+async fn git_notes() {
+    let builder = LimmatChildBuilder::new(
+        r##"
+            git_notes = true
+            [[tests]]
+            name = "pass"
+            command = "true"
+            [[tests]]
+            name = "fail"
+            command = "exit 4"
+        "##,
     )
-    .expect("failed to glob")
-    .collect::<result::Result<Vec<PathBuf>, GlobError>>()
-    .expect("error in glob result")
-    .into_iter()
-    .filter(|p| !p.is_dir())
-    .collect();
-    assert_that!(artifacts_files, len(eq(1)));
-    let artifact_path = artifacts_files.pop().unwrap();
-    expect_that!(artifact_path.file_name(), some(eq("foo")));
+    .await
+    .unwrap();
+
+    let mut child = builder.start(["watch", "--once", "HEAD^"]).await.unwrap();
+    timeout(Duration::from_secs(10), child.expect_exit_code(1))
+        .await
+        .expect("child didn't shut down")
+        .unwrap();
+    let output = Command::new("git")
+        .current_dir(&builder.repo_dir)
+        .args(["notes", "--ref", "limmat", "show", "HEAD"])
+        .output()
+        .await
+        .unwrap();
     expect_that!(
-        artifact_path
-            .parent()
-            .and_then(|p| p.parent())
-            .and_then(|p| p.parent()),
-        some(eq(temp_dir.path()))
+        String::from_utf8(output.stdout).unwrap(),
+        eq("fail: exit code 4\npass: exit code 0\n")
     );
-    expect_that!(fs::read_to_string(artifact_path), ok(eq("hwat\n")));
 }
-static DEP_CONFIG: &str = r##"
-    num_worktrees = 1
-
-    [[tests]]
-    name = "dep"
-    command = "echo 'ye mighty' >> $LIMMAT_ARTIFACTS/ozy"
-
-    [[tests]]
-    name = "dep2"
-    command = "echo 'and dispair' >> $LIMMAT_ARTIFACTS/trunkless"
-
-    [[tests]]
-    name = "main"
-    depends_on = ["dep", "dep2"]
-    command = "cat $LIMMAT_ARTIFACTS_dep/ozy $LIMMAT_ARTIFACTS_dep2/trunkless"
-    separate_outputs = true
-"##;
 
 #[googletest::test]
 #[tokio::test]
-async fn dependency_artifacts_get_cmd() {
-    let builder = LimmatChildBuilder::new(DEP_CONFIG.to_string())
+async fn git_notes_with_shard() {
+    // Enough tests that the shard is very unlikely to be empty.
+    let tests: String = (0..16)
+        .map(|i| format!("[[tests]]\nname = \"test_{i}\"\ncommand = \"true\"\n"))
+        .collect();
+    let builder = LimmatChildBuilder::new(format!("git_notes = true\n{tests}"))
         .await
         .unwrap();
+
     let mut child = builder
-        .start(["get", "--run", "main", "HEAD^", "stdout"])
+        .start(["watch", "--once", "--shard", "1/2", "HEAD^"])
         .await
         .unwrap();
-    timeout(Duration::from_secs(5), child.expect_exit_code(0))
+    timeout(Duration::from_secs(10), child.expect_exit_code(0))
         .await
         .expect("child didn't shut down")
         .unwrap();
-    expect_that!(
-        fs::read_to_string(child.stdout().unwrap().trim()),
-        ok(eq("ye mighty\nand dispair\n"))
-    );
+    let output = Command::new("git")
+        .current_dir(&builder.repo_dir)
+        .args(["notes", "--ref", "limmat", "show", "HEAD"])
+        .output()
+        .await
+        .unwrap();
+    let note = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<&str> = note.lines().collect();
+    // Only the tests in this shard, so not all of them.
+    expect_that!(lines.len(), gt(0));
+    expect_that!(lines.len(), lt(16));
+    expect_that!(lines, each(ends_with(": exit code 0")));
 }
 
 #[googletest::test]
 #[tokio::test]
-async fn dependency_artifacts_test_cmd() {
-    let builder = LimmatChildBuilder::new(DEP_CONFIG.to_string())
+async fn pin_cmd() {
+    let builder = LimmatChildBuilder::new(
+        r##"
+            [[tests]]
+            name = "release_build"
+            command = "true"
+        "##,
+    )
+    .await
+    .unwrap();
+    let mut child = builder
+        .start(["pin", "release_build", "HEAD"])
+        .await
+        .unwrap();
+    timeout(Duration::from_secs(5), child.expect_exit_code(50))
+        .await
+        .expect("child didn't shut down")
+        .unwrap();
+
+    let mut child = builder
+        .start(["pin", "--run", "release_build", "HEAD"])
         .await
         .unwrap();
-    let mut child = builder.start(["test", "main"]).await.unwrap();
     timeout(Duration::from_secs(5), child.expect_exit_code(0))
         .await
         .expect("child didn't shut down")
         .unwrap();
-    expect_that!(child.stdout().unwrap(), eq("ye mighty\nand dispair\n"));
+    let mut child = builder
+        .start(["artifacts", "release_build", "HEAD"])
+        .await
+        .unwrap();
+    timeout(Duration::from_secs(5), child.expect_exit_code(0))
+        .await
+        .expect("child didn't shut down")
+        .unwrap();
+    let pinned_path = Path::new(child.stdout().unwrap().trim()).with_file_name("pinned");
+    expect_true!(pinned_path.exists());
+
+    let mut child = builder
+        .start(["pin", "--unpin", "release_build", "HEAD"])
+        .await
+        .unwrap();
+    timeout(Duration::from_secs(5), child.expect_exit_code(0))
+        .await
+        .expect("child didn't shut down")
+        .unwrap();
+    expect_false!(pinned_path.exists());
 }
 
 #[googletest::test]
 #[tokio::test]
-async fn error_exit_code_tests() {
+async fn attestation() {
     let temp_dir = TempDir::new().unwrap();
-    let builder = LimmatChildBuilder::new(format!(
+    let key = temp_dir.path().join("key");
+    let status = Command::new("ssh-keygen")
+        .args(["-q", "-t", "ed25519", "-N", "", "-f"])
+        .arg(&key)
+        .status()
+        .await
+        .unwrap();
+    assert!(status.success(), "ssh-keygen failed");
+    let pubkey = fs::read_to_string(temp_dir.path().join("key.pub")).unwrap();
+    let allowed_signers = temp_dir.path().join("allowed_signers");
+    fs::write(&allowed_signers, format!("tester@example.com {pubkey}")).unwrap();
+    let attestation = temp_dir.path().join("attestation.json");
+
+    let builder = LimmatChildBuilder::new(
         r##"
-            num_worktrees = 1
             [[tests]]
-            name = "test"
-            error_exit_codes = [2]
-            command = "echo >> {}/ran; exit 2"
+            name = "expensive"
+            command = "true"
         "##,
-        temp_dir.path().display(),
-    ))
+    )
     .await
     .unwrap();
+    let mut child = builder
+        .start([
+            "attest",
+            "--run",
+            "expensive",
+            "HEAD",
+            "--key",
+            key.to_str().unwrap(),
+            "--output",
+            attestation.to_str().unwrap(),
+        ])
+        .await
+        .unwrap();
+    timeout(Duration::from_secs(5), child.expect_exit_code(0))
+        .await
+        .expect("child didn't shut down")
+        .unwrap();
 
-    let mut child = builder.start(["test", "test"]).await.unwrap();
-    // `limmat test` exits with 1 for any error.
-    timeout(Duration::from_secs(5), child.expect_exit_code(1))
+    let verify_args = [
+        "verify-attestation",
+        attestation.to_str().unwrap(),
+        "--allowed-signers",
+        allowed_signers.to_str().unwrap(),
+        "--rev",
+        "HEAD",
+    ];
+    let mut child = builder.start(verify_args).await.unwrap();
+    timeout(Duration::from_secs(5), child.expect_exit_code(0))
         .await
         .expect("child didn't shut down")
         .unwrap();
-    assert_that!(
-        fs::read_to_string(temp_dir.path().join("ran")),
-        ok(eq("\n"))
+    expect_that!(
+        child.stdout().unwrap(),
+        contains_substring("signed by tester@example.com")
     );
 
-    // Run it a second time, it should get run again.
-    let mut child = builder.start(["test", "test"]).await.unwrap();
+    let tampered = fs::read_to_string(&attestation)
+        .unwrap()
+        .replace("\"host\": \"", "\"host\": \"not-");
+    fs::write(&attestation, tampered).unwrap();
+    let mut child = builder.start(verify_args).await.unwrap();
     timeout(Duration::from_secs(5), child.expect_exit_code(1))
         .await
         .expect("child didn't shut down")
         .unwrap();
-    expect_that!(
-        fs::read_to_string(temp_dir.path().join("ran")),
-        ok(eq("\n\n")),
-        "Erroring test not re-ran?"
-    );
 }
 
 #[googletest::test]